@@ -0,0 +1,32 @@
+extends MeshInstance3D
+
+# Draws the light the Display's last recompute let through as see-through quads, one layer
+# of the lit volume per depth. Set capture_lit_volume on the Display for it to have any
+
+@export var display: Display
+@export var color = Color(1.0, 0.9, 0.6, 0.08)
+
+func _ready():
+	var material = StandardMaterial3D.new()
+	material.shading_mode = BaseMaterial3D.SHADING_MODE_UNSHADED
+	material.transparency = BaseMaterial3D.TRANSPARENCY_ALPHA
+	material.cull_mode = BaseMaterial3D.CULL_DISABLED
+	material.vertex_color_use_as_albedo = true
+	material_override = material
+	display.recompute_finished.connect(_rebuild)
+
+func _rebuild(_elapsed_usec):
+	var immediate = ImmediateMesh.new()
+	var to_local = global_transform.affine_inverse()
+	var started = false
+	for item in display.get_lit_volume_quads():
+		for quad in item["quads"]:
+			if not started:
+				immediate.surface_begin(Mesh.PRIMITIVE_TRIANGLES)
+				started = true
+			for corner in [0, 1, 2, 0, 2, 3]:
+				immediate.surface_set_color(color)
+				immediate.surface_add_vertex(to_local * quad[corner])
+	if started:
+		immediate.surface_end()
+	mesh = immediate
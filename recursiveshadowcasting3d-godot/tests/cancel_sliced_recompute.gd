@@ -0,0 +1,65 @@
+extends SceneTree
+
+# Starts sliced recomputes, cancels them partway through and checks that the visibility still
+# holds the last complete recompute's cells. Run after building the extension, from the
+# repository root:
+#   godot --headless --path recursiveshadowcasting3d-godot --script res://tests/cancel_sliced_recompute.gd
+
+var failures = []
+var cancelled = []
+
+func _initialize():
+	run()
+
+func run():
+	var display = Display.new()
+	root.add_child(display)
+	display.recompute_cancelled.connect(func(origin): cancelled.append(origin))
+	# Scattered pillars, so that the views split up into many pieces
+	for x in range(20, 80, 3):
+		for z in range(20, 80, 4):
+			for y in range(40, 60, 5):
+				display.set_occluded(Vector3i(x, y, z))
+
+	display.set_origin_and_recompute(Vector3(50, 50, 50))
+	var before = display.get_visible_positions()
+
+	# A microsecond a frame scans a view or so, so the recompute is far from done when cancelled
+	var far = Vector3(31, 45, 62)
+	display.start_sliced_recompute(far, 1)
+	await create_timer(0.005).timeout
+	await process_frame
+	check(display.is_recompute_pending(), "the sliced recompute is still in progress")
+	check(display.get_visible_positions() == before, "cells seen partway through stay hidden")
+	check(display.cancel_pending_recompute(), "there was a recompute to cancel")
+	check(not display.is_recompute_pending(), "the cancelled recompute is gone")
+	check(cancelled == [far], "recompute_cancelled was emitted with its origin")
+	check(display.get_visible_positions() == before, "the last complete result is kept")
+	check(not display.cancel_pending_recompute(), "there is nothing left to cancel")
+	await process_frame
+	check(display.get_visible_positions() == before, "nothing of it is applied later")
+
+	# Starting a recompute from the eye cancels the sliced one in progress
+	display.start_sliced_recompute(far, 1)
+	await process_frame
+	display.set_origin_and_recompute(Vector3(50, 50, 50))
+	check(cancelled.size() == 2, "a recompute from the eye cancels a sliced one")
+	check(display.get_visible_positions() == before, "the recompute from the eye is applied")
+
+	# Left to finish, a sliced recompute sees what a recompute from the eye does
+	display.start_sliced_recompute(far, 100000)
+	while display.is_recompute_pending():
+		await process_frame
+	var sliced = display.get_visible_positions()
+	display.set_origin_and_recompute(far)
+	check(sliced == display.get_visible_positions(), "a finished sliced recompute is applied")
+	check(cancelled.size() == 2, "a finished recompute is not cancelled")
+
+	display.free()
+	for failure in failures:
+		printerr("Failed: ", failure)
+	quit(1 if failures else 0)
+
+func check(passed: bool, what: String):
+	if not passed:
+		failures.append(what)
@@ -0,0 +1,38 @@
+extends SceneTree
+
+# Duplicates Displays with cells set and checks that edits to one stay its own, unless they
+# share an occlusion grid. Run after building the extension, from the repository root:
+#   godot --headless --path recursiveshadowcasting3d-godot --script res://tests/duplicate_display.gd
+
+var failures = []
+
+func _initialize():
+	var original = Display.new()
+	original.set_occluded(Vector3i(1, 2, 3))
+	original.set_occluded(Vector3i(4, 5, 6))
+
+	var copy = original.duplicate()
+	check(copy.is_occluded(Vector3i(1, 2, 3)), "the copy has the original's cells")
+	check(copy.is_occluded(Vector3i(4, 5, 6)), "the copy has all of the original's cells")
+	check(copy.get_occluded_count() == 2, "the copy has no other cells")
+	copy.set_occluded(Vector3i(7, 8, 9))
+	original.carve_box(Vector3i(1, 2, 3), Vector3i(1, 2, 3))
+	check(not original.is_occluded(Vector3i(7, 8, 9)), "edits to the copy stay on the copy")
+	check(copy.is_occluded(Vector3i(1, 2, 3)), "edits to the original stay on the original")
+
+	# A node sharing an occlusion grid leaves its own cells out, and the copy shares the grid too
+	original.occlusion_grid = OcclusionGrid3D.new()
+	var sharing = original.duplicate()
+	check(sharing.occlusion_grid == original.occlusion_grid, "the copy shares the grid")
+	sharing.set_occluded(Vector3i(2, 2, 2))
+	check(original.is_occluded(Vector3i(2, 2, 2)), "edits to a shared grid reach every node")
+
+	for node in [original, copy, sharing]:
+		node.free()
+	for failure in failures:
+		printerr("Failed: ", failure)
+	quit(1 if failures else 0)
+
+func check(passed: bool, what: String):
+	if not passed:
+		failures.append(what)
@@ -0,0 +1,76 @@
+extends SceneTree
+
+# Calls Display with cells outside the grid, buffers and layers of the wrong size, and handles
+# that name nothing, and checks each returns its own error code. Run after building the
+# extension, from the repository root:
+#   godot --headless --path recursiveshadowcasting3d-godot --script res://tests/error_codes.gd
+
+# OutOfBounds.SILENT, so the expected failures print nothing
+const OUT_OF_BOUNDS_SILENT = 2
+
+var failures = []
+
+func _initialize():
+	var display = Display.new()
+	display.out_of_bounds = OUT_OF_BOUNDS_SILENT
+	var size = Vector3i(100, 100, 100)
+
+	# Out of bounds
+	expect(display.set_occluded(Vector3i(1, 2, 3)), OK, "set_occluded inside the grid")
+	for cell in [Vector3i(-1, 0, 0), Vector3i(0, 100, 0), Vector3i(0, 0, 250)]:
+		expect(display.set_occluded(cell), ERR_PARAMETER_RANGE_ERROR, "set_occluded at %s" % cell)
+	expect(display.set_one_way_occluder(Vector3i(100, 0, 0), Vector3i(1, 0, 0)),
+		ERR_PARAMETER_RANGE_ERROR, "set_one_way_occluder outside the grid")
+	expect(display.set_tag(Vector3i(0, -5, 0), 1), ERR_PARAMETER_RANGE_ERROR,
+		"set_tag outside the grid")
+	expect(display.set_occlusion_layer(size.z, PackedByteArray()), ERR_PARAMETER_RANGE_ERROR,
+		"set_occlusion_layer past the last layer")
+	expect(display.carve_box(Vector3i(200, 200, 200), Vector3i(300, 300, 300)),
+		ERR_PARAMETER_RANGE_ERROR, "carve_box wholly outside the grid")
+
+	# Bad dimensions
+	var layer = PackedByteArray()
+	layer.resize(size.x * size.y)
+	expect(display.set_occlusion_layer(0, layer), OK, "set_occlusion_layer of the right size")
+	layer.resize(size.x * size.y - 1)
+	expect(display.set_occlusion_layer(0, layer), ERR_INVALID_PARAMETER,
+		"set_occlusion_layer a byte short")
+	var buffer = PackedByteArray()
+	buffer.resize(size.x * size.y * size.z)
+	expect(display.set_external_visibility_buffer(buffer, Vector3i(100, 100, 99)),
+		ERR_INVALID_PARAMETER, "set_external_visibility_buffer of another size")
+	buffer.resize(10)
+	expect(display.set_external_visibility_buffer(buffer, size), ERR_INVALID_PARAMETER,
+		"set_external_visibility_buffer too short for its size")
+
+	# Unknown handles
+	var view = display.create_view()
+	expect(display.set_view_max_depth(view, 10), OK, "set_view_max_depth of a view")
+	display.destroy_view(view)
+	for handle in [view, view + 1, -1]:
+		expect(display.set_view_max_depth(handle, 10), ERR_DOES_NOT_EXIST,
+			"set_view_max_depth of handle %d" % handle)
+		expect(display.clear_vision_modifiers(handle), ERR_DOES_NOT_EXIST,
+			"clear_vision_modifiers of handle %d" % handle)
+		expect(display.recompute_view(handle, Vector3(1, 1, 1)), ERR_DOES_NOT_EXIST,
+			"recompute_view of handle %d" % handle)
+	expect(display.set_occluded_channel(Vector3i(1, 1, 1), 5, true), ERR_DOES_NOT_EXIST,
+		"set_occluded_channel of a channel never created")
+	expect(display.rollback_to_checkpoint(12345), ERR_DOES_NOT_EXIST,
+		"rollback_to_checkpoint of a checkpoint never taken")
+
+	# Bad data
+	expect(display.restore_state(Resource.new()), ERR_INVALID_PARAMETER,
+		"restore_state of another kind of resource")
+	var state = display.capture_state()
+	state.version += 1
+	expect(display.restore_state(state), ERR_INVALID_DATA, "restore_state of a newer version")
+
+	display.free()
+	for failure in failures:
+		printerr("Failed: ", failure)
+	quit(1 if failures else 0)
+
+func expect(returned: int, code: int, what: String):
+	if returned != code:
+		failures.append("%s returned %s, not %s" % [what, error_string(returned), error_string(code)])
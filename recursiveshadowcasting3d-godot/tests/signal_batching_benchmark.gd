@@ -0,0 +1,71 @@
+extends SceneTree
+
+# Times emitting a recompute's signals with over 10000 cells revealed and watches changed, in
+# batches and with fine_grained_signals, and checks the batches take under a millisecond. Run
+# after building the extension, from the repository root:
+#   godot --headless --path recursiveshadowcasting3d-godot --script res://tests/signal_batching_benchmark.gd
+
+const EVENTS = 10000
+const BATCH_USEC_LIMIT = 1000
+
+var failures = []
+var revealed_batches = []
+var revealed_cells = []
+var watch_batches = []
+var watch_events = []
+
+func _initialize():
+	var batched = run(false)
+	var fine_grained = run(true)
+	print("Signal emission for %d reveals and watches: %d usec batched, %d usec fine-grained" % [
+		EVENTS, batched, fine_grained])
+	check(batched < BATCH_USEC_LIMIT, "batched signals take %d usec, over %d" % [
+		batched, BATCH_USEC_LIMIT])
+	for failure in failures:
+		printerr("Failed: ", failure)
+	quit(1 if failures else 0)
+
+# The "signal_usec" of a recompute from the middle of an empty grid, which reveals every cell it
+# sees and changes a watch on each of EVENTS of them
+func run(fine_grained: bool) -> int:
+	revealed_batches.clear()
+	revealed_cells.clear()
+	watch_batches.clear()
+	watch_events.clear()
+	var display = Display.new()
+	root.add_child(display)
+	display.fine_grained_signals = fine_grained
+	display.voxels_revealed.connect(func(cells): revealed_batches.append(cells))
+	display.voxel_revealed.connect(func(cell): revealed_cells.append(cell))
+	display.watches_changed.connect(func(ids, states): watch_batches.append([ids, states]))
+	display.watch_changed.connect(func(id, visible): watch_events.append([id, visible]))
+
+	var origin = Vector3i(50, 50, 50)
+	var ids = []
+	for x in range(-10, 10):
+		for y in range(-10, 10):
+			for z in range(-12, 13):
+				ids.append(display.add_watch(origin + Vector3i(x, y, z)))
+	display.set_origin_and_recompute(Vector3(origin))
+	var usec = display.get_last_recompute_stats()["signal_usec"]
+
+	check(revealed_batches.size() == 1, "voxels_revealed is emitted once")
+	var revealed = revealed_batches[0].size() if revealed_batches else 0
+	check(revealed >= EVENTS, "%d cells are revealed, fewer than %d" % [revealed, EVENTS])
+	check(watch_batches.size() == 1, "watches_changed is emitted once")
+	if watch_batches:
+		check(Array(watch_batches[0][0]) == ids, "every watch changed, in id order")
+		check(Array(watch_batches[0][1]).all(func(state): return state == 1),
+			"every watched cell became visible")
+	if fine_grained:
+		check(revealed_cells.size() == revealed, "voxel_revealed comes for each cell")
+		check(watch_events.size() == ids.size(), "watch_changed comes for each watch")
+	else:
+		check(revealed_cells.is_empty() and watch_events.is_empty(),
+			"fine-grained signals are off by default")
+	display.free()
+	return usec
+
+func check(passed: bool, what: String):
+	if not passed:
+		failures.append(what)
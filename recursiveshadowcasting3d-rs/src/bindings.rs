@@ -0,0 +1,36 @@
+use godot::prelude::*;
+
+use crate::bitset::Index3;
+
+/// Which value of a bound property makes its cells occlude
+#[derive(GodotConvert, Clone, Copy, Default, PartialEq)]
+#[godot(via = i64)]
+pub enum OccludeWhen {
+    /// Occlude while the property is true, like a door mesh that is only shown while closed
+    #[default]
+    True,
+    /// Occlude while the property is false, like a door's `open` flag
+    False,
+}
+
+/// Cells that occlude or not following a boolean property of a node, such as a door's
+pub struct OccluderBinding {
+    pub id: i64,
+    // an id rather than a Gd, so a freed node is noticed instead of touched
+    pub node: InstanceId,
+    pub property: StringName,
+    pub occlude_when: OccludeWhen,
+    pub cells: Vec<Index3>,
+    /// Whether the cells were set to occlude when the property was last read
+    pub occluding: bool,
+}
+
+impl OccluderBinding {
+    /// Whether the cells should occlude now.
+    /// None once the node is freed or the property is no longer a bool
+    pub fn poll(&self) -> Option<bool> {
+        let node = Gd::<Object>::try_from_instance_id(self.node).ok()?;
+        let value = node.get(&self.property).try_to::<bool>().ok()?;
+        Some(value == (self.occlude_when == OccludeWhen::True))
+    }
+}
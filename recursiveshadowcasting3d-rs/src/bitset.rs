@@ -0,0 +1,1013 @@
+use std::{collections::VecDeque, ops::Range};
+
+use godot::prelude::*;
+
+pub type Index3 = (usize, usize, usize);
+
+/// Index of a cell in grids. Negative coordinates wrap around to indices past the end of any
+/// grid, so get() and set() treat them as outside it
+pub fn cell_index(cell: Vector3i) -> Index3 {
+    (cell.x as usize, cell.y as usize, cell.z as usize)
+}
+
+/// The cell at an index, undoing cell_index()
+pub fn index_cell((x, y, z): Index3) -> Vector3i {
+    Vector3i::new(x as i32, y as i32, z as i32)
+}
+
+/// The cell holding a position in grid space. Cells are one unit wide and centered on whole
+/// coordinates, as the casts treat them, so this rounds to the nearest cell center
+pub fn cell_at(position: Vector3) -> Vector3i {
+    position.round().cast_int()
+}
+
+/// The cells along an axis of `len` cells that shifting by `offset` keeps: where they are, and
+/// where they move to. Cell `i` moves to `i - offset`, and both are empty when none stay
+pub fn shift_span(len: usize, offset: i32) -> (Range<usize>, Range<usize>) {
+    let kept = len.saturating_sub(offset.unsigned_abs() as usize);
+    match offset >= 0 {
+        true => (len - kept..len, 0..kept),
+        false => (0..kept, len - kept..len),
+    }
+}
+
+/// The inclusive boxes of cells that shifting a grid of `size` by `offset` leaves empty, as
+/// slabs that do not overlap: the one across x first, then what is left of y, then of z
+pub fn shift_vacated(size: Index3, offset: Vector3i) -> Vec<(Index3, Index3)> {
+    let lens = [size.0, size.1, size.2];
+    let offsets = [offset.x, offset.y, offset.z];
+    let moved = [0, 1, 2].map(|axis| shift_span(lens[axis], offsets[axis]).1);
+    let mut boxes = Vec::new();
+    for axis in 0..3 {
+        let vacated = match offsets[axis] >= 0 {
+            true => moved[axis].end..lens[axis],
+            false => 0..moved[axis].start,
+        };
+        let spans = [0, 1, 2].map(|other| match other.cmp(&axis) {
+            std::cmp::Ordering::Less => moved[other].clone(),
+            std::cmp::Ordering::Equal => vacated.clone(),
+            std::cmp::Ordering::Greater => 0..lens[other],
+        });
+        if spans.iter().any(Range::is_empty) {
+            continue;
+        }
+        boxes.push((
+            (spans[0].start, spans[1].start, spans[2].start),
+            (spans[0].end - 1, spans[1].end - 1, spans[2].end - 1),
+        ));
+    }
+    boxes
+}
+
+/// Which axis points up in the positions scripts pass in and get back. The grid itself is
+/// always y-up, as the node's own space is, so with Z its y and z trade places at every call
+#[derive(GodotConvert, Var, Export, Clone, Copy, Default, PartialEq)]
+#[godot(via = i64)]
+pub enum UpAxis {
+    #[default]
+    Y,
+    Z,
+}
+
+impl UpAxis {
+    /// A position from a script, in the grid's axes
+    pub fn to_grid<T: Axes>(self, value: T) -> T {
+        match self {
+            UpAxis::Y => value,
+            UpAxis::Z => value.swap_up(),
+        }
+    }
+
+    /// A position in the grid's axes, as scripts get it back
+    pub fn from_grid<T: Axes>(self, value: T) -> T {
+        // Swapping is its own inverse
+        self.to_grid(value)
+    }
+}
+
+/// Values that UpAxis converts between a script's axes and the grid's
+pub trait Axes {
+    /// Trade y and z places
+    fn swap_up(self) -> Self;
+}
+
+impl Axes for Vector3i {
+    fn swap_up(self) -> Self {
+        Vector3i::new(self.x, self.z, self.y)
+    }
+}
+
+impl Axes for Vector3 {
+    fn swap_up(self) -> Self {
+        Vector3::new(self.x, self.z, self.y)
+    }
+}
+
+/// Slopes and other pairs of axes named after the grid's, such as (z, x). Trading y and z
+/// turns every pair a plane or portal opening can lie along into the other order
+impl Axes for Vector2 {
+    fn swap_up(self) -> Self {
+        Vector2::new(self.y, self.x)
+    }
+}
+
+impl Axes for Rect2 {
+    fn swap_up(self) -> Self {
+        Rect2::new(self.position.swap_up(), self.size.swap_up())
+    }
+}
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Copy `len` bits from bit `from` of `source` to bit `to` of `target`, where they must all be
+/// unset, a word at a time
+fn copy_bits(source: &[u64], from: usize, target: &mut [u64], to: usize, len: usize) {
+    let mut done = 0;
+    while done < len {
+        let count = (len - done).min(WORD_BITS);
+        let (word, offset) = ((from + done) / WORD_BITS, (from + done) % WORD_BITS);
+        let mut bits = source[word] >> offset;
+        if offset + count > WORD_BITS {
+            bits |= source[word + 1] << (WORD_BITS - offset);
+        }
+        if count < WORD_BITS {
+            bits &= (1 << count) - 1;
+        }
+        let (word, offset) = ((to + done) / WORD_BITS, (to + done) % WORD_BITS);
+        target[word] |= bits << offset;
+        if offset + count > WORD_BITS {
+            target[word + 1] |= bits >> (WORD_BITS - offset);
+        }
+        done += count;
+    }
+}
+
+/// Dense 3D grid of booleans packed into 64 bit words.
+/// Cells are laid out x-major, then y, then z, so a run of cells along z is a run of bits.
+/// The words are only allocated once a cell is set, so grids that stay empty cost nothing
+#[derive(Clone, Default)]
+pub struct BitGrid {
+    size: Index3,
+    // empty until a cell is set, then one bit per cell
+    words: Vec<u64>,
+}
+
+impl BitGrid {
+    pub fn new(size: Index3) -> Self {
+        Self {
+            size,
+            words: Vec::new(),
+        }
+    }
+
+    fn word_count(&self) -> usize {
+        (self.size.0 * self.size.1 * self.size.2).div_ceil(WORD_BITS)
+    }
+
+    /// Allocate the words of a grid that has no cells set yet
+    fn allocate(&mut self) {
+        if self.words.is_empty() {
+            self.words = vec![0; self.word_count()];
+        }
+    }
+
+    /// Word `word` of the cells, 0 if none have been allocated
+    fn word(&self, word: usize) -> u64 {
+        self.words.get(word).copied().unwrap_or(0)
+    }
+
+    /// Bytes allocated for the cells
+    pub fn memory_bytes(&self) -> usize {
+        self.words.capacity() * size_of::<u64>()
+    }
+
+    /// Rebuild a grid from the bytes written by to_bytes(), or None if they do not fit the size
+    pub fn from_bytes(size: Index3, bytes: &[u8]) -> Option<Self> {
+        let mut grid = Self::new(size);
+        if bytes.len() != grid.word_count() * 8 {
+            return None;
+        }
+        grid.allocate();
+        for (word, chunk) in grid.words.iter_mut().zip(bytes.chunks_exact(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().ok()?);
+        }
+        // Clear any stray bits past the last cell, so counts stay right
+        if let Some(last) = grid.words.len().checked_sub(1) {
+            grid.words[last] &= grid.valid_mask(last);
+        }
+        Some(grid)
+    }
+
+    /// The packed cells as little-endian bytes, for saving
+    pub fn to_bytes(&self) -> Vec<u8> {
+        (0..self.word_count())
+            .flat_map(|word| self.word(word).to_le_bytes())
+            .collect()
+    }
+
+    pub fn size(&self) -> Index3 {
+        self.size
+    }
+
+    fn bit(&self, index: Index3) -> usize {
+        Self::bit_in(self.size, index)
+    }
+
+    fn bit_in(size: Index3, (x, y, z): Index3) -> usize {
+        (x * size.1 + y) * size.2 + z
+    }
+
+    pub fn get(&self, index: Index3) -> Option<bool> {
+        if index.0 >= self.size.0 || index.1 >= self.size.1 || index.2 >= self.size.2 {
+            return None;
+        }
+        let bit = self.bit(index);
+        Some((self.word(bit / WORD_BITS) >> (bit % WORD_BITS)) & 1 == 1)
+    }
+
+    /// Returns false if the index is out of bounds
+    pub fn set(&mut self, index: Index3, value: bool) -> bool {
+        if index.0 >= self.size.0 || index.1 >= self.size.1 || index.2 >= self.size.2 {
+            return false;
+        }
+        let bit = self.bit(index);
+        let mask = 1 << (bit % WORD_BITS);
+        if value {
+            self.allocate();
+            self.words[bit / WORD_BITS] |= mask;
+        } else if let Some(word) = self.words.get_mut(bit / WORD_BITS) {
+            *word &= !mask;
+        }
+        true
+    }
+
+    fn index_of_bit(&self, bit: usize) -> Index3 {
+        let z = bit % self.size.2;
+        let y = bit / self.size.2 % self.size.1;
+        let x = bit / self.size.2 / self.size.1;
+        (x, y, z)
+    }
+
+    /// Unset every cell
+    pub fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    /// Mask of the bits in word `word` that correspond to cells (the last word may be partial)
+    fn valid_mask(&self, word: usize) -> u64 {
+        let len = self.size.0 * self.size.1 * self.size.2;
+        let remaining = len - word * WORD_BITS;
+        if remaining >= WORD_BITS {
+            u64::MAX
+        } else {
+            (1 << remaining) - 1
+        }
+    }
+
+    /// Move every cell by `-offset`, so the cell at `offset` ends up at (0, 0, 0). Cells moved
+    /// past an edge are dropped and the ones left behind are unset. Cells are moved in runs,
+    /// whole slabs at once when only x moves, and word by word in place when the move is a
+    /// whole number of words, as it is for slabs of a multiple of 64 cells
+    pub fn shift(&mut self, offset: Vector3i) {
+        if self.words.is_empty() || offset == Vector3i::ZERO {
+            return;
+        }
+        let spans = [
+            shift_span(self.size.0, offset.x),
+            shift_span(self.size.1, offset.y),
+            shift_span(self.size.2, offset.z),
+        ];
+        if spans.iter().any(|(kept, _)| kept.is_empty()) {
+            self.clear();
+            return;
+        }
+        let min = (spans[0].0.start, spans[1].0.start, spans[2].0.start);
+        let max = (spans[0].0.end - 1, spans[1].0.end - 1, spans[2].0.end - 1);
+        let from = Self::bit_in(self.size, min);
+        let to = Self::bit_in(
+            self.size,
+            (spans[0].1.start, spans[1].1.start, spans[2].1.start),
+        );
+
+        if from % WORD_BITS != to % WORD_BITS {
+            let mut shifted = vec![0; self.words.len()];
+            Self::for_each_run(self.size, min, max, |start, end| {
+                copy_bits(
+                    &self.words,
+                    start,
+                    &mut shifted,
+                    start - from + to,
+                    end - start,
+                );
+            });
+            self.words = shifted;
+            return;
+        }
+
+        // Runs move a whole number of words, so each word of a run lands on one word. Moving
+        // towards lower words, going up reads every word before it is written over
+        let mut runs = Vec::new();
+        Self::for_each_run(self.size, min, max, |start, end| runs.push((start, end)));
+        let down = to < from;
+        if !down {
+            runs.reverse();
+        }
+        let target = |word: usize| word + to / WORD_BITS - from / WORD_BITS;
+        for (start, end) in runs {
+            let (first, last) = (start / WORD_BITS, (end - 1) / WORD_BITS);
+            let move_word = |words: &mut [u64], word: usize| {
+                let lo = start.max(word * WORD_BITS) - word * WORD_BITS;
+                let hi = end.min((word + 1) * WORD_BITS) - word * WORD_BITS;
+                let mask = match hi - lo {
+                    WORD_BITS => u64::MAX,
+                    len => ((1 << len) - 1) << lo,
+                };
+                let moved = words[word] & mask;
+                let target = &mut words[target(word)];
+                *target = *target & !mask | moved;
+            };
+            let ends = match (down, first == last) {
+                (_, true) => [first, first],
+                (true, false) => [first, last],
+                (false, false) => [last, first],
+            };
+            move_word(&mut self.words, ends[0]);
+            if last > first + 1 {
+                self.words.copy_within(first + 1..last, target(first + 1));
+            }
+            if ends[1] != ends[0] {
+                move_word(&mut self.words, ends[1]);
+            }
+        }
+        for (min, max) in shift_vacated(self.size, offset) {
+            self.set_box(min, max, false);
+        }
+    }
+
+    /// Set every cell that is unset both here and in `other`, which must be the same size.
+    /// Returns how many cells were newly set
+    pub fn fill_unset_in_both(&mut self, other: &BitGrid) -> usize {
+        self.allocate();
+        let mut count = 0;
+        for word in 0..self.words.len() {
+            let new = !self.words[word] & !other.word(word) & self.valid_mask(word);
+            count += new.count_ones() as usize;
+            self.words[word] |= new;
+        }
+        count
+    }
+
+    /// Set in `reached`, which must be the same size, every cell unset here that can be reached
+    /// from `seed` through the faces of unset cells. Cells already set in `reached` are not
+    /// entered again, and nothing is reached from a seed that is set or outside the grid
+    pub fn flood_unset(&self, seed: Index3, reached: &mut BitGrid) {
+        if self.get(seed) != Some(false) || reached.get(seed) != Some(false) {
+            return;
+        }
+        let mut queue = VecDeque::from([seed]);
+        reached.set(seed, true);
+        while let Some((x, y, z)) = queue.pop_front() {
+            // Out of range neighbors wrap around to usize::MAX and are rejected by get()
+            for neighbor in [
+                (x.wrapping_sub(1), y, z),
+                (x + 1, y, z),
+                (x, y.wrapping_sub(1), z),
+                (x, y + 1, z),
+                (x, y, z.wrapping_sub(1)),
+                (x, y, z + 1),
+            ] {
+                if self.get(neighbor) == Some(false) && reached.get(neighbor) == Some(false) {
+                    reached.set(neighbor, true);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+
+    /// Number of set cells
+    pub fn count_set(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Set every cell that is set in `other`, which must be the same size
+    pub fn union_with(&mut self, other: &BitGrid) {
+        if other.words.is_empty() {
+            return;
+        }
+        self.allocate();
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            *a |= b;
+        }
+    }
+
+    /// Unset every cell that is unset in `other`, which must be the same size
+    pub fn intersect_with(&mut self, other: &BitGrid) {
+        if other.words.is_empty() {
+            self.clear();
+            return;
+        }
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            *a &= b;
+        }
+    }
+
+    /// Unset every cell that is set in `other`, which must be the same size
+    pub fn subtract(&mut self, other: &BitGrid) {
+        for (a, b) in self.words.iter_mut().zip(&other.words) {
+            *a &= !b;
+        }
+    }
+
+    /// Call `f` with every set cell, in ascending x, then y, then z order
+    pub fn for_each_set(&self, mut f: impl FnMut(Index3)) {
+        for (word, &bits) in self.words.iter().enumerate() {
+            let mut bits = bits;
+            while bits != 0 {
+                let bit = word * WORD_BITS + bits.trailing_zeros() as usize;
+                f(self.index_of_bit(bit));
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    /// Unset every set cell for which `keep` returns false
+    pub fn retain_set(&mut self, mut keep: impl FnMut(Index3) -> bool) {
+        for word in 0..self.words.len() {
+            let mut bits = self.words[word];
+            while bits != 0 {
+                let offset = bits.trailing_zeros() as usize;
+                if !keep(self.index_of_bit(word * WORD_BITS + offset)) {
+                    self.words[word] &= !(1 << offset);
+                }
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    /// Write every cell as a 0 or 1 byte into `bytes`, which must hold exactly one byte per cell,
+    /// in the same x-major, then y, then z layout as the grid
+    pub fn write_bytes(&self, bytes: &mut [u8]) {
+        bytes.fill(0);
+        for (word, &bits) in self.words.iter().enumerate() {
+            let mut bits = bits;
+            while bits != 0 {
+                bytes[word * WORD_BITS + bits.trailing_zeros() as usize] = 1;
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    /// Call `f` with every cell that differs from `other` (which must be the same size),
+    /// along with its value here, in ascending x, then y, then z order
+    pub fn for_each_difference(&self, other: &BitGrid, mut f: impl FnMut(Index3, bool)) {
+        for word in 0..self.words.len().max(other.words.len()) {
+            let (a, b) = (self.word(word), other.word(word));
+            let mut bits = a ^ b;
+            while bits != 0 {
+                let offset = bits.trailing_zeros() as usize;
+                f(
+                    self.index_of_bit(word * WORD_BITS + offset),
+                    (a >> offset) & 1 == 1,
+                );
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    /// Call `f` with a cell of every run of `run` cells along z, starting at a multiple of
+    /// `run`, that holds a cell differing from `other` (which must be the same size). Runs are
+    /// skipped over a word at a time, and one split between two words may be passed twice
+    pub fn for_each_differing_run(&self, other: &BitGrid, run: usize, mut f: impl FnMut(Index3)) {
+        for word in 0..self.words.len().max(other.words.len()) {
+            let mut bits = self.word(word) ^ other.word(word);
+            while bits != 0 {
+                let offset = bits.trailing_zeros() as usize;
+                let index = self.index_of_bit(word * WORD_BITS + offset);
+                f(index);
+                let run_end = ((index.2 / run + 1) * run).min(self.size.2);
+                let next = offset + run_end - index.2;
+                bits = match next < WORD_BITS {
+                    true => bits & (u64::MAX << next),
+                    false => 0,
+                };
+            }
+        }
+    }
+
+    /// Clip the inclusive box spanned by two corners (in any order) to the grid.
+    /// Returns the clipped inclusive bounds, and whether any part of the box was cut off
+    pub fn clip_box(&self, from: Vector3i, to: Vector3i) -> (Option<(Index3, Index3)>, bool) {
+        let min = from.coord_min(to);
+        let max = from.coord_max(to);
+        let grid_max = Vector3i::new(
+            self.size.0 as i32 - 1,
+            self.size.1 as i32 - 1,
+            self.size.2 as i32 - 1,
+        );
+        let clipped_min = min.coord_max(Vector3i::ZERO);
+        let clipped_max = max.coord_min(grid_max);
+        let was_clipped = clipped_min != min || clipped_max != max;
+
+        if clipped_min.x > clipped_max.x
+            || clipped_min.y > clipped_max.y
+            || clipped_min.z > clipped_max.z
+        {
+            return (None, was_clipped);
+        }
+
+        (
+            Some((cell_index(clipped_min), cell_index(clipped_max))),
+            was_clipped,
+        )
+    }
+
+    /// Call `f` with the half-open bit ranges covering an inclusive, in-bounds box.
+    /// Runs that are contiguous in memory (full z rows, full y slabs) are merged into one range
+    fn for_each_run(size: Index3, min: Index3, max: Index3, mut f: impl FnMut(usize, usize)) {
+        let mut pending: Option<(usize, usize)> = None;
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                let start = Self::bit_in(size, (x, y, min.2));
+                let end = Self::bit_in(size, (x, y, max.2)) + 1;
+                pending = match pending {
+                    Some((s, e)) if e == start => Some((s, end)),
+                    Some((s, e)) => {
+                        f(s, e);
+                        Some((start, end))
+                    }
+                    None => Some((start, end)),
+                };
+            }
+        }
+        if let Some((s, e)) = pending {
+            f(s, e);
+        }
+    }
+
+    /// Call `f` with each word overlapping the half-open bit range, and a mask of the bits in range
+    fn for_each_word_in(start: usize, end: usize, mut f: impl FnMut(usize, u64)) {
+        let mut bit = start;
+        while bit < end {
+            let word = bit / WORD_BITS;
+            let lo = bit % WORD_BITS;
+            let hi = (end - word * WORD_BITS).min(WORD_BITS);
+            let mask = if hi - lo == WORD_BITS {
+                u64::MAX
+            } else {
+                ((1 << (hi - lo)) - 1) << lo
+            };
+            f(word, mask);
+            bit = (word + 1) * WORD_BITS;
+        }
+    }
+
+    /// Number of set cells in an inclusive, in-bounds box
+    pub fn count_in_box(&self, min: Index3, max: Index3) -> usize {
+        let mut count = 0;
+        Self::for_each_run(self.size, min, max, |start, end| {
+            Self::for_each_word_in(start, end, |word, mask| {
+                count += (self.word(word) & mask).count_ones() as usize;
+            });
+        });
+        count
+    }
+
+    /// Call `f` with every set cell in an inclusive, in-bounds box, in ascending x, then y, then z order
+    pub fn for_each_set_in_box(&self, min: Index3, max: Index3, mut f: impl FnMut(Index3)) {
+        Self::for_each_run(self.size, min, max, |start, end| {
+            Self::for_each_word_in(start, end, |word, mask| {
+                let mut bits = self.word(word) & mask;
+                while bits != 0 {
+                    f(self.index_of_bit(word * WORD_BITS + bits.trailing_zeros() as usize));
+                    bits &= bits - 1;
+                }
+            });
+        });
+    }
+
+    /// Call `f` with every set cell in the slice at `index` across `axis` (0 for x, 1 for y,
+    /// 2 for z), as its coordinates along the other two axes in x, y, z order.
+    /// Slices outside the grid have no cells
+    pub fn for_each_set_in_slice(
+        &self,
+        axis: usize,
+        index: usize,
+        mut f: impl FnMut(usize, usize),
+    ) {
+        let size = [self.size.0, self.size.1, self.size.2];
+        if axis > 2 || index >= size[axis] || size.contains(&0) {
+            return;
+        }
+        let mut min = [0; 3];
+        let mut max = size.map(|len| len - 1);
+        (min[axis], max[axis]) = (index, index);
+        let (min, max) = ((min[0], min[1], min[2]), (max[0], max[1], max[2]));
+        self.for_each_set_in_box(min, max, |(x, y, z)| match axis {
+            0 => f(y, z),
+            1 => f(x, z),
+            _ => f(x, y),
+        });
+    }
+
+    /// Set or unset every cell in an inclusive, in-bounds box
+    pub fn set_box(&mut self, min: Index3, max: Index3, value: bool) {
+        if !value && self.words.is_empty() {
+            return;
+        }
+        self.allocate();
+        let words = &mut self.words;
+        Self::for_each_run(self.size, min, max, |start, end| {
+            Self::for_each_word_in(start, end, |word, mask| {
+                if value {
+                    words[word] |= mask;
+                } else {
+                    words[word] &= !mask;
+                }
+            });
+        });
+    }
+
+    /// Whether every cell in an inclusive, in-bounds box is set
+    pub fn is_box_full(&self, min: Index3, max: Index3) -> bool {
+        let mut full = true;
+        Self::for_each_run(self.size, min, max, |start, end| {
+            if full {
+                Self::for_each_word_in(start, end, |word, mask| {
+                    full &= self.word(word) & mask == mask;
+                });
+            }
+        });
+        full
+    }
+
+    /// A grid `factor` times coarser along every axis, where each cell covers a block of
+    /// `factor` cells per axis and is set if at least `fraction` of them and at least one are.
+    /// The grid is padded with unset cells up to a multiple of `factor`
+    pub fn downsampled(&self, factor: usize, fraction: f32) -> BitGrid {
+        let size = (
+            self.size.0.div_ceil(factor),
+            self.size.1.div_ceil(factor),
+            self.size.2.div_ceil(factor),
+        );
+        let mut coarse = BitGrid::new(size);
+        if self.words.is_empty() {
+            return coarse;
+        }
+        let needed = ((fraction * factor.pow(3) as f32).ceil() as usize).max(1);
+        for x in 0..size.0 {
+            for y in 0..size.1 {
+                for z in 0..size.2 {
+                    let min = (x * factor, y * factor, z * factor);
+                    let max = (
+                        (min.0 + factor).min(self.size.0) - 1,
+                        (min.1 + factor).min(self.size.1) - 1,
+                        (min.2 + factor).min(self.size.2) - 1,
+                    );
+                    if self.count_in_box(min, max) >= needed {
+                        coarse.set((x, y, z), true);
+                    }
+                }
+            }
+        }
+        coarse
+    }
+
+    /// Set every cell of `fine` under a set cell of this grid, which is `factor` times coarser
+    /// as downsampled() makes it. Cells under unset ones are left alone
+    pub fn upsample_into(&self, factor: usize, fine: &mut BitGrid) {
+        let size = fine.size;
+        self.for_each_set(|(x, y, z)| {
+            let min = (x * factor, y * factor, z * factor);
+            if min.0 >= size.0 || min.1 >= size.1 || min.2 >= size.2 {
+                return;
+            }
+            let max = (
+                (min.0 + factor).min(size.0) - 1,
+                (min.1 + factor).min(size.1) - 1,
+                (min.2 + factor).min(size.2) - 1,
+            );
+            fine.set_box(min, max, true);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_allocated_by_the_first_set_cell() {
+        let mut grid = BitGrid::new((100, 100, 100));
+        assert_eq!(grid.memory_bytes(), 0);
+        grid.set((1, 2, 3), false);
+        grid.clear();
+        assert_eq!((grid.count_set(), grid.get((1, 2, 3))), (0, Some(false)));
+        assert_eq!(grid.clone().memory_bytes(), 0);
+        assert_eq!(grid.memory_bytes(), 0);
+
+        grid.set((1, 2, 3), true);
+        assert_eq!(grid.memory_bytes(), 1_000_000usize.div_ceil(64) * 8);
+    }
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 16) as usize
+        }
+
+        /// The inclusive bounds of a random box in a grid of `size`
+        fn in_box(&mut self, size: Index3) -> (Index3, Index3) {
+            let mut axis = |len: usize| {
+                let (a, b) = (self.next() % len, self.next() % len);
+                (a.min(b), a.max(b))
+            };
+            let ((x0, x1), (y0, y1), (z0, z1)) = (axis(size.0), axis(size.1), axis(size.2));
+            ((x0, y0, z0), (x1, y1, z1))
+        }
+
+        fn grid(&mut self, size: Index3, cells: usize) -> BitGrid {
+            let mut grid = BitGrid::new(size);
+            for _ in 0..cells {
+                let index = (
+                    self.next() % size.0,
+                    self.next() % size.1,
+                    self.next() % size.2,
+                );
+                grid.set(index, true);
+            }
+            grid
+        }
+    }
+
+    /// Every cell of a grid in its own layout, read one by one
+    fn cells(grid: &BitGrid) -> Vec<bool> {
+        let (sx, sy, sz) = grid.size();
+        let mut cells = Vec::with_capacity(sx * sy * sz);
+        for x in 0..sx {
+            for y in 0..sy {
+                for z in 0..sz {
+                    cells.push(grid.get((x, y, z)).unwrap());
+                }
+            }
+        }
+        cells
+    }
+
+    fn in_box((x, y, z): Index3, min: Index3, max: Index3) -> bool {
+        (min.0..=max.0).contains(&x) && (min.1..=max.1).contains(&y) && (min.2..=max.2).contains(&z)
+    }
+
+    // Rows of 70 cells and slabs of 64 put boxes across word boundaries both ways
+    const SIZES: [Index3; 4] = [(1, 1, 1), (5, 3, 70), (6, 8, 8), (9, 7, 13)];
+
+    #[test]
+    fn boxes_count_and_set_the_cells_inside_them() {
+        let mut rng = Rng(7);
+        for size in SIZES {
+            let grid = rng.grid(size, 200);
+            for _ in 0..50 {
+                let (min, max) = rng.in_box(size);
+                let mut expected = 0;
+                let mut set = grid.clone();
+                set.set_box(min, max, true);
+                let mut unset = grid.clone();
+                unset.set_box(min, max, false);
+                grid.for_each_set(|index| expected += usize::from(in_box(index, min, max)));
+                assert_eq!(
+                    grid.count_in_box(min, max),
+                    expected,
+                    "{size:?} {min:?} {max:?}"
+                );
+                assert!(set.is_box_full(min, max));
+                assert_eq!(unset.count_in_box(min, max), 0);
+
+                let mut visited = Vec::new();
+                grid.for_each_set_in_box(min, max, |index| visited.push(index));
+                assert_eq!(visited.len(), expected);
+                for (index, ((was, set), unset)) in cells(&grid)
+                    .into_iter()
+                    .zip(cells(&set))
+                    .zip(cells(&unset))
+                    .enumerate()
+                {
+                    let inside = in_box(grid.index_of_bit(index), min, max);
+                    assert_eq!(set, was || inside);
+                    assert_eq!(unset, was && !inside);
+                }
+            }
+        }
+        // Unsetting a box never allocates a grid with nothing set
+        let mut empty = BitGrid::new((40, 40, 40));
+        empty.set_box((0, 0, 0), (39, 39, 39), false);
+        assert_eq!(empty.memory_bytes(), 0);
+    }
+
+    #[test]
+    fn boxes_are_clipped_to_the_grid() {
+        let grid = BitGrid::new((4, 5, 6));
+        let clip = |from: (i32, i32, i32), to: (i32, i32, i32)| {
+            grid.clip_box(
+                Vector3i::new(from.0, from.1, from.2),
+                Vector3i::new(to.0, to.1, to.2),
+            )
+        };
+        assert_eq!(
+            clip((1, 2, 3), (3, 4, 5)),
+            (Some(((1, 2, 3), (3, 4, 5))), false)
+        );
+        // Corners in any order
+        assert_eq!(
+            clip((3, 0, 5), (0, 4, 0)),
+            (Some(((0, 0, 0), (3, 4, 5))), false)
+        );
+        assert_eq!(
+            clip((-2, 1, 1), (9, 2, 2)),
+            (Some(((0, 1, 1), (3, 2, 2))), true)
+        );
+        assert_eq!(
+            clip((2, 2, 5), (2, 2, 6)),
+            (Some(((2, 2, 5), (2, 2, 5))), true)
+        );
+        // Wholly outside, past either end of an axis
+        assert_eq!(clip((4, 0, 0), (7, 4, 5)), (None, true));
+        assert_eq!(clip((0, -3, 0), (3, -1, 5)), (None, true));
+
+        // A clipped box counts what is inside the grid
+        let mut rng = Rng(3);
+        let grid = rng.grid((9, 7, 13), 300);
+        for _ in 0..100 {
+            let mut corner = || {
+                Vector3i::new(
+                    rng.next() as i32 % 15 - 3,
+                    rng.next() as i32 % 13 - 3,
+                    rng.next() as i32 % 19 - 3,
+                )
+            };
+            let (from, to) = (corner(), corner());
+            let (min, max) = (from.coord_min(to), from.coord_max(to));
+            let mut expected = 0;
+            grid.for_each_set(|index| {
+                let cell = index_cell(index);
+                expected += usize::from(cell.coord_max(min) == cell && cell.coord_min(max) == cell);
+            });
+            let (clipped, _) = grid.clip_box(from, to);
+            let counted = clipped.map_or(0, |(min, max)| grid.count_in_box(min, max));
+            assert_eq!(counted, expected, "{from} {to}");
+        }
+    }
+
+    #[test]
+    fn filling_both_unset_sets_only_real_cells() {
+        let mut rng = Rng(5);
+        for size in SIZES {
+            let mut grid = rng.grid(size, 40);
+            let other = rng.grid(size, 40);
+            let before = cells(&grid);
+            let filled = grid.fill_unset_in_both(&other);
+            let mut expected = 0;
+            for ((was, now), set_in_other) in
+                before.into_iter().zip(cells(&grid)).zip(cells(&other))
+            {
+                assert_eq!(now, was || !set_in_other);
+                expected += usize::from(!was && !set_in_other);
+            }
+            assert_eq!(filled, expected);
+            // Bits past the last cell stay unset, so counts stay right
+            let mut full = BitGrid::new(size);
+            assert_eq!(
+                full.fill_unset_in_both(&BitGrid::new(size)),
+                size.0 * size.1 * size.2
+            );
+            assert_eq!(full.count_set(), size.0 * size.1 * size.2);
+        }
+    }
+
+    #[test]
+    fn shifts_move_cells_and_unset_what_they_leave() {
+        let mut rng = Rng(11);
+        for size in SIZES {
+            let grid = rng.grid(size, 120);
+            for offset in [
+                Vector3i::new(1, 0, 0),
+                Vector3i::new(-2, 0, 0),
+                Vector3i::new(0, 1, -3),
+                Vector3i::new(2, -1, 5),
+                Vector3i::new(0, 0, 1),
+                Vector3i::new(size.0 as i32, 0, 0),
+            ] {
+                let mut shifted = grid.clone();
+                shifted.shift(offset);
+                let (sx, sy, sz) = size;
+                for x in 0..sx {
+                    for y in 0..sy {
+                        for z in 0..sz {
+                            let from = Vector3i::new(x as i32, y as i32, z as i32) + offset;
+                            let was = from.x >= 0
+                                && from.y >= 0
+                                && from.z >= 0
+                                && grid.get(cell_index(from)) == Some(true);
+                            assert_eq!(
+                                shifted.get((x, y, z)),
+                                Some(was),
+                                "{size:?} by {offset} at {:?}",
+                                (x, y, z)
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn differences_are_every_cell_that_differs_in_order() {
+        let mut rng = Rng(13);
+        for size in SIZES {
+            let a = rng.grid(size, 80);
+            let b = rng.grid(size, 80);
+            // Including against a grid with no words allocated, either way round
+            for (a, b) in [
+                (&a, &b),
+                (&a, &BitGrid::new(size)),
+                (&BitGrid::new(size), &b),
+            ] {
+                let mut differences = Vec::new();
+                a.for_each_difference(b, |index, value| differences.push((index, value)));
+                let expected: Vec<(Index3, bool)> = cells(a)
+                    .into_iter()
+                    .zip(cells(b))
+                    .enumerate()
+                    .filter(|(_, (a, b))| a != b)
+                    .map(|(bit, (a, _))| (BitGrid::new(size).index_of_bit(bit), a))
+                    .collect();
+                assert_eq!(differences, expected);
+            }
+        }
+    }
+
+    /// What Display::seal_enclosed_regions() does to its grid
+    fn seal(grid: &mut BitGrid, seed: Index3) -> usize {
+        let mut reached = BitGrid::new(grid.size());
+        grid.flood_unset(seed, &mut reached);
+        grid.fill_unset_in_both(&reached)
+    }
+
+    #[test]
+    fn sealing_fills_closed_pockets_and_leaves_open_ones() {
+        // Two hollow 5 cell boxes, each with a 3 cell pocket, the second with a hole in its side
+        let mut grid = BitGrid::new((14, 9, 9));
+        for (min, max) in [((1, 1, 1), (5, 5, 5)), ((7, 1, 1), (11, 5, 5))] {
+            grid.set_box(min, max, true);
+            grid.set_box(
+                (min.0 + 1, min.1 + 1, min.2 + 1),
+                (max.0 - 1, max.1 - 1, max.2 - 1),
+                false,
+            );
+        }
+        grid.set((9, 3, 5), false);
+        let occluded = grid.count_set();
+
+        assert_eq!(seal(&mut grid, (0, 0, 0)), 27);
+        assert_eq!(grid.count_set(), occluded + 27);
+        assert!(grid.is_box_full((2, 2, 2), (4, 4, 4)));
+        assert_eq!(grid.count_in_box((8, 2, 2), (10, 4, 4)), 0);
+        assert_eq!(grid.get((9, 3, 5)), Some(false));
+
+        // Sealing again finds nothing new, and set or outside seeds reach nothing
+        assert_eq!(seal(&mut grid, (13, 8, 8)), 0);
+        let mut reached = BitGrid::new(grid.size());
+        grid.flood_unset((1, 1, 1), &mut reached);
+        grid.flood_unset((14, 0, 0), &mut reached);
+        assert_eq!(reached.count_set(), 0);
+    }
+
+    #[test]
+    fn set_cells_come_in_ascending_order_however_they_were_set() {
+        let mut rng = Rng(29);
+        for size in SIZES {
+            let mut indices = Vec::new();
+            let mut grid = BitGrid::new(size);
+            for _ in 0..120 {
+                let index = (
+                    rng.next() % size.0,
+                    rng.next() % size.1,
+                    rng.next() % size.2,
+                );
+                grid.set(index, true);
+                indices.push(index);
+            }
+            indices.sort();
+            indices.dedup();
+            let mut listed = Vec::new();
+            grid.for_each_set(|index| listed.push(index));
+            assert_eq!(listed, indices, "{size:?}");
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use godot::prelude::*;
+
+use crate::{
+    bitset::{BitGrid, Index3},
+    error::ShadowcastError,
+};
+
+/// Most channels a node can have, the default one included
+pub const MAX_CHANNELS: usize = 8;
+pub const DEFAULT_CHANNEL_NAME: &str = "default";
+
+/// The union of the channels one kind of cast is made against
+struct Composite {
+    // bit n set for every channel n in the union
+    mask: u64,
+    // None while mask is only the default channel, which is then cast against directly
+    grid: Option<BitGrid>,
+}
+
+impl Composite {
+    fn contains(&self, channel: usize) -> bool {
+        (self.mask >> channel) & 1 == 1
+    }
+
+    fn refresh(&mut self, default: &BitGrid, grids: &[BitGrid], extra: Option<&BitGrid>) {
+        if self.mask == 1 && extra.is_none() {
+            self.grid = None;
+            return;
+        }
+        let mut grid = match self.contains(0) {
+            true => default.clone(),
+            false => BitGrid::new(default.size()),
+        };
+        for (channel, cells) in (1..).zip(grids) {
+            if self.contains(channel) {
+                grid.union_with(cells);
+            }
+        }
+        if let Some(extra) = extra {
+            grid.union_with(extra);
+        }
+        self.grid = Some(grid);
+    }
+
+    fn refresh_cell(
+        &mut self,
+        default: &BitGrid,
+        grids: &[BitGrid],
+        extra: Option<&BitGrid>,
+        index: Index3,
+    ) {
+        let Some(grid) = self.grid.as_mut() else {
+            return;
+        };
+        let mask = self.mask;
+        let occluded = std::iter::once(default)
+            .chain(grids)
+            .enumerate()
+            .any(|(channel, cells)| (mask >> channel) & 1 == 1 && cells.get(index) == Some(true))
+            || extra.is_some_and(|extra| extra.get(index) == Some(true));
+        grid.set(index, occluded);
+    }
+}
+
+/// Named occlusion grids beside the default one, e.g. "fog" that blocks sight but not light,
+/// and the unions of them that sight and light are cast against.
+///
+/// Channel 0 is the default grid, which the node keeps itself and passes in where needed,
+/// so casting against it alone costs nothing extra. Other unions are kept up to date
+/// cell by cell as their channels are edited
+pub struct Channels {
+    // names and grids of channels 1 and up
+    names: Vec<String>,
+    grids: Vec<BitGrid>,
+    // cells that a probability seed resolved to block, which sight is also cast against
+    resolved: Option<BitGrid>,
+    sight: Composite,
+    light: Composite,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self {
+            names: Vec::new(),
+            grids: Vec::new(),
+            resolved: None,
+            sight: Composite {
+                mask: 1,
+                grid: None,
+            },
+            light: Composite {
+                mask: 1,
+                grid: None,
+            },
+        }
+    }
+}
+
+impl Channels {
+    /// Number of channels, the default one included
+    pub fn count(&self) -> usize {
+        self.names.len() + 1
+    }
+
+    /// Add an empty channel the size of `default`, returning its index
+    pub fn create(&mut self, name: &str, default: &BitGrid) -> Result<usize, ShadowcastError> {
+        if self.find(name).is_some() {
+            return Err(ShadowcastError::InvalidParameter(format!(
+                "A channel named {} already exists",
+                name
+            )));
+        }
+        if self.count() >= MAX_CHANNELS {
+            return Err(ShadowcastError::InvalidParameter(format!(
+                "Nodes can have at most {} channels",
+                MAX_CHANNELS
+            )));
+        }
+        self.names.push(name.to_string());
+        self.grids.push(BitGrid::new(default.size()));
+        Ok(self.count() - 1)
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        if name == DEFAULT_CHANNEL_NAME {
+            return Some(0);
+        }
+        self.names
+            .iter()
+            .position(|other| other == name)
+            .map(|channel| channel + 1)
+    }
+
+    /// The grid of a channel other than the default one
+    pub fn grid(&self, channel: usize) -> Option<&BitGrid> {
+        self.grids.get(channel.checked_sub(1)?)
+    }
+
+    /// Set or unset a cell of a channel other than the default one, keeping the unions up
+    /// to date. Returns false if the channel or the index does not exist
+    pub fn set(&mut self, default: &BitGrid, channel: usize, index: Index3, value: bool) -> bool {
+        let Some(grid) = channel
+            .checked_sub(1)
+            .and_then(|grid| self.grids.get_mut(grid))
+        else {
+            return false;
+        };
+        if !grid.set(index, value) {
+            return false;
+        }
+        self.refresh_cell(default, index);
+        true
+    }
+
+    pub fn sight_mask(&self) -> u64 {
+        self.sight.mask
+    }
+
+    pub fn light_mask(&self) -> u64 {
+        self.light.mask
+    }
+
+    /// Whether sight is cast against a channel
+    pub fn blocks_sight(&self, channel: usize) -> bool {
+        self.sight.contains(channel)
+    }
+
+    /// Whether light is cast against a channel
+    pub fn blocks_light(&self, channel: usize) -> bool {
+        self.light.contains(channel)
+    }
+
+    /// Cast sight against the channels whose bits are set in `mask` from now on.
+    /// Returns false if it names a channel that does not exist
+    pub fn set_sight_mask(&mut self, default: &BitGrid, mask: u64) -> bool {
+        if mask >> self.count() != 0 {
+            return false;
+        }
+        self.sight.mask = mask;
+        self.sight
+            .refresh(default, &self.grids, self.resolved.as_ref());
+        true
+    }
+
+    /// set_sight_mask() for light
+    pub fn set_light_mask(&mut self, default: &BitGrid, mask: u64) -> bool {
+        if mask >> self.count() != 0 {
+            return false;
+        }
+        self.light.mask = mask;
+        self.light.refresh(default, &self.grids, None);
+        true
+    }
+
+    /// Bring the unions up to date after `default` changed at one cell
+    pub fn refresh_cell(&mut self, default: &BitGrid, index: Index3) {
+        self.sight
+            .refresh_cell(default, &self.grids, self.resolved.as_ref(), index);
+        self.light.refresh_cell(default, &self.grids, None, index);
+    }
+
+    /// Shift every channel as BitGrid::shift() does, after `default` was shifted the same way
+    pub fn shift(&mut self, default: &BitGrid, offset: Vector3i) {
+        for grid in self.grids.iter_mut().chain(&mut self.resolved) {
+            grid.shift(offset);
+        }
+        self.refresh(default);
+    }
+
+    /// Cast sight against the cells a probability seed resolved to block as well, or stop with
+    /// None. They must be the size of `default`
+    pub fn set_resolved(&mut self, default: &BitGrid, resolved: Option<BitGrid>) {
+        self.resolved = resolved;
+        self.sight
+            .refresh(default, &self.grids, self.resolved.as_ref());
+    }
+
+    /// Mark whether one cell a probability seed was resolved for blocks sight
+    pub fn set_resolved_cell(&mut self, default: &BitGrid, index: Index3, blocked: bool) {
+        let Some(resolved) = self.resolved.as_mut() else {
+            return;
+        };
+        resolved.set(index, blocked);
+        self.sight
+            .refresh_cell(default, &self.grids, self.resolved.as_ref(), index);
+    }
+
+    /// Rebuild the unions after `default` changed all over or was resized.
+    /// Channels and resolved cells that no longer fit the default grid are emptied or dropped
+    pub fn refresh(&mut self, default: &BitGrid) {
+        for grid in &mut self.grids {
+            if grid.size() != default.size() {
+                *grid = BitGrid::new(default.size());
+            }
+        }
+        if self
+            .resolved
+            .as_ref()
+            .is_some_and(|resolved| resolved.size() != default.size())
+        {
+            self.resolved = None;
+        }
+        self.sight
+            .refresh(default, &self.grids, self.resolved.as_ref());
+        self.light.refresh(default, &self.grids, None);
+    }
+
+    /// Bytes allocated for the channels and their unions, besides the default channel
+    pub fn memory_bytes(&self) -> usize {
+        let composites = [&self.sight, &self.light]
+            .into_iter()
+            .filter_map(|composite| composite.grid.as_ref())
+            .chain(&self.resolved)
+            .map(BitGrid::memory_bytes);
+        self.grids
+            .iter()
+            .map(BitGrid::memory_bytes)
+            .chain(composites)
+            .sum()
+    }
+
+    /// The grid sight is cast against, given the default channel's grid
+    pub fn sight_grid<'a>(&'a self, default: &'a BitGrid) -> &'a BitGrid {
+        self.sight.grid.as_ref().unwrap_or(default)
+    }
+
+    /// The grid light is cast against, given the default channel's grid
+    pub fn light_grid<'a>(&'a self, default: &'a BitGrid) -> &'a BitGrid {
+        self.light.grid.as_ref().unwrap_or(default)
+    }
+}
@@ -0,0 +1,368 @@
+use godot::{builtin::real, prelude::*};
+use ndarray::Array3;
+
+use crate::{
+    bitset::{BitGrid, Index3},
+    patch::{decode_patch, encode_patch},
+    shadowcast::CornerRule,
+};
+
+/// What a checkpoint puts back besides the buffers: the origin and the settings that shape
+/// recomputes, so the next one continues from where the checkpoint was taken
+#[derive(Clone, Copy)]
+pub struct CheckpointSettings {
+    pub origin: Vector3i,
+    pub origin_float: Vector3,
+    pub corner_rule: CornerRule,
+    pub track_visibility_fraction: bool,
+    pub lod_start_depth: i32,
+    pub lod_factor: i32,
+    pub budget_depth_reduction: usize,
+}
+
+/// The buffers a checkpoint keeps, in full
+#[derive(Clone)]
+pub struct CheckpointBuffers {
+    pub visible: BitGrid,
+    pub explored: BitGrid,
+    pub light_level: Array3<real>,
+    pub light_color: Array3<[real; 3]>,
+    pub visibility_fraction: Array3<f32>,
+}
+
+impl CheckpointBuffers {
+    fn empty() -> Self {
+        Self {
+            visible: BitGrid::new((0, 0, 0)),
+            explored: BitGrid::new((0, 0, 0)),
+            light_level: Array3::zeros((0, 0, 0)),
+            light_color: Array3::default((0, 0, 0)),
+            visibility_fraction: Array3::zeros((0, 0, 0)),
+        }
+    }
+
+    pub fn size(&self) -> Index3 {
+        self.visible.size()
+    }
+}
+
+/// A grid as the changes from another grid, or from an empty one of its size when the other
+/// is of another size
+struct GridDelta {
+    size: Index3,
+    patch: Vec<u8>,
+}
+
+impl GridDelta {
+    fn new(base: &BitGrid, current: &BitGrid) -> Self {
+        let patch = match base.size() == current.size() {
+            true => encode_patch(base, current),
+            false => encode_patch(&BitGrid::new(current.size()), current),
+        };
+        Self {
+            size: current.size(),
+            patch,
+        }
+    }
+
+    fn apply(&self, grid: &mut BitGrid) {
+        if grid.size() != self.size {
+            *grid = BitGrid::new(self.size);
+        }
+        let changes = decode_patch(self.size, &self.patch).expect("checkpoint patches are valid");
+        for (index, value) in changes {
+            grid.set(index, value);
+        }
+    }
+}
+
+/// An array as the cells that differ from another array, or from zeros when the other is of
+/// another shape, by their index in the array's logical order
+struct ArrayDelta<T> {
+    dim: Index3,
+    changes: Vec<(usize, T)>,
+}
+
+impl<T: Copy + Default + PartialEq> ArrayDelta<T> {
+    fn new(base: &Array3<T>, current: &Array3<T>) -> Self {
+        let changes = match base.dim() == current.dim() {
+            true => current
+                .iter()
+                .zip(base)
+                .enumerate()
+                .filter(|(_, (value, base))| value != base)
+                .map(|(i, (&value, _))| (i, value))
+                .collect(),
+            false => current
+                .iter()
+                .enumerate()
+                .filter(|&(_, value)| *value != T::default())
+                .map(|(i, &value)| (i, value))
+                .collect(),
+        };
+        Self {
+            dim: current.dim(),
+            changes,
+        }
+    }
+
+    fn apply(&self, array: &mut Array3<T>) {
+        if array.dim() != self.dim {
+            *array = Array3::default(self.dim);
+        }
+        let (_, size_y, size_z) = self.dim;
+        for &(i, value) in &self.changes {
+            array[(i / (size_y * size_z), i / size_z % size_y, i % size_z)] = value;
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.changes.len() * size_of::<(usize, T)>()
+    }
+}
+
+/// One checkpoint's buffers as the changes from the previous checkpoint's
+struct Checkpoint {
+    id: i64,
+    settings: CheckpointSettings,
+    visible: GridDelta,
+    explored: GridDelta,
+    light_level: ArrayDelta<real>,
+    light_color: ArrayDelta<[real; 3]>,
+    visibility_fraction: ArrayDelta<f32>,
+}
+
+impl Checkpoint {
+    fn new(
+        id: i64,
+        settings: CheckpointSettings,
+        base: &CheckpointBuffers,
+        buffers: &CheckpointBuffers,
+    ) -> Self {
+        Self {
+            id,
+            settings,
+            visible: GridDelta::new(&base.visible, &buffers.visible),
+            explored: GridDelta::new(&base.explored, &buffers.explored),
+            light_level: ArrayDelta::new(&base.light_level, &buffers.light_level),
+            light_color: ArrayDelta::new(&base.light_color, &buffers.light_color),
+            visibility_fraction: ArrayDelta::new(
+                &base.visibility_fraction,
+                &buffers.visibility_fraction,
+            ),
+        }
+    }
+
+    fn apply(&self, buffers: &mut CheckpointBuffers) {
+        self.visible.apply(&mut buffers.visible);
+        self.explored.apply(&mut buffers.explored);
+        self.light_level.apply(&mut buffers.light_level);
+        self.light_color.apply(&mut buffers.light_color);
+        self.visibility_fraction
+            .apply(&mut buffers.visibility_fraction);
+    }
+}
+
+/// Visibility checkpoints in the order they were pushed, see push_visibility_checkpoint().
+/// The first keeps its changes from empty buffers, every later one its changes from the one
+/// before it
+#[derive(Default)]
+pub struct Checkpoints {
+    checkpoints: Vec<Checkpoint>,
+    // buffers of the last checkpoint, which the next one is taken against
+    last: Option<CheckpointBuffers>,
+    next_id: i64,
+}
+
+impl Checkpoints {
+    /// Keep a checkpoint, returning its id. Ids count up and are never reused
+    pub fn push(&mut self, settings: CheckpointSettings, buffers: CheckpointBuffers) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let empty = CheckpointBuffers::empty();
+        let base = self.last.as_ref().unwrap_or(&empty);
+        self.checkpoints
+            .push(Checkpoint::new(id, settings, base, &buffers));
+        self.last = Some(buffers);
+        id
+    }
+
+    /// The settings and buffers of a checkpoint, or None if there is no checkpoint with this id
+    pub fn get(&self, id: i64) -> Option<(CheckpointSettings, CheckpointBuffers)> {
+        let position = self.position(id)?;
+        Some((
+            self.checkpoints[position].settings,
+            self.buffers_at(position),
+        ))
+    }
+
+    /// Drop every checkpoint after the one with this id, whose buffers, as get() returned them,
+    /// the next checkpoint is then taken against. Does nothing if there is no checkpoint with
+    /// this id
+    pub fn rollback(&mut self, id: i64, buffers: &CheckpointBuffers) {
+        if let Some(position) = self.position(id) {
+            self.checkpoints.truncate(position + 1);
+            self.last = Some(buffers.clone());
+        }
+    }
+
+    /// Drop every checkpoint older than the one with this id, returning how many were dropped.
+    /// None if there is no checkpoint with this id
+    pub fn drop_before(&mut self, id: i64) -> Option<usize> {
+        let position = self.position(id)?;
+        if position > 0 {
+            let buffers = self.buffers_at(position);
+            let first = &self.checkpoints[position];
+            let rebased = Checkpoint::new(
+                first.id,
+                first.settings,
+                &CheckpointBuffers::empty(),
+                &buffers,
+            );
+            self.checkpoints[position] = rebased;
+            self.checkpoints.drain(..position);
+        }
+        Some(position)
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        let last = self.last.as_ref().map_or(0, |last| {
+            last.visible.memory_bytes()
+                + last.explored.memory_bytes()
+                + last.light_level.len() * size_of::<real>()
+                + last.light_color.len() * size_of::<[real; 3]>()
+                + last.visibility_fraction.len() * size_of::<f32>()
+        });
+        let deltas: usize = self
+            .checkpoints
+            .iter()
+            .map(|checkpoint| {
+                checkpoint.visible.patch.len()
+                    + checkpoint.explored.patch.len()
+                    + checkpoint.light_level.memory_bytes()
+                    + checkpoint.light_color.memory_bytes()
+                    + checkpoint.visibility_fraction.memory_bytes()
+            })
+            .sum();
+        last + deltas
+    }
+
+    fn position(&self, id: i64) -> Option<usize> {
+        self.checkpoints
+            .iter()
+            .position(|checkpoint| checkpoint.id == id)
+    }
+
+    /// The buffers of the checkpoint at a position, rebuilt from the first
+    fn buffers_at(&self, position: usize) -> CheckpointBuffers {
+        let mut buffers = CheckpointBuffers::empty();
+        for checkpoint in &self.checkpoints[..=position] {
+            checkpoint.apply(&mut buffers);
+        }
+        buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(turn: i32) -> CheckpointSettings {
+        CheckpointSettings {
+            origin: Vector3i::new(turn, 0, 0),
+            origin_float: Vector3::new(turn as real, 0.0, 0.0),
+            corner_rule: CornerRule::default(),
+            track_visibility_fraction: turn % 2 == 0,
+            lod_start_depth: turn,
+            lod_factor: 0,
+            budget_depth_reduction: 0,
+        }
+    }
+
+    /// Ten turns of buffers, each changing some cells of the turn before and exploring more
+    fn turns(size: Index3) -> Vec<CheckpointBuffers> {
+        let mut seed = 77u64;
+        let mut next = move |len: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize % len
+        };
+        let mut buffers = CheckpointBuffers {
+            visible: BitGrid::new(size),
+            explored: BitGrid::new(size),
+            light_level: Array3::zeros(size),
+            light_color: Array3::default(size),
+            visibility_fraction: Array3::zeros(size),
+        };
+        (0..10)
+            .map(|turn| {
+                buffers.visible.clear();
+                for _ in 0..40 {
+                    let cell = (next(size.0), next(size.1), next(size.2));
+                    buffers.visible.set(cell, true);
+                    buffers.light_level[cell] = turn as real * 0.1;
+                    buffers.light_color[cell] = [turn as real * 0.1, 0.0, turn as real * 0.05];
+                    buffers.visibility_fraction[cell] = 1.0 / (turn + 1) as f32;
+                }
+                buffers.explored.union_with(&buffers.visible);
+                buffers.clone()
+            })
+            .collect()
+    }
+
+    fn assert_identical(restored: &CheckpointBuffers, expected: &CheckpointBuffers) {
+        assert_eq!(restored.visible.size(), expected.visible.size());
+        assert_eq!(restored.visible.to_bytes(), expected.visible.to_bytes());
+        assert_eq!(restored.explored.to_bytes(), expected.explored.to_bytes());
+        assert_eq!(restored.light_level, expected.light_level);
+        assert_eq!(restored.light_color, expected.light_color);
+        assert_eq!(restored.visibility_fraction, expected.visibility_fraction);
+    }
+
+    #[test]
+    fn ten_turn_undo_restores_identical_buffers() {
+        let turns = turns((12, 10, 8));
+        let mut checkpoints = Checkpoints::default();
+        let ids: Vec<i64> = (0..10)
+            .map(|turn| checkpoints.push(settings(turn), turns[turn as usize].clone()))
+            .collect();
+        for turn in (0..10).rev() {
+            let (restored_settings, restored) = checkpoints.get(ids[turn]).unwrap();
+            assert_eq!(restored_settings.origin, settings(turn as i32).origin);
+            assert_eq!(restored_settings.lod_start_depth, turn as i32);
+            assert_identical(&restored, &turns[turn]);
+            checkpoints.rollback(ids[turn], &restored);
+            assert_eq!(checkpoints.checkpoints.len(), turn + 1);
+        }
+        // checkpoints pushed after a rollback are taken against the rolled back buffers
+        let id = checkpoints.push(settings(5), turns[5].clone());
+        assert_identical(&checkpoints.get(id).unwrap().1, &turns[5]);
+        assert!(checkpoints.get(ids[1]).is_none());
+    }
+
+    #[test]
+    fn get_leaves_later_checkpoints() {
+        let turns = turns((6, 6, 6));
+        let mut checkpoints = Checkpoints::default();
+        let ids: Vec<i64> = (0..10)
+            .map(|turn| checkpoints.push(settings(turn), turns[turn as usize].clone()))
+            .collect();
+        checkpoints.get(ids[2]).unwrap();
+        assert_identical(&checkpoints.get(ids[9]).unwrap().1, &turns[9]);
+    }
+
+    #[test]
+    fn drop_before_keeps_later_checkpoints_identical() {
+        let turns = turns((8, 8, 8));
+        let mut checkpoints = Checkpoints::default();
+        let ids: Vec<i64> = (0..10)
+            .map(|turn| checkpoints.push(settings(turn), turns[turn as usize].clone()))
+            .collect();
+        assert_eq!(checkpoints.drop_before(ids[4]), Some(4));
+        assert!(checkpoints.get(ids[3]).is_none());
+        for turn in 4..10 {
+            assert_identical(&checkpoints.get(ids[turn]).unwrap().1, &turns[turn]);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use godot::{builtin::real, prelude::*};
+
+use crate::{
+    bitset::{BitGrid, cell_index, index_cell},
+    shadowcast::{Pass, Rect, UnitPlane3d, all_passes},
+};
+
+/// Offsets a clipped slope rect is widened by past the cone, so that rounding in the solutions
+/// never cuts off a direction inside it
+const SLACK: real = 1e-4;
+/// How far outside the cone a direction may test, in cosine, and still count as inside
+const COS_TOLERANCE: real = 1e-5;
+
+/// The directions within `half_angle` radians of an axis, as a view cone from the eye
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ViewCone {
+    axis: Vector3,
+    cos_half_angle: real,
+}
+
+impl ViewCone {
+    /// None for a zero axis or a half angle of a half turn or more, which leave nothing out
+    pub fn new(axis: Vector3, half_angle: real) -> Option<Self> {
+        if axis == Vector3::ZERO || half_angle >= std::f32::consts::PI as real {
+            return None;
+        }
+        Some(ViewCone {
+            axis: axis.normalized(),
+            cos_half_angle: half_angle.max(0.0).cos(),
+        })
+    }
+
+    /// Whether a direction is inside the cone. The zero direction, the eye itself, is
+    pub fn contains(&self, direction: Vector3) -> bool {
+        let length = direction.length();
+        direction.dot(self.axis) >= (self.cos_half_angle - COS_TOLERANCE) * length
+    }
+
+    /// The slope rect of the part of a pass's frustum inside the cone, shrunk to the bounds of
+    /// where the two intersect one layer deep. None if they do not intersect at all
+    pub fn clip_pass(&self, &(slope_rect, reverse_z, plane): &Pass) -> Option<Rect> {
+        let axis = plane.to_local_float(self.axis) * if reverse_z { -1.0 } else { 1.0 };
+        // The edge of the cone contains() tests against, so that no direction it lets in is lost
+        let c = self.cos_half_angle - COS_TOLERANCE;
+        let (u0, u1) = offset_span(slope_rect.sx, slope_rect.ex);
+        let (v0, v1) = offset_span(slope_rect.sy, slope_rect.ey);
+
+        // The bounds of the intersection are reached at corners of the rect inside the cone,
+        // where the cone's edge crosses a side of the rect, or where the edge itself turns
+        // back in u or v
+        let mut candidates: Vec<(real, real)> = Vec::new();
+        for u in [u0, u1] {
+            for v in [v0, v1] {
+                candidates.push((u, v));
+            }
+            let p = axis.x * u + axis.z;
+            for v in edge_crossings(axis.y, p, c, u) {
+                candidates.push((u, v));
+            }
+        }
+        for v in [v0, v1] {
+            let p = axis.y * v + axis.z;
+            for u in edge_crossings(axis.x, p, c, v) {
+                candidates.push((u, v));
+            }
+        }
+        for (u, v) in turning_points(axis.x, axis.y, axis.z, c) {
+            candidates.push((u, v));
+        }
+        for (v, u) in turning_points(axis.y, axis.x, axis.z, c) {
+            candidates.push((u, v));
+        }
+
+        // Points on the edge are let in past rounding, and those on the opposite nappe kept out
+        let tolerance = SLACK * 0.1;
+        let edge = ViewCone {
+            axis,
+            cos_half_angle: c,
+        };
+        let inside = |&&(u, v): &&(real, real)| {
+            (u0 - tolerance..=u1 + tolerance).contains(&u)
+                && (v0 - tolerance..=v1 + tolerance).contains(&v)
+                && edge.contains(Vector3::new(u, v, 1.0))
+        };
+        let mut bounds: Option<(real, real, real, real)> = None;
+        for &(u, v) in candidates.iter().filter(inside) {
+            bounds = Some(match bounds {
+                Some((min_u, min_v, max_u, max_v)) => {
+                    (min_u.min(u), min_v.min(v), max_u.max(u), max_v.max(v))
+                }
+                None => (u, v, u, v),
+            });
+        }
+        let (min_u, min_v, max_u, max_v) = bounds?;
+
+        // Sides the cone does not come within reach of keep their slopes, infinite ones included
+        let start = |slope: real, from: real, bound: real| match bound - SLACK <= from {
+            true => slope,
+            false => 1.0 / (bound - SLACK),
+        };
+        let end = |slope: real, to: real, bound: real| match bound + SLACK >= to {
+            true => slope,
+            false => 1.0 / (bound + SLACK),
+        };
+        Some(Rect {
+            sx: start(slope_rect.sx, u0, min_u),
+            sy: start(slope_rect.sy, v0, min_v),
+            ex: end(slope_rect.ex, u1, max_u),
+            ey: end(slope_rect.ey, v1, max_v),
+        })
+    }
+
+    /// Every pass in all_passes() order with its slope rect clipped to the cone, None for the
+    /// passes that look entirely outside it
+    pub fn clip_passes(&self) -> Vec<Option<Pass>> {
+        all_passes()
+            .map(|pass| {
+                self.clip_pass(&pass)
+                    .map(|slope_rect| (slope_rect, pass.1, pass.2))
+            })
+            .collect()
+    }
+
+    /// Hide the visible cells whose centers lie outside the cone from `eye`, all but `origin`
+    pub fn keep_inside(&self, visible: &mut BitGrid, eye: Vector3, origin: Vector3i) {
+        let mut outside = Vec::new();
+        visible.for_each_set(|index| {
+            let cell = index_cell(index);
+            if cell != origin && !self.contains(cell.cast_float() - eye) {
+                outside.push(index);
+            }
+        });
+        for index in outside {
+            visible.set(index, false);
+        }
+        visible.set(cell_index(origin), true);
+    }
+}
+
+/// The direction from the eye through offsets (u, v) of a pass's slopes one layer deep, in the
+/// grid's axes
+pub fn pass_direction(u: real, v: real, reverse_z: bool, plane: UnitPlane3d) -> Vector3 {
+    let direction = Vector3::new(u, v, 1.0) * if reverse_z { -1.0 } else { 1.0 };
+    plane.to_grid_float(direction)
+}
+
+/// The directions from the eye through the four corners of a pass's slope rect, in the grid's
+/// axes, in the order (start, start), (end, start), (end, end) and (start, end)
+pub fn corner_directions(&(slope_rect, reverse_z, plane): &Pass) -> [Vector3; 4] {
+    let (u0, u1) = offset_span(slope_rect.sx, slope_rect.ex);
+    let (v0, v1) = offset_span(slope_rect.sy, slope_rect.ey);
+    [(u0, v0), (u1, v0), (u1, v1), (u0, v1)].map(|(u, v)| pass_direction(u, v, reverse_z, plane))
+}
+
+/// The offsets, one layer deep, between two slopes
+fn offset_span(start: real, end: real) -> (real, real) {
+    let (a, b) = (1.0 / start, 1.0 / end);
+    (a.min(b), a.max(b))
+}
+
+/// Where the edge of a cone of unit axis `a` with cosine `c` crosses a side of the rect at a
+/// fixed offset `fixed` along one axis, as offsets along the other, whose part of the axis is
+/// `along`. `p` is the dot product of the axis with the side's point at offset 0 along the other
+fn edge_crossings(along: real, p: real, c: real, fixed: real) -> Vec<real> {
+    // (p + along t)^2 = c^2 (fixed^2 + 1 + t^2)
+    let a = along * along - c * c;
+    let b = 2.0 * p * along;
+    let k = p * p - c * c * (fixed * fixed + 1.0);
+    solve_quadratic(a, b, k)
+}
+
+/// The points of the edge of a cone of unit axis (ax, ay, az) with cosine `c` one layer deep
+/// where it turns back along the first offset, as (first, second) offsets
+fn turning_points(ax: real, ay: real, az: real, c: real) -> Vec<(real, real)> {
+    // Along the edge, the first offset turns back where the gradient of
+    // (ax u + ay v + az)^2 - c^2 (u^2 + v^2 + 1) has no part along v, at v = s ay / c^2
+    // with s the dot product with the axis
+    let c2 = c * c;
+    if c2 < 1e-6 {
+        return Vec::new();
+    }
+    let k = 1.0 - ay * ay / c2;
+    if k.abs() < 1e-6 {
+        return Vec::new();
+    }
+    solve_quadratic(ax * ax - k * c2, 2.0 * ax * az, az * az - k * c2)
+        .into_iter()
+        .map(|u| {
+            let s = (ax * u + az) / k;
+            (u, s * ay / c2)
+        })
+        .collect()
+}
+
+/// The real roots of a t^2 + b t + c = 0, or of b t + c = 0 when a is about 0
+fn solve_quadratic(a: real, b: real, c: real) -> Vec<real> {
+    if a.abs() < 1e-9 {
+        return match b.abs() < 1e-9 {
+            true => Vec::new(),
+            false => vec![-c / b],
+        };
+    }
+    // A touching edge may round to just below 0
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < -1e-6 {
+        return Vec::new();
+    }
+    // Kept clear of cancelling b, which loses the small root when a is tiny
+    let q = -0.5 * (b + discriminant.max(0.0).sqrt().copysign(b));
+    match q == 0.0 {
+        true => vec![0.0],
+        false => vec![q / a, c / q],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    use crate::shadowcast::{
+        INITIAL_SLOPE_RECTS, NarrowPolicy, NarrowRects, RectLimit, cast_light,
+        tests::{caster, random_grid},
+    };
+
+    /// Cones whose edges cross between passes, along the diagonals their planes meet at, and
+    /// around the poles, where every pass along an axis meets
+    fn cones() -> Vec<ViewCone> {
+        let axes = [
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(0.0, -1.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::new(1.0, 1.0, 0.0),
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::new(-1.0, 0.3, 1.0),
+            Vector3::new(0.2, -0.05, -1.0),
+            Vector3::new(0.01, 1.0, 0.02),
+        ];
+        let mut cones = Vec::new();
+        for axis in axes {
+            let (quarter, half) = (FRAC_PI_4 as real, FRAC_PI_2 as real);
+            for half_angle in [0.05, 0.3, quarter, 1.2, half, 2.0, 2.8] {
+                cones.push(ViewCone::new(axis, half_angle).unwrap());
+            }
+        }
+        cones
+    }
+
+    #[test]
+    fn clipped_passes_hold_every_direction_of_the_cone_and_little_else() {
+        let steps = 200;
+        for cone in cones() {
+            for pass in all_passes() {
+                let (u0, u1) = offset_span(pass.0.sx, pass.0.ex);
+                let (v0, v1) = offset_span(pass.0.sy, pass.0.ey);
+                let clipped = cone.clip_pass(&pass);
+                // Bounds of the sampled directions inside the cone
+                let mut seen: Option<(real, real, real, real)> = None;
+                for i in 0..=steps {
+                    for j in 0..=steps {
+                        let u = u0 + (u1 - u0) * i as real / steps as real;
+                        let v = v0 + (v1 - v0) * j as real / steps as real;
+                        let direction = pass_direction(u, v, pass.1, pass.2);
+                        if !cone.contains(direction) {
+                            continue;
+                        }
+                        let Some(slope_rect) = clipped else {
+                            panic!("{cone:?} sees {direction} but dropped its pass");
+                        };
+                        let (cu0, cu1) = offset_span(slope_rect.sx, slope_rect.ex);
+                        let (cv0, cv1) = offset_span(slope_rect.sy, slope_rect.ey);
+                        assert!(
+                            (cu0..=cu1).contains(&u) && (cv0..=cv1).contains(&v),
+                            "{cone:?} sees {direction} outside the clipped pass"
+                        );
+                        seen = Some(match seen {
+                            Some((a, b, c, d)) => (a.min(u), b.min(v), c.max(u), d.max(v)),
+                            None => (u, v, u, v),
+                        });
+                    }
+                }
+
+                // The clipped pass is no wider than the samples and a step around them
+                let (Some(slope_rect), Some((min_u, min_v, max_u, max_v))) = (clipped, seen) else {
+                    continue;
+                };
+                let (cu0, cu1) = offset_span(slope_rect.sx, slope_rect.ex);
+                let (cv0, cv1) = offset_span(slope_rect.sy, slope_rect.ey);
+                let step = 1.0 / steps as real + SLACK;
+                assert!(
+                    cu0 >= min_u - step
+                        && cv0 >= min_v - step
+                        && cu1 <= max_u + step
+                        && cv1 <= max_v + step,
+                    "{cone:?} clipped a pass wider than it sees"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn corner_directions_span_each_pass() {
+        // Every quadrant looks 45 degrees off its axis at its outer corner, and along it at
+        // its inner one
+        for pass in all_passes() {
+            let corners = corner_directions(&pass);
+            let along = pass_direction(0.0, 0.0, pass.1, pass.2);
+            assert!(corners.contains(&along));
+            assert_eq!(along.length(), 1.0);
+            let outer = corners
+                .iter()
+                .map(|corner| corner.length())
+                .fold(0.0, real::max);
+            assert!((outer - (3.0 as real).sqrt()).abs() < 1e-6);
+        }
+        // A cone straight down sees the passes looking down and none of those looking up
+        let down = ViewCone::new(Vector3::new(0.0, -1.0, 0.0), 0.3).unwrap();
+        for (pass, clipped) in all_passes().zip(down.clip_passes()) {
+            let looks_down = corner_directions(&pass).iter().all(|corner| corner.y < 0.0);
+            assert_eq!(clipped.is_some(), looks_down && pass.2 == UnitPlane3d::ZX);
+        }
+        assert_eq!(INITIAL_SLOPE_RECTS.len() * 6, down.clip_passes().len());
+    }
+
+    #[test]
+    fn casting_clipped_passes_sees_the_cone_of_a_whole_cast() {
+        let mut seed = 21;
+        let occluded = random_grid((25, 25, 25), &mut seed);
+        let origin = Vector3i::new(12, 12, 12);
+        let eye = origin.cast_float() + Vector3::new(0.1, -0.2, 0.3);
+        let cast = |passes: &[Option<Pass>]| {
+            let mut visible = BitGrid::new(occluded.size());
+            let mut caster = caster(&occluded, &mut visible, origin);
+            caster.jitter = eye - origin.cast_float();
+            caster.rects = RectLimit::new(
+                0,
+                NarrowRects {
+                    min_width: 0.25,
+                    policy: NarrowPolicy::Continue,
+                },
+            );
+            caster.mark_origin_visible();
+            for (slope_rect, reverse_z, plane) in passes.iter().flatten() {
+                cast_light(&mut caster, slope_rect, 1, *reverse_z, plane);
+            }
+            visible
+        };
+        let whole: Vec<Option<Pass>> = all_passes().map(Some).collect();
+        for cone in cones() {
+            let mut expected = cast(&whole);
+            cone.keep_inside(&mut expected, eye, origin);
+            let mut clipped = cast(&cone.clip_passes());
+            cone.keep_inside(&mut clipped, eye, origin);
+
+            // A cell straddling the cone's edge with its center inside may be seen only through
+            // its part outside, which the clipped passes no longer look through. Cells wholly
+            // inside are seen the same
+            let half_angle = cone.cos_half_angle.acos();
+            let wholly_inside = |cell: Vector3i| {
+                let direction = cell.cast_float() - eye;
+                let length = direction.length();
+                let angle = (direction.dot(cone.axis) / length).clamp(-1.0, 1.0).acos();
+                let radius = ((3.0 as real).sqrt() / 2.0 / length).min(1.0);
+                angle + radius.asin() <= half_angle
+            };
+            let mut differences = Vec::new();
+            expected.for_each_difference(&clipped, |index, in_whole| {
+                let cell = index_cell(index);
+                if !in_whole || wholly_inside(cell) {
+                    differences.push((cell, in_whole));
+                }
+            });
+            assert!(differences.is_empty(), "{cone:?}: {differences:?}");
+        }
+    }
+}
@@ -1,8 +1,24 @@
+use std::f32::consts::FRAC_PI_2;
+
 use godot::{
-    classes::{MeshInstance3D, StandardMaterial3D, TubeTrailMesh},
+    classes::{
+        CylinderMesh, Label3D, MeshInstance3D, StandardMaterial3D, TubeTrailMesh,
+        base_material_3d::{BillboardMode, ShadingMode},
+    },
     prelude::*,
 };
 
+use crate::error::ShadowcastError;
+
+/// Extras drawn along with a debug line. The default is a plain line
+#[derive(Clone, Default)]
+pub struct DebugLineOptions {
+    /// Whether a cone at the end shows which way the line goes
+    pub arrowhead: bool,
+    /// Text shown at the middle of the line, always facing the camera
+    pub label: Option<String>,
+}
+
 #[derive(GodotClass)]
 #[class(init, base=MeshInstance3D)]
 pub struct DebugLine3D {
@@ -16,10 +32,13 @@ impl DebugLine3D {
         end: Vector3,
         scene_prefab: &Gd<PackedScene>,
         color: Color,
-    ) -> Gd<Self> {
-        let mut new = scene_prefab
-            .try_instantiate_as::<Self>()
-            .expect("scene_prefab should be of type DebugLine3D");
+        options: &DebugLineOptions,
+    ) -> Result<Gd<Self>, ShadowcastError> {
+        let Some(mut new) = scene_prefab.try_instantiate_as::<Self>() else {
+            return Err(ShadowcastError::InvalidResource(
+                "Debug line scene should be of type DebugLine3D".to_string(),
+            ));
+        };
 
         // Transform the line segment going from (-0.5, 0, 0) to (0.5, 0, 0)
         // such that it starts at start and ends at end
@@ -28,12 +47,16 @@ impl DebugLine3D {
         let target_direction = (target_vector / target_length).normalized();
 
         // Change the line segment's length such that it matches the length of (end - start)
-        let mut trail_mesh = new
-            .get_mesh()
-            .expect("Should have mesh here")
-            .try_cast::<TubeTrailMesh>()
-            .expect("Should be TubeTrailMesh here");
-        trail_mesh.set_section_length(target_length / 4.0);
+        let Some(Ok(mut trail_mesh)) = new.get_mesh().map(|mesh| mesh.try_cast::<TubeTrailMesh>())
+        else {
+            new.queue_free();
+            return Err(ShadowcastError::InvalidResource(
+                "Debug line scene should have a TubeTrailMesh".to_string(),
+            ));
+        };
+        // section_length stays f32 even in double-precision builds
+        #[allow(clippy::unnecessary_cast)]
+        trail_mesh.set_section_length((target_length / 4.0) as f32);
 
         // Rotate the segment such that it points in the same direction as (end - start)
         if target_direction != Vector3::RIGHT && target_direction != Vector3::LEFT {
@@ -46,13 +69,51 @@ impl DebugLine3D {
         new.set_position((end + start) / 2.0);
 
         // Set color
-        trail_mesh
+        let Some(Ok(mut material)) = trail_mesh
             .get_material()
-            .expect("Should have material here")
-            .try_cast::<StandardMaterial3D>()
-            .expect("Should be StandardMaterial3d")
-            .set_albedo(color);
+            .map(|material| material.try_cast::<StandardMaterial3D>())
+        else {
+            new.queue_free();
+            return Err(ShadowcastError::InvalidResource(
+                "Debug line mesh should have a StandardMaterial3D".to_string(),
+            ));
+        };
+        material.set_albedo(color);
+
+        // The line runs along local x, so its end is half its length out
+        if options.arrowhead {
+            new.add_child(&arrowhead(target_length, color));
+        }
+        if let Some(text) = &options.label {
+            let mut label = Label3D::new_alloc();
+            label.set_text(text.as_str());
+            label.set_billboard_mode(BillboardMode::ENABLED);
+            label.set_modulate(color);
+            new.add_child(&label);
+        }
 
-        new
+        Ok(new)
     }
 }
+
+/// A cone a fifth as long as a line of `length`, pointing along local x with its tip at the
+/// line's end. Mesh sizes stay f32 even in double-precision builds
+#[allow(clippy::unnecessary_cast)]
+fn arrowhead(length: real, color: Color) -> Gd<MeshInstance3D> {
+    let height = (length / 5.0) as f32;
+    let mut cone = CylinderMesh::new_gd();
+    cone.set_top_radius(0.0);
+    cone.set_bottom_radius(height * 0.4);
+    cone.set_height(height);
+    let mut material = StandardMaterial3D::new_gd();
+    material.set_shading_mode(ShadingMode::UNSHADED);
+    material.set_albedo(color);
+
+    let mut arrowhead = MeshInstance3D::new_alloc();
+    arrowhead.set_mesh(&cone);
+    arrowhead.set_material_override(&material);
+    // Cylinders stand along y, turn the tip towards x
+    arrowhead.set_rotation(Vector3::new(0.0, 0.0, -FRAC_PI_2 as real));
+    arrowhead.set_position(Vector3::new(length / 2.0 - height as real / 2.0, 0.0, 0.0));
+    arrowhead
+}
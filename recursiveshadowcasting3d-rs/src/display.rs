@@ -1,135 +1,858 @@
-use std::{
-    f32::INFINITY,
-    ops::{Add, Sub},
-    time::Instant,
+use std::sync::Arc;
+
+use godot::{
+    builtin::real,
+    classes::{
+        ClassDb, Engine, FileAccess, Image, ImageTexture, ImmediateMesh, MeshInstance3D,
+        Performance, StandardMaterial3D, Time,
+        base_material_3d::{CullMode, Flags, ShadingMode, Transparency},
+        file_access::ModeFlags,
+        image::Format,
+        mesh::PrimitiveType,
+    },
+    global::{Error, PropertyUsageFlags},
+    meta::PropertyInfo,
+    obj::WithBaseField,
+    prelude::*,
 };
+use ndarray::{Array3, s};
 
-use godot::{obj::WithBaseField, prelude::*};
-use ndarray::Array3;
+use crate::{
+    bindings::{OccludeWhen, OccluderBinding},
+    bitset::{BitGrid, Index3, UpAxis, cell_at, cell_index, index_cell, shift_span, shift_vacated},
+    channels::Channels,
+    checkpoints::{CheckpointBuffers, CheckpointSettings, Checkpoints},
+    cone::ViewCone,
+    debug_line_3d::{DebugLine3D, DebugLineOptions},
+    distance_field::DistanceField,
+    error::{ShadowcastError, catch_panic},
+    explain::explain_cell,
+    exposed::ExposedCells,
+    floors::FloorSeparators,
+    fov_result::FovResult,
+    input_log::{HASH_START, InputLog, decode_calls, roll_hash},
+    lights::{
+        Emitter, Emitters, Falloff, LightCache, LightSource, MAX_SOFT_SAMPLES, clear_light_levels,
+        soft_sample_offsets,
+    },
+    line_of_sight::{cast_rays, march_ray, supercover, visible_in_box, visible_targets},
+    occlusion_grid::{OcclusionGrid3D, SharedGrid},
+    occlusion_source::OcclusionSource,
+    pass_cache::{PassCache, PassSettings},
+    patch::{decode_patch, encode_patch},
+    peek::{FACE_PEEKS, peek_jitters},
+    portals::{Portal, PortalGraph, Room},
+    probability::{BlockProbabilities, resolve_all, resolves_blocked, seeded_chance, transmission},
+    propagation::propagate,
+    reload::GridState,
+    sampler_import::SamplerImport,
+    shadowcast::{
+        AngleCull, Caster, CornerRule, DebugRect, LayeredCast, Lod, MAX_DEPTH, NarrowPolicy,
+        NarrowRects, OneWayCells, PASS_COUNT, Pass, Rect, RectLimit, SlicedCast, TracedItem,
+        UnitPlane3d, all_passes, cast_layered, cast_light, is_valid_slope_rect, trace_json,
+        walk_frustum,
+    },
+    snapshot::{FovSnapshot, VisibilitySnapshot},
+    state::{EXTENSION_VERSION, STATE_VERSION, ShadowcastState, check_version},
+    terrain::Terrain,
+    views::{RangeShape, View, VisionModifier, VisionModifierKind},
+    visibility_chunks::DirtyChunks,
+    watches::Watches,
+};
 
-use crate::debug_line_3d::DebugLine3D;
+/// Names of the Performance monitors of performance_monitors, in the order monitor_value()
+/// takes them
+const MONITORS: [&str; 4] = [
+    "recompute_usec",
+    "visible_voxels",
+    "work_items",
+    "cache_hits",
+];
 
-const MAX_DEPTH: usize = 15;
+/// Storage-only property the occluded and explored cells are copied, saved and kept across a
+/// GDExtension reload through, see get_property()
+const GRID_STATE_PROPERTY: &str = "grid_state";
 
-enum UnitPlane3d {
-    XY,
-    ZY,
-    ZX,
-}
+const CROSS_SECTION_VISIBLE: Color = Color::from_rgba(0.2, 0.9, 0.3, 0.5);
+const CROSS_SECTION_OCCLUDED: Color = Color::from_rgba(0.5, 0.5, 0.5, 0.6);
+const CROSS_SECTION_SEEN_OCCLUDED: Color = Color::from_rgba(0.9, 0.9, 0.9, 0.6);
+const CROSS_SECTION_EXPLORED: Color = Color::from_rgba(0.2, 0.3, 0.8, 0.3);
 
-struct Rect {
-    sx: f32,
-    sy: f32,
-    // represent end, not length
-    ex: f32,
-    ey: f32,
+fn index_to_position(index: Index3) -> Vector3 {
+    index_cell(index).cast_float()
 }
 
-impl Rect {
-    fn is_valid(&self) -> bool {
-        self.sx < self.ex && self.sy < self.ey
-    }
+/// The six cells sharing a face with a cell. Out of range neighbors wrap around to usize::MAX,
+/// which BitGrid::get() rejects
+fn face_neighbors((x, y, z): Index3) -> [Index3; 6] {
+    [
+        (x.wrapping_sub(1), y, z),
+        (x + 1, y, z),
+        (x, y.wrapping_sub(1), z),
+        (x, y + 1, z),
+        (x, y, z.wrapping_sub(1)),
+        (x, y, z + 1),
+    ]
+}
 
-    fn intersects(&self, other: &Rect) -> bool {
-        self.sx < other.ex && self.ex > other.sx && self.sy < other.ey && self.ey > other.sy
+/// Shift a per-cell array as BitGrid::shift() shifts a grid of `size`. Arrays of another size,
+/// such as ones that were never allocated, are left alone
+fn shift_array<T: Clone + Default>(array: &mut Array3<T>, size: Index3, offset: Vector3i) {
+    if array.dim() != size {
+        return;
     }
+    let (from_x, to_x) = shift_span(size.0, offset.x);
+    let (from_y, to_y) = shift_span(size.1, offset.y);
+    let (from_z, to_z) = shift_span(size.2, offset.z);
+    let mut shifted = Array3::default(size);
+    shifted
+        .slice_mut(s![to_x, to_y, to_z])
+        .assign(&array.slice(s![from_x, from_y, from_z]));
+    *array = shifted;
+}
 
-    fn intersection(&self, other: &Rect) -> Option<Rect> {
-        if !self.intersects(other) {
-            return None;
-        }
+/// Size of the grid of a node that was never given one
+const DEFAULT_GRID_SIZE: Index3 = (100, 100, 100);
 
-        let result = Rect {
-            sx: self.sx.max(other.sx),
-            sy: self.sy.max(other.sy),
-            ex: self.ex.min(other.ex),
-            ey: self.ey.min(other.ey),
-        };
+/// The occlusion grid and the visible, effective visible and explored grids of a new node,
+/// none of which allocate until a cell is set
+fn default_grids() -> [BitGrid; 4] {
+    [(); 4].map(|_| BitGrid::new(DEFAULT_GRID_SIZE))
+}
 
-        if result.is_valid() {
-            Some(result)
-        } else {
-            None
-        }
+/// What sight is cast against: the occlusion source if one is set, else the sight grid.
+/// Takes the fields it reads so the caller can still borrow others mutably
+fn sight_source<'a>(
+    source: &'a Option<Box<dyn OcclusionSource>>,
+    channels: &'a Channels,
+    occluded: &'a BitGrid,
+) -> &'a dyn OcclusionSource {
+    match source {
+        Some(source) => source.as_ref(),
+        None => channels.sight_grid(occluded),
     }
+}
 
-    fn swap_start_and_end(&self) -> Rect {
-        Rect {
-            sx: self.ex,
-            sy: self.ey,
-            ex: self.sx,
-            ey: self.sy,
-        }
+/// What light is cast against: the occlusion source if one is set, else the light grid
+fn light_source<'a>(
+    source: &'a Option<Box<dyn OcclusionSource>>,
+    channels: &'a Channels,
+    occluded: &'a BitGrid,
+) -> &'a dyn OcclusionSource {
+    match source {
+        Some(source) => source.as_ref(),
+        None => channels.light_grid(occluded),
     }
-
-    pub const ZERO: Rect = Rect {
-        sx: 0.0,
-        sy: 0.0,
-        ex: 0.0,
-        ey: 0.0,
-    };
 }
 
-impl Add for Rect {
-    type Output = Rect;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        Rect {
-            sx: self.sx + rhs.sx,
-            sy: self.sy + rhs.sy,
-            ex: self.ex + rhs.ex,
-            ey: self.ey + rhs.ey,
-        }
+/// The passes a recompute with `settings` casts, clipped to its view cone without those
+/// looking entirely outside it
+fn eye_passes(settings: &PassSettings) -> Vec<Pass> {
+    match settings.view_cone {
+        Some(cone) => cone.clip_passes().into_iter().flatten().collect(),
+        None => all_passes().collect(),
     }
 }
 
-impl Sub for Rect {
-    type Output = Rect;
+/// How calls report a position outside the grid. The call fails the same way either way
+#[derive(GodotConvert, Var, Export, Clone, Copy, Default, PartialEq)]
+#[godot(via = i64)]
+pub enum OutOfBounds {
+    #[default]
+    ScriptError,
+    Warning,
+    Silent,
+}
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        Rect {
-            sx: self.sx - rhs.sx,
-            sy: self.sy - rhs.sy,
-            ex: self.ex - rhs.ex,
-            ey: self.ey - rhs.ey,
-        }
-    }
+/// How a recompute from the eye found the cells it sees
+#[derive(Clone, Copy, PartialEq)]
+enum CastPath {
+    /// Pass by pass, reusing the passes no edit touched since the last one
+    Cached,
+    Portals,
+    FloorSeparators,
+    Rays,
+    /// Over several frames, by start_sliced_recompute()
+    Sliced,
+}
+
+/// A recompute start_sliced_recompute() spreads over frames, cast into a grid of its own so
+/// that the visibility keeps the last complete result until it is done
+struct SlicedRecompute {
+    origin: Vector3,
+    origin_int: Vector3i,
+    settings: PassSettings,
+    cast: SlicedCast,
+    visible: BitGrid,
+    usec_per_frame: u64,
+    // time spent scanning so far
+    usec: u64,
+    // generation of the grid the cast has been reading, so edits start it over
+    grid_generation: u64,
 }
 
+/// Shadowcasts visibility and light from an origin through a grid of occluded cells.
+///
+/// Everything that lists cells, from the position getters to the signals and saved states,
+/// lists them in ascending x, then y, then z order. The lists are built from the grids once
+/// casting is done, not in the order the passes reached cells, so identical recomputes
+/// give identical arrays. With up_axis Z the lists still follow the grid, so they come in
+/// ascending x, then z, then y order.
+///
+/// Calls that edit the grid or recompute return OK, or the Error they failed with
 #[derive(GodotClass)]
-#[class(base=Node3D)]
+#[class(tool, base=Node3D)]
 pub struct Display {
     base: Base<Node3D>,
     #[export]
     debug_line_scene: OnEditor<Gd<PackedScene>>,
-    occluded: Array3<bool>,
+    /// Light level a visible cell needs to exceed to count as effectively visible
+    #[export]
+    darkness_threshold: real,
+    /// How far in cells emissive cells light their surroundings in bake_lights()
+    #[export]
+    emissive_radius: i32,
+    /// Whether cells within innate_light_radius of the origin count as lit
+    #[export]
+    use_innate_light: bool,
+    /// Radius of the observer's own light, in cells
+    #[export]
+    innate_light_radius: real,
+    /// Shadowcasts per light in bake_lights(), each from a different point inside the light's
+    /// cell, so cells at shadow edges get fractional light. Baking costs this many times as much
+    /// as a hard bake. 1 gives hard shadows, and it is capped at 64
+    #[export]
+    soft_samples: i32,
+    /// Where in the low-discrepancy sequence soft samples start, for reproducible bakes
+    #[export]
+    soft_seed: i64,
+    /// Whether light squeezes between occluders that only touch at an edge or corner
+    #[export]
+    corner_rule: CornerRule,
+    /// Offset in cells from the origin recomputes are given to the eye they see from, such as
+    /// (0, 1.8, 0) for eyes 1.8 cells above a character's feet. Recomputes see from the cell
+    /// the eye is in, and what is left of the offset within that cell moves the point passes
+    /// cast from, so an eye near the top of its cell sees over a wall as high as its cell
+    #[export]
+    eye_offset: Vector3,
+    /// Size of a cell in this node's own space, such as (1, 0.5, 1) for half height cells.
+    /// Stretching every cell the same way keeps lines straight and cells boxes, so it changes
+    /// where cells are and how they are drawn but not which ones are visible. eye_offset, the
+    /// view cone and radii such as max_depth and light falloff still count cells. Components
+    /// that are not positive count as 1
+    #[export]
+    cell_size: Vector3,
+    /// Direction the eye looks in. Recomputes from the eye only see the cells whose centers
+    /// are within view_cone_half_angle of it, and passes looking entirely outside the cone are
+    /// left out before scanning anything. Zero sees all around
+    #[export]
+    view_cone_direction: Vector3,
+    /// Angle in radians between view_cone_direction and the edge of what the eye sees, PI or
+    /// more for all around
+    #[export]
+    view_cone_half_angle: real,
+    /// How calls given a position outside the grid report it
+    #[export]
+    out_of_bounds: OutOfBounds,
+    /// Which axis is up in the positions, sizes, planes and portal openings scripts pass in and
+    /// get back. The grid always lies y-up in this node's own space, so with UP_Z a position's
+    /// y and z trade places on the way in and out. Byte arrays and images keep the grid's own
+    /// layout, as do terrain heights, saved states, patches and debug lines
+    #[export]
+    up_axis: UpAxis,
+    /// Whether trace_visible_path() ends at the first cell that blocks the path
+    #[export]
+    stop_path_at_occluder: bool,
+    /// Whether recomputes also emit voxel_revealed for each cell voxels_revealed holds and
+    /// watch_changed for each watch watches_changed holds, for scripts written against them.
+    /// Every one is a signal of its own, which costs far more than the batches with many cells
+    #[export]
+    fine_grained_signals: bool,
+    /// Fraction of the cells under a cell of make_downsampled_copy() that must be occluded for
+    /// it to be. 0 occludes it when any one is
+    #[export]
+    downsample_occluded_fraction: real,
+    /// Fraction of a cell a brush of paint_occlusion_sphere() and paint_occlusion_cylinder()
+    /// must cover to paint it. 0 paints the cells whose center is inside the brush
+    #[export]
+    coverage_threshold: real,
+    /// Furthest distance in cells get_distance_to_occluder() measures. Larger caps make the
+    /// first query after an edit slower
+    #[export]
+    distance_field_radius: i32,
+    /// Cells along each axis of the chunks take_dirty_visibility_chunks() reports. Changing it
+    /// starts the tracking over, with every chunk dirty
+    #[export]
+    #[var(get, set = set_visibility_chunk_size)]
+    visibility_chunk_size: i32,
+    /// Whether recomputes also work out how much of each visible cell is in view,
+    /// for get_visibility_fraction(). This makes recomputes slower
+    #[export]
+    track_visibility_fraction: bool,
+    /// Whether recomputes only report the shell of the grid that can be seen: occluded cells
+    /// in view, and cells next to an occluded cell along x, y or z. Other empty cells still let
+    /// sight through, but are left out of the visible cells, explored cells and signals.
+    /// The origin is always reported
+    #[export]
+    report_surfaces_only: bool,
+    /// Radius around the origin within which visible cells are fully identified, for sight
+    /// that only makes out shapes further away. Past it only occluded cells are seen, as
+    /// silhouettes, see get_visibility_tier(). 0 identifies every visible cell
+    #[export]
+    detail_radius: real,
+    /// Depth from which the recompute samples occluders in coarser blocks, trading exactness
+    /// near block edges for bounded work at long range. 0 disables it
+    #[export]
+    lod_start_depth: i32,
+    /// How much the LOD block size grows every lod_start_depth layers
+    #[export]
+    lod_factor: i32,
+    /// Angle in radians below which a cell seen head on is too small to matter. Layers with
+    /// cells smaller than this are handled as subtended_angle_cull says. It works alongside
+    /// lod_start_depth, with the wider block of the two used at each layer. 0 disables it
+    #[export]
+    min_subtended_angle: real,
+    /// Whether layers past min_subtended_angle sample occluders in blocks wide enough to
+    /// subtend it, or end the cast. Ending it shortens the reach of views with a spherical
+    /// range the same way, and they still cut off what lies outside their sphere
+    #[export]
+    subtended_angle_cull: AngleCull,
+    /// Whether cells exactly at the maximum depth from the origin are in range, along an axis
+    /// or in a view's spherical range. Ranges are measured between cell centers, from the cell
+    /// the origin is in, so where they end does not move as the origin moves within its cell.
+    /// Applies to recomputes, views and line of sight queries alike
+    #[export]
+    range_is_inclusive: bool,
+    /// Most views (pieces of a pass at one depth) a recompute scans before it stops going deeper,
+    /// to bound the time dense noise can take. Nearer layers are always finished before further
+    /// ones start. 0 for no limit. Casting through portals or floor separators is not limited
+    #[export]
+    max_work_items: i64,
+    /// Most unblocked pieces one view is split into by the occluders in it before the smallest
+    /// are merged into their bounding box, to bound the time a view riddled with small gaps
+    /// can take. Merging can make extra cells visible, but never hides one. 0 for no limit
+    #[export]
+    max_rects_per_node: i64,
+    /// Fraction of a cell below which the width or height of an unblocked piece of a view is
+    /// too narrow for cells deeper in to be seen through it exactly, as deep in a narrow
+    /// corridor. 0 treats no piece as narrow
+    #[export]
+    narrow_rect_width: real,
+    /// What happens to pieces narrower than narrow_rect_width: dropped, snapped to the nearest
+    /// cell boundaries or cast through as they are
+    #[export]
+    narrow_rect_policy: NarrowPolicy,
+    /// Whether recomputes record every view they scan, for dump_last_recompute_trace().
+    /// Recomputes that cast through portals, floor separators or with rays are not recorded
+    #[export]
+    capture_trace: bool,
+    /// Whether recomputes keep the unblocked pieces of every view they scan, for
+    /// get_lit_volume_quads(). Recomputes that cast through portals, floor separators or with
+    /// rays keep none
+    #[export]
+    capture_lit_volume: bool,
+    /// Recompute time in microseconds above which recompute_over_budget is emitted.
+    /// 0 disables the check
+    #[export]
+    budget_warning_usec: i64,
+    /// Whether every recompute over budget_warning_usec makes the following ones reach one layer
+    /// less deep, with the layers restored one at a time once recomputes take under half of it
+    #[export]
+    reduce_depth_over_budget: bool,
+    /// Depth at or below which recomputes from the eye test every cell in range with rays
+    /// instead of shadowcasting, which is quicker for tiny ranges such as a candle's. Rays see
+    /// the open cells shadowcasting sees with narrow_rect_policy Continue, but also floors and
+    /// walls at grazing angles that shadowcasting leaves out. Such recomputes keep no trace or
+    /// lit volume, and count every visible cell as fully visible. Recomputes through portals or floor separators and peeks always
+    /// shadowcast. 0 never uses rays
+    #[export]
+    ray_cast_max_depth: i64,
+    /// Whether recomputes leave out blocks of occluded cells buried in other occluders, which
+    /// those around them already shadow. Scans merge occluded cells into blocks that nearly
+    /// always take in a cell with a face on an open one, so this seldom leaves any block out,
+    /// while keeping track of such cells costs a little on the first recompute after edits
+    #[export]
+    cull_buried_occluders: bool,
+    /// Whether a bound occluder changing recomputes from the last origin right away, so that
+    /// opening a door needs no other call
+    #[export]
+    auto_recompute: bool,
+    /// Whether this node adds its recompute time, visible cells, work items and cached passes
+    /// to Godot's Performance monitors while in the tree of a running game, as
+    /// shadowcast/recompute_usec and so on. When another Display already has those names, this
+    /// node's instance id is added to the end of each
+    #[export]
+    performance_monitors: bool,
+    /// Fog image shade of cells never seen. Fog images are greyscale, so only the luminance is used
+    #[export]
+    fog_unexplored_color: Color,
+    /// Fog image shade of cells seen before, but not by the last recompute
+    #[export]
+    fog_explored_color: Color,
+    /// Fog image shade of cells seen by the last recompute
+    #[export]
+    fog_visible_color: Color,
+    /// Whether every recompute draws the cross section picked by cross_section_axis and
+    /// cross_section_index as colored quads, at its place in the grid
+    #[export]
+    draw_cross_section: bool,
+    /// 0 for x, 1 for y and 2 for z, as in get_visibility_cross_section()
+    #[export]
+    cross_section_axis: i32,
+    #[export]
+    cross_section_index: i32,
+    /// Occlusion grid to share with every other Display it is assigned to, so they keep one
+    /// copy of the map between them. Edits through any of them go to it, and the others catch
+    /// up by their next frame or recompute. A grid that was never assigned takes on this node's
+    /// cells, any other replaces them. Unassigning it leaves this node a copy of its own.
+    /// The grid must only be edited on the main thread, and never from a callback of a query
+    /// on another node sharing it
+    #[export]
+    #[var(get, set = set_occlusion_grid)]
+    occlusion_grid: Option<Gd<OcclusionGrid3D>>,
+    // the default channel's grid, that of occlusion_grid while one is assigned
+    occluded: SharedGrid,
+    // generation of occluded as of the last time this node caught up with edits to it
+    grid_generation: u64,
+    // set cells in occluded, kept up to date by every edit so get_occluded_count() is O(1)
+    occluded_count: usize,
+    // occlusion channels beside the default one in occluded, and what sight and light cast against
+    channels: Channels,
+    // the grid as of the last take_change_patch(), while tracking changes
+    change_base: Option<BitGrid>,
+    // occluded cells that only block sight one way, see set_one_way_occluder()
+    one_way: OneWayCells,
+    // chance each cell with one blocks sight, see set_block_probability()
+    block_probability: BlockProbabilities,
+    // seed the block probabilities were resolved with, see set_probability_seed()
+    probability_seed: Option<u64>,
+    // game-defined tag per cell, allocated by the first set_tag()
+    tags: Option<Array3<u16>>,
+    // heightmap that occludes alongside the grid, see set_terrain_heights()
+    terrain: Option<Terrain>,
+    // the game's own occlusion, cast against instead of the grid, see set_occlusion_source()
+    occlusion_source: Option<Box<dyn OcclusionSource>>,
+    // cells seen from the origin by the last recompute
+    visible: BitGrid,
+    // shared copy of `visible` for snapshots, made on the first snapshot after each recompute
+    visible_snapshot: Option<Arc<BitGrid>>,
+    // fraction of each cell in view as of the last recompute, when track_visibility_fraction is set
+    visibility_fraction: Array3<f32>,
+    // TIER_* of each cell as of the last recompute, when detail_radius is set
+    visibility_tiers: Array3<u8>,
+    // visible cells that are also lit, as of the last recompute or light bake
+    effective_visible: BitGrid,
+    // cells seen by any recompute since the grid was last resized or clear_explored()
+    explored: BitGrid,
+    // reused between flood fills, to avoid reallocating a grid-sized visited set
+    flood_scratch: BitGrid,
+    // power received per cell by the last compute_propagation()
+    propagation: Array3<f32>,
+    lights: Vec<LightSource>,
+    next_light_id: i64,
+    // cells following a node's property, see bind_occluder_to_property()
+    occluder_bindings: Vec<OccluderBinding>,
+    next_binding_id: i64,
+    // cells that give off light of their own, see set_emissive()
+    emissive: Emitters,
+    // observers with their own results, created with create_view()
+    views: Vec<View>,
+    next_view_id: i64,
+    // accumulated light per cell as of the last bake_lights()
+    light_level: Array3<real>,
+    // light_level tinted by each light's color, as red, green and blue
+    light_color: Array3<[real; 3]>,
+    // time taken by each soft sample of the last bake_lights()
+    last_bake_sample_usec: Vec<u64>,
+    // time the last bake_lights() took to add up the light of every source
+    last_bake_accumulate_usec: u64,
+    // light sources the last bake_lights() cast, and those it took from light_cache
+    last_bake_cast_lights: usize,
+    last_bake_cached_lights: usize,
+    // light of every source as of the last bake_lights(), so bakes only cast lights that edits touched
+    light_cache: LightCache,
+    // distances to the nearest occluder, brought up to date by the first query after edits
+    distance_field: DistanceField,
+    // occluded cells of the sight grid with a face on an open one, brought up to date by the
+    // first recompute after edits, for cull_buried_occluders
+    exposed: ExposedCells,
+    // chunks whose visibility changed since take_dirty_visibility_chunks()
+    dirty_chunks: DirtyChunks,
     origin: Vector3i,
     origin_float: Vector3,
+    // timings of the last recompute, from Time rather than std::time so they work in web exports
+    last_recompute_usec: u64,
+    last_pass_usec: Vec<u64>,
+    last_pass_work_items: Vec<usize>,
+    last_cached_passes: usize,
+    last_work_items: usize,
+    last_truncated: bool,
+    last_max_rects: usize,
+    last_rect_merges: usize,
+    // views scanned by the last recompute when capture_trace is set, kept to be reused
+    last_trace: Vec<TracedItem>,
+    // how the last recompute cast, which leaves the pass cache as it was unless Cached, and
+    // how many rays it traced
+    last_cast_path: CastPath,
+    last_rays: usize,
+    // stats of every sub-origin of the last recompute_with_peek(), empty after other recomputes
+    last_peeks: VariantArray,
+    // peek offsets of the last recompute, as called, None before the first
+    last_peek_offsets: Option<Vec<Vector3>>,
+    // layers taken off the depth of recomputes by reduce_depth_over_budget
+    budget_depth_reduction: usize,
+    // per-pass results from the last origin, so recomputes only re-run passes that edits touched
+    pass_cache: PassCache,
+    // caller-provided buffer that recomputes also write visibility into, one byte per cell
+    external_visibility: Option<PackedByteArray>,
+    // calls recorded since start_input_log(), for save_input_log()
+    input_log: Option<InputLog>,
+    // import_via_sampler() in progress, advanced a batch every frame
+    sampler_import: Option<SamplerImport>,
+    // start_sliced_recompute() in progress, advanced for its time every frame
+    sliced_recompute: Option<SlicedRecompute>,
+    // visibility as of each push_visibility_checkpoint() still kept
+    checkpoints: Checkpoints,
+    // signals raised during a recompute, held back to be emitted in order once it is done
+    pending_signals: Option<Vec<(&'static str, Vec<Variant>)>>,
+    // time taken to emit the signals the last recompute held back
+    last_signal_usec: u64,
+    // cells reported by watches_changed, see add_watch()
+    watches: Watches,
+    // rooms and openings between them, which recomputes cast through when any are registered
+    portals: PortalGraph,
+    // floors between storeys, which recomputes cast through when any are declared
+    floors: FloorSeparators,
+    // quads drawn for draw_cross_section, replaced by every recompute
+    cross_section_mesh: Option<Gd<MeshInstance3D>>,
+    // meshes of debug_draw_cells() and debug_draw_lines(), with the seconds each has left
+    debug_drawings: Vec<(Gd<MeshInstance3D>, Option<f64>)>,
+    // cells seen by the last recompute, for the visible_voxels monitor
+    last_visible_cells: usize,
+    // the Performance monitors this node added, removed again when it leaves the tree
+    monitor_ids: Vec<StringName>,
 }
 
 #[godot_api]
 impl INode3D for Display {
     fn init(base: Base<Node3D>) -> Self {
+        let [occluded, visible, effective_visible, explored] = default_grids();
         Self {
             base,
             debug_line_scene: OnEditor::default(),
-            occluded: Array3::from_elem((100, 100, 100), false),
+            darkness_threshold: 0.0,
+            emissive_radius: 4,
+            use_innate_light: true,
+            innate_light_radius: 1.5,
+            soft_samples: 1,
+            soft_seed: 0,
+            corner_rule: CornerRule::Block,
+            eye_offset: Vector3::ZERO,
+            cell_size: Vector3::ONE,
+            view_cone_direction: Vector3::ZERO,
+            view_cone_half_angle: std::f32::consts::FRAC_PI_4 as real,
+            out_of_bounds: OutOfBounds::ScriptError,
+            up_axis: UpAxis::Y,
+            stop_path_at_occluder: true,
+            fine_grained_signals: false,
+            downsample_occluded_fraction: 0.5,
+            coverage_threshold: 0.0,
+            distance_field_radius: 8,
+            visibility_chunk_size: 16,
+            track_visibility_fraction: false,
+            report_surfaces_only: false,
+            detail_radius: 0.0,
+            lod_start_depth: 0,
+            lod_factor: 2,
+            min_subtended_angle: 0.0,
+            subtended_angle_cull: AngleCull::Coarsen,
+            range_is_inclusive: true,
+            max_work_items: 0,
+            max_rects_per_node: 0,
+            narrow_rect_width: 0.25,
+            narrow_rect_policy: NarrowPolicy::Snap,
+            capture_trace: false,
+            capture_lit_volume: false,
+            budget_warning_usec: 0,
+            reduce_depth_over_budget: false,
+            ray_cast_max_depth: 0,
+            cull_buried_occluders: false,
+            auto_recompute: false,
+            performance_monitors: true,
+            fog_unexplored_color: Color::BLACK,
+            fog_explored_color: Color::from_rgb(0.5, 0.5, 0.5),
+            fog_visible_color: Color::WHITE,
+            draw_cross_section: false,
+            cross_section_axis: 1,
+            cross_section_index: 0,
+            occlusion_grid: None,
+            occluded: SharedGrid::new(occluded),
+            grid_generation: 0,
+            occluded_count: 0,
+            channels: Channels::default(),
+            change_base: None,
+            one_way: OneWayCells::new(),
+            block_probability: BlockProbabilities::new(),
+            probability_seed: None,
+            tags: None,
+            terrain: None,
+            occlusion_source: None,
+            visible,
+            visible_snapshot: None,
+            visibility_fraction: Array3::zeros((0, 0, 0)),
+            visibility_tiers: Array3::zeros((0, 0, 0)),
+            effective_visible,
+            explored,
+            flood_scratch: BitGrid::default(),
+            propagation: Array3::zeros((0, 0, 0)),
+            lights: Vec::new(),
+            next_light_id: 0,
+            occluder_bindings: Vec::new(),
+            next_binding_id: 0,
+            emissive: Emitters::new(),
+            views: Vec::new(),
+            next_view_id: 0,
+            light_level: Array3::zeros((0, 0, 0)),
+            light_color: Array3::default((0, 0, 0)),
+            last_bake_sample_usec: Vec::new(),
+            last_bake_accumulate_usec: 0,
+            last_bake_cast_lights: 0,
+            last_bake_cached_lights: 0,
+            light_cache: LightCache::default(),
+            distance_field: DistanceField::default(),
+            exposed: ExposedCells::default(),
+            dirty_chunks: DirtyChunks::new(16),
             origin: Vector3i::ZERO,
             origin_float: Vector3::ZERO,
+            last_recompute_usec: 0,
+            last_pass_usec: Vec::new(),
+            last_pass_work_items: Vec::new(),
+            last_cached_passes: 0,
+            last_work_items: 0,
+            last_truncated: false,
+            last_max_rects: 0,
+            last_rect_merges: 0,
+            last_trace: Vec::new(),
+            last_cast_path: CastPath::Cached,
+            last_rays: 0,
+            last_peeks: VariantArray::new(),
+            last_peek_offsets: None,
+            budget_depth_reduction: 0,
+            pass_cache: PassCache::default(),
+            external_visibility: None,
+            input_log: None,
+            sampler_import: None,
+            sliced_recompute: None,
+            checkpoints: Checkpoints::default(),
+            pending_signals: None,
+            last_signal_usec: 0,
+            watches: Watches::default(),
+            portals: PortalGraph::default(),
+            floors: FloorSeparators::default(),
+            cross_section_mesh: None,
+            debug_drawings: Vec::new(),
+            last_visible_cells: 0,
+            monitor_ids: Vec::new(),
+        }
+    }
+
+    fn enter_tree(&mut self) {
+        self.add_performance_monitors();
+    }
+
+    fn exit_tree(&mut self) {
+        self.remove_performance_monitors();
+    }
+
+    fn process(&mut self, delta: f64) {
+        self.sync_shared_grid();
+        self.update_occluder_bindings();
+        self.advance_sampler_import();
+        self.advance_sliced_recompute();
+        self.expire_debug_drawings(delta);
+    }
+
+    /// The occluded and explored cells go through the storage-only grid_state property as a
+    /// GridState, so duplicate() and saved scenes get a copy of their own rather than sharing or
+    /// losing them, and a GDExtension reload, which keeps storage properties across init(),
+    /// keeps them too. Exported settings are storage properties already. A node sharing
+    /// occlusion_grid leaves the property out, so taking it in does not overwrite the grid the
+    /// others share. What a copy does not get is left behind with a warning on the copy
+    fn get_property(&self, property: StringName) -> Option<Variant> {
+        if property != StringName::from(GRID_STATE_PROPERTY) || self.occlusion_grid.is_some() {
+            return None;
+        }
+        let bytes = GridState::encode(&self.occluded.grid(), &self.explored);
+        GridState::note_left_behind(&bytes, self.left_out_of_grid_state());
+        Some(PackedByteArray::from(bytes).to_variant())
+    }
+
+    fn set_property(&mut self, property: StringName, value: Variant) -> bool {
+        if property != StringName::from(GRID_STATE_PROPERTY) {
+            return false;
+        }
+        match value.try_to::<PackedByteArray>() {
+            Ok(bytes) => self.restore_grid_state(bytes.as_slice()),
+            Err(_) => godot_script_error!("{} must be bytes", GRID_STATE_PROPERTY),
+        }
+        true
+    }
+
+    fn get_property_list(&mut self) -> Vec<PropertyInfo> {
+        match self.occlusion_grid {
+            Some(_) => Vec::new(),
+            None => vec![PropertyInfo {
+                usage: PropertyUsageFlags::STORAGE,
+                ..PropertyInfo::new_var::<PackedByteArray>(GRID_STATE_PROPERTY)
+            }],
         }
     }
 }
 
 #[godot_api]
 impl Display {
+    /// Values scripts can pass for the plane, range shape, corner rule, out-of-bounds, falloff,
+    /// occlude-when, up axis, angle cull, narrow policy and vision modifier enums, the debug
+    /// drawing styles and the visibility tiers
+    #[constant]
+    const PLANE_XY: i64 = 0;
+    #[constant]
+    const PLANE_ZY: i64 = 1;
+    #[constant]
+    const PLANE_ZX: i64 = 2;
+    #[constant]
+    const RANGE_CUBE: i64 = 0;
+    #[constant]
+    const RANGE_SPHERE: i64 = 1;
+    #[constant]
+    const CORNER_BLOCK: i64 = 0;
+    #[constant]
+    const CORNER_ALLOW: i64 = 1;
+    #[constant]
+    const OUT_OF_BOUNDS_SCRIPT_ERROR: i64 = 0;
+    #[constant]
+    const OUT_OF_BOUNDS_WARNING: i64 = 1;
+    #[constant]
+    const OUT_OF_BOUNDS_SILENT: i64 = 2;
+    #[constant]
+    const FALLOFF_LINEAR: i64 = 0;
+    #[constant]
+    const FALLOFF_INVERSE_SQUARE: i64 = 1;
+    #[constant]
+    const FALLOFF_STEPPED: i64 = 2;
+    #[constant]
+    const OCCLUDE_WHEN_TRUE: i64 = 0;
+    #[constant]
+    const OCCLUDE_WHEN_FALSE: i64 = 1;
+    #[constant]
+    const UP_Y: i64 = 0;
+    #[constant]
+    const UP_Z: i64 = 1;
+    #[constant]
+    const ANGLE_COARSEN: i64 = 0;
+    #[constant]
+    const ANGLE_STOP: i64 = 1;
+    #[constant]
+    const NARROW_STOP: i64 = 0;
+    #[constant]
+    const NARROW_SNAP: i64 = 1;
+    #[constant]
+    const NARROW_CONTINUE: i64 = 2;
+    #[constant]
+    const DEBUG_WIREFRAME: i64 = 0;
+    #[constant]
+    const DEBUG_FILLED: i64 = 1;
+    #[constant]
+    const TIER_UNSEEN: i64 = 0;
+    #[constant]
+    const TIER_SILHOUETTE: i64 = 1;
+    #[constant]
+    const TIER_FULL: i64 = 2;
+    #[constant]
+    const VISION_RANGE_MULTIPLIER: i64 = 0;
+    #[constant]
+    const VISION_RANGE_FLOOR: i64 = 1;
+    #[constant]
+    const VISION_BLINDNESS: i64 = 2;
+    #[constant]
+    const VISION_DARKVISION: i64 = 3;
+
+    /// Emitted by a recompute with every cell it saw that the one before did not, in ascending
+    /// x, then y, then z order of the grid. Only worked out while something is connected
+    #[signal]
+    fn voxels_revealed(cells: PackedVector3Array);
+
+    /// Emitted for each cell of voxels_revealed in turn while fine_grained_signals is set
+    #[signal]
+    fn voxel_revealed(cell: Vector3);
+
+    /// Emitted by a recompute with the watches, see add_watch(), whose cell became or stopped
+    /// being visible, in ascending id order, and 1 or 0 for whether each is visible now
+    #[signal]
+    fn watches_changed(ids: PackedInt64Array, states: PackedByteArray);
+
+    /// Emitted for each watch of watches_changed in turn while fine_grained_signals is set
+    #[signal]
+    fn watch_changed(id: i64, visible: bool);
+
+    /// Emitted when cells become or stop being effectively visible (both seen and lit)
+    #[signal]
+    fn effective_visibility_changed(revealed: PackedVector3Array, hidden: PackedVector3Array);
+
+    /// Emitted when cells change visibility tier, see get_visibility_tier(), with each cell and
+    /// its new tier. Only emitted while detail_radius is set
+    #[signal]
+    fn visibility_tier_changed(cells: PackedVector3Array, tiers: PackedInt32Array);
+
+    /// Emitted when a recompute stopped short of max depth to stay within max_work_items.
+    /// Every cell up to `completed_depth` layers away is still right
+    #[signal]
+    fn recompute_truncated(work_items: i64, completed_depth: i64);
+
+    /// Emitted when a recompute took longer than budget_warning_usec. `stats` is
+    /// get_last_recompute_stats() along with "depth_histogram", the number of visible cells
+    /// at each depth from the origin
+    #[signal]
+    fn recompute_over_budget(elapsed_usec: i64, stats: Dictionary);
+
+    /// Emitted at the end of every set_origin_and_recompute(), with the time it took.
+    /// The rest of its stats are in get_last_recompute_stats()
+    #[signal]
+    fn recompute_finished(elapsed_usec: i64);
+
+    /// Emitted when a recompute start_sliced_recompute() began was dropped before it was done,
+    /// by cancel_pending_recompute() or another recompute, with the origin it cast from
+    #[signal]
+    fn recompute_cancelled(origin: Vector3);
+
+    /// Emitted when a vision modifier with a duration lapses, with the view's handle and the
+    /// modifier's kind
+    #[signal]
+    fn vision_modifier_expired(handle: i64, kind: i64);
+
+    /// Emitted by import_via_sampler() after every batch, with the number of cells imported so
+    /// far and in all. The import is done once they are equal
+    #[signal]
+    fn sampler_import_progress(imported: i64, total: i64);
+
+    /// Emitted by scroll_grid() with the offset it scrolled by and the cells it left empty to
+    /// be refilled, as inclusive boxes from vacated_from[i] to vacated_to[i] that do not overlap
+    #[signal]
+    fn grid_scrolled(
+        offset: Vector3i,
+        vacated_from: PackedVector3Array,
+        vacated_to: PackedVector3Array,
+    );
+
+    #[allow(clippy::too_many_arguments)]
     pub fn draw_debug_line(
         &mut self,
-        sx: f32,
-        sy: f32,
-        sz: f32,
-        ex: f32,
-        ey: f32,
-        ez: f32,
+        sx: real,
+        sy: real,
+        sz: real,
+        ex: real,
+        ey: real,
+        ez: real,
         color: Color,
     ) {
         let start = Vector3 {
@@ -142,388 +865,5112 @@ impl Display {
             y: ey,
             z: ez,
         };
-        let line = DebugLine3D::new(start, end, &self.debug_line_scene, color);
+        self.draw_debug_line_with(start, end, color, &DebugLineOptions::default());
+    }
+
+    /// Draw a debug line with an arrowhead at `end` or a label, as `options` ask
+    pub fn draw_debug_line_with(
+        &mut self,
+        start: Vector3,
+        end: Vector3,
+        color: Color,
+        options: &DebugLineOptions,
+    ) {
+        // Debug lines are optional, so an unset scene only skips them
+        if self.debug_line_scene.is_invalid() {
+            return;
+        }
+        let size = self.grid_cell_size();
+        let (start, end) = (start * size, end * size);
+        let line = match DebugLine3D::new(start, end, &self.debug_line_scene, color, options) {
+            Ok(line) => line,
+            Err(error) => {
+                error.report();
+                return;
+            }
+        };
         self.base_mut()
             .call_deferred("add_child", &[line.to_variant()]);
         // self.base_mut().add_child(&line);
     }
 
-    fn draw_debug_rect_xy(&mut self, depth: f32, rect: &Rect, color: Color) {
-        self.draw_debug_line(rect.sx, rect.sy, depth, rect.ex, rect.sy, depth, color);
-        self.draw_debug_line(rect.sx, rect.sy, depth, rect.sx, rect.ey, depth, color);
-        self.draw_debug_line(rect.ex, rect.sy, depth, rect.ex, rect.ey, depth, color);
-        self.draw_debug_line(rect.sx, rect.ey, depth, rect.ex, rect.ey, depth, color);
+    fn draw_debug_rect(&mut self, debug_rect: &DebugRect) {
+        let DebugRect {
+            plane,
+            depth,
+            rect,
+            color,
+        } = *debug_rect;
+        let corner = |x: real, y: real| plane.to_grid_float(Vector3::new(x, y, depth));
+        let corners = [
+            corner(rect.sx, rect.sy),
+            corner(rect.ex, rect.sy),
+            corner(rect.ex, rect.ey),
+            corner(rect.sx, rect.ey),
+        ];
+        for (i, from) in corners.iter().enumerate() {
+            let to = corners[(i + 1) % corners.len()];
+            self.draw_debug_line(from.x, from.y, from.z, to.x, to.y, to.z, color);
+        }
     }
 
-    fn draw_debug_rect_zy(&mut self, depth: f32, rect: &Rect, color: Color) {
-        self.draw_debug_line(depth, rect.sy, rect.sx, depth, rect.sy, rect.ex, color);
-        self.draw_debug_line(depth, rect.sy, rect.sx, depth, rect.ey, rect.sx, color);
-        self.draw_debug_line(depth, rect.sy, rect.ex, depth, rect.ey, rect.ex, color);
-        self.draw_debug_line(depth, rect.ey, rect.sx, depth, rect.ey, rect.ex, color);
+    #[func]
+    pub fn set_occluded(&mut self, pos: Vector3i) -> Error {
+        self.log_call("set_occluded", &[pos.to_variant()]);
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        if !self.set_occluder(index, true) {
+            return self.report_out_of_bounds(pos);
+        }
+        self.one_way.remove(&index);
+        self.invalidate_occluders(pos, pos);
+        Error::OK
     }
 
-    fn draw_debug_rect_zx(&mut self, depth: f32, rect: &Rect, color: Color) {
-        self.draw_debug_line(rect.sy, depth, rect.sx, rect.sy, depth, rect.ex, color);
-        self.draw_debug_line(rect.sy, depth, rect.sx, rect.ey, depth, rect.sx, color);
-        self.draw_debug_line(rect.sy, depth, rect.ex, rect.ey, depth, rect.ex, color);
-        self.draw_debug_line(rect.ey, depth, rect.sx, rect.ey, depth, rect.ex, color);
+    /// Occlude a cell for sight going one way only, like an arrow slit or one-way mirror.
+    /// `open_direction` is the way sight passes through freely: the cell does not block
+    /// a viewer looking at it along that direction, e.g. Vector3i(0, 0, 1) lets viewers with a
+    /// smaller z see through towards larger z. Only the direction's sign per axis matters.
+    /// Far layers sampled in LOD blocks treat it as a plain occluder.
+    /// set_occluded() makes it a plain occluder again, carving it clears it
+    #[func]
+    pub fn set_one_way_occluder(&mut self, pos: Vector3i, open_direction: Vector3i) -> Error {
+        self.log_call(
+            "set_one_way_occluder",
+            &[pos.to_variant(), open_direction.to_variant()],
+        );
+        let pos = self.up_axis.to_grid(pos);
+        let open_direction = self.up_axis.to_grid(open_direction);
+        let index = cell_index(pos);
+        if open_direction == Vector3i::ZERO {
+            godot_script_error!("One-way occluders need a nonzero open direction");
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        if !self.set_occluder(index, true) {
+            return self.report_out_of_bounds(pos);
+        }
+        self.one_way.insert(index, open_direction.sign());
+        self.invalidate_occluders(pos, pos);
+        Error::OK
     }
 
+    /// Give a cell a chance to block sight, from 0 to 1, such as dense foliage that should
+    /// probably but not certainly hide what is behind it. 0 removes it. Occluded cells block
+    /// regardless. Without a probability seed the cell does not block casts, and
+    /// get_transmission() says how likely sight is to get through it. With one, it blocks
+    /// sight or not as the seed decides. Light is not affected
     #[func]
-    pub fn set_occluded(&mut self, pos: Vector3i) {
-        let index = (pos.x as usize, pos.y as usize, pos.z as usize);
-        if let Some(val) = self.occluded.get_mut(index) {
-            *val = true;
-        } else {
-            godot_script_error!("Out of bounds at position {}", pos)
+    pub fn set_block_probability(&mut self, pos: Vector3i, probability: real) -> Error {
+        self.log_call(
+            "set_block_probability",
+            &[pos.to_variant(), probability.to_variant()],
+        );
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(pos);
+        }
+        if !(0.0..=1.0).contains(&probability) {
+            godot_script_error!("Block probability {} is not between 0 and 1", probability);
+            return Error::ERR_INVALID_PARAMETER;
         }
+        let probability = probability as f32;
+        match probability > 0.0 {
+            true => self.block_probability.insert(index, probability),
+            false => self.block_probability.remove(&index),
+        };
+        if let Some(seed) = self.probability_seed {
+            let blocked = resolves_blocked(seed, index, probability);
+            self.channels
+                .set_resolved_cell(&self.occluded.grid(), index, blocked);
+            self.pass_cache.invalidate_box(pos, pos);
+            self.exposed.invalidate_box(pos, pos);
+        }
+        Error::OK
     }
 
+    /// A cell's chance to block sight from set_block_probability(), 0 outside the grid
     #[func]
-    pub fn set_origin_and_recompute(&mut self, origin: Vector3) {
-        // Set origin
-        self.origin = origin.cast_int();
-        self.origin_float = origin;
+    pub fn get_block_probability(&self, pos: Vector3i) -> real {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.block_probability.get(&index).copied().unwrap_or(0.0) as real
+    }
 
-        for initial_slope_rect in [
-            Rect {
-                sx: INFINITY,
-                sy: INFINITY,
-                ex: 1.0,
-                ey: 1.0,
-            },
-            Rect {
-                sx: -1.0,
-                sy: INFINITY,
-                ex: INFINITY,
-                ey: 1.0,
-            },
-            Rect {
-                sx: INFINITY,
-                sy: -1.0,
-                ex: 1.0,
-                ey: INFINITY,
-            },
-            Rect {
-                sx: -1.0,
-                sy: -1.0,
-                ex: INFINITY,
-                ey: INFINITY,
-            },
-        ] {
-            for reverse_z in [false, true] {
-                for plane in [UnitPlane3d::XY, UnitPlane3d::ZX, UnitPlane3d::ZY] {
-                    // Profile shadowcasting
-                    let now = Instant::now();
-                    cast_light(self, &initial_slope_rect, 1, false, reverse_z, &plane);
-                    let elapsed_time = now.elapsed();
-                    println!(
-                        "Running cast_light() took {} microseconds.",
-                        elapsed_time.as_micros()
-                    );
+    /// Decide once which cells with a block probability block sight, so that every cast from
+    /// now on (recomputes, views and line of sight alike) agrees on them until the seed changes.
+    /// Set a new seed every frame for foliage that sways, or a recorded one to replay a frame
+    #[func]
+    pub fn set_probability_seed(&mut self, seed: i64) {
+        self.log_call("set_probability_seed", &[seed.to_variant()]);
+        self.probability_seed = Some(seed as u64);
+        self.resolve_block_probability();
+    }
 
-                    // Visualize shadowcasting
-                    cast_light(self, &initial_slope_rect, 1, true, reverse_z, &plane);
-                }
+    /// Stop resolving block probabilities with a seed, so cells with one no longer block casts
+    /// and get_transmission() accounts for them instead
+    #[func]
+    pub fn clear_probability_seed(&mut self) {
+        self.log_call("clear_probability_seed", &[]);
+        self.probability_seed = None;
+        self.resolve_block_probability();
+    }
+
+    /// The seed of the last set_probability_seed(), 0 if none is set
+    #[func]
+    pub fn get_probability_seed(&self) -> i64 {
+        self.probability_seed.unwrap_or(0) as i64
+    }
+
+    #[func]
+    pub fn has_probability_seed(&self) -> bool {
+        self.probability_seed.is_some()
+    }
+
+    /// Chance that sight from the origin of the last recompute reaches a visible cell through
+    /// the cells with a block probability along the straight line to it, 1 if there are none.
+    /// 0 for cells that were not seen. With a probability seed set, those cells were already
+    /// resolved, so every visible cell gets 1
+    #[func]
+    pub fn get_transmission(&self, pos: Vector3i) -> real {
+        let pos = self.up_axis.to_grid(pos);
+        if self.visible.get(cell_index(pos)) != Some(true) {
+            return 0.0;
+        }
+        if self.probability_seed.is_some() || self.block_probability.is_empty() {
+            return 1.0;
+        }
+        let path = supercover(self.eye(), pos.cast_float());
+        transmission(&self.block_probability, &path) as real
+    }
+
+    /// Resolve every cell with a block probability for the current seed, or stop casting
+    /// against them without one
+    fn resolve_block_probability(&mut self) {
+        let resolved = self
+            .probability_seed
+            .map(|seed| resolve_all(&self.block_probability, seed, self.occluded.size()));
+        self.channels.set_resolved(&self.occluded.grid(), resolved);
+        self.pass_cache.invalidate_all();
+        self.exposed.invalidate_all();
+    }
+
+    /// Set or unset a cell of the occlusion grid, keeping occluded_count up to date.
+    /// Returns false if the index is out of bounds
+    fn set_occluder(&mut self, index: Index3, value: bool) -> bool {
+        let Some(was) = self.occluded.get(index) else {
+            return false;
+        };
+        self.occluded.set(index, value);
+        self.occluded_count = self.occluded_count + value as usize - was as usize;
+        self.channels.refresh_cell(&self.occluded.grid(), index);
+        true
+    }
+
+    /// Whether a cell is occluded, false outside the grid
+    #[func]
+    pub fn is_occluded(&self, pos: Vector3i) -> bool {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.occluded.get(index).unwrap_or(false)
+    }
+
+    /// Add an empty occlusion channel, such as "fog" that should block sight but not light,
+    /// and return its index, or -1 with an error if the name is taken or there are too many.
+    /// The default channel, index 0 and named "default", is the grid set_occluded() and every
+    /// other edit works on. Channels beside it are not part of saved states
+    #[func]
+    pub fn create_channel(&mut self, name: GString) -> i64 {
+        self.log_call("create_channel", &[name.to_variant()]);
+        match self
+            .channels
+            .create(&name.to_string(), &self.occluded.grid())
+        {
+            Ok(channel) => channel as i64,
+            Err(error) => {
+                error.report();
+                -1
             }
         }
     }
-}
 
-fn cast_light(
-    display: &mut Display,
-    slope_rect: &Rect,
-    depth: usize,
-    draw_debug: bool,
-    reverse_z: bool,
-    plane: &UnitPlane3d,
-) {
-    if depth > MAX_DEPTH {
-        return;
+    /// Index of the channel with a name, or -1 if there is none
+    #[func]
+    pub fn find_channel(&self, name: GString) -> i64 {
+        self.channels
+            .find(&name.to_string())
+            .map_or(-1, |channel| channel as i64)
     }
 
-    let origin = match plane {
-        UnitPlane3d::XY => display.origin,
-        UnitPlane3d::ZY => Vector3i {
-            x: display.origin.z,
-            y: display.origin.y,
-            z: display.origin.x,
-        },
-        UnitPlane3d::ZX => Vector3i {
-            x: display.origin.z,
-            y: display.origin.x,
-            z: display.origin.y,
-        },
-    };
-
-    let origin_float = origin.cast_float();
-
-    let z = match reverse_z {
-        true => -(depth as i32),
-        false => depth as i32,
-    };
-    let z_f32 = z as f32;
-
-    // Calculate the rectangle encompassing the view at this depth, given our slopes and offset (view rect)
-    let z_half_offset = match reverse_z {
-        true => 0.5,
-        false => -0.5,
-    };
-
-    let view_rect = match reverse_z {
-        true => Rect {
-            ex: ((z_f32 + z_half_offset) / slope_rect.sx) + origin_float.x,
-            ey: ((z_f32 + z_half_offset) / slope_rect.sy) + origin_float.y,
-            sx: ((z_f32 + z_half_offset) / slope_rect.ex) + origin_float.x,
-            sy: ((z_f32 + z_half_offset) / slope_rect.ey) + origin_float.y,
-        },
-        false => Rect {
-            sx: ((z_f32 + z_half_offset) / slope_rect.sx) + origin_float.x,
-            sy: ((z_f32 + z_half_offset) / slope_rect.sy) + origin_float.y,
-            ex: ((z_f32 + z_half_offset) / slope_rect.ex) + origin_float.x,
-            ey: ((z_f32 + z_half_offset) / slope_rect.ey) + origin_float.y,
-        },
-    };
-
-    // Visualize view rectangle
-    if draw_debug {
-        match plane {
-            UnitPlane3d::XY => display.draw_debug_rect_xy(
-                z_f32 + origin_float.z + z_half_offset,
-                &view_rect,
-                Color::CYAN,
-            ),
-            UnitPlane3d::ZY => display.draw_debug_rect_zy(
-                z_f32 + origin_float.z + z_half_offset,
-                &view_rect,
-                Color::CYAN,
-            ),
-            UnitPlane3d::ZX => display.draw_debug_rect_zx(
-                z_f32 + origin_float.z + z_half_offset,
-                &view_rect,
-                Color::CYAN,
-            ),
+    /// Occlude a cell in one channel, or clear it with `value` false.
+    /// Channel 0 is the default grid, as with set_occluded() and carve_box()
+    #[func]
+    pub fn set_occluded_channel(&mut self, pos: Vector3i, channel: i64, value: bool) -> Error {
+        self.log_call(
+            "set_occluded_channel",
+            &[pos.to_variant(), channel.to_variant(), value.to_variant()],
+        );
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(pos);
+        }
+        if channel < 0 || channel as usize >= self.channels.count() {
+            godot_script_error!("No channel with index {}", channel);
+            return Error::ERR_DOES_NOT_EXIST;
+        }
+        let channel = channel as usize;
+        if channel == 0 {
+            self.set_occluder(index, value);
+            self.one_way.remove(&index);
+            self.invalidate_occluders(pos, pos);
+            return Error::OK;
+        }
+        self.channels
+            .set(&self.occluded.grid(), channel, index, value);
+        if self.channels.blocks_sight(channel) {
+            self.pass_cache.invalidate_box(pos, pos);
+            self.exposed.invalidate_box(pos, pos);
+        }
+        if self.channels.blocks_light(channel) {
+            self.light_cache.invalidate_box(pos, pos);
         }
+        Error::OK
     }
 
-    // Find start and end xy indices which could possibly occlude the view
-    let mut s_ix = view_rect.sx.floor() as usize;
-    let mut s_iy = view_rect.sy.floor() as usize;
-
-    if s_ix > 0 {
-        s_ix = s_ix - 1;
-    }
-    if s_iy > 0 {
-        s_iy = s_iy - 1;
-    }
-
-    let e_ix = view_rect.ex.ceil() as usize + 1;
-    let e_iy = view_rect.ey.ceil() as usize + 1;
-
-    // Find occluded indices, convert them to rectangles
-    let mut occluding_rectangles: Vec<Rect> = Vec::new();
-    for x in s_ix..e_ix {
-        for y in s_iy..e_iy {
-            let mut x_check = 0;
-            let mut y_check = 0;
-            let mut z_check = 0;
-            match plane {
-                UnitPlane3d::XY => {
-                    x_check = x;
-                    y_check = y;
-                    z_check = (z + origin.z) as usize;
-                },
-                UnitPlane3d::ZY => {
-                    x_check = (z + origin.z) as usize;
-                    y_check = y;
-                    z_check = x;
-                },
-                UnitPlane3d::ZX => {
-                    x_check = y;
-                    y_check = (z + origin.z) as usize;
-                    z_check = x;
-                },
-            };
-            if display
-                .occluded
-                .get((x_check, y_check, z_check))
-                .is_some_and(|occluded| *occluded)
-            {
-                let rect_occluded = get_cube_occlusion(
-                    x as f32,
-                    y as f32,
-                    z_f32,
-                    origin_float,
-                    slope_rect,
-                    reverse_z,
-                );
+    /// Whether a cell is occluded in one channel, false outside the grid or for unknown channels
+    #[func]
+    pub fn is_occluded_channel(&self, pos: Vector3i, channel: i64) -> bool {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        match channel {
+            0 => self.occluded.get(index),
+            _ => self
+                .channels
+                .grid(channel.max(0) as usize)
+                .and_then(|grid| grid.get(index)),
+        }
+        .unwrap_or(false)
+    }
 
-                if draw_debug {
-                    match plane {
-                        UnitPlane3d::XY => display.draw_debug_rect_xy(
-                            z_f32 + origin_float.z + z_half_offset,
-                            &rect_occluded,
-                            Color::RED,
-                        ),
-                        UnitPlane3d::ZY => display.draw_debug_rect_zy(
-                            z_f32 + origin_float.z + z_half_offset,
-                            &rect_occluded,
-                            Color::RED,
-                        ),
-                        UnitPlane3d::ZX => display.draw_debug_rect_zx(
-                            z_f32 + origin_float.z + z_half_offset,
-                            &rect_occluded,
-                            Color::RED,
-                        ),
-                    }
-                }
+    /// Which channels block sight in recomputes, views and visibility queries, as a mask with
+    /// bit n set for channel n. Defaults to 1, the default channel alone
+    #[func]
+    pub fn set_sight_channels(&mut self, mask: i64) -> Error {
+        self.log_call("set_sight_channels", &[mask.to_variant()]);
+        if mask < 0
+            || !self
+                .channels
+                .set_sight_mask(&self.occluded.grid(), mask as u64)
+        {
+            godot_script_error!("Channel mask {} names channels that do not exist", mask);
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        self.pass_cache.invalidate_all();
+        self.exposed.invalidate_all();
+        Error::OK
+    }
 
-                occluding_rectangles.push(rect_occluded);
-            }
-            // here's where you would put your logic for showing/hiding the object at (x,y,depth)
+    #[func]
+    pub fn get_sight_channels(&self) -> i64 {
+        self.channels.sight_mask() as i64
+    }
+
+    /// Which channels block light in bake_lights(), as in set_sight_channels()
+    #[func]
+    pub fn set_light_channels(&mut self, mask: i64) -> Error {
+        self.log_call("set_light_channels", &[mask.to_variant()]);
+        if mask < 0
+            || !self
+                .channels
+                .set_light_mask(&self.occluded.grid(), mask as u64)
+        {
+            godot_script_error!("Channel mask {} names channels that do not exist", mask);
+            return Error::ERR_INVALID_PARAMETER;
         }
+        self.light_cache.invalidate_all();
+        Error::OK
     }
 
-    // Find the difference between the view rect and these rectangles,
-    let unblocked = rectangle_minus_rectangles(view_rect, occluding_rectangles);
+    #[func]
+    pub fn get_light_channels(&self) -> i64 {
+        self.channels.light_mask() as i64
+    }
 
-    // Convert unblocked rectangles back to slopes, then make recursive calls at next depth
-    for rect in unblocked {
-        let new_slope_rect = match reverse_z {
-            true => Rect {
-                ex: (z_f32 + z_half_offset) / (rect.sx - origin_float.x),
-                ey: (z_f32 + z_half_offset) / (rect.sy - origin_float.y),
-                sx: (z_f32 + z_half_offset) / (rect.ex - origin_float.x),
-                sy: (z_f32 + z_half_offset) / (rect.ey - origin_float.y),
-            },
-            false => Rect {
-                sx: (z_f32 + z_half_offset) / (rect.sx - origin_float.x),
-                sy: (z_f32 + z_half_offset) / (rect.sy - origin_float.y),
-                ex: (z_f32 + z_half_offset) / (rect.ex - origin_float.x),
-                ey: (z_f32 + z_half_offset) / (rect.ey - origin_float.y),
-            },
-        };
-        cast_light(
-            display,
-            &new_slope_rect,
-            depth + 1,
-            draw_debug,
-            reverse_z,
-            plane,
+    /// Occlude every cell at or below a terrain surface, as well as those occluded in the grid.
+    /// `heights` holds one height per (x, z) column at index x * depth + z, and a column of
+    /// height h covers the cells with y <= h. This is much cheaper to edit than filling in the
+    /// grid below the surface, and recomputes sample whole blocks of columns at a time in LOD.
+    /// Columns beyond width and depth are empty. Only shadowcasting sees the terrain:
+    /// is_occluded(), the flood fills and compute_propagation() only see the grid
+    #[func]
+    pub fn set_terrain_heights(
+        &mut self,
+        width: i32,
+        depth: i32,
+        heights: PackedFloat32Array,
+    ) -> Error {
+        self.log_call(
+            "set_terrain_heights",
+            &[width.to_variant(), depth.to_variant(), heights.to_variant()],
         );
+        if width < 0 || depth < 0 {
+            godot_script_error!("Terrain size {}x{} is negative", width, depth);
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        let Some(terrain) = Terrain::new(width as usize, depth as usize, heights.as_slice()) else {
+            godot_script_error!(
+                "Terrain of {}x{} columns needs {} heights, got {}",
+                width,
+                depth,
+                width as i64 * depth as i64,
+                heights.len()
+            );
+            return Error::ERR_INVALID_PARAMETER;
+        };
+        self.terrain = Some(terrain);
+        self.pass_cache.invalidate_all();
+        self.light_cache.invalidate_all();
+        Error::OK
     }
-}
 
-/// Get occlusion from the front and side sides of a cube from some origin point, at some depth
-fn get_cube_occlusion(
-    x: f32,
-    y: f32,
-    z: f32,
-    origin: Vector3,
-    slope_rect: &Rect,
-    reverse_z: bool,
-) -> Rect {
-    let z_half_offset = match reverse_z {
-        true => 0.5,
-        false => -0.5,
-    };
+    /// Remove the terrain, leaving only the grid to occlude
+    #[func]
+    pub fn clear_terrain(&mut self) {
+        self.log_call("clear_terrain", &[]);
+        if self.terrain.take().is_some() {
+            self.pass_cache.invalidate_all();
+            self.light_cache.invalidate_all();
+        }
+    }
 
-    let base_occluded = Rect {
-        sx: x - 0.5,
-        sy: y - 0.5,
-        ex: x + 0.5,
-        ey: y + 0.5,
-    };
+    /// Share the grid of an OcclusionGrid3D, see occlusion_grid
+    #[func]
+    pub fn set_occlusion_grid(&mut self, grid: Option<Gd<OcclusionGrid3D>>) {
+        let mut shared = match &grid {
+            Some(resource) => resource.bind().grid.clone(),
+            None if self.occlusion_grid.is_some() => SharedGrid::new(self.occluded.grid().clone()),
+            None => self.occluded.clone(),
+        };
+        self.occlusion_grid = grid;
+        if shared.ptr_eq(&self.occluded) {
+            return;
+        }
+        if shared.size() == (0, 0, 0) {
+            shared.replace(self.occluded.grid().clone());
+            shared.record_change(None);
+        }
+        self.occluded = shared;
+        self.grid_generation = self.occluded.generation();
+        self.catch_up_with(None);
+    }
 
-    // grow rectangle based on occlusion of cube side-faces
-    let mut extra = Rect::ZERO;
+    /// Cast sight and light against a source of the game's own rather than the grid, which
+    /// then only sets their extent, see OcclusionSource. Recomputes, views, lights, line of
+    /// sight queries, portals, floor separators and explain_visibility() all read it, and
+    /// sight and light channels do not apply to it. Queries of the grid's own data, such as
+    /// is_occluded(), layers, distance fields and saved states, still read the grid, and
+    /// make_downsampled_copy() fails. None goes back to the grid. Rust only, as sources are
+    /// Rust types
+    pub fn set_occlusion_source(&mut self, source: Option<Box<dyn OcclusionSource>>) {
+        self.occlusion_source = source;
+        self.pass_cache.invalidate_all();
+        self.light_cache.invalidate_all();
+    }
 
-    if slope_rect.ex > 0.0 && slope_rect.ex.is_finite() {
-        extra.sx = (x + z_half_offset - origin.x) / (z - z_half_offset);
+    /// Tell cached passes that the occlusion source changed in the inclusive box between two
+    /// corners, in grid coordinates like the source's own
+    pub fn occlusion_source_changed(&mut self, from: Vector3i, to: Vector3i) {
+        let (min, max) = (from.coord_min(to), from.coord_max(to));
+        self.pass_cache.invalidate_box(min, max);
+        self.light_cache.invalidate_box(min, max);
     }
-    if slope_rect.ey > 0.0 && slope_rect.ey.is_finite() {
-        extra.sy = (y + z_half_offset - origin.y) / (z - z_half_offset);
+
+    /// Whether the terrain covers a cell, false without terrain
+    #[func]
+    pub fn is_under_terrain(&self, pos: Vector3i) -> bool {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.terrain
+            .as_ref()
+            .is_some_and(|terrain| terrain.occludes(index))
     }
-    if slope_rect.sx < 0.0 && slope_rect.sx.is_finite() {
-        extra.ex = (x - z_half_offset - origin.x) / (z - z_half_offset);
+
+    /// Size of the grid in cells
+    #[func]
+    pub fn get_grid_size(&self) -> Vector3i {
+        let (x, y, z) = self.occluded.size();
+        self.up_axis
+            .from_grid(Vector3i::new(x as i32, y as i32, z as i32))
     }
-    if slope_rect.sy < 0.0 && slope_rect.sy.is_finite() {
-        extra.ey = (y - z_half_offset - origin.y) / (z - z_half_offset);
+
+    /// One z-layer of a grid as one 0 or 1 byte per cell at index x * size.y + y
+    fn layer_bytes(grid: &BitGrid, z: i32) -> PackedByteArray {
+        let (size_x, size_y, size_z) = grid.size();
+        if z < 0 || z as usize >= size_z {
+            godot_script_error!("Layer {} is outside the grid", z);
+            return PackedByteArray::new();
+        }
+        let mut bytes = vec![0; size_x * size_y];
+        grid.for_each_set_in_slice(2, z as usize, |x, y| bytes[x * size_y + y] = 1);
+        PackedByteArray::from(bytes.as_slice())
     }
 
-    match reverse_z {
-        true => base_occluded + extra.swap_start_and_end(),
-        false => base_occluded - extra,
+    /// Occlusion of one z-layer, as one 0 or 1 byte per cell at index x * size.y + y
+    #[func]
+    pub fn get_occlusion_layer(&self, z: i32) -> PackedByteArray {
+        Self::layer_bytes(&self.occluded.grid(), z)
     }
-}
 
-/// Boolean difference: remove all rectangles from rectangle
-/// The result is decomposed into a *reasonably small* set of rectangles, (since optimally small is NP-hard)
-fn rectangle_minus_rectangles(rectangle: Rect, rectangles: Vec<Rect>) -> Vec<Rect> {
-    let mut result = vec![rectangle];
-
-    for subtract_rect in rectangles {
-        let mut new_result = Vec::new();
-
-        for rect in result {
-            if let Some(intersection) = rect.intersection(&subtract_rect) {
-                // Split the rectangle around the intersection
-                let mut splits = Vec::new();
-
-                // Left part
-                if rect.sx < intersection.sx {
-                    splits.push(Rect {
-                        sx: rect.sx,
-                        sy: rect.sy,
-                        ex: intersection.sx,
-                        ey: rect.ey,
-                    });
-                }
+    /// Visibility of one z-layer as of the last recompute, laid out as in get_occlusion_layer()
+    #[func]
+    pub fn get_visibility_layer(&self, z: i32) -> PackedByteArray {
+        Self::layer_bytes(&self.visible, z)
+    }
 
-                // Right part
-                if intersection.ex < rect.ex {
-                    splits.push(Rect {
-                        sx: intersection.ex,
-                        sy: rect.sy,
-                        ex: rect.ex,
-                        ey: rect.ey,
-                    });
-                }
+    /// The chunks of visibility_chunk_size cells holding a cell whose visibility changed since
+    /// the last call, as the chunk coordinates of their lowest corner cell divided by the chunk
+    /// size, in ascending x, then y, then z order of the grid. The first call, and the first
+    /// after a resize, reports every chunk
+    #[func]
+    pub fn take_dirty_visibility_chunks(&mut self) -> PackedVector3Array {
+        self.dirty_chunks
+            .take()
+            .into_iter()
+            .map(|chunk| self.up_axis.from_grid(index_cell(chunk)).cast_float())
+            .collect()
+    }
 
-                // Top part (only the middle section to avoid overlap)
-                if rect.sy < intersection.sy {
-                    splits.push(Rect {
-                        sx: intersection.sx,
-                        sy: rect.sy,
-                        ex: intersection.ex,
-                        ey: intersection.sy,
-                    });
-                }
+    /// Resize the chunks of take_dirty_visibility_chunks(), see visibility_chunk_size
+    #[func]
+    pub fn set_visibility_chunk_size(&mut self, size: i32) {
+        if size < 1 {
+            godot_script_error!("Chunk size {} is less than 1", size);
+            return;
+        }
+        self.visibility_chunk_size = size;
+        self.dirty_chunks = DirtyChunks::new(size as usize);
+        self.dirty_chunks.update(&self.visible);
+    }
 
-                // Bottom part (only the middle section to avoid overlap)
-                if intersection.ey < rect.ey {
-                    splits.push(Rect {
-                        sx: intersection.sx,
-                        sy: intersection.ey,
-                        ex: intersection.ex,
-                        ey: rect.ey,
-                    });
-                }
+    /// Distance in cells from a cell to the center of the nearest occluded cell of the default
+    /// grid, 0 for occluded cells. Distances follow steps to the 26 neighbors, so they are
+    /// exact along axes and diagonals and up to about 13% long in between. Cells further than
+    /// distance_field_radius from every occluder read as INF, and cells outside the grid as -1.
+    /// The distances are worked out by the first query after edits, only around what changed
+    #[func]
+    pub fn get_distance_to_occluder(&mut self, pos: Vector3i) -> f32 {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        if self.occluded.get(index).is_none() {
+            self.report_out_of_bounds(pos);
+            return -1.0;
+        }
+        self.sync_shared_grid();
+        let radius = self.distance_field_radius.max(0) as usize;
+        self.distance_field.update(&self.occluded.grid(), radius)[index]
+    }
+
+    /// get_distance_to_occluder() for one z-layer, laid out as in get_occlusion_layer()
+    #[func]
+    pub fn get_distance_layer(&mut self, z: i32) -> PackedFloat32Array {
+        let (_, _, size_z) = self.occluded.size();
+        if z < 0 || z as usize >= size_z {
+            godot_script_error!("Layer {} is outside the grid", z);
+            return PackedFloat32Array::new();
+        }
+        self.sync_shared_grid();
+        let radius = self.distance_field_radius.max(0) as usize;
+        let distances = self.distance_field.update(&self.occluded.grid(), radius);
+        let layer: Vec<f32> = distances
+            .slice(s![.., .., z as usize])
+            .iter()
+            .copied()
+            .collect();
+        PackedFloat32Array::from(layer.as_slice())
+    }
+
+    /// The state of one slice of cells across an axis (0 for x, 1 for y, 2 for z), as one byte
+    /// per cell holding flags: 1 if the last recompute saw it, 2 if it is occluded (by the grid
+    /// or the terrain) and 4 if any recompute has seen it. Cells are laid out by their
+    /// coordinates along the other two axes in x, y, z order, the first one major, so a z slice
+    /// is laid out as in get_occlusion_layer(). Slices outside the grid give an empty array
+    #[func]
+    pub fn get_visibility_cross_section(&self, axis: i32, index: i32) -> PackedByteArray {
+        match self.cross_section(axis, index) {
+            Some((bytes, _)) => PackedByteArray::from(bytes.as_slice()),
+            None => PackedByteArray::new(),
+        }
+    }
+
+    /// The flags of get_visibility_cross_section() and the length of the slice's second axis,
+    /// or None with an error for slices outside the grid
+    fn cross_section(&self, axis: i32, index: i32) -> Option<(Vec<u8>, usize)> {
+        let (size_x, size_y, size_z) = self.occluded.size();
+        let size = [size_x, size_y, size_z];
+        if !(0..3).contains(&axis) || index < 0 || index as usize >= size[axis as usize] {
+            godot_script_error!("Slice {} across axis {} is outside the grid", index, axis);
+            return None;
+        }
+        let (axis, index) = (axis as usize, index as usize);
+        let (rows, columns) = match axis {
+            0 => (size_y, size_z),
+            1 => (size_x, size_z),
+            _ => (size_x, size_y),
+        };
 
-                // Add all valid splits
-                for split in splits {
-                    if split.is_valid() {
-                        new_result.push(split);
+        let mut bytes = vec![0; rows * columns];
+        self.visible
+            .for_each_set_in_slice(axis, index, |u, v| bytes[u * columns + v] |= 1);
+        self.occluded
+            .grid()
+            .for_each_set_in_slice(axis, index, |u, v| bytes[u * columns + v] |= 2);
+        self.explored
+            .for_each_set_in_slice(axis, index, |u, v| bytes[u * columns + v] |= 4);
+        if let Some(terrain) = &self.terrain {
+            for u in 0..rows {
+                for v in 0..columns {
+                    let cell = match axis {
+                        0 => (index, u, v),
+                        1 => (u, index, v),
+                        _ => (u, v, index),
+                    };
+                    if terrain.occludes(cell) {
+                        bytes[u * columns + v] |= 2;
                     }
                 }
-            } else {
-                // No intersection, keep the rectangle as is
-                new_result.push(rect);
             }
         }
+        Some((bytes, columns))
+    }
+
+    /// Replace the quads drawn for draw_cross_section, or remove them if it is off
+    fn update_cross_section_mesh(&mut self) {
+        if let Some(mut mesh) = self.cross_section_mesh.take() {
+            mesh.queue_free();
+        }
+        if !self.draw_cross_section {
+            return;
+        }
+        let (axis, index) = (self.cross_section_axis, self.cross_section_index);
+        let Some((bytes, columns)) = self.cross_section(axis, index) else {
+            return;
+        };
+
+        let mut material = StandardMaterial3D::new_gd();
+        material.set_shading_mode(ShadingMode::UNSHADED);
+        material.set_flag(Flags::ALBEDO_FROM_VERTEX_COLOR, true);
+        material.set_transparency(Transparency::ALPHA);
+        material.set_cull_mode(CullMode::DISABLED);
+        let mut quads = ImmediateMesh::new_gd();
+        quads.surface_begin(PrimitiveType::TRIANGLES);
+        for (i, &flags) in bytes.iter().enumerate() {
+            let color = match (flags & 1 != 0, flags & 2 != 0, flags & 4 != 0) {
+                (true, true, _) => CROSS_SECTION_SEEN_OCCLUDED,
+                (true, false, _) => CROSS_SECTION_VISIBLE,
+                (false, true, _) => CROSS_SECTION_OCCLUDED,
+                (false, false, true) => CROSS_SECTION_EXPLORED,
+                (false, false, false) => continue,
+            };
+            // Corners of the cell's square through its center, across the slice's axis
+            let (u, v) = ((i / columns) as real, (i % columns) as real);
+            let corner = |du: real, dv: real| {
+                let (u, v, w) = (u + du, v + dv, index as real);
+                match axis {
+                    0 => Vector3::new(w, u, v),
+                    1 => Vector3::new(u, w, v),
+                    _ => Vector3::new(u, v, w),
+                }
+            };
+            quads.surface_set_color(color);
+            for (du, dv) in [
+                (-0.5, -0.5),
+                (0.5, -0.5),
+                (0.5, 0.5),
+                (-0.5, -0.5),
+                (0.5, 0.5),
+                (-0.5, 0.5),
+            ] {
+                quads.surface_add_vertex(corner(du, dv));
+            }
+        }
+        quads.surface_end();
 
-        result = new_result;
+        let mut mesh = MeshInstance3D::new_alloc();
+        mesh.set_mesh(&quads);
+        mesh.set_scale(self.grid_cell_size());
+        mesh.set_material_override(&material);
+        self.base_mut()
+            .call_deferred("add_child", &[mesh.to_variant()]);
+        self.cross_section_mesh = Some(mesh);
     }
 
-    result
+    /// Draw cells as DEBUG_WIREFRAME or DEBUG_FILLED boxes, all in one mesh, for a script to
+    /// show its own sets of cells. Positions are rounded to the cell they are in. The boxes
+    /// disappear after `lifetime` seconds, or with debug_clear_drawings() if it is 0
+    #[func]
+    pub fn debug_draw_cells(
+        &mut self,
+        cells: PackedVector3Array,
+        color: Color,
+        style: i64,
+        lifetime: f64,
+    ) {
+        let filled = match style {
+            Self::DEBUG_WIREFRAME => false,
+            Self::DEBUG_FILLED => true,
+            _ => {
+                godot_script_error!("Style {} is not DEBUG_WIREFRAME or DEBUG_FILLED", style);
+                return;
+            }
+        };
+        if cells.is_empty() {
+            return;
+        }
+        let mut boxes = ImmediateMesh::new_gd();
+        let primitive = match filled {
+            true => PrimitiveType::TRIANGLES,
+            false => PrimitiveType::LINES,
+        };
+        boxes.surface_begin(primitive);
+        for &cell in cells.as_slice() {
+            let center = cell_at(self.up_axis.to_grid(cell)).cast_float();
+            let corner = |bits: usize| {
+                let offset = |bit: usize| if bits & bit != 0 { 0.5 } else { -0.5 };
+                center + Vector3::new(offset(1), offset(2), offset(4))
+            };
+            let corners: &[usize] = match filled {
+                // Two triangles per face, corners numbered by their x, y and z bits
+                true => &[
+                    0, 2, 3, 0, 3, 1, 4, 5, 7, 4, 7, 6, 0, 1, 5, 0, 5, 4, 2, 6, 7, 2, 7, 3, 0, 4,
+                    6, 0, 6, 2, 1, 3, 7, 1, 7, 5,
+                ],
+                // Both ends of every edge
+                false => &[
+                    0, 1, 2, 3, 4, 5, 6, 7, 0, 2, 1, 3, 4, 6, 5, 7, 0, 4, 1, 5, 2, 6, 3, 7,
+                ],
+            };
+            for &i in corners {
+                boxes.surface_add_vertex(corner(i));
+            }
+        }
+        boxes.surface_end();
+        self.add_debug_drawing(boxes, color, lifetime);
+    }
+
+    /// Draw lines from points[0] to points[1], points[2] to points[3] and so on, in the same
+    /// space as the positions of debug_draw_cells(), which they disappear like
+    #[func]
+    pub fn debug_draw_lines(&mut self, points: PackedVector3Array, color: Color, lifetime: f64) {
+        if points.len() % 2 != 0 {
+            godot_script_error!("Lines need an even number of points, got {}", points.len());
+            return;
+        }
+        if points.is_empty() {
+            return;
+        }
+        let mut lines = ImmediateMesh::new_gd();
+        lines.surface_begin(PrimitiveType::LINES);
+        for &point in points.as_slice() {
+            lines.surface_add_vertex(self.up_axis.to_grid(point));
+        }
+        lines.surface_end();
+        self.add_debug_drawing(lines, color, lifetime);
+    }
+
+    /// Remove everything debug_draw_cells() and debug_draw_lines() drew
+    #[func]
+    pub fn debug_clear_drawings(&mut self) {
+        for (mut mesh, _) in self.debug_drawings.drain(..) {
+            mesh.queue_free();
+        }
+    }
+
+    fn add_debug_drawing(&mut self, mesh: Gd<ImmediateMesh>, color: Color, lifetime: f64) {
+        let mut material = StandardMaterial3D::new_gd();
+        material.set_shading_mode(ShadingMode::UNSHADED);
+        material.set_albedo(color);
+        if color.a < 1.0 {
+            material.set_transparency(Transparency::ALPHA);
+        }
+        material.set_cull_mode(CullMode::DISABLED);
+        let mut instance = MeshInstance3D::new_alloc();
+        instance.set_mesh(&mesh);
+        instance.set_scale(self.grid_cell_size());
+        instance.set_material_override(&material);
+        self.base_mut()
+            .call_deferred("add_child", &[instance.to_variant()]);
+        self.debug_drawings
+            .push((instance, (lifetime > 0.0).then_some(lifetime)));
+    }
+
+    /// Count down the lifetimes of debug drawings, removing the ones that ran out
+    fn expire_debug_drawings(&mut self, delta: f64) {
+        self.debug_drawings.retain_mut(|(mesh, remaining)| {
+            let Some(remaining) = remaining else {
+                return true;
+            };
+            *remaining -= delta;
+            if *remaining > 0.0 {
+                return true;
+            }
+            mesh.queue_free();
+            false
+        });
+    }
+
+    /// Overwrite the occlusion of one z-layer from bytes laid out as in get_occlusion_layer(),
+    /// where any nonzero byte is occluded
+    #[func]
+    pub fn set_occlusion_layer(&mut self, z: i32, layer: PackedByteArray) -> Error {
+        self.log_call("set_occlusion_layer", &[z.to_variant(), layer.to_variant()]);
+        let (size_x, size_y, size_z) = self.occluded.size();
+        if z < 0 || z as usize >= size_z {
+            godot_script_error!("Layer {} is outside the grid", z);
+            return Error::ERR_PARAMETER_RANGE_ERROR;
+        }
+        if layer.len() != size_x * size_y {
+            godot_script_error!(
+                "Layer has {} bytes, but the grid needs {}",
+                layer.len(),
+                size_x * size_y
+            );
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        self.one_way
+            .retain(|&(_, _, cell_z), _| cell_z != z as usize);
+        for (i, &byte) in layer.as_slice().iter().enumerate() {
+            self.set_occluder((i / size_y, i % size_y, z as usize), byte != 0);
+        }
+        self.invalidate_occluders(
+            Vector3i::new(0, 0, z),
+            Vector3i::new(size_x as i32 - 1, size_y as i32 - 1, z),
+        );
+        Error::OK
+    }
+
+    /// Save the occlusion grid, one-way occluders, terrain, light sources and settings
+    /// into a ShadowcastState resource
+    #[func]
+    pub fn capture_state(&self) -> Gd<Resource> {
+        let (x, y, z) = self.occluded.size();
+        let mut state = ShadowcastState::new_gd();
+        {
+            let mut state = state.bind_mut();
+            state.version = STATE_VERSION;
+            state.extension_version = EXTENSION_VERSION.into();
+            state.size = Vector3i::new(x as i32, y as i32, z as i32);
+            state.occluded = PackedByteArray::from(self.occluded.to_bytes().as_slice());
+            state.origin = self.origin_float;
+            for light in &self.lights {
+                state.light_ids.push(light.id);
+                state.light_positions.push(light.position.cast_float());
+                state.light_radii.push(light.radius as i32);
+                state.light_intensities.push(light.intensity as f64);
+                state.light_falloffs.push(light.falloff.to_godot() as i32);
+                state.light_steps.push(light.steps as i32);
+                state.light_colors.push(light.color);
+            }
+            if let Some(terrain) = &self.terrain {
+                state.terrain_width = terrain.width() as i32;
+                state.terrain_depth = terrain.depth() as i32;
+                state.terrain_heights = PackedFloat32Array::from(terrain.heights());
+            }
+            for (&(x, y, z), direction) in &self.one_way {
+                state
+                    .one_way_positions
+                    .push(Vector3::new(x as real, y as real, z as real));
+                state.one_way_directions.push(direction.cast_float());
+            }
+            for (&(x, y, z), emitter) in &self.emissive {
+                state
+                    .emissive_positions
+                    .push(Vector3::new(x as real, y as real, z as real));
+                state.emissive_colors.push(emitter.color);
+                state.emissive_intensities.push(emitter.intensity as f64);
+            }
+            state.emissive_radius = self.emissive_radius;
+            state.next_light_id = self.next_light_id;
+            state.darkness_threshold = self.darkness_threshold;
+            state.use_innate_light = self.use_innate_light;
+            state.innate_light_radius = self.innate_light_radius;
+            state.soft_samples = self.soft_samples;
+            state.soft_seed = self.soft_seed;
+            state.corner_rule = self.corner_rule;
+            state.track_visibility_fraction = self.track_visibility_fraction;
+            state.lod_start_depth = self.lod_start_depth;
+            state.lod_factor = self.lod_factor;
+            if let Some(tags) = &self.tags {
+                let bytes: Vec<u8> = tags.iter().flat_map(|tag| tag.to_le_bytes()).collect();
+                state.tags = PackedByteArray::from(bytes.as_slice());
+            }
+        }
+        state.upcast()
+    }
+
+    /// What the node holds beside its grid and settings that capture_state() does not save,
+    /// as names for a warning
+    fn uncaptured_registrations(&self) -> Vec<&'static str> {
+        [
+            (!self.views.is_empty(), "views"),
+            (!self.occluder_bindings.is_empty(), "occluder bindings"),
+            (!self.portals.is_empty(), "portals"),
+            (!self.floors.is_empty(), "floor separators"),
+            (self.channels.count() > 1, "occlusion channels"),
+            (!self.block_probability.is_empty(), "block probabilities"),
+            (self.change_base.is_some(), "change recording"),
+            (
+                self.external_visibility.is_some(),
+                "external visibility buffer",
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(present, name)| present.then_some(name))
+        .collect()
+    }
+
+    /// What the node holds beside its grid, explored cells and settings that grid_state does not
+    /// carry to a copy, as names for a warning
+    fn left_out_of_grid_state(&self) -> Vec<&'static str> {
+        let mut left_out = self.uncaptured_registrations();
+        left_out.extend(
+            [
+                (!self.lights.is_empty(), "light sources"),
+                (self.terrain.is_some(), "terrain"),
+                (!self.one_way.is_empty(), "one-way occluders"),
+                (!self.emissive.is_empty(), "emissive cells"),
+                (self.tags.is_some(), "tags"),
+            ]
+            .into_iter()
+            .filter_map(|(present, name)| present.then_some(name)),
+        );
+        left_out
+    }
+
+    /// Restore what capture_state() saved, resizing every buffer to the saved grid and
+    /// rebaking lights. Visibility is cleared until the next recompute.
+    /// States from another format version are rejected with a script error
+    #[func]
+    pub fn restore_state(&mut self, state: Gd<Resource>) -> Error {
+        self.log_call("restore_state", &[state.to_variant()]);
+        let Ok(state) = state.try_cast::<ShadowcastState>() else {
+            godot_script_error!("Not a ShadowcastState");
+            return Error::ERR_INVALID_PARAMETER;
+        };
+        let state = state.bind();
+        if let Err(error) = check_version(state.version) {
+            return error.report();
+        }
+        let lights_len = state.light_ids.len();
+        if state.light_positions.len() != lights_len
+            || state.light_radii.len() != lights_len
+            || state.light_intensities.len() != lights_len
+            || ![0, lights_len].contains(&state.light_falloffs.len())
+            || state.light_steps.len() != state.light_falloffs.len()
+            || ![0, lights_len].contains(&state.light_colors.len())
+        {
+            godot_script_error!("ShadowcastState light arrays have different lengths");
+            return Error::ERR_INVALID_DATA;
+        }
+        let falloffs: Option<Vec<Falloff>> = state
+            .light_falloffs
+            .as_slice()
+            .iter()
+            .map(|&falloff| Falloff::try_from_godot(falloff as i64).ok())
+            .collect();
+        let Some(falloffs) = falloffs else {
+            godot_script_error!("ShadowcastState has a light with an unknown falloff");
+            return Error::ERR_INVALID_DATA;
+        };
+        let emissive_len = state.emissive_positions.len();
+        if state.emissive_colors.len() != emissive_len
+            || state.emissive_intensities.len() != emissive_len
+        {
+            godot_script_error!("ShadowcastState emissive arrays have different lengths");
+            return Error::ERR_INVALID_DATA;
+        }
+        if state.size.x < 0 || state.size.y < 0 || state.size.z < 0 {
+            godot_script_error!("ShadowcastState has a negative size {}", state.size);
+            return Error::ERR_INVALID_DATA;
+        }
+        let size = (
+            state.size.x as usize,
+            state.size.y as usize,
+            state.size.z as usize,
+        );
+        let Some(occluded) = BitGrid::from_bytes(size, state.occluded.as_slice()) else {
+            godot_script_error!("ShadowcastState occlusion data does not match its size");
+            return Error::ERR_INVALID_DATA;
+        };
+        if state.one_way_positions.len() != state.one_way_directions.len() {
+            godot_script_error!("ShadowcastState one-way arrays have different lengths");
+            return Error::ERR_INVALID_DATA;
+        }
+        let tags = match state.tags.is_empty() {
+            true => None,
+            false => {
+                let tags: Vec<u16> = state
+                    .tags
+                    .as_slice()
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                    .collect();
+                let tags = (state.tags.len() % 2 == 0)
+                    .then(|| Array3::from_shape_vec(size, tags).ok())
+                    .flatten();
+                let Some(tags) = tags else {
+                    godot_script_error!("ShadowcastState tags do not match its size");
+                    return Error::ERR_INVALID_DATA;
+                };
+                Some(tags)
+            }
+        };
+        let terrain = match state.terrain_heights.is_empty() {
+            true => None,
+            false => {
+                let width = state.terrain_width.max(0) as usize;
+                let depth = state.terrain_depth.max(0) as usize;
+                let Some(terrain) = Terrain::new(width, depth, state.terrain_heights.as_slice())
+                else {
+                    godot_script_error!("ShadowcastState terrain heights do not match its size");
+                    return Error::ERR_INVALID_DATA;
+                };
+                Some(terrain)
+            }
+        };
+
+        self.occluded_count = occluded.count_set();
+        self.occluded.replace(occluded);
+        self.channels.refresh(&self.occluded.grid());
+        self.block_probability
+            .retain(|&index, _| self.occluded.get(index).is_some());
+        self.resolve_block_probability();
+        self.one_way = state
+            .one_way_positions
+            .as_slice()
+            .iter()
+            .zip(state.one_way_directions.as_slice())
+            .map(|(position, direction)| {
+                let position = cell_at(position);
+                let index = cell_index(position);
+                (index, direction.cast_int().sign())
+            })
+            .filter(|&(index, _)| self.occluded.get(index).unwrap_or(false))
+            .collect();
+        self.tags = tags;
+        self.terrain = terrain;
+        self.reset_buffers(size);
+        self.record_grid_change(None);
+        self.origin = self.eye_cell(state.origin);
+        self.origin_float = state.origin;
+
+        self.lights = (0..lights_len)
+            .map(|i| LightSource {
+                id: state.light_ids[i],
+                position: cell_at(state.light_positions[i]),
+                radius: state.light_radii[i].max(0) as usize,
+                intensity: state.light_intensities[i] as real,
+                color: state.light_colors.get(i).unwrap_or(Color::WHITE),
+                falloff: falloffs.get(i).copied().unwrap_or_default(),
+                steps: state.light_steps.get(i).unwrap_or(1).max(1) as usize,
+            })
+            .collect();
+        self.next_light_id = state.next_light_id;
+        self.emissive = (0..emissive_len)
+            .map(|i| {
+                let position = cell_at(state.emissive_positions[i]);
+                let index = cell_index(position);
+                let emitter = Emitter {
+                    color: state.emissive_colors[i],
+                    intensity: state.emissive_intensities[i] as real,
+                };
+                (index, emitter)
+            })
+            .filter(|&(index, _)| self.occluded.get(index).is_some())
+            .collect();
+        self.emissive_radius = state.emissive_radius;
+        self.darkness_threshold = state.darkness_threshold;
+        self.use_innate_light = state.use_innate_light;
+        self.innate_light_radius = state.innate_light_radius;
+        self.soft_samples = state.soft_samples;
+        self.soft_seed = state.soft_seed;
+        self.corner_rule = state.corner_rule;
+        self.track_visibility_fraction = state.track_visibility_fraction;
+        self.lod_start_depth = state.lod_start_depth;
+        self.lod_factor = state.lod_factor;
+        drop(state);
+
+        self.rebake_lights();
+        Error::OK
+    }
+
+    /// Count occluded cells in the inclusive box between two corners.
+    /// Only the part of the box inside the grid is counted, with a warning if anything was cut off
+    #[func]
+    pub fn count_occluded_in_box(&self, from: Vector3i, to: Vector3i) -> i64 {
+        let (clipped, was_clipped) = self.clip_box(from, to);
+        if was_clipped {
+            godot_warn!("Box from {} to {} is partially outside the grid", from, to);
+        }
+        clipped.map_or(0, |(min, max)| self.occluded.count_in_box(min, max) as i64)
+    }
+
+    /// Number of occluded cells in the whole grid, including edits just made through other
+    /// nodes sharing occlusion_grid
+    #[func]
+    pub fn get_occluded_count(&mut self) -> i64 {
+        self.sync_shared_grid();
+        self.occluded_count as i64
+    }
+
+    /// Every occluded cell in the inclusive box between two corners, in ascending x, then y,
+    /// then z order. Only the part of the box inside the grid is listed, with a warning if
+    /// anything was cut off
+    #[func]
+    pub fn get_occluded_cells_in_box(&self, from: Vector3i, to: Vector3i) -> PackedVector3Array {
+        let (clipped, was_clipped) = self.clip_box(from, to);
+        if was_clipped {
+            godot_warn!("Box from {} to {} is partially outside the grid", from, to);
+        }
+        let mut positions = PackedVector3Array::new();
+        if let Some((min, max)) = clipped {
+            self.occluded
+                .grid()
+                .for_each_set_in_box(min, max, |index| positions.push(self.position_of(index)));
+        }
+        positions
+    }
+
+    /// Whether every cell in the inclusive box between two corners is occluded.
+    /// Only the part of the box inside the grid is checked, with a warning if anything was cut off.
+    /// A box entirely outside the grid is never fully occluded
+    #[func]
+    pub fn is_box_fully_occluded(&self, from: Vector3i, to: Vector3i) -> bool {
+        let (clipped, was_clipped) = self.clip_box(from, to);
+        if was_clipped {
+            godot_warn!("Box from {} to {} is partially outside the grid", from, to);
+        }
+        clipped.is_some_and(|(min, max)| self.occluded.is_box_full(min, max))
+    }
+
+    /// Flood-fill empty space (6-connected) from a seed cell outside any enclosed pockets,
+    /// and mark every empty cell the fill did not reach as occluded.
+    /// Returns the number of cells sealed
+    #[func]
+    pub fn seal_enclosed_regions(&mut self, outside_seed: Vector3i) -> i64 {
+        self.log_call("seal_enclosed_regions", &[outside_seed.to_variant()]);
+        let seed = cell_index(self.up_axis.to_grid(outside_seed));
+        match self.occluded.get(seed) {
+            None => {
+                self.report_out_of_bounds(index_cell(seed));
+                return 0;
+            }
+            Some(true) => {
+                godot_script_error!("Seed {} is occluded", outside_seed);
+                return 0;
+            }
+            Some(false) => {}
+        }
+
+        let size = self.occluded.size();
+        if self.flood_scratch.size() == size {
+            self.flood_scratch.clear();
+        } else {
+            self.flood_scratch = BitGrid::new(size);
+        }
+
+        self.occluded
+            .grid()
+            .flood_unset(seed, &mut self.flood_scratch);
+
+        let sealed = self.occluded.fill_unset_in_both(&self.flood_scratch);
+        self.occluded_count += sealed;
+        if sealed > 0 {
+            self.channels.refresh(&self.occluded.grid());
+            self.pass_cache.invalidate_all();
+            self.light_cache.invalidate_all();
+            self.distance_field.invalidate_all();
+            self.exposed.invalidate_all();
+            self.record_grid_change(None);
+        }
+        sealed as i64
+    }
+
+    /// Clear occlusion in the inclusive box between two corners.
+    /// Only the part of the box inside the grid is cleared, with a warning if anything was cut off
+    #[func]
+    pub fn carve_box(&mut self, from: Vector3i, to: Vector3i) -> Error {
+        self.log_call("carve_box", &[from.to_variant(), to.to_variant()]);
+        let (clipped, was_clipped) = self.clip_box(from, to);
+        if was_clipped {
+            godot_warn!("Box from {} to {} is partially outside the grid", from, to);
+        }
+        let Some((min, max)) = clipped else {
+            return Error::ERR_PARAMETER_RANGE_ERROR;
+        };
+        self.occluded_count -= self.occluded.count_in_box(min, max);
+        self.occluded.set_box(min, max, false);
+        self.channels.refresh(&self.occluded.grid());
+        self.one_way.retain(|&(x, y, z), _| {
+            !(min.0..=max.0).contains(&x)
+                || !(min.1..=max.1).contains(&y)
+                || !(min.2..=max.2).contains(&z)
+        });
+        self.invalidate_occluders(index_cell(min), index_cell(max));
+        Error::OK
+    }
+
+    /// Occlude or clear every cell inside a sphere, as coverage_threshold decides for cells on
+    /// its edge, returning how many cells changed. Only the part inside the grid is painted
+    #[func]
+    pub fn paint_occlusion_sphere(&mut self, center: Vector3, radius: real, value: bool) -> i64 {
+        self.log_call(
+            "paint_occlusion_sphere",
+            &[center.to_variant(), radius.to_variant(), value.to_variant()],
+        );
+        let center = self.up_axis.to_grid(center);
+        self.paint_brush(
+            center,
+            Vector3::splat(radius),
+            |point| point.distance_to(center) - radius,
+            value,
+        )
+    }
+
+    /// Occlude or clear every cell inside a cylinder standing along `axis` (0 for x, 1 for y and
+    /// 2 for z), `height` long and centered on `center`, as paint_occlusion_sphere() does
+    #[func]
+    pub fn paint_occlusion_cylinder(
+        &mut self,
+        center: Vector3,
+        radius: real,
+        height: real,
+        axis: i32,
+        value: bool,
+    ) -> i64 {
+        self.log_call(
+            "paint_occlusion_cylinder",
+            &[
+                center.to_variant(),
+                radius.to_variant(),
+                height.to_variant(),
+                axis.to_variant(),
+                value.to_variant(),
+            ],
+        );
+        let direction = match axis {
+            0 => Vector3::RIGHT,
+            1 => Vector3::UP,
+            2 => Vector3::BACK,
+            _ => {
+                godot_script_error!("Axis {} is not 0, 1 or 2", axis);
+                return 0;
+            }
+        };
+        let center = self.up_axis.to_grid(center);
+        let direction = self.up_axis.to_grid(direction);
+        let half_height = height / 2.0;
+        let extent = Vector3::splat(radius) * (Vector3::ONE - direction) + direction * half_height;
+        self.paint_brush(
+            center,
+            extent,
+            |point| {
+                let offset = point - center;
+                let along = offset.dot(direction);
+                let across = (offset - direction * along).length();
+                (across - radius).max(along.abs() - half_height)
+            },
+            value,
+        )
+    }
+
+    /// Clear the occluded cells inside a sphere as an explosion would, each with
+    /// `destruction_chance`, then recompute as the last recompute did, from its origin and with
+    /// its peek offsets. Which cells go depends only on `noise_seed` and the cell, so the same
+    /// call always clears the same ones. Cells on the sphere's edge are in it as
+    /// coverage_threshold decides, and only the part inside the grid is touched. Returns a
+    /// Dictionary with "destroyed", the cells cleared, and "revealed" and "hidden", the cells
+    /// the recompute sees that the one before did not and the other way around. Nothing recomputes when no cell was cleared or before
+    /// the first recompute, and cells revealed behind the cleared ones are left standing
+    #[func]
+    pub fn destroy_occluders_sphere(
+        &mut self,
+        center: Vector3,
+        radius: real,
+        noise_seed: i64,
+        destruction_chance: real,
+    ) -> Dictionary {
+        self.log_call(
+            "destroy_occluders_sphere",
+            &[
+                center.to_variant(),
+                radius.to_variant(),
+                noise_seed.to_variant(),
+                destruction_chance.to_variant(),
+            ],
+        );
+        let center = self.up_axis.to_grid(center);
+        let chance = destruction_chance.clamp(0.0, 1.0) as f32;
+        let mut destroyed = PackedVector3Array::new();
+        let mut revealed = PackedVector3Array::new();
+        let mut hidden = PackedVector3Array::new();
+        let brush = self.brush_cells(center, Vector3::splat(radius), |point| {
+            point.distance_to(center) - radius
+        });
+        if let Some(((min, max), cells)) = brush {
+            for index in cells {
+                if self.occluded.get(index) == Some(true)
+                    && seeded_chance(noise_seed as u64, index, chance)
+                {
+                    self.set_occluder(index, false);
+                    self.one_way.remove(&index);
+                    destroyed.push(self.position_of(index));
+                }
+            }
+            if !destroyed.is_empty() {
+                self.invalidate_occluders(index_cell(min), index_cell(max));
+                // Recompute as the last recompute did, already logged as part of this call
+                if let Some(peek_offsets) = self.last_peek_offsets.clone() {
+                    let previous = self.visible.clone();
+                    let origin = self.up_axis.from_grid(self.origin_float);
+                    self.pending_signals = Some(Vec::new());
+                    self.recompute_at(origin, &peek_offsets);
+                    self.emit_pending_signals();
+                    if previous.size() == self.visible.size() {
+                        self.visible
+                            .for_each_difference(
+                                &previous,
+                                |index, now_visible| match now_visible {
+                                    true => revealed.push(self.position_of(index)),
+                                    false => hidden.push(self.position_of(index)),
+                                },
+                            );
+                    }
+                }
+            }
+        }
+
+        let mut result = Dictionary::new();
+        result.set("destroyed", destroyed);
+        result.set("revealed", revealed);
+        result.set("hidden", hidden);
+        result
+    }
+
+    /// Paint the cells within `extent` of `center` that a brush covers, given the brush as a
+    /// function of a grid position that is negative inside and never more than the distance to
+    /// the brush outside. Returns how many cells changed
+    fn paint_brush(
+        &mut self,
+        center: Vector3,
+        extent: Vector3,
+        distance: impl Fn(Vector3) -> real,
+        value: bool,
+    ) -> i64 {
+        let Some(((min, max), cells)) = self.brush_cells(center, extent, distance) else {
+            return 0;
+        };
+        let mut changed = 0;
+        let mut touched = false;
+        for index in cells {
+            touched |= self.one_way.remove(&index).is_some();
+            if self.occluded.get(index) != Some(value) {
+                self.set_occluder(index, value);
+                changed += 1;
+            }
+        }
+        if changed > 0 || touched {
+            self.invalidate_occluders(index_cell(min), index_cell(max));
+        }
+        changed
+    }
+
+    /// The cells inside the grid a brush covers, as paint_brush() paints them, along with the
+    /// inclusive box of the grid they are in. None if the brush is outside the grid
+    fn brush_cells(
+        &self,
+        center: Vector3,
+        extent: Vector3,
+        distance: impl Fn(Vector3) -> real,
+    ) -> Option<((Index3, Index3), Vec<Index3>)> {
+        // Half the diagonal of a cell, within which of its center a brush's edge may cut it
+        const HALF_DIAGONAL: real = 0.866;
+        const SAMPLES: usize = 4;
+        let (Some((min, max)), _) = self
+            .occluded
+            .clip_box(cell_at(center - extent), cell_at(center + extent))
+        else {
+            return None;
+        };
+        let threshold = self.coverage_threshold.clamp(0.0, 1.0);
+        let covered = |cell: Vector3| {
+            let at_center = distance(cell);
+            if threshold <= 0.0 || at_center <= -HALF_DIAGONAL || at_center >= HALF_DIAGONAL {
+                return at_center <= 0.0;
+            }
+            let step = 1.0 / SAMPLES as real;
+            let offset = |i: usize| (i as real + 0.5) * step - 0.5;
+            let mut inside = 0;
+            for x in 0..SAMPLES {
+                for y in 0..SAMPLES {
+                    for z in 0..SAMPLES {
+                        let sample = cell + Vector3::new(offset(x), offset(y), offset(z));
+                        inside += (distance(sample) <= 0.0) as usize;
+                    }
+                }
+            }
+            inside as real >= threshold * SAMPLES.pow(3) as real
+        };
+
+        let mut cells = Vec::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    if covered(index_to_position((x, y, z))) {
+                        cells.push((x, y, z));
+                    }
+                }
+            }
+        }
+        Some(((min, max), cells))
+    }
+
+    /// Scroll the window of the world the grid covers by `offset` cells, for worlds that stream
+    /// in around the player. Everything in the grid moves by `-offset` and this node by
+    /// `offset` along its own axes, so world_to_cell() and cell_to_world_center() still agree
+    /// on every cell that stays. Cells scrolled past an edge are dropped and the ones scrolled
+    /// in are empty, as grid_scrolled lists. Occlusion, channels, tags, terrain, visibility,
+    /// explored cells, light levels, lights, emitters, bindings, views, rooms and the origin
+    /// all move along
+    #[func]
+    pub fn scroll_grid(&mut self, offset: Vector3i) {
+        self.log_call("scroll_grid", &[offset.to_variant()]);
+        let offset = self.up_axis.to_grid(offset);
+        if offset == Vector3i::ZERO {
+            return;
+        }
+        let size = self.occluded.size();
+        let shift = |index: Index3| {
+            let index = cell_index(index_cell(index) - offset);
+            (index.0 < size.0 && index.1 < size.1 && index.2 < size.2).then_some(index)
+        };
+
+        self.occluded.shift(offset);
+        self.occluded_count = self.occluded.count_set();
+        self.channels.shift(&self.occluded.grid(), offset);
+        self.record_grid_change(None);
+        if let Some(base) = self.change_base.as_mut() {
+            base.shift(offset);
+        }
+        self.one_way = std::mem::take(&mut self.one_way)
+            .into_iter()
+            .filter_map(|(index, direction)| Some((shift(index)?, direction)))
+            .collect();
+        self.block_probability = std::mem::take(&mut self.block_probability)
+            .into_iter()
+            .filter_map(|(index, probability)| Some((shift(index)?, probability)))
+            .collect();
+        if let Some(tags) = self.tags.as_mut() {
+            shift_array(tags, size, offset);
+        }
+        self.terrain = self.terrain.as_ref().map(|terrain| terrain.shifted(offset));
+
+        self.visible.shift(offset);
+        self.visible_snapshot = None;
+        self.effective_visible.shift(offset);
+        self.explored.shift(offset);
+        shift_array(&mut self.visibility_fraction, size, offset);
+        shift_array(&mut self.visibility_tiers, size, offset);
+        shift_array(&mut self.propagation, size, offset);
+        shift_array(&mut self.light_level, size, offset);
+        shift_array(&mut self.light_color, size, offset);
+        if let Some(buffer) = self.external_visibility.as_mut() {
+            self.visible.write_bytes(buffer.as_mut_slice());
+        }
+        self.origin -= offset;
+        self.origin_float -= offset.cast_float();
+        self.pass_cache.invalidate_all();
+        self.light_cache.invalidate_all();
+        self.distance_field.invalidate_all();
+        self.exposed.invalidate_all();
+
+        for light in &mut self.lights {
+            light.position -= offset;
+        }
+        self.emissive = std::mem::take(&mut self.emissive)
+            .into_iter()
+            .filter_map(|(index, emitter)| Some((shift(index)?, emitter)))
+            .collect();
+        for binding in &mut self.occluder_bindings {
+            binding.cells = binding
+                .cells
+                .iter()
+                .filter_map(|&index| shift(index))
+                .collect();
+        }
+        for view in &mut self.views {
+            view.visible.shift(offset);
+            view.origin -= offset;
+        }
+        self.portals.shift(offset);
+        self.floors.shift(offset);
+
+        self.base_mut().translate_object_local(offset.cast_float());
+        self.update_cross_section_mesh();
+
+        let mut vacated_from = PackedVector3Array::new();
+        let mut vacated_to = PackedVector3Array::new();
+        for (min, max) in shift_vacated(size, offset) {
+            vacated_from.push(self.position_of(min));
+            vacated_to.push(self.position_of(max));
+        }
+        let script_offset = self.up_axis.from_grid(offset);
+        self.base_mut().emit_signal(
+            "grid_scrolled",
+            &[
+                script_offset.to_variant(),
+                vacated_from.to_variant(),
+                vacated_to.to_variant(),
+            ],
+        );
+    }
+
+    /// bind_occluder_to_property() on the node's `visible`, such as a door mesh that is hidden
+    /// while the door is open
+    #[func]
+    pub fn bind_occluder_to_node(
+        &mut self,
+        node: Gd<Node3D>,
+        cells: PackedVector3Array,
+        occlude_when: OccludeWhen,
+    ) -> i64 {
+        self.bind_occluder_to_property(node, cells, StringName::from("visible"), occlude_when)
+    }
+
+    /// Make cells occlude while a boolean property of a node is true, or while it is false with
+    /// OCCLUDE_WHEN_FALSE, returning a handle for unbind_occluder() or -1 with an error.
+    /// The cells are set to match right away, and again whenever the property is found to have
+    /// changed, which is checked every frame. Set auto_recompute to recompute along with it.
+    /// Once the node is freed the binding goes away, leaving the cells as they last were
+    #[func]
+    pub fn bind_occluder_to_property(
+        &mut self,
+        node: Gd<Node3D>,
+        cells: PackedVector3Array,
+        property: StringName,
+        occlude_when: OccludeWhen,
+    ) -> i64 {
+        let cells: Vec<Vector3i> = cells
+            .as_slice()
+            .iter()
+            .map(|&cell| self.cell_of(cell))
+            .collect();
+        if let Some(&cell) = cells
+            .iter()
+            .find(|&&cell| self.occluded.get(cell_index(cell)).is_none())
+        {
+            self.report_out_of_bounds(cell);
+            return -1;
+        }
+        let mut binding = OccluderBinding {
+            id: self.next_binding_id,
+            node: node.instance_id(),
+            property,
+            occlude_when,
+            cells: cells.into_iter().map(cell_index).collect(),
+            occluding: false,
+        };
+        let Some(occluding) = binding.poll() else {
+            godot_script_error!("{} has no boolean property {}", node, binding.property);
+            return -1;
+        };
+        self.set_bound_cells(&binding.cells, occluding);
+        binding.occluding = occluding;
+        self.next_binding_id += 1;
+        let id = binding.id;
+        self.occluder_bindings.push(binding);
+        id
+    }
+
+    /// Stop cells following their node, leaving them as they are.
+    /// Returns false if there is no binding with this handle
+    #[func]
+    pub fn unbind_occluder(&mut self, id: i64) -> bool {
+        let count = self.occluder_bindings.len();
+        self.occluder_bindings.retain(|binding| binding.id != id);
+        self.occluder_bindings.len() != count
+    }
+
+    /// Set bound cells whose property changed, dropping bindings whose node is gone,
+    /// and recompute if anything changed and auto_recompute is set
+    fn update_occluder_bindings(&mut self) {
+        if self.occluder_bindings.is_empty() {
+            return;
+        }
+        let mut changed = false;
+        let bindings: Vec<OccluderBinding> = std::mem::take(&mut self.occluder_bindings)
+            .into_iter()
+            .filter_map(|mut binding| {
+                let occluding = binding.poll()?;
+                if occluding != binding.occluding {
+                    self.set_bound_cells(&binding.cells, occluding);
+                    binding.occluding = occluding;
+                    changed = true;
+                }
+                Some(binding)
+            })
+            .collect();
+        self.occluder_bindings = bindings;
+        if changed && self.auto_recompute {
+            let origin = self.up_axis.from_grid(self.origin_float);
+            self.set_origin_and_recompute(origin);
+        }
+    }
+
+    /// Occlude or clear cells as set_occluded() and carve_box() would
+    fn set_bound_cells(&mut self, cells: &[Index3], occluding: bool) {
+        for &index in cells {
+            // Replays have no bindings, so log what they did as the edit it amounts to
+            let pos = self.up_axis.from_grid(index_cell(index));
+            self.log_call(
+                "set_occluded_channel",
+                &[pos.to_variant(), 0.to_variant(), occluding.to_variant()],
+            );
+            self.set_occluder(index, occluding);
+            self.one_way.remove(&index);
+            let cell = index_cell(index);
+            self.invalidate_occluders(cell, cell);
+        }
+    }
+
+    /// Start recording occluder edits for take_change_patch(), such as on a server that sends
+    /// its edits to clients. Call it again to drop the edits recorded so far
+    #[func]
+    pub fn begin_tracking_changes(&mut self) {
+        self.change_base = Some(self.occluded.grid().clone());
+    }
+
+    /// The occluder edits since begin_tracking_changes() or the last take, for
+    /// apply_change_patch() on a grid that matched this one at that point. A cell edited
+    /// back to its old value is left out. One-way directions and terrain are not included.
+    /// Empty with an error if not tracking, or if restore_state() resized the grid since,
+    /// in which case tracking starts over
+    #[func]
+    pub fn take_change_patch(&mut self) -> PackedByteArray {
+        let Some(base) = &self.change_base else {
+            godot_script_error!("Call begin_tracking_changes() before taking change patches");
+            return PackedByteArray::new();
+        };
+        if base.size() != self.occluded.size() {
+            godot_script_error!("The grid was resized since the last change patch");
+            self.change_base = Some(self.occluded.grid().clone());
+            return PackedByteArray::new();
+        }
+        let patch = encode_patch(base, &self.occluded.grid());
+        self.change_base = Some(self.occluded.grid().clone());
+        PackedByteArray::from(patch.as_slice())
+    }
+
+    /// Apply a patch from take_change_patch(), as set_occluded() and carve_box() would.
+    /// Patches must be applied in the order they were taken. Nothing is applied if the patch
+    /// is malformed or was taken from a grid of another size
+    #[func]
+    pub fn apply_change_patch(&mut self, patch: PackedByteArray) -> Error {
+        self.log_call("apply_change_patch", &[patch.to_variant()]);
+        let changes = match decode_patch(self.occluded.size(), patch.as_slice()) {
+            Ok(changes) => changes,
+            Err(error) => return error.report(),
+        };
+        let Some(&(first, _)) = changes.first() else {
+            return Error::OK;
+        };
+
+        let (mut min, mut max) = (first, first);
+        for &(index, value) in &changes {
+            self.set_occluder(index, value);
+            self.one_way.remove(&index);
+            min = (min.0.min(index.0), min.1.min(index.1), min.2.min(index.2));
+            max = (max.0.max(index.0), max.1.max(index.1), max.2.max(index.2));
+        }
+        self.invalidate_occluders(index_cell(min), index_cell(max));
+        Error::OK
+    }
+
+    /// Import occlusion from another voxel store, such as a voxel terrain module, a batch at a
+    /// time over the following frames rather than cell by cell from a script. `sampler` is
+    /// called with a PackedVector3Array of cell positions and must return a PackedByteArray of
+    /// one byte per position, nonzero for occluded. The import covers the inclusive box
+    /// between two corners, clipped to the grid with a warning, `batch_size` cells a frame, or
+    /// all of it in the next frame for 0 or less. Cells that change lose any one-way
+    /// occlusion, as with apply_change_patch(). Starting another import or calling
+    /// cancel_sampler_import() stops this one where it is
+    #[func]
+    pub fn import_via_sampler(
+        &mut self,
+        region_from: Vector3i,
+        region_to: Vector3i,
+        sampler: Callable,
+        batch_size: i32,
+    ) -> Error {
+        if !sampler.is_valid() {
+            godot_script_error!("The sampler is not a valid Callable");
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        let (clipped, was_clipped) = self.clip_box(region_from, region_to);
+        if was_clipped {
+            godot_warn!(
+                "Region from {} to {} is partially outside the grid",
+                region_from,
+                region_to
+            );
+        }
+        let Some((min, max)) = clipped else {
+            return Error::ERR_PARAMETER_RANGE_ERROR;
+        };
+        if self.sampler_import.is_some() {
+            godot_warn!("Stopped the sampler import in progress to start another");
+        }
+        let batch_size = batch_size.max(0) as usize;
+        self.sampler_import = Some(SamplerImport::new(sampler, min, max, batch_size));
+        Error::OK
+    }
+
+    /// Stop the sampler import in progress, keeping the batches already imported.
+    /// Returns false if there was none
+    #[func]
+    pub fn cancel_sampler_import(&mut self) -> bool {
+        self.sampler_import.take().is_some()
+    }
+
+    /// Whether an import_via_sampler() is still in progress
+    #[func]
+    pub fn is_sampler_import_running(&self) -> bool {
+        self.sampler_import.is_some()
+    }
+
+    /// Sample and apply the next batch of the sampler import in progress
+    fn advance_sampler_import(&mut self) {
+        let Some(import) = self.sampler_import.clone() else {
+            return;
+        };
+        let batch = import.next_batch();
+        let positions: PackedVector3Array =
+            batch.iter().map(|&index| self.position_of(index)).collect();
+        let result = {
+            // Let the sampler call back into this node, such as to cancel the import
+            let _guard = self.base_mut();
+            import.sampler.call(&[positions.to_variant()])
+        };
+        // Another import over another box can have the same sampler and progress
+        if self.sampler_import.as_ref() != Some(&import) {
+            return;
+        }
+        let bytes = match result.try_to::<PackedByteArray>() {
+            Ok(bytes) if bytes.len() == batch.len() => bytes,
+            _ => {
+                godot_script_error!(
+                    "The sampler must return a PackedByteArray of one byte per position, \
+                     stopped the import"
+                );
+                self.sampler_import = None;
+                return;
+            }
+        };
+
+        // Replays have no sampler, so log the batch as the patch it amounts to
+        let base = self
+            .recording_log()
+            .is_some()
+            .then(|| self.occluded.grid().clone());
+        let mut changed: Option<(Index3, Index3)> = None;
+        for (&index, &byte) in batch.iter().zip(bytes.as_slice()) {
+            let value = byte != 0;
+            if self.occluded.get(index) == Some(value) {
+                continue;
+            }
+            self.set_occluder(index, value);
+            self.one_way.remove(&index);
+            changed = Some(match changed {
+                Some((min, max)) => (
+                    (min.0.min(index.0), min.1.min(index.1), min.2.min(index.2)),
+                    (max.0.max(index.0), max.1.max(index.1), max.2.max(index.2)),
+                ),
+                None => (index, index),
+            });
+        }
+        if let Some((min, max)) = changed {
+            self.invalidate_occluders(index_cell(min), index_cell(max));
+            if let Some(base) = base {
+                let patch =
+                    PackedByteArray::from(encode_patch(&base, &self.occluded.grid()).as_slice());
+                self.log_call("apply_change_patch", &[patch.to_variant()]);
+            }
+        }
+
+        let Some(import) = self.sampler_import.as_mut() else {
+            return;
+        };
+        import.imported += batch.len();
+        let (imported, total) = (import.imported, import.total());
+        if import.is_done() {
+            self.sampler_import = None;
+        }
+        self.emit_deferred(
+            "sampler_import_progress",
+            vec![(imported as i64).to_variant(), (total as i64).to_variant()],
+        );
+    }
+
+    /// Spread sound/smell-like power from an origin, where walls attenuate instead of block:
+    /// every step into an empty cell costs `air_cost` and into an occluded cell `wall_cost`.
+    /// Read the results with get_propagation_level()
+    #[func]
+    pub fn compute_propagation(
+        &mut self,
+        origin: Vector3,
+        initial_power: f32,
+        wall_cost: f32,
+        air_cost: f32,
+    ) -> Error {
+        if wall_cost < 0.0 || air_cost < 0.0 {
+            godot_script_error!("Propagation costs must not be negative");
+            return Error::ERR_INVALID_PARAMETER;
+        }
+
+        let origin = self.cell_of(origin);
+        let index = cell_index(origin);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(origin);
+        }
+
+        propagate(
+            &self.occluded.grid(),
+            index,
+            initial_power,
+            wall_cost,
+            air_cost,
+            &mut self.propagation,
+        );
+        Error::OK
+    }
+
+    /// Power received at a cell by the last compute_propagation(), 0 if it never arrived
+    #[func]
+    pub fn get_propagation_level(&self, pos: Vector3i) -> f32 {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.propagation.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// Recompute what can be seen from an origin. Should the recompute panic, the panic is
+    /// reported as an error and nothing is visible until the next one.
+    /// The signals it raises are emitted once it is done, in the order voxels_revealed,
+    /// voxel_revealed for each of its cells, watches_changed, watch_changed for each of its
+    /// watches, effective_visibility_changed, visibility_tier_changed, recompute_truncated,
+    /// recompute_over_budget and recompute_finished. Only the fine-grained voxel_revealed and
+    /// watch_changed come more than once, and those only with fine_grained_signals
+    #[func]
+    pub fn set_origin_and_recompute(&mut self, origin: Vector3) -> Error {
+        self.log_property_changes();
+        self.log_call("set_origin_and_recompute", &[origin.to_variant()]);
+        self.pending_signals = Some(Vec::new());
+        let outcome = self.recompute_at(origin, &[]);
+        let visible = &self.visible;
+        if let Some(log) = self.input_log.as_mut().filter(|log| log.recording) {
+            log.record_hash(visible);
+        }
+        self.emit_pending_signals();
+        outcome
+    }
+
+    /// Emit the signals held back by a recompute, timing it for get_last_recompute_stats()
+    fn emit_pending_signals(&mut self) {
+        let time = Time::singleton();
+        let start = time.get_ticks_usec();
+        for (signal, args) in self.pending_signals.take().unwrap_or_default() {
+            self.base_mut().emit_signal(signal, &args);
+        }
+        self.last_signal_usec = time.get_ticks_usec() - start;
+    }
+
+    /// Emit a signal, or hold it back until the end of the recompute raising it
+    fn emit_deferred(&mut self, signal: &'static str, args: Vec<Variant>) {
+        match self.pending_signals.as_mut() {
+            Some(pending) => pending.push((signal, args)),
+            None => {
+                self.base_mut().emit_signal(signal, &args);
+            }
+        }
+    }
+
+    /// Recompute what can be seen from a cell as set_cell_origin_and_recompute() does, adding
+    /// what can be seen by leaning out of it. The visibility is the union of the casts from the
+    /// eye and from the eye moved by each of `peek_offsets`, in cells, or by half a cell toward
+    /// each face when there are none. Offsets are clamped to the cell's faces, and those that
+    /// lean against an occluded cell or would cast from the same point again are skipped.
+    /// What each sub-origin saw is in the "peeks" of get_last_recompute_stats().
+    ///
+    /// Peeking is one-sided: can_see() and the other line of sight queries still look from the
+    /// eye alone, so they can disagree with is_visible() after a peek, and a cell seen only
+    /// while peeking need not see the origin back. Visibility fractions, the lit volume, debug
+    /// drawings, the trace and max_work_items cover the cast from the eye only
+    #[func]
+    pub fn recompute_with_peek(
+        &mut self,
+        origin_cell: Vector3i,
+        peek_offsets: PackedVector3Array,
+    ) -> Error {
+        self.log_property_changes();
+        self.log_call(
+            "recompute_with_peek",
+            &[origin_cell.to_variant(), peek_offsets.to_variant()],
+        );
+        let offsets = match peek_offsets.is_empty() {
+            true => FACE_PEEKS.as_slice(),
+            false => peek_offsets.as_slice(),
+        };
+        self.pending_signals = Some(Vec::new());
+        let outcome = self.recompute_at(origin_cell.cast_float(), offsets);
+        let visible = &self.visible;
+        if let Some(log) = self.input_log.as_mut().filter(|log| log.recording) {
+            log.record_hash(visible);
+        }
+        self.emit_pending_signals();
+        outcome
+    }
+
+    /// The body of set_origin_and_recompute() and recompute_with_peek(), after the call is
+    /// logged
+    fn recompute_at(&mut self, origin: Vector3, peek_offsets: &[Vector3]) -> Error {
+        self.cancel_pending_recompute();
+        self.sync_shared_grid();
+        let origin = self.up_axis.to_grid(origin);
+        let origin_int = self.eye_cell(origin);
+        let index = cell_index(origin_int);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(origin_int);
+        }
+        let offsets_in_grid: Vec<Vector3> = peek_offsets
+            .iter()
+            .map(|&offset| self.up_axis.to_grid(offset))
+            .collect();
+        let grid = self.occluded.grid();
+        let jitters = peek_jitters(
+            sight_source(&self.occlusion_source, &self.channels, &grid),
+            grid.size(),
+            origin_int,
+            self.eye_offset_in_cell().1,
+            &offsets_in_grid,
+        );
+        drop(grid);
+        let peeks: Vec<(Vector3, Option<Vector3>)> =
+            peek_offsets.iter().copied().zip(jitters).collect();
+        if let Err(error) = catch_panic(|| self.recompute(origin, origin_int, &peeks, None)) {
+            // Signals from before the panic describe results that are being thrown away
+            if let Some(pending) = self.pending_signals.as_mut() {
+                pending.clear();
+            }
+            self.discard_visibility();
+            return error.report();
+        }
+        Error::OK
+    }
+
+    /// Watch a cell, for watches_changed to report when it becomes or stops being visible
+    /// after a recompute. Returns the watch's id, or -1 with an error outside the grid. Ids are
+    /// not reused, and the watch stays on the same coordinates if the grid scrolls
+    #[func]
+    pub fn add_watch(&mut self, pos: Vector3i) -> i64 {
+        let pos = self.up_axis.to_grid(pos);
+        if self.occluded.get(cell_index(pos)).is_none() {
+            self.report_out_of_bounds(pos);
+            return -1;
+        }
+        self.watches.add(pos, &self.visible)
+    }
+
+    /// Returns false if there is no watch with this id
+    #[func]
+    pub fn remove_watch(&mut self, id: i64) -> bool {
+        self.watches.remove(id)
+    }
+
+    /// Recompute what can be seen from the center of a cell, the same as
+    /// set_origin_and_recompute() given the cell's whole coordinates
+    #[func]
+    pub fn set_cell_origin_and_recompute(&mut self, cell: Vector3i) -> Error {
+        self.set_origin_and_recompute(cell.cast_float())
+    }
+
+    /// The cell the origin of the last recompute was in
+    #[func]
+    pub fn get_origin_cell(&self) -> Vector3i {
+        self.up_axis.from_grid(self.origin)
+    }
+
+    /// Recompute what can be seen from an origin as set_origin_and_recompute() does, spread
+    /// over the following frames by scanning views for up to `usec_per_frame` microseconds a
+    /// frame. The visibility keeps the last complete recompute's cells until the cast is done,
+    /// which then applies it and raises the signals of a recompute. Edits to the occluders in
+    /// the meantime start the cast over. It casts every pass within the view cone plainly,
+    /// leaving out the pass cache, portals, floor separators, rays and max_work_items, keeps no
+    /// trace or lit volume and counts every visible cell as fully visible.
+    /// cancel_pending_recompute() and any other recompute from the eye cancel it
+    #[func]
+    pub fn start_sliced_recompute(&mut self, origin: Vector3, usec_per_frame: i64) -> Error {
+        self.cancel_pending_recompute();
+        self.sync_shared_grid();
+        let origin = self.up_axis.to_grid(origin);
+        let origin_int = self.eye_cell(origin);
+        if self.occluded.get(cell_index(origin_int)).is_none() {
+            return self.report_out_of_bounds(origin_int);
+        }
+        let settings = self.eye_settings();
+        self.sliced_recompute = Some(SlicedRecompute {
+            origin,
+            origin_int,
+            settings,
+            cast: SlicedCast::new(eye_passes(&settings).into_iter()),
+            visible: BitGrid::new(self.visible.size()),
+            usec_per_frame: usec_per_frame.max(0) as u64,
+            usec: 0,
+            grid_generation: self.occluded.generation(),
+        });
+        Error::OK
+    }
+
+    /// Drop the recompute start_sliced_recompute() has in progress, leaving the visibility as
+    /// the last complete recompute left it, and emit recompute_cancelled. Returns false if
+    /// there was none
+    #[func]
+    pub fn cancel_pending_recompute(&mut self) -> bool {
+        let Some(sliced) = self.sliced_recompute.take() else {
+            return false;
+        };
+        let origin = self.up_axis.from_grid(sliced.origin);
+        self.emit_deferred("recompute_cancelled", vec![origin.to_variant()]);
+        true
+    }
+
+    /// Whether a start_sliced_recompute() is still in progress
+    #[func]
+    pub fn is_recompute_pending(&self) -> bool {
+        self.sliced_recompute.is_some()
+    }
+
+    /// Scan views of the sliced recompute in progress for up to its time a frame, checking the
+    /// time after every view, and apply it once it is done. It goes into the input log as the
+    /// set_origin_and_recompute() it amounts to
+    fn advance_sliced_recompute(&mut self) {
+        let Some(mut sliced) = self.sliced_recompute.take() else {
+            return;
+        };
+        if sliced.visible.size() != self.visible.size() {
+            // The grid was replaced by one of another size, which the origin may be outside
+            let origin = self.up_axis.from_grid(sliced.origin);
+            self.emit_deferred("recompute_cancelled", vec![origin.to_variant()]);
+            return;
+        }
+        let generation = self.occluded.generation();
+        if sliced.grid_generation != generation {
+            sliced.cast = SlicedCast::new(eye_passes(&sliced.settings).into_iter());
+            sliced.visible.clear();
+            sliced.grid_generation = generation;
+        }
+
+        let time = Time::singleton();
+        let start = time.get_ticks_usec();
+        let usec_per_frame = sliced.usec_per_frame;
+        let settings = sliced.settings;
+        let grid = self.occluded.grid();
+        let mut caster = Caster {
+            occluded: sight_source(&self.occlusion_source, &self.channels, &grid),
+            visible: &mut sliced.visible,
+            origin: sliced.origin_int,
+            jitter: settings.eye_jitter,
+            max_depth: settings.max_depth,
+            lod: settings.lod,
+            corner_rule: settings.corner_rule,
+            debug_rects: None,
+            lit_rects: None,
+            fractions: None,
+            bounds: None,
+            one_way: Some(&self.one_way),
+            blockers: None,
+            terrain: self.terrain.as_ref(),
+            exposed: None,
+            rects: RectLimit::new(settings.max_rects, settings.narrow),
+        };
+        let advanced = catch_panic(|| {
+            sliced.cast.advance(&mut caster, || {
+                time.get_ticks_usec() - start >= usec_per_frame
+            })
+        });
+        drop(grid);
+        sliced.usec += time.get_ticks_usec() - start;
+        let done = match advanced {
+            Ok(done) => done,
+            Err(error) => {
+                // Dropped like a cancelled one, keeping the last complete recompute's cells
+                error.report();
+                return;
+            }
+        };
+        if !done {
+            self.sliced_recompute = Some(sliced);
+            return;
+        }
+
+        self.log_property_changes();
+        let (origin, origin_int) = (sliced.origin, sliced.origin_int);
+        let script_origin = self.up_axis.from_grid(origin);
+        self.log_call("set_origin_and_recompute", &[script_origin.to_variant()]);
+        self.pending_signals = Some(Vec::new());
+        if let Err(error) = catch_panic(|| self.recompute(origin, origin_int, &[], Some(sliced))) {
+            if let Some(pending) = self.pending_signals.as_mut() {
+                pending.clear();
+            }
+            self.discard_visibility();
+            error.report();
+        }
+        let visible = &self.visible;
+        if let Some(log) = self.input_log.as_mut().filter(|log| log.recording) {
+            log.record_hash(visible);
+        }
+        self.emit_pending_signals();
+    }
+
+    /// The body of set_origin_and_recompute(), for an origin inside the grid. For
+    /// recompute_with_peek(), `peeks` pairs every offset with the jitter to peek from, None
+    /// where peek_jitters() skipped it. A finished sliced recompute brings the cells it saw
+    fn recompute(
+        &mut self,
+        origin: Vector3,
+        origin_int: Vector3i,
+        peeks: &[(Vector3, Option<Vector3>)],
+        sliced: Option<SlicedRecompute>,
+    ) {
+        // Set origin
+        self.origin = origin_int;
+        self.origin_float = origin;
+        self.last_peek_offsets = Some(peeks.iter().map(|&(offset, _)| offset).collect());
+
+        let time = Time::singleton();
+        let start = time.get_ticks_usec() - sliced.as_ref().map_or(0, |sliced| sliced.usec);
+        self.last_pass_usec.clear();
+        self.last_pass_work_items.clear();
+        self.last_cached_passes = 0;
+        self.last_work_items = 0;
+        self.last_truncated = false;
+        self.last_max_rects = 0;
+        self.last_rect_merges = 0;
+        self.last_trace.clear();
+        self.last_peeks = VariantArray::new();
+
+        let settings = self.eye_settings();
+        self.visible_snapshot = None;
+        let fine_grained = self.fine_grained_signals;
+        let connected = |signal: &str| !self.base().get_signal_connection_list(signal).is_empty();
+        let reveals_wanted =
+            connected("voxels_revealed") || (fine_grained && connected("voxel_revealed"));
+        let previous = reveals_wanted.then(|| self.visible.clone());
+        let (path, truncated_at) = match sliced {
+            Some(sliced) => {
+                self.visible = sliced.visible;
+                self.last_work_items = sliced.cast.work_items;
+                (CastPath::Sliced, None)
+            }
+            None => self.cast_from_eye(settings),
+        };
+        self.last_cast_path = path;
+        if let Some(cone) = settings.view_cone {
+            let eye = self.origin.cast_float() + settings.eye_jitter;
+            cone.keep_inside(&mut self.visible, eye, self.origin);
+        }
+        if !peeks.is_empty() {
+            let eye_usec = time.get_ticks_usec() - start;
+            self.cast_peeks(peeks, settings, path, eye_usec);
+        }
+
+        let origin_index = cell_index(self.origin);
+        self.visible.set(origin_index, true);
+        if self.report_surfaces_only {
+            self.keep_surfaces_only();
+        }
+        if settings.track_fractions {
+            self.update_visibility_fraction(origin_index, path != CastPath::Cached);
+        } else {
+            self.visibility_fraction = Array3::zeros((0, 0, 0));
+        }
+        self.last_recompute_usec = time.get_ticks_usec() - start;
+        self.last_visible_cells = self.visible.count_set();
+
+        // Visualize shadowcasting
+        if path == CastPath::Cached {
+            let debug_rects: Vec<DebugRect> = self
+                .pass_cache
+                .passes()
+                .flat_map(|cached| cached.debug_rects.iter().copied())
+                .collect();
+            for debug_rect in &debug_rects {
+                self.draw_debug_rect(debug_rect);
+            }
+        }
+
+        if let Some(buffer) = self.external_visibility.as_mut() {
+            self.visible.write_bytes(buffer.as_mut_slice());
+        }
+        self.explored.union_with(&self.visible);
+        self.update_cross_section_mesh();
+
+        if let Some(previous) = previous.filter(|previous| previous.size() == self.visible.size()) {
+            let mut revealed = PackedVector3Array::new();
+            self.visible
+                .for_each_difference(&previous, |index, now_visible| {
+                    if now_visible {
+                        revealed.push(self.position_of(index));
+                    }
+                });
+            if !revealed.is_empty() {
+                self.emit_deferred("voxels_revealed", vec![revealed.to_variant()]);
+            }
+            if fine_grained {
+                for &cell in revealed.as_slice() {
+                    self.emit_deferred("voxel_revealed", vec![cell.to_variant()]);
+                }
+            }
+        }
+        let watch_changes = self.watches.update(&self.visible);
+        if !watch_changes.is_empty() {
+            let ids: PackedInt64Array = watch_changes.iter().map(|&(id, _)| id).collect();
+            let states: PackedByteArray = watch_changes
+                .iter()
+                .map(|&(_, visible)| visible as u8)
+                .collect();
+            self.emit_deferred(
+                "watches_changed",
+                vec![ids.to_variant(), states.to_variant()],
+            );
+            if fine_grained {
+                for (id, visible) in watch_changes {
+                    self.emit_deferred(
+                        "watch_changed",
+                        vec![id.to_variant(), visible.to_variant()],
+                    );
+                }
+            }
+        }
+        self.update_effective_visibility();
+        self.update_visibility_tiers();
+        self.dirty_chunks.update(&self.visible);
+
+        if let Some(completed_depth) = truncated_at {
+            let work_items = self.last_work_items as i64;
+            self.emit_deferred(
+                "recompute_truncated",
+                vec![
+                    work_items.to_variant(),
+                    (completed_depth as i64).to_variant(),
+                ],
+            );
+        }
+        // A sliced recompute takes as long as it was allowed over several frames
+        if path != CastPath::Sliced {
+            self.check_recompute_budget();
+        }
+        let elapsed = self.last_recompute_usec as i64;
+        self.emit_deferred("recompute_finished", vec![elapsed.to_variant()]);
+    }
+
+    /// Cast what the eye sees into the visibility: through floor separators or portals when
+    /// any are declared, with rays at depths up to ray_cast_max_depth, and otherwise with the
+    /// pass cache. Returns how it cast, and the depth it completed if max_work_items cut it short
+    fn cast_from_eye(&mut self, settings: PassSettings) -> (CastPath, Option<usize>) {
+        self.visible.clear();
+        self.last_rays = 0;
+        let grid = self.occluded.grid();
+        // With floor separators declared, sight along y ends at the nearest floor each way
+        if !self.floors.is_empty() {
+            self.floors.cast(
+                sight_source(&self.occlusion_source, &self.channels, &grid),
+                &self.one_way,
+                self.terrain.as_ref(),
+                &mut self.visible,
+                self.origin,
+                &settings,
+            );
+            return (CastPath::FloorSeparators, None);
+        }
+        // With portals registered, only the rooms that can be seen into are scanned
+        if !self.portals.is_empty()
+            && self.portals.cast(
+                sight_source(&self.occlusion_source, &self.channels, &grid),
+                &self.one_way,
+                self.terrain.as_ref(),
+                &mut self.visible,
+                self.origin,
+                &settings,
+            )
+        {
+            return (CastPath::Portals, None);
+        }
+        self.visible.clear();
+        if self.ray_cast_max_depth > 0 && settings.max_depth <= self.ray_cast_max_depth as usize {
+            self.last_rays = cast_rays(
+                sight_source(&self.occlusion_source, &self.channels, &grid),
+                Some(&self.one_way),
+                self.terrain.as_ref(),
+                &mut self.visible,
+                &settings,
+                self.origin,
+            );
+            return (CastPath::Rays, None);
+        }
+        drop(grid);
+
+        let outcome = self.cast_cached_passes(settings);
+        self.last_work_items = outcome.work_items;
+        self.last_truncated = outcome.truncated;
+        let truncated_at = outcome.truncated.then_some(outcome.completed_depth);
+        (CastPath::Cached, truncated_at)
+    }
+
+    /// Union into the visibility what the eye sees from each peek jitter, for
+    /// recompute_with_peek(), keeping the stats of the eye's cast and every peek in last_peeks
+    fn cast_peeks(
+        &mut self,
+        peeks: &[(Vector3, Option<Vector3>)],
+        settings: PassSettings,
+        path: CastPath,
+        eye_usec: u64,
+    ) {
+        let time = Time::singleton();
+        // visible cells, cells no earlier sub-origin saw and time of each sub-origin cast
+        let mut stats = |offset: Vector3, cast: Option<(usize, usize, u64)>| {
+            let (visible, added, usec) = cast.unwrap_or_default();
+            let mut entry = Dictionary::new();
+            entry.set("offset", offset);
+            entry.set("skipped", cast.is_none());
+            entry.set("visible_cells", visible as i64);
+            entry.set("added_cells", added as i64);
+            entry.set("usec", usec as i64);
+            self.last_peeks.push(&entry.to_variant());
+        };
+        let eye_cells = self.visible.count_set();
+        stats(Vector3::ZERO, Some((eye_cells, eye_cells, eye_usec)));
+
+        for &(offset, jitter) in peeks {
+            let Some(jitter) = jitter else {
+                stats(offset, None);
+                continue;
+            };
+            let start = time.get_ticks_usec();
+            let settings = PassSettings {
+                eye_jitter: jitter,
+                ..settings
+            };
+            let mut seen = BitGrid::new(self.visible.size());
+            let grid = self.occluded.grid();
+            let cast_through_portals = path == CastPath::Portals
+                && self.portals.cast(
+                    sight_source(&self.occlusion_source, &self.channels, &grid),
+                    &self.one_way,
+                    self.terrain.as_ref(),
+                    &mut seen,
+                    self.origin,
+                    &settings,
+                );
+            if path == CastPath::FloorSeparators {
+                self.floors.cast(
+                    sight_source(&self.occlusion_source, &self.channels, &grid),
+                    &self.one_way,
+                    self.terrain.as_ref(),
+                    &mut seen,
+                    self.origin,
+                    &settings,
+                );
+            } else if !cast_through_portals {
+                Caster {
+                    occluded: sight_source(&self.occlusion_source, &self.channels, &grid),
+                    visible: &mut seen,
+                    origin: self.origin,
+                    jitter,
+                    max_depth: settings.max_depth,
+                    lod: settings.lod,
+                    corner_rule: settings.corner_rule,
+                    debug_rects: None,
+                    lit_rects: None,
+                    fractions: None,
+                    bounds: None,
+                    one_way: Some(&self.one_way),
+                    blockers: None,
+                    terrain: self.terrain.as_ref(),
+                    exposed: None,
+                    rects: RectLimit::new(settings.max_rects, settings.narrow),
+                }
+                .cast_all();
+            }
+            drop(grid);
+            if let Some(cone) = settings.view_cone {
+                cone.keep_inside(&mut seen, self.origin.cast_float() + jitter, self.origin);
+            }
+            let before = self.visible.count_set();
+            self.visible.union_with(&seen);
+            let added = self.visible.count_set() - before;
+            let usec = time.get_ticks_usec() - start;
+            stats(offset, Some((seen.count_set(), added, usec)));
+        }
+    }
+
+    /// Leave the results consistent after a recompute panicked partway through: nothing is
+    /// visible until the next one, which casts every pass again. Explored cells keep what
+    /// earlier recomputes saw
+    fn discard_visibility(&mut self) {
+        self.visible_snapshot = None;
+        self.visible.clear();
+        self.visibility_fraction = Array3::zeros((0, 0, 0));
+        self.pass_cache = PassCache::default();
+        if let Some(buffer) = self.external_visibility.as_mut() {
+            buffer.as_mut_slice().fill(0);
+        }
+        self.update_effective_visibility();
+        self.update_visibility_tiers();
+        self.dirty_chunks.update(&self.visible);
+    }
+
+    /// Emit recompute_over_budget if the last recompute took too long, and adjust
+    /// budget_depth_reduction for reduce_depth_over_budget
+    fn check_recompute_budget(&mut self) {
+        if self.budget_warning_usec <= 0 {
+            return;
+        }
+        let budget = self.budget_warning_usec as u64;
+        let elapsed = self.last_recompute_usec;
+        if elapsed <= budget {
+            if elapsed < budget / 2 {
+                self.budget_depth_reduction = self.budget_depth_reduction.saturating_sub(1);
+            }
+            return;
+        }
+
+        // Always leave the origin's neighbours in range
+        if self.reduce_depth_over_budget {
+            self.budget_depth_reduction = (self.budget_depth_reduction + 1).min(self.reach() - 1);
+        }
+        let mut stats = self.get_last_recompute_stats();
+        stats.set("depth_histogram", self.depth_histogram());
+        self.emit_deferred(
+            "recompute_over_budget",
+            vec![(elapsed as i64).to_variant(), stats.to_variant()],
+        );
+    }
+
+    /// How many visible cells there are at each depth (largest distance along any axis)
+    /// from the origin of the last recompute
+    fn depth_histogram(&self) -> PackedInt64Array {
+        let mut histogram = vec![0i64; self.reach() + 1];
+        self.visible.for_each_set(|index| {
+            let delta = (index_cell(index) - self.origin).abs();
+            let depth = delta.x.max(delta.y).max(delta.z) as usize;
+            if let Some(count) = histogram.get_mut(depth) {
+                *count += 1;
+            }
+        });
+        histogram.into_iter().collect()
+    }
+
+    /// How many layers reduce_depth_over_budget currently takes off the depth of recomputes
+    #[func]
+    pub fn get_budget_depth_reduction(&self) -> i64 {
+        self.budget_depth_reduction as i64
+    }
+
+    /// Give recomputes their full depth again, dropping what reduce_depth_over_budget took off
+    #[func]
+    pub fn reset_budget_depth_reduction(&mut self) {
+        self.budget_depth_reduction = 0;
+    }
+
+    /// Cast every pass into the pass cache and union them into the visibility. Only the passes
+    /// that an occluder edit may have changed since they were cached are re-run, side by side
+    /// one layer at a time so that max_work_items cuts off the furthest layers first
+    fn cast_cached_passes(&mut self, settings: PassSettings) -> LayeredCast {
+        let time = Time::singleton();
+        let size = self.occluded.size();
+        self.pass_cache.retarget(self.origin, settings);
+        let passes: Vec<Option<Pass>> = match settings.view_cone {
+            Some(cone) => cone.clip_passes(),
+            None => all_passes().map(Some).collect(),
+        };
+        let dirty: Vec<usize> = (0..PASS_COUNT)
+            .filter(|&pass| !self.pass_cache.is_clean(pass))
+            .collect();
+        self.last_cached_passes = PASS_COUNT - dirty.len();
+
+        let grid = self.occluded.grid();
+        let occluded = sight_source(&self.occlusion_source, &self.channels, &grid);
+        // Only known for the grid, not for sources of the game's own
+        let exposed = (self.cull_buried_occluders && self.occlusion_source.is_none()).then(|| {
+            self.exposed
+                .update(self.channels.sight_grid(&grid), &self.one_way)
+        });
+        // Passes looking entirely outside the view cone are cleared but not cast
+        let cast: Vec<usize> = dirty
+            .iter()
+            .copied()
+            .filter(|&pass| passes[pass].is_some())
+            .collect();
+        let mut casters: Vec<Caster> = self
+            .pass_cache
+            .start_passes(&dirty, size)
+            .into_iter()
+            .zip(&dirty)
+            .filter(|&(_, &pass)| passes[pass].is_some())
+            .map(|(cached, _)| Caster {
+                occluded,
+                visible: &mut cached.visible,
+                origin: self.origin,
+                jitter: settings.eye_jitter,
+                max_depth: settings.max_depth,
+                lod: settings.lod,
+                corner_rule: settings.corner_rule,
+                debug_rects: Some(&mut cached.debug_rects),
+                lit_rects: settings.capture_lit_volume.then_some(&mut cached.lit_rects),
+                fractions: settings.track_fractions.then_some(&mut cached.fractions),
+                bounds: None,
+                one_way: Some(&self.one_way),
+                blockers: None,
+                terrain: self.terrain.as_ref(),
+                exposed,
+                rects: RectLimit::new(settings.max_rects, settings.narrow),
+            })
+            .collect();
+        let cast_passes: Vec<Pass> = cast.iter().filter_map(|&pass| passes[pass]).collect();
+        match self.capture_trace {
+            true => self.last_trace.clear(),
+            false => self.last_trace = Vec::new(),
+        }
+        let outcome = cast_layered(
+            &mut casters,
+            &cast_passes,
+            self.max_work_items.max(0) as usize,
+            || time.get_ticks_usec(),
+            self.capture_trace.then_some(&mut self.last_trace),
+        );
+        for item in &mut self.last_trace {
+            item.pass = cast[item.pass];
+        }
+        self.last_max_rects = casters
+            .iter()
+            .map(|caster| caster.rects.most_seen)
+            .max()
+            .unwrap_or(0);
+        self.last_rect_merges = casters.iter().map(|caster| caster.rects.merged_views).sum();
+        drop(casters);
+
+        self.last_pass_usec = vec![0; PASS_COUNT];
+        self.last_pass_work_items = vec![0; PASS_COUNT];
+        for (i, &pass) in cast.iter().enumerate() {
+            self.last_pass_usec[pass] = outcome.pass_usec[i];
+            self.last_pass_work_items[pass] = outcome.pass_work_items[i];
+        }
+        // Cut short results are not worth keeping, the next recompute tries them again
+        if outcome.truncated {
+            for &pass in &dirty {
+                self.pass_cache.invalidate_pass(pass);
+            }
+        }
+
+        for cached in self.pass_cache.passes() {
+            self.visible.union_with(&cached.visible);
+        }
+        outcome
+    }
+
+    /// Which axis is up in the positions scripts pass in and get back
+    pub fn up_axis(&self) -> UpAxis {
+        self.up_axis
+    }
+
+    /// The input log, while one is being recorded
+    fn recording_log(&mut self) -> Option<&mut InputLog> {
+        self.input_log.as_mut().filter(|log| log.recording)
+    }
+
+    /// Add a call to the input log, if one is being recorded
+    fn log_call(&mut self, method: &str, args: &[Variant]) {
+        if let Some(log) = self.recording_log() {
+            log.push(method, args);
+        }
+    }
+
+    /// Log the exported properties that changed since they were last logged, as calls to
+    /// "set", if an input log is being recorded
+    fn log_property_changes(&mut self) {
+        if self.recording_log().is_none() {
+            return;
+        }
+        let values: Vec<(StringName, Variant)> = Self::stored_properties()
+            .into_iter()
+            .map(|name| {
+                let value = self.base_mut().get(&name);
+                (name, value)
+            })
+            .collect();
+        let Some(log) = self.recording_log() else {
+            return;
+        };
+        for (name, value) in values {
+            if log.properties.get(name.clone()).as_ref() != Some(&value) {
+                log.push("set", &[name.to_variant(), value.clone()]);
+                log.properties.set(name, value);
+            }
+        }
+    }
+
+    /// Names of the exported properties of Display that saved scenes keep
+    fn stored_properties() -> Vec<StringName> {
+        let storage = PropertyUsageFlags::STORAGE.ord() as i64;
+        ClassDb::singleton()
+            .class_get_property_list_ex(&Self::class_name().to_string_name())
+            .no_inheritance(true)
+            .done()
+            .iter_shared()
+            .filter(|property| {
+                property
+                    .get("usage")
+                    .and_then(|usage| usage.try_to::<i64>().ok())
+                    .is_some_and(|usage| usage & storage != 0)
+            })
+            .filter_map(|property| property.get("name")?.try_to::<StringName>().ok())
+            .collect()
+    }
+
+    /// Drop the cached passes, light and distances that occluders of the default grid changing
+    /// within the inclusive box could affect
+    fn invalidate_occluders(&mut self, min: Vector3i, max: Vector3i) {
+        self.pass_cache.invalidate_box(min, max);
+        self.light_cache.invalidate_box(min, max);
+        self.distance_field.invalidate_box(min, max);
+        self.exposed.invalidate_box(min, max);
+        self.record_grid_change(Some((min, max)));
+    }
+
+    /// Bump the generation of the default grid for an edit within the inclusive box, or
+    /// anywhere for None, after catching up with the edits other nodes sharing it made before
+    fn record_grid_change(&mut self, change: Option<(Vector3i, Vector3i)>) {
+        self.sync_shared_grid();
+        self.grid_generation = self.occluded.record_change(change);
+    }
+
+    /// Catch up with the edits other nodes sharing occlusion_grid made since this node last
+    /// did, dropping what it cached of the cells they touched
+    fn sync_shared_grid(&mut self) {
+        let generation = self.occluded.generation();
+        if generation != self.grid_generation {
+            let changes = self.occluded.changes_since(self.grid_generation);
+            self.grid_generation = generation;
+            self.catch_up_with(changes);
+        }
+    }
+
+    /// Drop what this node cached of the cells that changed in the default grid, given as the
+    /// inclusive box of each edit or None for every cell, and fit the rest to the grid's size
+    fn catch_up_with(&mut self, changes: Option<Vec<Option<(Vector3i, Vector3i)>>>) {
+        let size = self.occluded.size();
+        if self.visible.size() != size {
+            self.reset_buffers(size);
+            let occluded = &self.occluded;
+            self.block_probability
+                .retain(|&index, _| occluded.get(index).is_some());
+            self.emissive
+                .retain(|&index, _| occluded.get(index).is_some());
+            self.resolve_block_probability();
+        }
+        match changes.filter(|changes| changes.iter().all(Option::is_some)) {
+            Some(changes) => {
+                for (min, max) in changes.into_iter().flatten() {
+                    self.pass_cache.invalidate_box(min, max);
+                    self.light_cache.invalidate_box(min, max);
+                    self.distance_field.invalidate_box(min, max);
+                    self.exposed.invalidate_box(min, max);
+                }
+            }
+            None => {
+                self.pass_cache.invalidate_all();
+                self.light_cache.invalidate_all();
+                self.distance_field.invalidate_all();
+                self.exposed.invalidate_all();
+            }
+        }
+        self.occluded_count = self.occluded.count_set();
+        self.channels.refresh(&self.occluded.grid());
+        let occluded = &self.occluded;
+        self.one_way
+            .retain(|&index, _| occluded.get(index).unwrap_or(false));
+    }
+
+    /// Put back the occluded and explored cells from grid_state. A state of another format
+    /// version, or that does not decode, is dropped with a warning, leaving the grid as it was,
+    /// the clean one init() made for a copy or after a reload
+    fn restore_grid_state(&mut self, bytes: &[u8]) {
+        let left_behind = GridState::take_left_behind(bytes);
+        if !left_behind.is_empty() {
+            godot_warn!(
+                "Copied a Display without its {}, which must be set up again on the copy",
+                left_behind.join(", ")
+            );
+        }
+        let state = match GridState::decode(bytes) {
+            Ok(state) => state,
+            Err(error) => {
+                godot_warn!(
+                    "Dropped the cells kept in {}, keeping the grid as it is: {}",
+                    GRID_STATE_PROPERTY,
+                    error
+                );
+                return;
+            }
+        };
+        self.occluded.replace(state.occluded);
+        self.record_grid_change(None);
+        self.catch_up_with(None);
+        self.explored = state.explored;
+    }
+
+    /// Start the per-cell buffers over for a grid of a new size, or a restored one
+    fn reset_buffers(&mut self, size: Index3) {
+        self.visible = BitGrid::new(size);
+        self.visible_snapshot = None;
+        self.effective_visible = BitGrid::new(size);
+        self.explored = BitGrid::new(size);
+        self.visibility_tiers = Array3::zeros((0, 0, 0));
+        self.pass_cache = PassCache::default();
+        self.light_cache = LightCache::default();
+        self.distance_field.invalidate_all();
+        self.exposed.invalidate_all();
+        if self.tags.as_ref().is_some_and(|tags| tags.dim() != size) {
+            self.tags = None;
+        }
+        if self.external_visibility.take().is_some() {
+            godot_warn!("Detached the external visibility buffer, the grid was replaced");
+        }
+    }
+
+    /// Report a cell outside the grid as out_of_bounds asks, returning the error to fail with
+    fn report_out_of_bounds(&self, cell: Vector3i) -> Error {
+        let pos = self.up_axis.from_grid(cell);
+        match self.out_of_bounds {
+            OutOfBounds::ScriptError => godot_script_error!("Out of bounds at position {}", pos),
+            OutOfBounds::Warning => godot_warn!("Out of bounds at position {}", pos),
+            OutOfBounds::Silent => {}
+        }
+        Error::ERR_PARAMETER_RANGE_ERROR
+    }
+
+    /// The grid cell holding a position a script passed in
+    fn cell_of(&self, position: Vector3) -> Vector3i {
+        cell_at(self.up_axis.to_grid(position))
+    }
+
+    /// A cell's position as scripts get it back
+    fn position_of(&self, index: Index3) -> Vector3 {
+        self.up_axis.from_grid(index_to_position(index))
+    }
+
+    /// Every set cell of a grid as scripts get them back, in ascending x, then y, then z order
+    /// of the grid
+    fn positions(&self, grid: &BitGrid) -> PackedVector3Array {
+        let mut positions = PackedVector3Array::new();
+        grid.for_each_set(|index| positions.push(self.position_of(index)));
+        positions
+    }
+
+    /// The part of the inclusive box between two corners a script passed in that is inside
+    /// the grid, and whether anything was cut off
+    fn clip_box(&self, from: Vector3i, to: Vector3i) -> (Option<(Index3, Index3)>, bool) {
+        self.occluded
+            .clip_box(self.up_axis.to_grid(from), self.up_axis.to_grid(to))
+    }
+
+    fn lod(&self) -> Lod {
+        Lod {
+            start_depth: self.lod_start_depth.max(0) as usize,
+            factor: self.lod_factor.max(0) as usize,
+            min_angle: self.min_subtended_angle,
+            angle_cull: self.subtended_angle_cull,
+        }
+    }
+
+    /// Shadowcast from an origin into a new FovResult, leaving this node's own results untouched
+    #[func]
+    pub fn compute_fov(&self, origin: Vector3) -> Option<Gd<FovResult>> {
+        let origin = self.eye_cell(self.up_axis.to_grid(origin));
+        let index = cell_index(origin);
+        if self.occluded.get(index).is_none() {
+            self.report_out_of_bounds(origin);
+            return None;
+        }
+
+        let mut visible = BitGrid::new(self.occluded.size());
+        Caster {
+            occluded: sight_source(
+                &self.occlusion_source,
+                &self.channels,
+                &self.occluded.grid(),
+            ),
+            visible: &mut visible,
+            origin,
+            jitter: self.eye_offset_in_cell().1,
+            max_depth: self.reach(),
+            lod: self.lod(),
+            corner_rule: self.corner_rule,
+            debug_rects: None,
+            lit_rects: None,
+            fractions: None,
+            bounds: None,
+            one_way: Some(&self.one_way),
+            blockers: None,
+            terrain: self.terrain.as_ref(),
+            exposed: None,
+            rects: RectLimit::new(self.max_rects_per_node.max(0) as usize, self.narrow_rects()),
+        }
+        .cast_all();
+        Some(FovResult::new_gd(
+            origin,
+            self.reach(),
+            visible,
+            self.up_axis,
+        ))
+    }
+
+    /// Call `callable` with the position and depth of every cell a recompute from `origin` that
+    /// reaches `max_depth` layers would see, once per cell even where passes overlap, for area
+    /// effects. Cells come nearest first, in order of depth and then in ascending x, then y,
+    /// then z order of the grid within a depth, so effects spreading out from the origin can be
+    /// timed by depth as they arrive. Occluders in view are included, as in
+    /// get_visible_positions(). This node's own results are left untouched, and the callable
+    /// must not edit the node
+    #[func]
+    pub fn apply_in_fov(&self, origin: Vector3, max_depth: i32, callable: Callable) {
+        let origin = self.cell_of(origin);
+        let index = cell_index(origin);
+        if self.occluded.get(index).is_none() {
+            self.report_out_of_bounds(origin);
+            return;
+        }
+        if max_depth < 0 {
+            godot_script_error!("Negative max depth {}", max_depth);
+            return;
+        }
+        if !callable.is_valid() {
+            godot_script_error!("apply_in_fov() needs a valid callable");
+            return;
+        }
+
+        let settings = PassSettings {
+            max_depth: max_depth as usize,
+            ..self.pass_settings()
+        };
+        let mut scratch = BitGrid::new(self.occluded.size());
+        walk_frustum(
+            sight_source(
+                &self.occlusion_source,
+                &self.channels,
+                &self.occluded.grid(),
+            ),
+            &self.one_way,
+            self.terrain.as_ref(),
+            &mut scratch,
+            &settings,
+            origin,
+            |index, depth, _| {
+                callable.call(&[
+                    self.position_of(index).to_variant(),
+                    (depth as i64).to_variant(),
+                ]);
+            },
+        );
+    }
+
+    /// The deepest layer recomputes scan, following range_is_inclusive
+    fn reach(&self) -> usize {
+        match self.range_is_inclusive {
+            true => MAX_DEPTH,
+            false => MAX_DEPTH - 1,
+        }
+    }
+
+    /// The settings recomputes from the eye cast with, pass_settings() with the depth
+    /// reduce_depth_over_budget leaves and the view cone
+    fn eye_settings(&self) -> PassSettings {
+        PassSettings {
+            max_depth: self.reach() - self.budget_depth_reduction,
+            view_cone: ViewCone::new(
+                self.up_axis.to_grid(self.view_cone_direction),
+                self.view_cone_half_angle,
+            ),
+            ..self.pass_settings()
+        }
+    }
+
+    /// The settings set_origin_and_recompute() casts with
+    fn pass_settings(&self) -> PassSettings {
+        PassSettings {
+            max_depth: self.reach(),
+            lod: self.lod(),
+            corner_rule: self.corner_rule,
+            track_fractions: self.track_visibility_fraction,
+            capture_lit_volume: self.capture_lit_volume,
+            max_rects: self.max_rects_per_node.max(0) as usize,
+            narrow: self.narrow_rects(),
+            eye_jitter: self.eye_offset_in_cell().1,
+            view_cone: None,
+        }
+    }
+
+    /// eye_offset in the grid's axes, as the whole cells it moves the eye by and what is left
+    /// of it within the eye's cell
+    fn eye_offset_in_cell(&self) -> (Vector3i, Vector3) {
+        let offset = self.up_axis.to_grid(self.eye_offset);
+        let cells = cell_at(offset);
+        (cells, offset - cells.cast_float())
+    }
+
+    /// The cell recomputes from a position in the grid's axes see from
+    fn eye_cell(&self, origin: Vector3) -> Vector3i {
+        cell_at(origin) + self.eye_offset_in_cell().0
+    }
+
+    /// Where the last recompute saw from, in the grid's axes
+    fn eye(&self) -> Vector3 {
+        self.origin_float + self.up_axis.to_grid(self.eye_offset)
+    }
+
+    fn narrow_rects(&self) -> NarrowRects {
+        NarrowRects {
+            min_width: self.narrow_rect_width,
+            policy: self.narrow_rect_policy,
+        }
+    }
+
+    /// Whether a recompute from `from` would see `to`, casting only as much as needed to tell
+    #[func]
+    pub fn can_see(&self, from: Vector3, to: Vector3) -> bool {
+        let mut scratch = BitGrid::new(self.occluded.size());
+        visible_targets(
+            sight_source(
+                &self.occlusion_source,
+                &self.channels,
+                &self.occluded.grid(),
+            ),
+            &self.one_way,
+            self.terrain.as_ref(),
+            &mut scratch,
+            &self.pass_settings(),
+            self.eye_cell(self.up_axis.to_grid(from)),
+            &[self.cell_of(to)],
+        )[0]
+    }
+
+    /// Whether a recompute from `from` would see any cell of the inclusive box between two
+    /// corners, such as part of a creature bigger than a cell. Agrees with can_see() on every
+    /// cell of the box, but stops casting at the first pass that sees into it
+    #[func]
+    pub fn can_see_box(&self, from: Vector3, box_from: Vector3i, box_to: Vector3i) -> bool {
+        self.visible_in_box(from, box_from, box_to).is_some()
+    }
+
+    /// can_see_box() as a Dictionary with "visible", and when it is true "cell", a cell of the
+    /// box that would be seen
+    #[func]
+    pub fn get_visible_cell_in_box(
+        &self,
+        from: Vector3,
+        box_from: Vector3i,
+        box_to: Vector3i,
+    ) -> Dictionary {
+        let mut result = Dictionary::new();
+        let cell = self.visible_in_box(from, box_from, box_to);
+        result.set("visible", cell.is_some());
+        if let Some(cell) = cell {
+            result.set("cell", self.up_axis.from_grid(cell));
+        }
+        result
+    }
+
+    fn visible_in_box(
+        &self,
+        from: Vector3,
+        box_from: Vector3i,
+        box_to: Vector3i,
+    ) -> Option<Vector3i> {
+        let box_from = self.up_axis.to_grid(box_from);
+        let box_to = self.up_axis.to_grid(box_to);
+        let mut scratch = BitGrid::new(self.occluded.size());
+        visible_in_box(
+            sight_source(
+                &self.occlusion_source,
+                &self.channels,
+                &self.occluded.grid(),
+            ),
+            &self.one_way,
+            self.terrain.as_ref(),
+            &mut scratch,
+            &self.pass_settings(),
+            self.eye_cell(self.up_axis.to_grid(from)),
+            box_from.coord_min(box_to),
+            box_from.coord_max(box_to),
+        )
+    }
+
+    /// can_see() from each of `froms` to `to`, as one 0 or 1 byte per entry of `froms`
+    #[func]
+    pub fn batch_can_see(&self, froms: PackedVector3Array, to: Vector3) -> PackedByteArray {
+        let settings = self.pass_settings();
+        let mut scratch = BitGrid::new(self.occluded.size());
+        let results: Vec<u8> = froms
+            .as_slice()
+            .iter()
+            .map(|from| {
+                let seen = visible_targets(
+                    sight_source(
+                        &self.occlusion_source,
+                        &self.channels,
+                        &self.occluded.grid(),
+                    ),
+                    &self.one_way,
+                    self.terrain.as_ref(),
+                    &mut scratch,
+                    &settings,
+                    self.eye_cell(self.up_axis.to_grid(*from)),
+                    &[self.cell_of(to)],
+                );
+                seen[0] as u8
+            })
+            .collect();
+        PackedByteArray::from(results.as_slice())
+    }
+
+    /// can_see() from `from` to each of `tos`, as one 0 or 1 byte per entry of `tos`.
+    /// All targets share a single partial cast
+    #[func]
+    pub fn batch_can_see_many(&self, from: Vector3, tos: PackedVector3Array) -> PackedByteArray {
+        let targets: Vec<Vector3i> = tos.as_slice().iter().map(|&to| self.cell_of(to)).collect();
+        let mut scratch = BitGrid::new(self.occluded.size());
+        let results: Vec<u8> = visible_targets(
+            sight_source(
+                &self.occlusion_source,
+                &self.channels,
+                &self.occluded.grid(),
+            ),
+            &self.one_way,
+            self.terrain.as_ref(),
+            &mut scratch,
+            &self.pass_settings(),
+            self.eye_cell(self.up_axis.to_grid(from)),
+            &targets,
+        )
+        .into_iter()
+        .map(u8::from)
+        .collect();
+        PackedByteArray::from(results.as_slice())
+    }
+
+    /// The cells the straight segment from `from` to `to` passes through, in order, for drawing
+    /// the path of a throw or shot. Each is a Dictionary with "cell", "visible" (seen by the
+    /// last recompute) and "occluded" (blocks the path). Where the path passes exactly through
+    /// an edge or corner, the cells around it that it only touches are listed too, and block it
+    /// only with CORNER_BLOCK. One-way occluders let it through from their open side, and the
+    /// cell it starts in never blocks it. This follows the segment itself rather than the cast,
+    /// so at shadow edges a cell can be visible behind a blocked path or the other way around
+    #[func]
+    pub fn trace_visible_path(&self, from: Vector3, to: Vector3) -> VariantArray {
+        let path_cells = supercover(self.up_axis.to_grid(from), self.up_axis.to_grid(to));
+        let start = path_cells[0].cell;
+        let grid = self.occluded.grid();
+        let occluded = sight_source(&self.occlusion_source, &self.channels, &grid);
+        let blocks = |cell: Vector3i, grazed: bool| {
+            let index = cell_index(cell);
+            let occludes = occluded.is_occluded_at(index)
+                || self
+                    .terrain
+                    .as_ref()
+                    .is_some_and(|terrain| terrain.occludes(index));
+            let sees_through = self.one_way.get(&index).is_some_and(|open_direction| {
+                let delta = cell - start;
+                delta.x * open_direction.x + delta.y * open_direction.y + delta.z * open_direction.z
+                    > 0
+            });
+            occludes
+                && !sees_through
+                && cell != start
+                && (!grazed || self.corner_rule == CornerRule::Block)
+        };
+
+        let mut path = VariantArray::new();
+        for path_cell in path_cells {
+            let blocked = blocks(path_cell.cell, path_cell.grazed);
+            let mut entry = Dictionary::new();
+            entry.set("cell", self.up_axis.from_grid(path_cell.cell));
+            entry.set(
+                "visible",
+                self.visible
+                    .get(cell_index(path_cell.cell))
+                    .unwrap_or(false),
+            );
+            entry.set("occluded", blocked);
+            path.push(&entry.to_variant());
+            if blocked && self.stop_path_at_occluder {
+                break;
+            }
+        }
+        path
+    }
+
+    /// How far sight goes from the origin of the last recompute along each direction, such as
+    /// for telling the size of a room: the distance to where the ray enters the first cell that
+    /// blocks sight or was not seen, capped at the recompute's range. Directions are normalized,
+    /// and read -1 if the ray leaves the grid first or 0 if they are zero. Nothing is cast,
+    /// the rays only read the last recompute's visibility
+    #[func]
+    pub fn sample_visible_distances(&self, directions: PackedVector3Array) -> PackedFloat32Array {
+        let grid = self.occluded.grid();
+        let occluded = sight_source(&self.occlusion_source, &self.channels, &grid);
+        let range = self.reach() as f64;
+        directions
+            .as_slice()
+            .iter()
+            .map(|&direction| {
+                let direction = self.up_axis.to_grid(direction);
+                if direction == Vector3::ZERO {
+                    return 0.0;
+                }
+                let hit = march_ray(self.eye(), direction, range, |cell| {
+                    let index = cell_index(cell);
+                    self.visible.get(index) != Some(true)
+                        || occluded.is_occluded_at(index)
+                        || self
+                            .terrain
+                            .as_ref()
+                            .is_some_and(|terrain| terrain.occludes(index))
+                });
+                match hit {
+                    Some((cell, _)) if self.visible.get(cell_index(cell)).is_none() => -1.0,
+                    Some((_, distance)) => distance as f32,
+                    None => range as f32,
+                }
+            })
+            .collect()
+    }
+
+    /// Bytes allocated for each of the node's buffers, by name, and their sum as "total".
+    /// Buffers are allocated on first use, so a node that was never recomputed, lit or edited
+    /// holds almost nothing, and optional ones are freed once their features are turned off
+    #[func]
+    pub fn get_memory_usage(&self) -> Dictionary {
+        let array = |array: &Array3<f32>| array.len() * size_of::<f32>();
+        let buffers = [
+            ("occluded", self.occluded.memory_bytes()),
+            ("channels", self.channels.memory_bytes()),
+            (
+                "change_base",
+                self.change_base.as_ref().map_or(0, BitGrid::memory_bytes),
+            ),
+            (
+                "one_way",
+                self.one_way.len() * size_of::<(Index3, Vector3i)>(),
+            ),
+            (
+                "tags",
+                self.tags
+                    .as_ref()
+                    .map_or(0, |tags| tags.len() * size_of::<u16>()),
+            ),
+            (
+                "terrain",
+                self.terrain.as_ref().map_or(0, Terrain::memory_bytes),
+            ),
+            ("visible", self.visible.memory_bytes()),
+            (
+                "visible_snapshot",
+                self.visible_snapshot
+                    .as_deref()
+                    .map_or(0, BitGrid::memory_bytes),
+            ),
+            ("visibility_fraction", array(&self.visibility_fraction)),
+            ("visibility_tiers", array(&self.visibility_tiers)),
+            ("effective_visible", self.effective_visible.memory_bytes()),
+            ("explored", self.explored.memory_bytes()),
+            ("flood_scratch", self.flood_scratch.memory_bytes()),
+            ("propagation", array(&self.propagation)),
+            ("light_level", self.light_level.len() * size_of::<real>()),
+            (
+                "light_color",
+                self.light_color.len() * size_of::<[real; 3]>(),
+            ),
+            ("pass_cache", self.pass_cache.memory_bytes()),
+            ("light_cache", self.light_cache.memory_bytes()),
+            ("distance_field", self.distance_field.memory_bytes()),
+            ("exposed", self.exposed.memory_bytes()),
+            (
+                "sliced_recompute",
+                self.sliced_recompute
+                    .as_ref()
+                    .map_or(0, |sliced| sliced.visible.memory_bytes()),
+            ),
+            ("dirty_chunks", self.dirty_chunks.memory_bytes()),
+            ("checkpoints", self.checkpoints.memory_bytes()),
+            (
+                "views",
+                self.views
+                    .iter()
+                    .map(|view| view.visible.memory_bytes())
+                    .sum::<usize>(),
+            ),
+            (
+                "external_visibility",
+                self.external_visibility
+                    .as_ref()
+                    .map_or(0, |buffer| buffer.len()),
+            ),
+        ];
+        let mut usage = Dictionary::new();
+        for (name, bytes) in buffers {
+            usage.set(name, bytes as i64);
+        }
+        let total: usize = buffers.iter().map(|(_, bytes)| bytes).sum();
+        usage.set("total", total as i64);
+        usage
+    }
+
+    /// What this build of the extension supports, for scripts to check before calling methods
+    /// a build may lack: "version" (semantic version), "features" (the Cargo features it was
+    /// compiled with), "double_precision" (whether it is built for double precision Godot),
+    /// "max_grid_size" (largest grid size along each axis) and "max_depth" (deepest layer a
+    /// recompute reaches)
+    #[func]
+    pub fn get_capabilities() -> Dictionary {
+        let double_precision = cfg!(feature = "double-precision");
+        let mut features = PackedStringArray::new();
+        if double_precision {
+            features.push("double-precision");
+        }
+        let mut capabilities = Dictionary::new();
+        capabilities.set("version", EXTENSION_VERSION);
+        capabilities.set("features", features);
+        capabilities.set("double_precision", double_precision);
+        capabilities.set("max_grid_size", Vector3i::splat(i32::MAX));
+        capabilities.set("max_depth", MAX_DEPTH as i64);
+        capabilities
+    }
+
+    /// Timings of the last set_origin_and_recompute(), in microseconds:
+    /// "total_usec" for all passes, and "pass_usec" for each of the 24 passes in order
+    /// (0 for passes reused from the cache). "cached_passes" and "recomputed_passes" count
+    /// the passes that were reused and re-run, "work_items" the views scanned, "pass_work_items"
+    /// those of each pass in order, and "truncated" whether max_work_items cut the recompute
+    /// short. "max_rects_per_node" is the most unblocked pieces any re-run view split into and
+    /// "rect_merges" how many views max_rects_per_node merged pieces of. "visible_cells" counts
+    /// the cells the recompute saw, and "version" is the extension's version. After
+    /// recompute_with_peek(), "peeks" holds a Dictionary per sub-origin, the eye's first, of
+    /// "offset" as given, "skipped", "visible_cells" it saw, "added_cells" no earlier one saw
+    /// and "usec", and is empty after other recomputes. "ray_cast" is whether the eye's cells
+    /// were tested with rays under ray_cast_max_depth rather than shadowcast, and "rays" how
+    /// many rays that traced. "signal_usec" is the time taken to emit the signals the
+    /// recompute held back, handlers included
+    #[func]
+    pub fn get_last_recompute_stats(&self) -> Dictionary {
+        let mut stats = Dictionary::new();
+        stats.set("version", EXTENSION_VERSION);
+        stats.set("total_usec", self.last_recompute_usec as i64);
+        stats.set("cached_passes", self.last_cached_passes as i64);
+        let recomputed_passes = match self.last_cast_path {
+            CastPath::Cached => PASS_COUNT - self.last_cached_passes,
+            _ => 0,
+        };
+        stats.set("recomputed_passes", recomputed_passes as i64);
+        let pass_usec: PackedInt64Array = self
+            .last_pass_usec
+            .iter()
+            .map(|&usec| usec as i64)
+            .collect();
+        stats.set("pass_usec", pass_usec);
+        stats.set("work_items", self.last_work_items as i64);
+        let pass_work_items: PackedInt64Array = self
+            .last_pass_work_items
+            .iter()
+            .map(|&work_items| work_items as i64)
+            .collect();
+        stats.set("pass_work_items", pass_work_items);
+        stats.set("truncated", self.last_truncated);
+        stats.set("max_rects_per_node", self.last_max_rects as i64);
+        stats.set("rect_merges", self.last_rect_merges as i64);
+        stats.set("visible_cells", self.visible.count_set() as i64);
+        stats.set("peeks", self.last_peeks.clone());
+        stats.set("ray_cast", self.last_cast_path == CastPath::Rays);
+        stats.set("rays", self.last_rays as i64);
+        stats.set("signal_usec", self.last_signal_usec as i64);
+        stats
+    }
+
+    /// Add a Performance monitor for each of MONITORS, for performance_monitors
+    fn add_performance_monitors(&mut self) {
+        if !self.performance_monitors || Engine::singleton().is_editor_hint() {
+            return;
+        }
+        let mut performance = Performance::singleton();
+        let instance_id = self.base().instance_id().to_i64();
+        for (monitor, name) in MONITORS.iter().enumerate() {
+            let mut id = StringName::from(format!("shadowcast/{}", name).as_str());
+            if performance.has_custom_monitor(&id) {
+                id = StringName::from(format!("shadowcast/{}_{}", name, instance_id).as_str());
+            }
+            let display = self.to_gd();
+            let callable = Callable::from_local_fn(*name, move |_| {
+                Ok(display.bind().monitor_value(monitor).to_variant())
+            });
+            performance.add_custom_monitor(&id, &callable);
+            self.monitor_ids.push(id);
+        }
+    }
+
+    fn remove_performance_monitors(&mut self) {
+        let mut performance = Performance::singleton();
+        for id in self.monitor_ids.drain(..) {
+            if performance.has_custom_monitor(&id) {
+                performance.remove_custom_monitor(&id);
+            }
+        }
+    }
+
+    /// The value of MONITORS[monitor] as of the last recompute
+    fn monitor_value(&self, monitor: usize) -> i64 {
+        match monitor {
+            0 => self.last_recompute_usec as i64,
+            1 => self.last_visible_cells as i64,
+            2 => self.last_work_items as i64,
+            _ => self.last_cached_passes as i64,
+        }
+    }
+
+    /// Write every view the last recompute scanned to a JSON file, for profiling outside Godot.
+    /// Needs capture_trace set during the recompute. The file holds an "items" array, in scan
+    /// order, of objects with "pass" (index into the 24 passes), "parent" (index of the item
+    /// this view was cut from, null at depth 1), "depth", "slope_rect" and "view_rect" (each
+    /// [sx, sy, ex, ey], with infinite slopes as "inf" or "-inf"), "occluders", "children" (the
+    /// pieces queued for the next depth) and "elapsed_nsec".
+    /// Returns false with an error if the file could not be written
+    #[func]
+    pub fn dump_last_recompute_trace(&self, path: GString) -> bool {
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+            godot_script_error!("Could not open {} for writing", path);
+            return false;
+        };
+        file.store_string(&trace_json(&self.last_trace));
+        file.close();
+        true
+    }
+
+    /// The light the last recompute let through, for building volumes such as god rays from.
+    /// Needs capture_lit_volume set during the recompute. Returns an Array with a Dictionary
+    /// per view light got through: "plane" (as in cast_custom()), "reverse_z", "depth" along
+    /// the pass, and "quads", an Array with a PackedVector3Array of 4 world space corners for
+    /// each unblocked piece of the view, on the face of its layer nearest the origin. Pieces
+    /// merged by max_rects_per_node come as their one bounding quad. There is an entry per view
+    /// scanned at most, so max_work_items bounds how many there are
+    #[func]
+    pub fn get_lit_volume_quads(&self) -> VariantArray {
+        let mut items = VariantArray::new();
+        if self.last_cast_path != CastPath::Cached {
+            return items;
+        }
+        let transform = self.grid_to_world();
+        for lit in self
+            .pass_cache
+            .passes()
+            .flat_map(|cached| &cached.lit_rects)
+        {
+            let corner = |x: real, y: real| {
+                transform * lit.plane.to_grid_float(Vector3::new(x, y, lit.face))
+            };
+            let quads: VariantArray = lit
+                .rects
+                .iter()
+                .map(|rect| {
+                    let corners = [
+                        corner(rect.sx, rect.sy),
+                        corner(rect.ex, rect.sy),
+                        corner(rect.ex, rect.ey),
+                        corner(rect.sx, rect.ey),
+                    ];
+                    PackedVector3Array::from(corners.as_slice()).to_variant()
+                })
+                .collect();
+            let mut item = Dictionary::new();
+            item.set("plane", self.plane_number(lit.plane));
+            item.set("reverse_z", lit.reverse_z);
+            item.set("depth", lit.depth as i64);
+            item.set("quads", quads);
+            items.push(&item.to_variant());
+        }
+        items
+    }
+
+    /// A plane in the grid's axes as cast_custom() numbers it in the script's
+    fn plane_number(&self, plane: UnitPlane3d) -> i64 {
+        match self.up_axis.from_grid(plane) {
+            UnitPlane3d::XY => 0,
+            UnitPlane3d::ZY => 1,
+            UnitPlane3d::ZX => 2,
+        }
+    }
+
+    /// Start recording every call that edits the grid, terrain, channels, tags or lights, and
+    /// every recompute along with a hash of what it saw, to reproduce a bug with
+    /// replay_input_log(). Exported properties are recorded as they are at each recompute.
+    /// The log starts from the grid and settings as they are now. What capture_state() does not
+    /// save, such as views and portals, is left out with a warning. Starting again drops the
+    /// log recorded so far
+    #[func]
+    pub fn start_input_log(&mut self) {
+        let left_behind = self.uncaptured_registrations();
+        if !left_behind.is_empty() {
+            godot_warn!(
+                "Recording an input log without the Display's {}, which replays will not have",
+                left_behind.join(", ")
+            );
+        }
+        let mut log = InputLog::default();
+        log.push("restore_state", &[self.capture_state().to_variant()]);
+        self.input_log = Some(log);
+        self.log_property_changes();
+    }
+
+    /// Stop recording, keeping the log for save_input_log()
+    #[func]
+    pub fn stop_input_log(&mut self) {
+        if let Some(log) = self.input_log.as_mut() {
+            log.recording = false;
+        }
+    }
+
+    /// Write the input log recorded since start_input_log() to a file, to attach to a bug report.
+    /// Returns false with an error if nothing was recorded or the file could not be written
+    #[func]
+    pub fn save_input_log(&self, path: GString) -> bool {
+        let Some(log) = &self.input_log else {
+            godot_script_error!("No input log was recorded, see start_input_log()");
+            return false;
+        };
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+            godot_script_error!("Could not open {} for writing", path);
+            return false;
+        };
+        file.store_var_ex(&log.to_variant())
+            .full_objects(true)
+            .done();
+        file.close();
+        true
+    }
+
+    /// Repeat the calls of a log written by save_input_log() on this Display, starting from the
+    /// grid and settings it was recorded from. With `verify_hashes`, what every recompute sees
+    /// is checked against the recording, stopping with ERR_INVALID_DATA at the first that
+    /// differs. Logs hold resources, so only replay logs from a source you trust
+    #[func]
+    pub fn replay_input_log(&mut self, path: GString, verify_hashes: bool) -> Error {
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::READ) else {
+            godot_script_error!("Could not open {} for reading", path);
+            return Error::ERR_FILE_CANT_OPEN;
+        };
+        let log = file.get_var_ex().allow_objects(true).done();
+        file.close();
+        let calls = match decode_calls(&log, &Self::stored_properties()) {
+            Ok(calls) => calls,
+            Err(error) => return error.report(),
+        };
+
+        // The replayed calls are not recorded again
+        let recording = self.input_log.take();
+        let mut hash = HASH_START;
+        let mut outcome = Error::OK;
+        for (i, call) in calls.iter().enumerate() {
+            self.base_mut().callv(&call.method, &call.args);
+            let Some(expected) = call.hash else {
+                continue;
+            };
+            hash = roll_hash(hash, &self.visible);
+            if verify_hashes && hash != expected {
+                godot_script_error!(
+                    "Replay differs from the recording after call {} to {}",
+                    i,
+                    call.method
+                );
+                outcome = Error::ERR_INVALID_DATA;
+                break;
+            }
+        }
+        self.input_log = recording;
+        outcome
+    }
+
+    /// Whether a cell was seen from the origin by the last recompute
+    #[func]
+    pub fn is_visible(&self, pos: Vector3i) -> bool {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.visible.get(index).unwrap_or(false)
+    }
+
+    /// Diagnose why a cell is or is not visible from the origin of the last recompute, by casting
+    /// every pass that holds part of the cell again, narrowed to just the cell's face.
+    /// Returns a Dictionary with:
+    /// - "verdict": "visible", "outside_grid", "out_of_range" (further than max depth),
+    ///   "occluded" (occluders cover the whole face) or "reachable" (the narrowed cast reaches it,
+    ///   so the recompute missed it due to how it split its view, max_work_items or portals)
+    /// - "depth": the cell's distance from the origin along its furthest axis
+    /// - "blockers": the occluded cells that cut into the narrowed views
+    /// - "passes": an Array with one Dictionary per pass holding part of the cell:
+    ///   "pass" (index in cast order), "quadrant", "plane" (as in cast_custom()), "reverse_z",
+    ///   "depth" along the pass, "view_rects" (the view left at the cell's layer, as Rect2s in
+    ///   the plane's own axes), "reached" and "blockers".
+    /// The narrowed views and the occluders cutting into them are drawn as debug lines
+    #[func]
+    pub fn explain_visibility(&mut self, target: Vector3i) -> Dictionary {
+        let target = self.up_axis.to_grid(target);
+        let index = cell_index(target);
+        let explanation = explain_cell(
+            sight_source(
+                &self.occlusion_source,
+                &self.channels,
+                &self.occluded.grid(),
+            ),
+            self.occluded.size(),
+            &self.one_way,
+            self.terrain.as_ref(),
+            &self.pass_settings(),
+            self.origin,
+            target,
+            self.visible.get(index).unwrap_or(false),
+        );
+
+        let mut passes = VariantArray::new();
+        let mut all_blockers = BitGrid::new(self.occluded.size());
+        for trace in &explanation.passes {
+            let view_rects: Array<Rect2> = trace
+                .view_rects
+                .iter()
+                .map(|rect| {
+                    self.up_axis.from_grid(Rect2::new(
+                        Vector2::new(rect.sx, rect.sy),
+                        Vector2::new(rect.ex - rect.sx, rect.ey - rect.sy),
+                    ))
+                })
+                .collect();
+            let mut blockers = PackedVector3Array::new();
+            for &blocker in &trace.blockers {
+                blockers.push(self.position_of(blocker));
+                all_blockers.set(blocker, true);
+            }
+            let mut pass = Dictionary::new();
+            pass.set("pass", trace.pass as i64);
+            pass.set("quadrant", (trace.pass / 6) as i64);
+            pass.set("plane", self.plane_number(trace.plane));
+            pass.set("reverse_z", trace.reverse_z);
+            pass.set("depth", trace.depth as i64);
+            pass.set("view_rects", view_rects);
+            pass.set("reached", trace.reached);
+            pass.set("blockers", blockers);
+            passes.push(&pass.to_variant());
+
+            for debug_rect in &trace.debug_rects {
+                self.draw_debug_rect(debug_rect);
+            }
+        }
+
+        let mut result = Dictionary::new();
+        result.set("verdict", explanation.verdict.name());
+        result.set("depth", explanation.depth as i64);
+        result.set("blockers", self.positions(&all_blockers));
+        result.set("passes", passes);
+        result
+    }
+
+    /// The size of a cell along the grid's axes, from cell_size
+    fn grid_cell_size(&self) -> Vector3 {
+        let size = self.up_axis.to_grid(self.cell_size);
+        let axis = |length: real| if length > 0.0 { length } else { 1.0 };
+        Vector3::new(axis(size.x), axis(size.y), axis(size.z))
+    }
+
+    /// From the grid's space, where cells are one unit wide and centered on whole coordinates,
+    /// to world space
+    fn grid_to_world(&self) -> Transform3D {
+        self.base().get_global_transform()
+            * Transform3D::new(Basis::from_scale(self.grid_cell_size()), Vector3::ZERO)
+    }
+
+    /// The cell holding a point in world space. The grid lies in this node's own space with
+    /// cell_size cells centered on whole multiples of it, so the node's position is the grid's
+    /// offset. The cell may be outside the grid
+    #[func]
+    pub fn world_to_cell(&self, world: Vector3) -> Vector3i {
+        let cell = cell_at(self.grid_to_world().affine_inverse() * world);
+        self.up_axis.from_grid(cell)
+    }
+
+    /// The center of a cell in world space, the inverse of world_to_cell()
+    #[func]
+    pub fn cell_to_world_center(&self, cell: Vector3i) -> Vector3 {
+        self.grid_to_world() * self.up_axis.to_grid(cell).cast_float()
+    }
+
+    /// The smallest world space box holding a cell, which is the cell itself unless the node
+    /// is rotated
+    #[func]
+    pub fn cell_to_world_aabb(&self, cell: Vector3i) -> Aabb {
+        let transform = self.grid_to_world();
+        let center = self.up_axis.to_grid(cell).cast_float();
+        let corners = [-0.5, 0.5].into_iter().flat_map(|dx| {
+            [-0.5, 0.5].into_iter().flat_map(move |dy| {
+                [-0.5, 0.5]
+                    .into_iter()
+                    .map(move |dz| transform * (center + Vector3::new(dx, dy, dz)))
+            })
+        });
+        let (min, max) = corners.fold(
+            (
+                Vector3::splat(real::INFINITY),
+                Vector3::splat(real::NEG_INFINITY),
+            ),
+            |(min, max), corner| (min.coord_min(corner), max.coord_max(corner)),
+        );
+        Aabb::new(min, max - min)
+    }
+
+    /// The layer (largest distance along any axis) at which a cell is first reached from the
+    /// origin. Every pass steps one layer at a time along its axis, so this is the depth the
+    /// cast first gets to the cell at
+    fn ring_of(&self, index: Index3) -> usize {
+        let delta = index_cell(index) - self.origin;
+        let delta = delta.abs();
+        delta.x.max(delta.y).max(delta.z) as usize
+    }
+
+    /// The depth at which a cell was first reached by the last recompute, -1 if it is not visible
+    #[func]
+    pub fn get_voxel_depth(&self, pos: Vector3i) -> i32 {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        match self.visible.get(index) {
+            Some(true) => self.ring_of(index) as i32,
+            _ => -1,
+        }
+    }
+
+    /// Every cell seen by the last recompute, as one PackedVector3Array per depth,
+    /// starting with the origin at depth 0. Iterate it backwards to go back to front
+    #[func]
+    pub fn get_visible_sorted_by_depth(&self) -> VariantArray {
+        let mut rings: Vec<PackedVector3Array> = Vec::new();
+        self.visible.for_each_set(|index| {
+            let ring = self.ring_of(index);
+            if rings.len() <= ring {
+                rings.resize_with(ring + 1, PackedVector3Array::new);
+            }
+            rings[ring].push(self.position_of(index));
+        });
+
+        let mut array = VariantArray::new();
+        for ring in rings {
+            array.push(&ring.to_variant());
+        }
+        array
+    }
+
+    /// How much of a cell's face was in view from the origin in the last recompute, from 0 to 1,
+    /// taking the best of the passes that reached it. Only tracked when track_visibility_fraction
+    /// is set, otherwise this is 1 for visible cells and 0 for the rest
+    #[func]
+    pub fn get_visibility_fraction(&self, pos: Vector3i) -> f32 {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        if !self.track_visibility_fraction {
+            return match self.visible.get(index) {
+                Some(true) => 1.0,
+                _ => 0.0,
+            };
+        }
+        self.visibility_fraction.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// How well the last recompute made out a cell: TIER_FULL for visible cells within
+    /// detail_radius of the origin, TIER_SILHOUETTE for visible occluded cells past it and
+    /// TIER_UNSEEN for the rest. Without detail_radius every visible cell is TIER_FULL
+    #[func]
+    pub fn get_visibility_tier(&self, pos: Vector3i) -> i64 {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.tier_of(index) as i64
+    }
+
+    /// Every cell for which get_visibility_tier() is `tier`, which must not be TIER_UNSEEN
+    #[func]
+    pub fn get_cells_with_tier(&self, tier: i64) -> PackedVector3Array {
+        if !(Self::TIER_SILHOUETTE..=Self::TIER_FULL).contains(&tier) {
+            godot_script_error!("Tier {} is not TIER_SILHOUETTE or TIER_FULL", tier);
+            return PackedVector3Array::new();
+        }
+        let mut positions = PackedVector3Array::new();
+        self.visible.for_each_set(|index| {
+            if self.tier_of(index) as i64 == tier {
+                positions.push(self.position_of(index));
+            }
+        });
+        positions
+    }
+
+    fn tier_of(&self, index: Index3) -> u8 {
+        if self.detail_radius <= 0.0 {
+            return match self.visible.get(index) {
+                Some(true) => Self::TIER_FULL as u8,
+                _ => Self::TIER_UNSEEN as u8,
+            };
+        }
+        self.visibility_tiers.get(index).copied().unwrap_or(0)
+    }
+
+    /// Sort the visible cells into tiers by their distance from the origin, for detail_radius,
+    /// and signal the cells whose tier changed
+    fn update_visibility_tiers(&mut self) {
+        if self.detail_radius <= 0.0 {
+            self.visibility_tiers = Array3::zeros((0, 0, 0));
+            return;
+        }
+        let size = self.visible.size();
+        let grid = self.occluded.grid();
+        let occluded = sight_source(&self.occlusion_source, &self.channels, &grid);
+        let terrain = self.terrain.as_ref();
+        let mut tiers = Array3::zeros(size);
+        self.visible.for_each_set(|index| {
+            let distance = index_to_position(index).distance_to(self.eye());
+            tiers[index] = if distance <= self.detail_radius {
+                Self::TIER_FULL as u8
+            } else if occluded.is_occluded_at(index)
+                || terrain.is_some_and(|terrain| terrain.occludes(index))
+            {
+                Self::TIER_SILHOUETTE as u8
+            } else {
+                Self::TIER_UNSEEN as u8
+            };
+        });
+        drop(grid);
+        let mut previous = std::mem::replace(&mut self.visibility_tiers, tiers);
+        if previous.dim() != size {
+            previous = Array3::zeros(size);
+        }
+
+        let mut cells = PackedVector3Array::new();
+        let mut changed = PackedInt32Array::new();
+        for (index, &tier) in self.visibility_tiers.indexed_iter() {
+            if previous[index] != tier {
+                cells.push(self.position_of(index));
+                changed.push(tier as i32);
+            }
+        }
+        if !cells.is_empty() {
+            self.emit_deferred(
+                "visibility_tier_changed",
+                vec![cells.to_variant(), changed.to_variant()],
+            );
+        }
+    }
+
+    /// Combine the per-pass fractions of the pass cache, keeping the maximum per cell
+    fn update_visibility_fraction(&mut self, origin_index: Index3, untracked: bool) {
+        let size = self.visible.size();
+        if self.visibility_fraction.dim() != size {
+            self.visibility_fraction = Array3::zeros(size);
+        } else {
+            self.visibility_fraction.fill(0.0);
+        }
+
+        // Casting through portals, floor separators or with rays does not track fractions, so visible cells are
+        // fully visible
+        if untracked {
+            let fractions = &mut self.visibility_fraction;
+            self.visible.for_each_set(|index| fractions[index] = 1.0);
+            return;
+        }
+
+        for cached in self.pass_cache.passes() {
+            for (&index, &fraction) in &cached.fractions {
+                if self.visible.get(index) != Some(true) {
+                    continue;
+                }
+                let best = &mut self.visibility_fraction[index];
+                *best = best.max(fraction.min(1.0));
+            }
+        }
+        self.visibility_fraction[origin_index] = 1.0;
+    }
+
+    /// A read-only copy of the last recompute's visibility, which later recomputes leave alone.
+    /// Its queries are safe to call from other threads, see FovSnapshot. Snapshots between two
+    /// recomputes share one copy, so taking many is cheap
+    #[func]
+    pub fn get_visibility_snapshot(&mut self) -> Gd<FovSnapshot> {
+        let visible = self
+            .visible_snapshot
+            .get_or_insert_with(|| Arc::new(self.visible.clone()));
+        let snapshot = VisibilitySnapshot {
+            origin: self.origin,
+            visible: Arc::clone(visible),
+        };
+        FovSnapshot::new_gd(snapshot, self.up_axis)
+    }
+
+    /// Every cell seen from the origin by the last recompute
+    #[func]
+    pub fn get_visible_positions(&self) -> PackedVector3Array {
+        self.positions(&self.visible)
+    }
+
+    /// Every empty cell seen by the last recompute that shares a face with a cell never seen,
+    /// where what is known of the map ends, for drawing its edge on an auto-map. Cells at the
+    /// edge of the grid only count for neighbors inside it
+    #[func]
+    pub fn get_knowledge_boundary(&self) -> PackedVector3Array {
+        self.positions(&self.knowledge_boundary())
+    }
+
+    /// get_knowledge_boundary() for one z-layer, laid out as in get_occlusion_layer()
+    #[func]
+    pub fn get_knowledge_boundary_layer(&self, z: i32) -> PackedByteArray {
+        Self::layer_bytes(&self.knowledge_boundary(), z)
+    }
+
+    /// The outline of what the last recompute saw of the occluders: every face of a seen
+    /// occluder whose neighbor across it is empty space, or was not seen, as its center point
+    /// followed by its outward normal. Faces between two seen occluders are inside what was seen,
+    /// and faces on the edge of the grid are left out
+    #[func]
+    pub fn get_silhouette_edges(&self) -> PackedVector3Array {
+        const NORMALS: [Vector3; 6] = [
+            Vector3::LEFT,
+            Vector3::RIGHT,
+            Vector3::DOWN,
+            Vector3::UP,
+            Vector3::FORWARD,
+            Vector3::BACK,
+        ];
+        let grid = self.occluded.grid();
+        let occluded = sight_source(&self.occlusion_source, &self.channels, &grid);
+        let terrain = self.terrain.as_ref();
+        let is_occluded = |index: Index3| {
+            occluded.is_occluded_at(index) || terrain.is_some_and(|terrain| terrain.occludes(index))
+        };
+        let mut edges = PackedVector3Array::new();
+        self.visible.for_each_set(|index| {
+            if !is_occluded(index) {
+                return;
+            }
+            for (neighbor, normal) in face_neighbors(index).into_iter().zip(NORMALS) {
+                let Some(seen) = self.visible.get(neighbor) else {
+                    continue;
+                };
+                if !(seen && is_occluded(neighbor)) {
+                    let normal = self.up_axis.from_grid(normal);
+                    edges.push(self.position_of(index) + normal * 0.5);
+                    edges.push(normal);
+                }
+            }
+        });
+        edges
+    }
+
+    fn knowledge_boundary(&self) -> BitGrid {
+        let grid = self.occluded.grid();
+        let occluded = sight_source(&self.occlusion_source, &self.channels, &grid);
+        let terrain = self.terrain.as_ref();
+        let mut boundary = BitGrid::new(self.visible.size());
+        self.visible.for_each_set(|index| {
+            let empty = !occluded.is_occluded_at(index)
+                && !terrain.is_some_and(|terrain| terrain.occludes(index));
+            if empty
+                && face_neighbors(index)
+                    .into_iter()
+                    .any(|neighbor| self.explored.get(neighbor) == Some(false))
+            {
+                boundary.set(index, true);
+            }
+        });
+        boundary
+    }
+
+    /// Have every recompute also write visibility into a caller-provided buffer, as one 0 or 1
+    /// byte per cell at index (x * size.y + y) * size.z + z. `size` must match the grid.
+    /// Packed arrays are copy-on-write, so read the written results back with
+    /// get_external_visibility_buffer() or detach_external_visibility_buffer()
+    #[func]
+    pub fn set_external_visibility_buffer(
+        &mut self,
+        buffer: PackedByteArray,
+        size: Vector3i,
+    ) -> Error {
+        let (x, y, z) = self.visible.size();
+        if self.up_axis.to_grid(size) != Vector3i::new(x as i32, y as i32, z as i32) {
+            godot_script_error!("Buffer size {} does not match the grid size", size);
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        if buffer.len() != x * y * z {
+            godot_script_error!(
+                "Buffer holds {} bytes, but the grid has {} cells",
+                buffer.len(),
+                x * y * z
+            );
+            return Error::ERR_INVALID_PARAMETER;
+        }
+
+        let mut buffer = buffer;
+        self.visible.write_bytes(buffer.as_mut_slice());
+        self.external_visibility = Some(buffer);
+        Error::OK
+    }
+
+    /// The attached visibility buffer, as of the last recompute, or an empty array if none is attached
+    #[func]
+    pub fn get_external_visibility_buffer(&self) -> PackedByteArray {
+        self.external_visibility.clone().unwrap_or_default()
+    }
+
+    /// Stop writing into the attached visibility buffer and hand it back,
+    /// or an empty array if none is attached
+    #[func]
+    pub fn detach_external_visibility_buffer(&mut self) -> PackedByteArray {
+        self.external_visibility.take().unwrap_or_default()
+    }
+
+    /// Create a view: an observer with its own visibility results, independent of
+    /// set_origin_and_recompute() and of other views. Returns a handle for the other view functions
+    #[func]
+    pub fn create_view(&mut self) -> i64 {
+        let id = self.next_view_id;
+        self.next_view_id += 1;
+        self.views.push(View::new(id));
+        id
+    }
+
+    /// Returns false if there is no view with this handle
+    #[func]
+    pub fn destroy_view(&mut self, handle: i64) -> bool {
+        let count = self.views.len();
+        self.views.retain(|view| view.id != handle);
+        self.views.len() != count
+    }
+
+    fn view(&self, handle: i64) -> Option<&View> {
+        let view = self.views.iter().find(|view| view.id == handle);
+        if view.is_none() {
+            godot_script_error!("No view with handle {}", handle);
+        }
+        view
+    }
+
+    fn view_mut(&mut self, handle: i64) -> Option<&mut View> {
+        let view = self.views.iter_mut().find(|view| view.id == handle);
+        if view.is_none() {
+            godot_script_error!("No view with handle {}", handle);
+        }
+        view
+    }
+
+    /// How many layers away from its origin a view reaches, applied from its next recompute
+    #[func]
+    pub fn set_view_max_depth(&mut self, handle: i64, max_depth: i32) -> Error {
+        let Some(view) = self.view_mut(handle) else {
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+        view.max_depth = max_depth.max(0) as usize;
+        Error::OK
+    }
+
+    /// The shape of the max_depth range around a view's origin, applied from its next recompute
+    #[func]
+    pub fn set_view_range_shape(&mut self, handle: i64, shape: RangeShape) -> Error {
+        let Some(view) = self.view_mut(handle) else {
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+        view.range_shape = shape;
+        Error::OK
+    }
+
+    /// set_view_range_shape() with RANGE_SPHERE when `spherical`, and RANGE_CUBE otherwise
+    #[func]
+    pub fn set_view_spherical_range(&mut self, handle: i64, spherical: bool) -> Error {
+        let shape = match spherical {
+            true => RangeShape::Sphere,
+            false => RangeShape::Cube,
+        };
+        self.set_view_range_shape(handle, shape)
+    }
+
+    /// Count a recompute of a view off its vision modifiers, signalling those that lapsed
+    fn tick_vision_modifiers(&mut self, handle: i64) {
+        let Some(view) = self.views.iter_mut().find(|view| view.id == handle) else {
+            return;
+        };
+        for lapsed in view.tick_modifiers() {
+            self.base_mut().emit_signal(
+                "vision_modifier_expired",
+                &[handle.to_variant(), lapsed.kind.to_variant()],
+            );
+        }
+    }
+
+    /// Put a status effect on a view, from its next recompute: VISION_RANGE_MULTIPLIER
+    /// multiplies its range by `magnitude`, VISION_RANGE_FLOOR keeps its range at least
+    /// `magnitude`, VISION_BLINDNESS limits it to 1, and VISION_DARKVISION has
+    /// get_view_lit_positions() count the cells within `magnitude` of it as lit.
+    /// Multipliers multiply together, the highest floor applies after them and blindness
+    /// overrides both. A modifier lapses after `duration` recomputes of the view, emitting
+    /// vision_modifier_expired, or lasts until clear_vision_modifiers() for 0 or less
+    #[func]
+    pub fn add_vision_modifier(
+        &mut self,
+        handle: i64,
+        kind: VisionModifierKind,
+        magnitude: real,
+        duration: i32,
+    ) -> Error {
+        let Some(view) = self.view_mut(handle) else {
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+        view.modifiers.push(VisionModifier {
+            kind,
+            magnitude,
+            remaining: (duration > 0).then_some(duration as u32),
+        });
+        Error::OK
+    }
+
+    /// Remove every vision modifier from a view, without emitting vision_modifier_expired
+    #[func]
+    pub fn clear_vision_modifiers(&mut self, handle: i64) -> Error {
+        let Some(view) = self.view_mut(handle) else {
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+        view.modifiers.clear();
+        Error::OK
+    }
+
+    /// How many layers a view's next recompute reaches, with its vision modifiers applied.
+    /// -1 for unknown handles
+    #[func]
+    pub fn get_view_effective_max_depth(&self, handle: i64) -> i64 {
+        self.view(handle)
+            .map_or(-1, |view| view.effective_max_depth() as i64)
+    }
+
+    /// The cells a view saw in its last recompute that are lit enough to make out, as effective
+    /// visibility is for the origin, with innate light around the view instead. Cells within
+    /// the view's darkvision count as lit
+    #[func]
+    pub fn get_view_lit_positions(&self, handle: i64) -> PackedVector3Array {
+        let Some(view) = self.view(handle) else {
+            return PackedVector3Array::new();
+        };
+        let origin = view.origin.cast_float();
+        let darkvision = view.darkvision_radius();
+        let mut lit = BitGrid::new(view.visible.size());
+        view.visible.for_each_set(|index| {
+            let distance = index_to_position(index).distance_to(origin);
+            if self.is_lit_from(index, origin)
+                || darkvision.is_some_and(|radius| distance <= radius)
+            {
+                lit.set(index, true);
+            }
+        });
+        self.positions(&lit)
+    }
+
+    /// Recompute what a view sees from an origin, leaving every other result untouched
+    #[func]
+    pub fn recompute_view(&mut self, handle: i64, origin: Vector3) -> Error {
+        self.sync_shared_grid();
+        let origin = self.cell_of(origin);
+        let index = cell_index(origin);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(origin);
+        }
+
+        let range_is_inclusive = self.range_is_inclusive;
+        let Some(view) = self.views.iter_mut().find(|view| view.id == handle) else {
+            godot_script_error!("No view with handle {}", handle);
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+        view.range_is_inclusive = range_is_inclusive;
+        let cast = catch_panic(|| {
+            view.recompute(
+                sight_source(
+                    &self.occlusion_source,
+                    &self.channels,
+                    &self.occluded.grid(),
+                ),
+                self.occluded.size(),
+                &self.one_way,
+                self.terrain.as_ref(),
+                origin,
+            )
+        });
+        if let Err(error) = cast {
+            view.visible.clear();
+            return error.report();
+        }
+        self.tick_vision_modifiers(handle);
+        Error::OK
+    }
+
+    /// The pass for cast_custom(), or an error for slopes that cover no area.
+    /// Planes are PLANE_XY (casting along z), PLANE_ZY (along x) and PLANE_ZX (along y)
+    fn custom_pass(
+        &self,
+        plane: UnitPlane3d,
+        reverse_z: bool,
+        slope_start: Vector2,
+        slope_end: Vector2,
+    ) -> Result<Pass, ShadowcastError> {
+        let (start, end) = (
+            self.up_axis.to_grid(slope_start),
+            self.up_axis.to_grid(slope_end),
+        );
+        let slope_rect = Rect {
+            sx: start.x,
+            sy: start.y,
+            ex: end.x,
+            ey: end.y,
+        };
+        if !is_valid_slope_rect(&slope_rect) {
+            return Err(ShadowcastError::InvalidParameter(format!(
+                "Slopes from {} to {} do not cover any area",
+                slope_start, slope_end
+            )));
+        }
+        Ok((slope_rect, reverse_z, self.up_axis.to_grid(plane)))
+    }
+
+    /// Run a single pass from `origin` through a custom frustum instead of the four quadrants,
+    /// e.g. only through a doorway, replacing the visibility of the last recompute.
+    /// Slopes are depth over offset from the casting axis, with INF on the axis itself,
+    /// as in the built-in quadrants from Vector2(INF, INF) to Vector2(1, 1)
+    #[func]
+    pub fn cast_custom(
+        &mut self,
+        origin: Vector3,
+        plane: UnitPlane3d,
+        reverse_z: bool,
+        slope_start: Vector2,
+        slope_end: Vector2,
+    ) -> Error {
+        self.sync_shared_grid();
+        let (slope_rect, reverse_z, plane) =
+            match self.custom_pass(plane, reverse_z, slope_start, slope_end) {
+                Ok(pass) => pass,
+                Err(error) => return error.report(),
+            };
+        let origin = self.up_axis.to_grid(origin);
+        let origin_int = self.eye_cell(origin);
+        let index = cell_index(origin_int);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(origin_int);
+        }
+        self.origin = origin_int;
+        self.origin_float = origin;
+
+        self.visible_snapshot = None;
+        self.visible.clear();
+        let settings = self.pass_settings();
+        let cast = catch_panic(|| {
+            let grid = self.occluded.grid();
+            let mut caster = Caster {
+                occluded: sight_source(&self.occlusion_source, &self.channels, &grid),
+                visible: &mut self.visible,
+                origin: self.origin,
+                jitter: settings.eye_jitter,
+                max_depth: settings.max_depth,
+                lod: settings.lod,
+                corner_rule: settings.corner_rule,
+                debug_rects: None,
+                lit_rects: None,
+                fractions: None,
+                bounds: None,
+                one_way: Some(&self.one_way),
+                blockers: None,
+                terrain: self.terrain.as_ref(),
+                exposed: None,
+                rects: RectLimit::new(settings.max_rects, settings.narrow),
+            };
+            caster.mark_origin_visible();
+            cast_light(&mut caster, &slope_rect, 1, reverse_z, &plane);
+            drop(grid);
+            if self.report_surfaces_only {
+                self.keep_surfaces_only();
+            }
+        });
+        if let Err(error) = cast {
+            self.discard_visibility();
+            return error.report();
+        }
+
+        if let Some(buffer) = self.external_visibility.as_mut() {
+            self.visible.write_bytes(buffer.as_mut_slice());
+        }
+        self.explored.union_with(&self.visible);
+        self.update_cross_section_mesh();
+        self.update_effective_visibility();
+        self.update_visibility_tiers();
+        self.dirty_chunks.update(&self.visible);
+        Error::OK
+    }
+
+    /// A new Display whose grid is `factor` times coarser along every axis, for a cheaper but
+    /// rougher recompute, such as on a strategic map. Coarse cell c covers the cells from
+    /// c * factor to c * factor + factor - 1 on each axis, with the grid padded with empty cells
+    /// to a multiple of `factor`, and is occluded as downsample_occluded_fraction asks.
+    /// Recompute it from floor(origin / factor), and show what it saw with
+    /// apply_upsampled_visibility(). It keeps this node's casting settings, but not its terrain,
+    /// one-way occluders, lights or portals. It is not in the scene tree, so add it or free it.
+    /// Null while an occlusion source is set, as the grid it would copy is not what is cast
+    #[func]
+    pub fn make_downsampled_copy(&self, factor: i32) -> Option<Gd<Display>> {
+        if factor < 1 {
+            godot_script_error!("Downsampling factor {} is less than 1", factor);
+            return None;
+        }
+        if self.occlusion_source.is_some() {
+            godot_script_error!("Cannot downsample while an occlusion source is set");
+            return None;
+        }
+        let occluded = self
+            .channels
+            .sight_grid(&self.occluded.grid())
+            .downsampled(factor as usize, self.downsample_occluded_fraction as f32);
+        let size = occluded.size();
+
+        let mut copy = Display::new_alloc();
+        {
+            let mut coarse = copy.bind_mut();
+            coarse.occluded_count = occluded.count_set();
+            coarse.occluded = SharedGrid::new(occluded);
+            coarse.visible = BitGrid::new(size);
+            coarse.effective_visible = BitGrid::new(size);
+            coarse.explored = BitGrid::new(size);
+            coarse.corner_rule = self.corner_rule;
+            coarse.out_of_bounds = self.out_of_bounds;
+            coarse.up_axis = self.up_axis;
+            coarse.lod_start_depth = self.lod_start_depth;
+            coarse.lod_factor = self.lod_factor;
+            coarse.min_subtended_angle = self.min_subtended_angle;
+            coarse.subtended_angle_cull = self.subtended_angle_cull;
+            coarse.range_is_inclusive = self.range_is_inclusive;
+            coarse.max_work_items = self.max_work_items;
+            coarse.max_rects_per_node = self.max_rects_per_node;
+            coarse.narrow_rect_width = self.narrow_rect_width;
+            coarse.narrow_rect_policy = self.narrow_rect_policy;
+        }
+        Some(copy)
+    }
+
+    /// Replace the visibility of the last recompute with that of a copy from
+    /// make_downsampled_copy() with the same `factor`, every cell counting as seen when the
+    /// coarse cell over it was. Explored cells, the cross section and effective visibility
+    /// follow as after a recompute, and the origin is left as it was
+    #[func]
+    pub fn apply_upsampled_visibility(&mut self, coarse: Gd<Display>, factor: i32) -> Error {
+        if factor < 1 {
+            godot_script_error!("Downsampling factor {} is less than 1", factor);
+            return Error::ERR_INVALID_PARAMETER;
+        }
+        let factor = factor as usize;
+        let (x, y, z) = self.occluded.size();
+        let coarse = coarse.bind();
+        if coarse.visible.size() != (x.div_ceil(factor), y.div_ceil(factor), z.div_ceil(factor)) {
+            godot_script_error!(
+                "The coarse grid is not this grid downsampled {} times",
+                factor
+            );
+            return Error::ERR_INVALID_PARAMETER;
+        }
+
+        self.visible_snapshot = None;
+        self.visible.clear();
+        coarse.visible.upsample_into(factor, &mut self.visible);
+        drop(coarse);
+
+        if let Some(buffer) = self.external_visibility.as_mut() {
+            self.visible.write_bytes(buffer.as_mut_slice());
+        }
+        self.explored.union_with(&self.visible);
+        self.update_cross_section_mesh();
+        self.update_effective_visibility();
+        self.update_visibility_tiers();
+        self.dirty_chunks.update(&self.visible);
+        Error::OK
+    }
+
+    /// cast_custom() into a view instead, leaving every other result untouched
+    #[func]
+    pub fn cast_custom_in_view(
+        &mut self,
+        handle: i64,
+        origin: Vector3,
+        plane: UnitPlane3d,
+        reverse_z: bool,
+        slope_start: Vector2,
+        slope_end: Vector2,
+    ) -> Error {
+        self.sync_shared_grid();
+        let pass = match self.custom_pass(plane, reverse_z, slope_start, slope_end) {
+            Ok(pass) => pass,
+            Err(error) => return error.report(),
+        };
+        let origin = self.cell_of(origin);
+        let index = cell_index(origin);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(origin);
+        }
+
+        let range_is_inclusive = self.range_is_inclusive;
+        let Some(view) = self.views.iter_mut().find(|view| view.id == handle) else {
+            godot_script_error!("No view with handle {}", handle);
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+        view.range_is_inclusive = range_is_inclusive;
+        let cast = catch_panic(|| {
+            view.cast_passes(
+                sight_source(
+                    &self.occlusion_source,
+                    &self.channels,
+                    &self.occluded.grid(),
+                ),
+                self.occluded.size(),
+                &self.one_way,
+                self.terrain.as_ref(),
+                origin,
+                [pass],
+            )
+        });
+        if let Err(error) = cast {
+            view.visible.clear();
+            return error.report();
+        }
+        self.tick_vision_modifiers(handle);
+        Error::OK
+    }
+
+    /// Whether a cell was seen by the last recompute_view() of a view
+    #[func]
+    pub fn is_visible_in_view(&self, handle: i64, pos: Vector3i) -> bool {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.view(handle)
+            .is_some_and(|view| view.visible.get(index).unwrap_or(false))
+    }
+
+    /// The visibility of a view, or None with an error for unknown handles and a warning
+    /// for views that were never recomputed
+    fn view_result(&self, handle: i64) -> Option<&BitGrid> {
+        let view = self.view(handle)?;
+        if view.visible.size() != self.occluded.size() {
+            godot_warn!("View {} was never recomputed, treating it as empty", handle);
+            return None;
+        }
+        Some(&view.visible)
+    }
+
+    /// Every cell seen by at least one of the views
+    #[func]
+    pub fn get_view_union(&self, handles: PackedInt64Array) -> PackedVector3Array {
+        let mut union = BitGrid::new(self.occluded.size());
+        for &handle in handles.as_slice() {
+            if let Some(visible) = self.view_result(handle) {
+                union.union_with(visible);
+            }
+        }
+
+        self.positions(&union)
+    }
+
+    /// Every cell seen by all of the views, none if no handles are given
+    #[func]
+    pub fn get_view_intersection(&self, handles: PackedInt64Array) -> PackedVector3Array {
+        let results: Option<Vec<&BitGrid>> = handles
+            .as_slice()
+            .iter()
+            .map(|&handle| self.view_result(handle))
+            .collect();
+        // An empty view empties the whole intersection
+        let Some((first, rest)) = results.as_deref().and_then(|results| results.split_first())
+        else {
+            return PackedVector3Array::new();
+        };
+
+        let mut intersection = (*first).clone();
+        for visible in rest {
+            intersection.intersect_with(visible);
+        }
+        self.positions(&intersection)
+    }
+
+    /// Every cell seen by view a but not by view b
+    #[func]
+    pub fn get_view_difference(&self, a: i64, b: i64) -> PackedVector3Array {
+        let Some(visible_a) = self.view_result(a) else {
+            return PackedVector3Array::new();
+        };
+
+        let mut difference = visible_a.clone();
+        if let Some(visible_b) = self.view_result(b) {
+            difference.subtract(visible_b);
+        }
+        self.positions(&difference)
+    }
+
+    /// Register an inclusive box of cells closed off by occluders, walls included, for portals
+    /// to connect. Rooms may share walls. Returns the room's index
+    #[func]
+    pub fn add_room(&mut self, min: Vector3i, max: Vector3i) -> i64 {
+        let (min, max) = (self.up_axis.to_grid(min), self.up_axis.to_grid(max));
+        self.portals.rooms.push(Room {
+            min: min.coord_min(max),
+            max: min.coord_max(max),
+        });
+        self.portals.rooms.len() as i64 - 1
+    }
+
+    /// Register an opening between the rooms containing two neighbouring cells, at the face
+    /// between them. `rect` is the cells the opening spans across the axis the cells neighbour
+    /// along, in (x, y) for neighbours along z, (z, y) along x and (z, x) along y.
+    /// Once any portal is registered, recomputes from inside a room only scan rooms that can be
+    /// seen into through chains of portals. The view gets split up differently than when casting
+    /// plainly, so a few cells right at shadow edges can differ.
+    /// Returns the portal's index, or -1 if it is invalid
+    #[func]
+    pub fn add_portal(&mut self, cell_a: Vector3i, cell_b: Vector3i, rect: Rect2) -> i64 {
+        let (a, b) = (self.up_axis.to_grid(cell_a), self.up_axis.to_grid(cell_b));
+        let offset = (b - a).abs();
+        if offset.x + offset.y + offset.z != 1 {
+            godot_script_error!("Portal cells {} and {} are not neighbours", cell_a, cell_b);
+            return -1;
+        }
+        if rect.size.x <= 0.0 || rect.size.y <= 0.0 {
+            godot_script_error!("Portal opening {} has no area", rect);
+            return -1;
+        }
+        let rooms = (
+            self.portals.room_of(a, Some(b)),
+            self.portals.room_of(b, Some(a)),
+        );
+        let (Some(room_a), Some(room_b)) = rooms else {
+            godot_script_error!(
+                "Portal cells {} and {} must both be in rooms",
+                cell_a,
+                cell_b
+            );
+            return -1;
+        };
+        if room_a == room_b {
+            godot_script_error!(
+                "Portal cells {} and {} are in the same room",
+                cell_a,
+                cell_b
+            );
+            return -1;
+        }
+
+        self.portals.portals.push(Portal {
+            rooms: [room_a, room_b],
+            cells: [a, b],
+            opening: self.up_axis.to_grid(rect),
+        });
+        self.portals.portals.len() as i64 - 1
+    }
+
+    /// Forget every room and portal, so recomputes cast plainly again
+    #[func]
+    pub fn clear_portals(&mut self) {
+        self.portals.clear();
+    }
+
+    /// Declare the layer of cells at `height` along the up axis a floor between storeys, which
+    /// recomputes from the eye treat as occluded whatever the grid holds, but for the holes cut
+    /// into it with add_floor_separator_hole(). Passes looking up or down then stop at the
+    /// nearest floor each way, and only go on through its holes, each cast as a pass narrowed
+    /// to it as portals are, so the storeys past it are not scanned. Such recomputes keep no
+    /// trace or lit volume, count every visible cell as fully visible and leave portals out.
+    /// Views, lights and line of sight queries keep reading the grid alone, so floors are best
+    /// declared over layers of occluders with the same holes carved into them
+    #[func]
+    pub fn add_floor_separator(&mut self, height: i32) {
+        if !self.floors.add(height) {
+            godot_script_error!("There already is a floor separator at height {}", height);
+        }
+    }
+
+    /// Cut a hole at `cell` into the floor separator at its height, which sight passes through
+    /// wherever the grid does not occlude it
+    #[func]
+    pub fn add_floor_separator_hole(&mut self, cell: Vector3i) {
+        if !self.floors.add_hole(self.up_axis.to_grid(cell)) {
+            godot_script_error!("No floor separator at the height of {}", cell);
+        }
+    }
+
+    /// Forget the floor separator at `height` along with its holes
+    #[func]
+    pub fn remove_floor_separator(&mut self, height: i32) {
+        if !self.floors.remove(height) {
+            godot_script_error!("No floor separator at height {}", height);
+        }
+    }
+
+    /// Forget every floor separator, so recomputes cast plainly again
+    #[func]
+    pub fn clear_floor_separators(&mut self) {
+        self.floors.clear();
+    }
+
+    /// Register a light source for bake_lights(), returning a handle for remove_light_source().
+    /// It fades linearly, as with FALLOFF_LINEAR
+    #[func]
+    pub fn add_light_source(&mut self, position: Vector3, radius: i32, intensity: real) -> i64 {
+        self.add_light_source_with_falloff(position, radius, intensity, Falloff::Linear, 1)
+    }
+
+    /// add_light_source() with a falloff of FALLOFF_LINEAR, FALLOFF_INVERSE_SQUARE or
+    /// FALLOFF_STEPPED. `steps` is the number of bands of a stepped light, and ignored otherwise
+    #[func]
+    pub fn add_light_source_with_falloff(
+        &mut self,
+        position: Vector3,
+        radius: i32,
+        intensity: real,
+        falloff: Falloff,
+        steps: i32,
+    ) -> i64 {
+        self.log_call(
+            "add_light_source_with_falloff",
+            &[
+                position.to_variant(),
+                radius.to_variant(),
+                intensity.to_variant(),
+                falloff.to_variant(),
+                steps.to_variant(),
+            ],
+        );
+        let id = self.next_light_id;
+        self.next_light_id += 1;
+        self.lights.push(LightSource {
+            id,
+            position: self.cell_of(position),
+            radius: radius.max(0) as usize,
+            intensity,
+            color: Color::WHITE,
+            falloff,
+            steps: steps.max(1) as usize,
+        });
+        id
+    }
+
+    /// Distances from a light source where its bands end, in ascending order and ending at its
+    /// radius, for drawing rings that match a stepped light. Other lights have a single band
+    #[func]
+    pub fn get_light_band_radii(&self, id: i64) -> PackedFloat32Array {
+        let Some(light) = self.lights.iter().find(|light| light.id == id) else {
+            godot_script_error!("No light source with handle {}", id);
+            return PackedFloat32Array::new();
+        };
+        light
+            .band_radii()
+            .into_iter()
+            .map(|radius| radius as f32)
+            .collect()
+    }
+
+    /// Change how bright a light source is. Cheap to bake again after, as the next
+    /// bake_lights() scales the light it already cast rather than casting it again, so lights
+    /// can flicker every frame. Returns false if there is no light source with this handle
+    #[func]
+    pub fn set_light_intensity(&mut self, id: i64, intensity: real) -> bool {
+        self.log_call(
+            "set_light_intensity",
+            &[id.to_variant(), intensity.to_variant()],
+        );
+        let Some(light) = self.lights.iter_mut().find(|light| light.id == id) else {
+            return false;
+        };
+        light.intensity = intensity;
+        true
+    }
+
+    /// Change the color of a light source, white for lights added without one. As cheap to
+    /// bake again after as set_light_intensity(). Returns false if there is no light source
+    /// with this handle
+    #[func]
+    pub fn set_light_color(&mut self, id: i64, color: Color) -> bool {
+        self.log_call("set_light_color", &[id.to_variant(), color.to_variant()]);
+        let Some(light) = self.lights.iter_mut().find(|light| light.id == id) else {
+            return false;
+        };
+        light.color = color;
+        true
+    }
+
+    /// Returns false if there is no light source with this handle
+    #[func]
+    pub fn remove_light_source(&mut self, id: i64) -> bool {
+        self.log_call("remove_light_source", &[id.to_variant()]);
+        let count = self.lights.len();
+        self.lights.retain(|light| light.id != id);
+        self.lights.len() != count
+    }
+
+    /// Make a cell give off light of its own, replacing any it gave off before. It shows up as
+    /// lit whenever it is seen, occluder or not, while still casting its shadow as usual, and
+    /// bake_lights() treats it as a light source of emissive_radius. An intensity of 0 or less
+    /// stops the emission, as does remove_emissive()
+    #[func]
+    pub fn set_emissive(&mut self, pos: Vector3i, color: Color, intensity: real) -> Error {
+        self.log_call(
+            "set_emissive",
+            &[pos.to_variant(), color.to_variant(), intensity.to_variant()],
+        );
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(pos);
+        }
+        if intensity <= 0.0 {
+            self.emissive.remove(&index);
+            return Error::OK;
+        }
+        self.emissive.insert(index, Emitter { color, intensity });
+        Error::OK
+    }
+
+    /// Returns false if the cell gave off no light
+    #[func]
+    pub fn remove_emissive(&mut self, pos: Vector3i) -> bool {
+        self.log_call("remove_emissive", &[pos.to_variant()]);
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.emissive.remove(&index).is_some()
+    }
+
+    /// The glow of an emissive cell as seen from the origin by the last recompute: its color
+    /// scaled by its intensity, fading linearly with distance to nothing just past the deepest
+    /// layer a recompute reaches. Transparent black for cells that are not seen or do not emit
+    #[func]
+    pub fn get_emissive_glow(&self, pos: Vector3i) -> Color {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        let (Some(true), Some(emitter)) = (self.visible.get(index), self.emissive.get(&index))
+        else {
+            return Color::from_rgba(0.0, 0.0, 0.0, 0.0);
+        };
+        let distance = index_to_position(index).distance_to(self.eye());
+        let falloff = (1.0 - distance / (MAX_DEPTH as real + 1.0)).max(0.0);
+        let brightness = (emitter.intensity * falloff) as f32;
+        Color::from_rgba(
+            emitter.color.r * brightness,
+            emitter.color.g * brightness,
+            emitter.color.b * brightness,
+            emitter.color.a,
+        )
+    }
+
+    /// Shadowcast from every light source and accumulate their light per cell.
+    /// Emissive cells light their surroundings as light sources of emissive_radius.
+    /// With soft_samples above 1 this is averaged over shadowcasts from several points in each
+    /// light's cell. Player visibility is unaffected, it always uses the center of its cell.
+    /// Only lights that moved, changed radius or falloff, or whose radius reaches an edited
+    /// occluder are cast again, the rest reuse their light from earlier bakes
+    #[func]
+    pub fn bake_lights(&mut self) {
+        self.log_call("bake_lights", &[]);
+        self.rebake_lights();
+    }
+
+    /// bake_lights() without logging the call, for restore_state() which is logged itself
+    fn rebake_lights(&mut self) {
+        self.sync_shared_grid();
+        if let Err(error) = catch_panic(|| self.bake()) {
+            // Unbaked, so every cell reads as unlit until the next bake
+            self.light_level = Array3::zeros((0, 0, 0));
+            self.light_color = Array3::default((0, 0, 0));
+            self.light_cache = LightCache::default();
+            self.update_effective_visibility();
+            error.report();
+        }
+    }
+
+    /// The body of bake_lights()
+    fn bake(&mut self) {
+        let samples = self.soft_samples.clamp(1, MAX_SOFT_SAMPLES as i32) as usize;
+        let jitters = match samples {
+            1 => vec![Vector3::ZERO],
+            _ => soft_sample_offsets(samples, self.soft_seed as u64),
+        };
+
+        let time = Time::singleton();
+        self.last_bake_sample_usec.clear();
+        self.last_bake_accumulate_usec = 0;
+        self.last_bake_cast_lights = 0;
+        self.last_bake_cached_lights = 0;
+        if self.lights.is_empty() && self.emissive.is_empty() {
+            // Nothing to bake, so cells read as unlit without holding a grid of zeros
+            self.light_level = Array3::zeros((0, 0, 0));
+            self.light_color = Array3::default((0, 0, 0));
+            self.light_cache.invalidate_all();
+            self.update_effective_visibility();
+            return;
+        }
+        let grid = self.occluded.grid();
+        let occluded = light_source(&self.occlusion_source, &self.channels, &grid);
+        let emitters: Vec<LightSource> = self
+            .emissive
+            .iter()
+            .map(|(&index, emitter)| LightSource {
+                id: -1,
+                position: index_cell(index),
+                radius: self.emissive_radius.max(0) as usize,
+                intensity: emitter.intensity,
+                color: emitter.color,
+                falloff: Falloff::Linear,
+                steps: 1,
+            })
+            .collect();
+        let missing =
+            self.light_cache
+                .retarget(grid.size(), &jitters, self.lights.iter().chain(&emitters));
+        self.last_bake_cast_lights = missing.len();
+        self.last_bake_cached_lights = self.lights.len() + emitters.len() - missing.len();
+        for jitter in jitters {
+            let sample_start = time.get_ticks_usec();
+            self.light_cache.cast(
+                occluded,
+                &self.one_way,
+                self.terrain.as_ref(),
+                &missing,
+                jitter,
+                1.0 / samples as real,
+            );
+            self.last_bake_sample_usec
+                .push(time.get_ticks_usec() - sample_start);
+        }
+
+        let accumulate_start = time.get_ticks_usec();
+        clear_light_levels(&grid, &mut self.light_level);
+        clear_light_levels(&grid, &mut self.light_color);
+        drop(grid);
+        self.light_cache.accumulate(
+            self.lights.iter().chain(&emitters),
+            &mut self.light_level,
+            &mut self.light_color,
+        );
+        self.last_bake_accumulate_usec = time.get_ticks_usec() - accumulate_start;
+        self.update_effective_visibility();
+    }
+
+    /// Timings of the last bake_lights(), in microseconds: "total_usec" for the whole bake,
+    /// "sample_usec" for the casts of each soft sample in order and "accumulate_usec" for adding
+    /// up the light of every source. "cast_lights" is the number of light sources it cast and
+    /// "cached_lights" the number whose light it reused, emissive cells included
+    #[func]
+    pub fn get_last_bake_stats(&self) -> Dictionary {
+        let mut stats = Dictionary::new();
+        let total: u64 =
+            self.last_bake_sample_usec.iter().sum::<u64>() + self.last_bake_accumulate_usec;
+        stats.set("total_usec", total as i64);
+        stats.set("accumulate_usec", self.last_bake_accumulate_usec as i64);
+        stats.set("cast_lights", self.last_bake_cast_lights as i64);
+        stats.set("cached_lights", self.last_bake_cached_lights as i64);
+        let sample_usec: PackedInt64Array = self
+            .last_bake_sample_usec
+            .iter()
+            .map(|&usec| usec as i64)
+            .collect();
+        stats.set("sample_usec", sample_usec);
+        stats
+    }
+
+    /// Accumulated light at a cell as of the last bake_lights()
+    #[func]
+    pub fn get_light_level(&self, pos: Vector3i) -> real {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.light_level.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// Accumulated light at a cell as of the last bake_lights() with every light tinted by its
+    /// color, emissive cells by theirs. Channels can go past 1 where bright lights overlap.
+    /// Black with an alpha of 1 where no light reaches
+    #[func]
+    pub fn get_light_color(&self, pos: Vector3i) -> Color {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        let [r, g, b] = self.light_color.get(index).copied().unwrap_or_default();
+        Color::from_rgb(r as f32, g as f32, b as f32)
+    }
+
+    /// Whether an occluded cell looks lit to a viewer, the roguelike convention for walls: a face
+    /// of the wall counts when the viewer is on its side and can see the empty cell in front of
+    /// it, and that cell was lit brighter than darkness_threshold by the last bake_lights().
+    /// A wall between a lit room and a dark corridor is then lit from the room only.
+    /// False for empty cells and outside the grid
+    #[func]
+    pub fn is_wall_lit_for_viewer(&self, wall: Vector3i, viewer_origin: Vector3) -> bool {
+        let wall = self.up_axis.to_grid(wall);
+        let viewer = self.up_axis.to_grid(viewer_origin);
+        let grid = self.occluded.grid();
+        let sight = sight_source(&self.occlusion_source, &self.channels, &grid);
+        let index = cell_index(wall);
+        if self.visible.get(index).is_none() || !sight.is_occluded_at(index) {
+            return false;
+        }
+        let offset = viewer - wall.cast_float();
+        let fronts: Vec<Vector3i> = [
+            Vector3i::new(-1, 0, 0),
+            Vector3i::new(1, 0, 0),
+            Vector3i::new(0, -1, 0),
+            Vector3i::new(0, 1, 0),
+            Vector3i::new(0, 0, -1),
+            Vector3i::new(0, 0, 1),
+        ]
+        .into_iter()
+        .filter(|&normal| offset.dot(normal.cast_float()) > 0.5)
+        .map(|normal| wall + normal)
+        .filter(|&front| {
+            let index = cell_index(front);
+            self.light_level.get(index).copied().unwrap_or(0.0) > self.darkness_threshold
+                && !sight.is_occluded_at(index)
+        })
+        .collect();
+        if fronts.is_empty() {
+            return false;
+        }
+        let mut scratch = BitGrid::new(self.occluded.size());
+        visible_targets(
+            sight,
+            &self.one_way,
+            self.terrain.as_ref(),
+            &mut scratch,
+            &self.pass_settings(),
+            cell_at(viewer),
+            &fronts,
+        )
+        .contains(&true)
+    }
+
+    /// Whether a cell is both seen from the origin and lit, either by a light source brighter
+    /// than darkness_threshold, by the observer's innate light or by giving off its own
+    #[func]
+    pub fn get_effective_visibility(&self, pos: Vector3i) -> bool {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.effective_visible.get(index).unwrap_or(false)
+    }
+
+    /// Every cell for which get_effective_visibility() is true
+    #[func]
+    pub fn get_effective_visible_positions(&self) -> PackedVector3Array {
+        self.positions(&self.effective_visible)
+    }
+
+    /// Tag a cell with a number from 0 to 65535 of the game's choosing, such as a kind of terrain
+    /// or loot, to look up visible cells by with get_visible_with_tag(). Tags do not affect
+    /// casting. Every cell starts out with tag 0, and tags take 2 bytes per cell once any is set
+    #[func]
+    pub fn set_tag(&mut self, pos: Vector3i, tag: i32) -> Error {
+        self.log_call("set_tag", &[pos.to_variant(), tag.to_variant()]);
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        if self.occluded.get(index).is_none() {
+            return self.report_out_of_bounds(pos);
+        }
+        let Ok(tag) = u16::try_from(tag) else {
+            godot_script_error!("Tag {} is outside 0 to 65535", tag);
+            return Error::ERR_PARAMETER_RANGE_ERROR;
+        };
+        let size = self.occluded.size();
+        let tags = self.tags.get_or_insert_with(|| Array3::zeros(size));
+        tags[index] = tag;
+        Error::OK
+    }
+
+    /// A cell's tag, 0 if it was never tagged or is outside the grid
+    #[func]
+    pub fn get_tag(&self, pos: Vector3i) -> i32 {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.tags
+            .as_ref()
+            .and_then(|tags| tags.get(index).copied())
+            .unwrap_or(0) as i32
+    }
+
+    /// Call `f` with every cell seen by the last recompute that has a tag
+    fn for_each_visible_with_tag(&self, tag: i32, mut f: impl FnMut(Index3)) {
+        let Ok(tag) = u16::try_from(tag) else {
+            return;
+        };
+        match &self.tags {
+            Some(tags) => self.visible.for_each_set(|index| {
+                if tags[index] == tag {
+                    f(index);
+                }
+            }),
+            None if tag == 0 => self.visible.for_each_set(f),
+            None => {}
+        }
+    }
+
+    /// Every cell seen by the last recompute with a tag, in ascending x, then y, then z order
+    #[func]
+    pub fn get_visible_with_tag(&self, tag: i32) -> PackedVector3Array {
+        let mut positions = PackedVector3Array::new();
+        self.for_each_visible_with_tag(tag, |index| positions.push(self.position_of(index)));
+        positions
+    }
+
+    /// Number of cells seen by the last recompute with a tag
+    #[func]
+    pub fn count_visible_with_tag(&self, tag: i32) -> i64 {
+        let mut count = 0;
+        self.for_each_visible_with_tag(tag, |_| count += 1);
+        count
+    }
+
+    /// Whether a cell was seen by any recompute since the grid was last resized or cleared
+    #[func]
+    pub fn is_explored(&self, pos: Vector3i) -> bool {
+        let pos = self.up_axis.to_grid(pos);
+        let index = cell_index(pos);
+        self.explored.get(index).unwrap_or(false)
+    }
+
+    /// Forget every cell seen so far, e.g. when entering a new level
+    #[func]
+    pub fn clear_explored(&mut self) {
+        self.log_call("clear_explored", &[]);
+        self.explored.clear();
+    }
+
+    /// Keep the visible, explored, light and visibility fraction buffers as they are, along
+    /// with the origin and the settings that shape recomputes, for rollback_to_checkpoint() to
+    /// go back to, such as at the start of every turn. Each checkpoint only keeps what changed
+    /// since the one before it. Returns its id, ids count up from 0
+    #[func]
+    pub fn push_visibility_checkpoint(&mut self) -> i64 {
+        self.log_call("push_visibility_checkpoint", &[]);
+        let settings = CheckpointSettings {
+            origin: self.origin,
+            origin_float: self.origin_float,
+            corner_rule: self.corner_rule,
+            track_visibility_fraction: self.track_visibility_fraction,
+            lod_start_depth: self.lod_start_depth,
+            lod_factor: self.lod_factor,
+            budget_depth_reduction: self.budget_depth_reduction,
+        };
+        let buffers = CheckpointBuffers {
+            visible: self.visible.clone(),
+            explored: self.explored.clone(),
+            light_level: self.light_level.clone(),
+            light_color: self.light_color.clone(),
+            visibility_fraction: self.visibility_fraction.clone(),
+        };
+        self.checkpoints.push(settings, buffers)
+    }
+
+    /// Put back the buffers, origin and settings of a checkpoint, as if the recomputes and
+    /// bakes since had not happened, and drop the checkpoints pushed after it. Occluder, light
+    /// and other edits since are kept, so the next recompute or bake_lights() sees them.
+    /// Checkpoints of a grid of another size, from before restore_state(), cannot be rolled
+    /// back to
+    #[func]
+    pub fn rollback_to_checkpoint(&mut self, id: i64) -> Error {
+        self.log_call("rollback_to_checkpoint", &[id.to_variant()]);
+        let Some((settings, buffers)) = self.checkpoints.get(id) else {
+            godot_script_error!("No visibility checkpoint with id {}", id);
+            return Error::ERR_DOES_NOT_EXIST;
+        };
+        if buffers.size() != self.occluded.size() {
+            godot_script_error!(
+                "Visibility checkpoint {} is of a grid of size {:?}, but the grid is {:?}",
+                id,
+                buffers.size(),
+                self.occluded.size()
+            );
+            return Error::ERR_INVALID_DATA;
+        }
+        self.checkpoints.rollback(id, &buffers);
+        self.origin = settings.origin;
+        self.origin_float = settings.origin_float;
+        self.corner_rule = settings.corner_rule;
+        self.track_visibility_fraction = settings.track_visibility_fraction;
+        self.lod_start_depth = settings.lod_start_depth;
+        self.lod_factor = settings.lod_factor;
+        self.budget_depth_reduction = settings.budget_depth_reduction;
+        self.visible = buffers.visible;
+        self.visible_snapshot = None;
+        self.explored = buffers.explored;
+        self.light_level = buffers.light_level;
+        self.light_color = buffers.light_color;
+        self.visibility_fraction = buffers.visibility_fraction;
+        self.last_visible_cells = self.visible.count_set();
+        if let Some(buffer) = self.external_visibility.as_mut() {
+            self.visible.write_bytes(buffer.as_mut_slice());
+        }
+        self.update_effective_visibility();
+        self.update_visibility_tiers();
+        self.dirty_chunks.update(&self.visible);
+        self.update_cross_section_mesh();
+        Error::OK
+    }
+
+    /// Drop every visibility checkpoint older than the one with this id, such as those past
+    /// the undo limit. Returns how many were dropped, or -1 if there is no checkpoint with
+    /// this id
+    #[func]
+    pub fn drop_checkpoints_before(&mut self, id: i64) -> i64 {
+        self.log_call("drop_checkpoints_before", &[id.to_variant()]);
+        match self.checkpoints.drop_before(id) {
+            Some(dropped) => dropped as i64,
+            None => {
+                godot_script_error!("No visibility checkpoint with id {}", id);
+                -1
+            }
+        }
+    }
+
+    /// One y-layer of fog as L8 pixels, x across and z down, shaded by the fog colors
+    fn fog_pixels(&self, y_layer: usize) -> PackedByteArray {
+        let (size_x, _, size_z) = self.occluded.size();
+        let shade = |color: Color| {
+            let luminance = 0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b;
+            (luminance.clamp(0.0, 1.0) * 255.0).round() as u8
+        };
+        let mut pixels = vec![shade(self.fog_unexplored_color); size_x * size_z];
+        let explored = shade(self.fog_explored_color);
+        self.explored
+            .for_each_set_in_slice(1, y_layer, |x, z| pixels[z * size_x + x] = explored);
+        let visible = shade(self.fog_visible_color);
+        self.visible
+            .for_each_set_in_slice(1, y_layer, |x, z| pixels[z * size_x + x] = visible);
+        PackedByteArray::from(pixels.as_slice())
+    }
+
+    /// A minimap image of one y-layer, one pixel per cell with x across and z down:
+    /// fog_visible_color where the last recompute sees, fog_explored_color where an earlier one
+    /// did and fog_unexplored_color elsewhere. Out of range layers give an empty image
+    #[func]
+    pub fn render_fog_image(&self, y_layer: i32) -> Gd<Image> {
+        let (size_x, size_y, size_z) = self.occluded.size();
+        if y_layer < 0 || y_layer as usize >= size_y || size_x == 0 || size_z == 0 {
+            godot_warn!("Layer {} is outside the grid", y_layer);
+            return Image::new_gd();
+        }
+        let pixels = self.fog_pixels(y_layer as usize);
+        Image::create_from_data(size_x as i32, size_z as i32, false, Format::L8, &pixels)
+            .unwrap_or_else(Image::new_gd)
+    }
+
+    /// render_fog_image() into an existing texture, updating it in place when its size and
+    /// format already match so materials using it stay valid
+    #[func]
+    pub fn update_fog_texture(&self, y_layer: i32, mut texture: Gd<ImageTexture>) {
+        let image = self.render_fog_image(y_layer);
+        if image.is_empty() {
+            return;
+        }
+        let same_size = texture.get_size() == image.get_size().cast_float();
+        if same_size && texture.get_format() == Format::L8 {
+            texture.update(&image);
+        } else {
+            texture.set_image(&image);
+        }
+    }
+
+    fn is_lit(&self, index: Index3) -> bool {
+        self.is_lit_from(index, self.eye())
+    }
+
+    /// is_lit() for an observer at `origin`, which innate light is around
+    fn is_lit_from(&self, index: Index3, origin: Vector3) -> bool {
+        let light = self.light_level.get(index).copied().unwrap_or(0.0);
+        light > self.darkness_threshold
+            || self.emissive.contains_key(&index)
+            || (self.use_innate_light
+                && index_to_position(index).distance_to(origin) <= self.innate_light_radius)
+    }
+
+    /// Drop the visible cells that are neither occluded nor next to an occluded cell,
+    /// for report_surfaces_only
+    fn keep_surfaces_only(&mut self) {
+        let origin = cell_index(self.origin);
+        let grid = self.occluded.grid();
+        let occluded = sight_source(&self.occlusion_source, &self.channels, &grid);
+        let terrain = self.terrain.as_ref();
+        let is_occluded = |index: Index3| {
+            occluded.is_occluded_at(index) || terrain.is_some_and(|terrain| terrain.occludes(index))
+        };
+        self.visible.retain_set(|index| {
+            index == origin
+                || is_occluded(index)
+                || face_neighbors(index).into_iter().any(is_occluded)
+        });
+    }
+
+    /// Recomposite visible AND lit, and signal the cells whose composite result changed
+    fn update_effective_visibility(&mut self) {
+        let mut effective = BitGrid::new(self.visible.size());
+        self.visible.for_each_set(|index| {
+            if self.is_lit(index) {
+                effective.set(index, true);
+            }
+        });
+
+        let mut revealed = PackedVector3Array::new();
+        let mut hidden = PackedVector3Array::new();
+        effective.for_each_difference(&self.effective_visible, |index, now_visible| {
+            if now_visible {
+                revealed.push(self.position_of(index));
+            } else {
+                hidden.push(self.position_of(index));
+            }
+        });
+        self.effective_visible = effective;
+
+        if !revealed.is_empty() || !hidden.is_empty() {
+            self.emit_deferred(
+                "effective_visibility_changed",
+                vec![revealed.to_variant(), hidden.to_variant()],
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_nodes_allocate_no_grid_words() {
+        let grids = default_grids();
+        assert!(grids.iter().all(|grid| grid.size() == DEFAULT_GRID_SIZE));
+        assert!(grids.iter().all(|grid| grid.memory_bytes() == 0));
+        let [occluded, ..] = grids;
+        assert_eq!(SharedGrid::new(occluded).memory_bytes(), 0);
+    }
 }
@@ -0,0 +1,220 @@
+use godot::prelude::*;
+use ndarray::{Array3, s};
+
+use crate::bitset::{BitGrid, Index3, cell_index, index_cell};
+
+/// Distance of cells further than the cap from every occluded cell
+pub const BEYOND_RADIUS: f32 = f32::INFINITY;
+
+/// Distances to the 13 neighbors a forward chamfer pass reads, those before a cell in x, then
+/// y, then z order. The backward pass reads the mirrored ones
+const CHAMFER: [(i32, i32, i32, f32); 13] = {
+    const A: f32 = 1.0;
+    const B: f32 = std::f32::consts::SQRT_2;
+    const C: f32 = 1.732_050_8;
+    [
+        (-1, -1, -1, C),
+        (-1, -1, 0, B),
+        (-1, -1, 1, C),
+        (-1, 0, -1, B),
+        (-1, 0, 0, A),
+        (-1, 0, 1, B),
+        (-1, 1, -1, C),
+        (-1, 1, 0, B),
+        (-1, 1, 1, C),
+        (0, -1, -1, B),
+        (0, -1, 0, A),
+        (0, -1, 1, B),
+        (0, 0, -1, A),
+    ]
+};
+
+/// Distance from every cell to the nearest occluded cell of a grid, up to a cap, brought up to
+/// date lazily after edits, see Display.get_distance_to_occluder()
+#[derive(Default)]
+pub struct DistanceField {
+    distances: Array3<f32>,
+    radius: usize,
+    // inclusive box of the cells edited since the distances were last brought up to date
+    dirty: Option<(Vector3i, Vector3i)>,
+    // whether every distance is out of date, as after the grid was replaced
+    dirty_all: bool,
+}
+
+impl DistanceField {
+    /// Cells in the inclusive box from `min` to `max` changed occlusion
+    pub fn invalidate_box(&mut self, min: Vector3i, max: Vector3i) {
+        self.dirty = Some(match self.dirty {
+            Some((dirty_min, dirty_max)) => (dirty_min.coord_min(min), dirty_max.coord_max(max)),
+            None => (min, max),
+        });
+    }
+
+    pub fn invalidate_all(&mut self) {
+        self.dirty_all = true;
+    }
+
+    /// The distances for `occluded` capped at `radius` cells, redone only around the cells
+    /// edited since the last call, or everywhere when the grid's size or the cap changed
+    pub fn update(&mut self, occluded: &BitGrid, radius: usize) -> &Array3<f32> {
+        let size = occluded.size();
+        if self.dirty_all || self.distances.dim() != size || self.radius != radius {
+            self.distances = Array3::from_elem(size, BEYOND_RADIUS);
+            self.radius = radius;
+            self.dirty_all = false;
+            self.dirty = None;
+            if size.0 > 0 && size.1 > 0 && size.2 > 0 {
+                let max = index_cell(size) - Vector3i::ONE;
+                self.recompute(occluded, Vector3i::ZERO, max);
+            }
+        } else if let Some((min, max)) = self.dirty.take() {
+            self.recompute(occluded, min, max);
+        }
+        &self.distances
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.distances.len() * size_of::<f32>()
+    }
+
+    /// Redo the distances of every cell within the cap of the inclusive box of edited cells.
+    /// Their nearest occluders lie within the cap of those cells, so the chamfer runs over the
+    /// box grown by twice the cap
+    fn recompute(&mut self, occluded: &BitGrid, edited_min: Vector3i, edited_max: Vector3i) {
+        let radius = self.radius as i32;
+        let (affected, _) = occluded.clip_box(
+            edited_min - Vector3i::splat(radius),
+            edited_max + Vector3i::splat(radius),
+        );
+        let (sources, _) = occluded.clip_box(
+            edited_min - Vector3i::splat(2 * radius),
+            edited_max + Vector3i::splat(2 * radius),
+        );
+        let (Some((affected_min, affected_max)), Some((min, max))) = (affected, sources) else {
+            return;
+        };
+
+        let dim = (max.0 - min.0 + 1, max.1 - min.1 + 1, max.2 - min.2 + 1);
+        let mut local = Array3::from_shape_fn(dim, |(x, y, z)| {
+            match occluded.get((min.0 + x, min.1 + y, min.2 + z)) == Some(true) {
+                true => 0.0,
+                false => f32::INFINITY,
+            }
+        });
+        let cells: Vec<Index3> = (0..dim.0)
+            .flat_map(|x| (0..dim.1).flat_map(move |y| (0..dim.2).map(move |z| (x, y, z))))
+            .collect();
+        let relax = |local: &mut Array3<f32>, (x, y, z): Index3, sign: i32| {
+            let mut best = local[(x, y, z)];
+            for (dx, dy, dz, distance) in CHAMFER {
+                let neighbor =
+                    index_cell((x, y, z)) + Vector3i::new(dx * sign, dy * sign, dz * sign);
+                if let Some(&through) = local.get(cell_index(neighbor)) {
+                    best = best.min(through + distance);
+                }
+            }
+            local[(x, y, z)] = best;
+        };
+        for &cell in &cells {
+            relax(&mut local, cell, 1);
+        }
+        for &cell in cells.iter().rev() {
+            relax(&mut local, cell, -1);
+        }
+
+        let offset = (
+            affected_min.0 - min.0,
+            affected_min.1 - min.1,
+            affected_min.2 - min.2,
+        );
+        let affected = local.slice(s![
+            offset.0..=offset.0 + affected_max.0 - affected_min.0,
+            offset.1..=offset.1 + affected_max.1 - affected_min.1,
+            offset.2..=offset.2 + affected_max.2 - affected_min.2,
+        ]);
+        self.distances
+            .slice_mut(s![
+                affected_min.0..=affected_max.0,
+                affected_min.1..=affected_max.1,
+                affected_min.2..=affected_max.2,
+            ])
+            .zip_mut_with(&affected, |distance, &local| {
+                *distance = match local <= radius as f32 {
+                    true => local,
+                    false => BEYOND_RADIUS,
+                };
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distances_are_in_cells_up_to_the_cap() {
+        let mut occluded = BitGrid::new((20, 20, 20));
+        occluded.set((10, 10, 10), true);
+        let distances = DistanceField::default().update(&occluded, 4).clone();
+        assert_eq!(distances[(10, 10, 10)], 0.0);
+        assert_eq!(distances[(11, 10, 10)], 1.0);
+        assert_eq!(distances[(14, 10, 10)], 4.0);
+        assert_eq!(distances[(10, 6, 10)], 4.0);
+        assert!((distances[(12, 12, 12)] - 2.0 * 3f32.sqrt()).abs() < 1e-5);
+        // Past the cap, and in a grid with nothing occluded at all
+        assert_eq!(distances[(15, 10, 10)], BEYOND_RADIUS);
+        assert_eq!(distances[(0, 0, 0)], BEYOND_RADIUS);
+        let empty = BitGrid::new((4, 4, 4));
+        let distances = DistanceField::default().update(&empty, 4).clone();
+        assert!(distances.iter().all(|&distance| distance == BEYOND_RADIUS));
+    }
+
+    #[test]
+    fn incremental_updates_match_a_full_rebuild() {
+        let n = 20;
+        let radius = 6;
+        let mut occluded = BitGrid::new((n, n, n));
+        let mut seed = 5u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (
+                seed as usize % n,
+                (seed >> 20) as usize % n,
+                (seed >> 40) as usize % n,
+            )
+        };
+        for _ in 0..15 {
+            occluded.set(next(), true);
+        }
+        let mut field = DistanceField::default();
+        field.update(&occluded, radius);
+        for edit in 0..20 {
+            // Add a cell, and every other edit take the first occluded one away
+            let added = next();
+            occluded.set(added, true);
+            field.invalidate_box(index_cell(added), index_cell(added));
+            let mut first = None;
+            occluded.for_each_set(|index| {
+                first = first.or(Some(index));
+            });
+            if let Some(removed) = first.filter(|_| edit % 2 == 0) {
+                occluded.set(removed, false);
+                field.invalidate_box(index_cell(removed), index_cell(removed));
+            }
+            let incremental = field.update(&occluded, radius).clone();
+            let rebuilt = DistanceField::default().update(&occluded, radius).clone();
+            assert_eq!(incremental, rebuilt, "after edit {edit}");
+        }
+    }
+
+    #[test]
+    fn changing_the_cap_rebuilds() {
+        let mut occluded = BitGrid::new((12, 12, 12));
+        occluded.set((2, 2, 2), true);
+        let mut field = DistanceField::default();
+        assert_eq!(field.update(&occluded, 3)[(8, 2, 2)], BEYOND_RADIUS);
+        assert_eq!(field.update(&occluded, 6)[(8, 2, 2)], 6.0);
+    }
+}
@@ -0,0 +1,322 @@
+use godot::{
+    builtin::real,
+    classes::{
+        Button, Control, EditorPlugin, EditorUndoRedoManager, HBoxContainer, HSlider,
+        IEditorPlugin, IVBoxContainer, InputEvent, InputEventMouseButton, InputEventMouseMotion,
+        Label, VBoxContainer, control::SizeFlags, editor_plugin::DockSlot,
+    },
+    global::MouseButton,
+    obj::WithBaseField,
+    prelude::*,
+};
+
+use crate::{bitset::UpAxis, display::Display, fov_result::FovResult};
+
+const EMPTY_COLOR: Color = Color::from_rgb(0.15, 0.15, 0.15);
+const OCCLUDED_COLOR: Color = Color::from_rgb(0.8, 0.8, 0.8);
+const PREVIEW_COLOR: Color = Color::from_rgba(1.0, 0.8, 0.2, 0.5);
+const CURSOR_COLOR: Color = Color::from_rgb(0.2, 0.6, 1.0);
+
+/// Adds the occlusion dock to the editor, editing whichever Display is selected
+#[derive(GodotClass)]
+#[class(tool, init, editor_plugin, base=EditorPlugin)]
+pub struct OcclusionEditorPlugin {
+    base: Base<EditorPlugin>,
+    dock: Option<Gd<OcclusionDock>>,
+}
+
+#[godot_api]
+impl IEditorPlugin for OcclusionEditorPlugin {
+    fn enter_tree(&mut self) {
+        let mut dock = OcclusionDock::new_alloc();
+        dock.set_name("Occlusion");
+        dock.bind_mut().undo_redo = self.base_mut().get_undo_redo();
+        self.base_mut()
+            .add_control_to_dock(DockSlot::RIGHT_UL, &dock);
+        self.dock = Some(dock);
+    }
+
+    fn exit_tree(&mut self) {
+        if let Some(mut dock) = self.dock.take() {
+            self.base_mut().remove_control_from_docks(&dock);
+            dock.queue_free();
+        }
+    }
+
+    fn handles(&self, object: Gd<Object>) -> bool {
+        object.try_cast::<Display>().is_ok()
+    }
+
+    fn edit(&mut self, object: Option<Gd<Object>>) {
+        let display = object.and_then(|object| object.try_cast::<Display>().ok());
+        if let Some(dock) = self.dock.as_mut() {
+            dock.bind_mut().set_display(display);
+        }
+    }
+}
+
+/// Shows a Display's occlusion one z-layer at a time as a grid of cells, with +y up,
+/// in the grid's own axes whatever the Display's up_axis.
+/// Left-clicking a cell toggles it through the Display's own functions, as an undoable action
+#[derive(GodotClass)]
+#[class(tool, init, base=VBoxContainer)]
+pub struct OcclusionDock {
+    base: Base<VBoxContainer>,
+    display: Option<Gd<Display>>,
+    undo_redo: Option<Gd<EditorUndoRedoManager>>,
+    layer: i32,
+    // cell under the mouse, in the current layer
+    cursor: Option<Vector3i>,
+    // visibility from the cursor, drawn over the grid until the next edit
+    preview: Option<Gd<FovResult>>,
+    layer_slider: Option<Gd<HSlider>>,
+    grid: Option<Gd<Control>>,
+    info: Option<Gd<Label>>,
+}
+
+#[godot_api]
+impl IVBoxContainer for OcclusionDock {
+    fn ready(&mut self) {
+        let this = self.to_gd();
+        let callable = |method: &str| Callable::from_object_method(&this, method);
+
+        let mut layer_row = HBoxContainer::new_alloc();
+        let mut layer_label = Label::new_alloc();
+        layer_label.set_text("Layer z");
+        layer_row.add_child(&layer_label);
+        let mut layer_slider = HSlider::new_alloc();
+        layer_slider.set_step(1.0);
+        layer_slider.set_h_size_flags(SizeFlags::EXPAND_FILL);
+        layer_slider.connect("value_changed", &callable("on_layer_changed"));
+        layer_row.add_child(&layer_slider);
+        self.base_mut().add_child(&layer_row);
+
+        let mut grid = Control::new_alloc();
+        grid.set_custom_minimum_size(Vector2::new(256.0, 256.0));
+        grid.set_v_size_flags(SizeFlags::EXPAND_FILL);
+        grid.set_clip_contents(true);
+        grid.connect("draw", &callable("draw_grid"));
+        grid.connect("gui_input", &callable("on_grid_input"));
+        self.base_mut().add_child(&grid);
+
+        let mut preview_button = Button::new_alloc();
+        preview_button.set_text("Preview FOV from cursor");
+        preview_button.connect("pressed", &callable("preview_from_cursor"));
+        self.base_mut().add_child(&preview_button);
+
+        let info = Label::new_alloc();
+        self.base_mut().add_child(&info);
+
+        self.layer_slider = Some(layer_slider);
+        self.grid = Some(grid);
+        self.info = Some(info);
+        self.refresh();
+    }
+}
+
+#[godot_api]
+impl OcclusionDock {
+    fn set_display(&mut self, display: Option<Gd<Display>>) {
+        self.display = display;
+        self.cursor = None;
+        self.preview = None;
+        self.refresh();
+    }
+
+    /// Fit the layer slider to the grid and redraw everything
+    fn refresh(&mut self) {
+        let size = self.grid_size();
+        self.layer = self.layer.clamp(0, (size.z - 1).max(0));
+        if let Some(layer_slider) = self.layer_slider.as_mut() {
+            layer_slider.set_max((size.z - 1).max(0) as f64);
+            layer_slider.set_value_no_signal(self.layer as f64);
+            layer_slider.set_editable(self.display.is_some());
+        }
+        self.update_info();
+        if let Some(grid) = self.grid.as_mut() {
+            grid.queue_redraw();
+        }
+    }
+
+    fn update_info(&mut self) {
+        let text = match (&self.display, self.cursor) {
+            (None, _) => "Select a Display to edit its occlusion".to_string(),
+            (Some(_), None) => "Left-click a cell to toggle its occlusion".to_string(),
+            (Some(display), Some(cursor)) => {
+                let cursor = display.bind().up_axis().from_grid(cursor);
+                let state = match display.bind().is_occluded(cursor) {
+                    true => "occluded",
+                    false => "empty",
+                };
+                format!("Cell {cursor}: {state}")
+            }
+        };
+        if let Some(info) = self.info.as_mut() {
+            info.set_text(text.as_str());
+        }
+    }
+
+    /// Size of the grid in its own axes, which the dock works in whatever the up axis
+    fn grid_size(&self) -> Vector3i {
+        self.display.as_ref().map_or(Vector3i::ZERO, |display| {
+            let display = display.bind();
+            display.up_axis().to_grid(display.get_grid_size())
+        })
+    }
+
+    fn up_axis(&self) -> UpAxis {
+        self.display
+            .as_ref()
+            .map_or(UpAxis::Y, |display| display.bind().up_axis())
+    }
+
+    /// Width of a cell on screen, fitting the whole layer into the grid control
+    fn cell_width(&self, size: Vector3i) -> real {
+        let Some(grid) = self.grid.as_ref() else {
+            return 0.0;
+        };
+        if size.x <= 0 || size.y <= 0 {
+            return 0.0;
+        }
+        let area = grid.get_size();
+        (area.x / size.x as real).min(area.y / size.y as real)
+    }
+
+    /// The cell of the current layer at a position on the grid control
+    fn cell_at(&self, position: Vector2) -> Option<Vector3i> {
+        let size = self.grid_size();
+        let cell_width = self.cell_width(size);
+        if cell_width <= 0.0 {
+            return None;
+        }
+        let x = (position.x / cell_width).floor() as i32;
+        let row = (position.y / cell_width).floor() as i32;
+        let cell = Vector3i::new(x, size.y - 1 - row, self.layer);
+        let in_layer = (0..size.x).contains(&cell.x) && (0..size.y).contains(&cell.y);
+        in_layer.then_some(cell)
+    }
+
+    /// Where a cell of the current layer is drawn on the grid control
+    fn cell_rect(&self, cell: Vector3i, size: Vector3i) -> Rect2 {
+        let cell_width = self.cell_width(size);
+        Rect2::new(
+            Vector2::new(
+                cell.x as real * cell_width,
+                (size.y - 1 - cell.y) as real * cell_width,
+            ),
+            Vector2::new(cell_width, cell_width),
+        )
+    }
+
+    #[func]
+    fn on_layer_changed(&mut self, value: f64) {
+        self.layer = value as i32;
+        self.cursor = None;
+        self.refresh();
+    }
+
+    #[func]
+    fn draw_grid(&mut self) {
+        let (Some(display), Some(mut grid)) = (self.display.clone(), self.grid.clone()) else {
+            return;
+        };
+        let size = self.grid_size();
+        if size.z <= 0 || self.cell_width(size) <= 0.0 {
+            return;
+        }
+        let occluded = display.bind().get_occlusion_layer(self.layer);
+        let up_axis = self.up_axis();
+        let preview = self.preview.as_ref().map(|preview| preview.bind());
+
+        for x in 0..size.x {
+            for y in 0..size.y {
+                let cell = Vector3i::new(x, y, self.layer);
+                let rect = self.cell_rect(cell, size);
+                let color = match occluded[(x * size.y + y) as usize] {
+                    0 => EMPTY_COLOR,
+                    _ => OCCLUDED_COLOR,
+                };
+                grid.draw_rect(rect, color);
+                if preview
+                    .as_ref()
+                    .is_some_and(|preview| preview.is_visible(up_axis.from_grid(cell)))
+                {
+                    grid.draw_rect(rect, PREVIEW_COLOR);
+                }
+            }
+        }
+
+        if let Some(cursor) = self.cursor {
+            grid.draw_rect_ex(self.cell_rect(cursor, size), CURSOR_COLOR)
+                .filled(false)
+                .width(2.0)
+                .done();
+        }
+    }
+
+    #[func]
+    fn on_grid_input(&mut self, event: Gd<InputEvent>) {
+        if let Ok(motion) = event.clone().try_cast::<InputEventMouseMotion>() {
+            let cursor = self.cell_at(motion.get_position());
+            if cursor != self.cursor {
+                self.cursor = cursor;
+                self.update_info();
+                if let Some(grid) = self.grid.as_mut() {
+                    grid.queue_redraw();
+                }
+            }
+        } else if let Ok(button) = event.try_cast::<InputEventMouseButton>() {
+            if button.is_pressed() && button.get_button_index() == MouseButton::LEFT {
+                if let Some(cell) = self.cell_at(button.get_position()) {
+                    self.toggle_occlusion(cell);
+                }
+            }
+        }
+    }
+
+    /// Toggle a cell with set_occluded() or carve_box(), as an action the editor can undo
+    fn toggle_occlusion(&mut self, cell: Vector3i) {
+        let (Some(display), Some(grid)) = (self.display.clone(), self.grid.clone()) else {
+            return;
+        };
+        let cell = self.up_axis().from_grid(cell);
+        let Some(undo_redo) = self.undo_redo.as_mut() else {
+            return;
+        };
+        let occluded = display.bind().is_occluded(cell);
+        let set = [cell.to_variant()];
+        let clear = [cell.to_variant(), cell.to_variant()];
+
+        undo_redo.create_action("Toggle occlusion");
+        match occluded {
+            true => {
+                undo_redo.add_do_method(&display, "carve_box", &clear);
+                undo_redo.add_undo_method(&display, "set_occluded", &set);
+            }
+            false => {
+                undo_redo.add_do_method(&display, "set_occluded", &set);
+                undo_redo.add_undo_method(&display, "carve_box", &clear);
+            }
+        }
+        // Redraw through the grid itself, since this dock is busy while the action commits
+        undo_redo.add_do_method(&grid, "queue_redraw", &[]);
+        undo_redo.add_undo_method(&grid, "queue_redraw", &[]);
+        undo_redo.commit_action();
+
+        self.preview = None;
+        self.update_info();
+    }
+
+    #[func]
+    fn preview_from_cursor(&mut self) {
+        let (Some(display), Some(cursor)) = (self.display.as_ref(), self.cursor) else {
+            godot_warn!("Hover a cell to preview the FOV from");
+            return;
+        };
+        let cursor = self.up_axis().from_grid(cursor);
+        self.preview = display.bind().compute_fov(cursor.cast_float());
+        if let Some(grid) = self.grid.as_mut() {
+            grid.queue_redraw();
+        }
+    }
+}
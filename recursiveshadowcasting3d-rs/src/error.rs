@@ -0,0 +1,121 @@
+use std::{
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use godot::{global::Error, prelude::*};
+
+/// Why an internal operation failed, for the #[func] that called it to report to the script
+#[derive(Debug)]
+pub enum ShadowcastError {
+    /// The script passed something that cannot be used, such as a channel name already taken
+    InvalidParameter(String),
+    /// Saved or shared data does not fit this grid, such as a patch taken from another size
+    InvalidData(String),
+    /// A scene or resource is not set up the way the node needs it
+    InvalidResource(String),
+    /// Rust code panicked partway through, which is a bug in this extension
+    Panicked(String),
+}
+
+impl ShadowcastError {
+    /// The Error a #[func] returns for this failure
+    pub fn code(&self) -> Error {
+        match self {
+            ShadowcastError::InvalidParameter(_) => Error::ERR_INVALID_PARAMETER,
+            ShadowcastError::InvalidData(_) => Error::ERR_INVALID_DATA,
+            ShadowcastError::InvalidResource(_) => Error::ERR_UNCONFIGURED,
+            ShadowcastError::Panicked(_) => Error::ERR_BUG,
+        }
+    }
+
+    /// Report the failure as a script error, returning its code
+    pub fn report(&self) -> Error {
+        godot_script_error!("{}", self);
+        self.code()
+    }
+}
+
+impl fmt::Display for ShadowcastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShadowcastError::InvalidParameter(message)
+            | ShadowcastError::InvalidData(message)
+            | ShadowcastError::InvalidResource(message) => write!(f, "{}", message),
+            ShadowcastError::Panicked(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+/// Run `f`, turning a panic into ShadowcastError::Panicked rather than letting it unwind into
+/// Godot. The caller must leave whatever `f` was changing in a consistent state.
+/// Builds that abort on panic, such as web exports, still abort
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, ShadowcastError> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .map_err(|payload| ShadowcastError::Panicked(panic_message(payload.as_ref())))
+}
+
+/// The message a panic was raised with
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    "panicked without a message".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_failure_returns_its_own_code() {
+        let message = || "what went wrong".to_string();
+        for (error, code) in [
+            (
+                ShadowcastError::InvalidParameter(message()),
+                Error::ERR_INVALID_PARAMETER,
+            ),
+            (
+                ShadowcastError::InvalidData(message()),
+                Error::ERR_INVALID_DATA,
+            ),
+            (
+                ShadowcastError::InvalidResource(message()),
+                Error::ERR_UNCONFIGURED,
+            ),
+            (ShadowcastError::Panicked(message()), Error::ERR_BUG),
+        ] {
+            assert_eq!(error.code(), code, "{error:?}");
+            assert_eq!(error.report(), code, "{error:?}");
+            assert!(error.to_string().ends_with("what went wrong"), "{error}");
+        }
+    }
+
+    #[test]
+    fn panics_are_caught_as_bugs_with_their_message() {
+        assert_eq!(catch_panic(|| 7).unwrap(), 7);
+        let raised = [
+            catch_panic(|| panic!("static message")),
+            catch_panic(|| panic!("formatted {}", "message")),
+            catch_panic(|| std::panic::panic_any(7)),
+        ];
+        let messages = [
+            "static message",
+            "formatted message",
+            "panicked without a message",
+        ];
+        for (raised, message) in raised.into_iter().zip(messages) {
+            let error = raised.unwrap_err();
+            assert_eq!(error.code(), Error::ERR_BUG);
+            assert!(
+                matches!(&error, ShadowcastError::Panicked(m) if m == message),
+                "{error:?}"
+            );
+            assert_eq!(error.to_string(), format!("Internal error: {message}"));
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+
+use godot::{builtin::real, prelude::*};
+
+use crate::{
+    bitset::{BitGrid, Index3, cell_index},
+    occlusion_source::OcclusionSource,
+    pass_cache::PassSettings,
+    shadowcast::{
+        Caster, DebugRect, OneWayCells, Pass, Rect, RectLimit, UnitPlane3d, all_passes, cast_light,
+        intersect_slope_rects,
+    },
+    terrain::Terrain,
+};
+
+/// Why a cell is or is not visible from an origin
+#[derive(Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Visible,
+    OutsideGrid,
+    /// Further from the origin than any pass reaches
+    OutOfRange,
+    /// Occluders cover all of the cell's face as seen from the origin
+    Occluded,
+    /// A cast narrowed to the cell reaches it, so something other than occluders along the way
+    /// kept it from being visible, such as how the full cast split its view or max_work_items
+    Reachable,
+}
+
+impl Verdict {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Verdict::Visible => "visible",
+            Verdict::OutsideGrid => "outside_grid",
+            Verdict::OutOfRange => "out_of_range",
+            Verdict::Occluded => "occluded",
+            Verdict::Reachable => "reachable",
+        }
+    }
+}
+
+/// One pass whose frustum holds part of the cell, cast only through the cell's face
+pub struct PassTrace {
+    /// Index into all_passes()
+    pub pass: usize,
+    pub plane: UnitPlane3d,
+    pub reverse_z: bool,
+    /// Layer of the cell along the pass's axis
+    pub depth: usize,
+    /// Pieces of the narrowed view left at the cell's layer, in plane-local coordinates
+    pub view_rects: Vec<Rect>,
+    pub reached: bool,
+    /// Occluded cells that cut into the narrowed view, in ascending x, then y, then z order
+    pub blockers: Vec<Index3>,
+    pub debug_rects: Vec<DebugRect>,
+}
+
+pub struct Explanation {
+    pub verdict: Verdict,
+    /// Largest distance along any axis from the origin
+    pub depth: usize,
+    pub passes: Vec<PassTrace>,
+}
+
+/// Explain whether `target` is visible from `origin` by casting each pass that holds part of it
+/// again, narrowed to the slopes through the target's face, and recording what blocks it.
+/// `visible` is whether the last full cast saw the target, and `size` the grid's
+#[allow(clippy::too_many_arguments)]
+pub fn explain_cell(
+    occluded: &dyn OcclusionSource,
+    size: Index3,
+    one_way: &OneWayCells,
+    terrain: Option<&Terrain>,
+    settings: &PassSettings,
+    origin: Vector3i,
+    target: Vector3i,
+    visible: bool,
+) -> Explanation {
+    let delta = target - origin;
+    let abs = delta.abs();
+    let mut explanation = Explanation {
+        verdict: Verdict::Visible,
+        depth: abs.x.max(abs.y).max(abs.z) as usize,
+        passes: Vec::new(),
+    };
+    let index = cell_index(target);
+    let mut scratch = BitGrid::new(size);
+    if scratch.get(index).is_none() {
+        explanation.verdict = Verdict::OutsideGrid;
+        return explanation;
+    }
+
+    let mut in_range = false;
+    for (pass, (initial_slope_rect, reverse_z, plane)) in all_passes().enumerate() {
+        let Some((slope_rect, depth)) =
+            narrow_to_cell((initial_slope_rect, reverse_z, plane), delta)
+        else {
+            continue;
+        };
+        if depth > settings.max_depth {
+            continue;
+        }
+        in_range = true;
+
+        let mut debug_rects = Vec::new();
+        let mut blockers = Vec::new();
+        let mut caster = Caster {
+            occluded,
+            visible: &mut scratch,
+            origin,
+            jitter: settings.eye_jitter,
+            max_depth: depth,
+            lod: settings.lod,
+            corner_rule: settings.corner_rule,
+            debug_rects: Some(&mut debug_rects),
+            lit_rects: None,
+            fractions: None,
+            bounds: None,
+            one_way: Some(one_way),
+            blockers: Some(&mut blockers),
+            terrain,
+            exposed: None,
+            rects: RectLimit::new(settings.max_rects, settings.narrow),
+        };
+        cast_light(&mut caster, &slope_rect, 1, reverse_z, &plane);
+
+        // View rects are drawn on the face of their layer nearest the origin
+        let local_z = plane.to_local(target).z as real;
+        let face = match reverse_z {
+            true => local_z + 0.5,
+            false => local_z - 0.5,
+        };
+        let view_rects = debug_rects
+            .iter()
+            .filter(|debug_rect| debug_rect.color == Color::CYAN && debug_rect.depth == face)
+            .map(|debug_rect| debug_rect.rect)
+            .collect();
+        let blockers: BTreeSet<Index3> = blockers.into_iter().collect();
+
+        explanation.passes.push(PassTrace {
+            pass,
+            plane,
+            reverse_z,
+            depth,
+            view_rects,
+            reached: scratch.get(index) == Some(true),
+            blockers: blockers.into_iter().collect(),
+            debug_rects,
+        });
+        scratch.clear();
+    }
+
+    let reached = explanation.passes.iter().any(|trace| trace.reached);
+    explanation.verdict = match (visible || target == origin, in_range, reached) {
+        (true, _, _) => Verdict::Visible,
+        (false, false, _) => Verdict::OutOfRange,
+        (false, true, true) => Verdict::Reachable,
+        (false, true, false) => Verdict::Occluded,
+    };
+    explanation
+}
+
+/// The slopes of a pass that look through the face of the cell at `delta` from the origin,
+/// and the cell's depth along the pass. None if the pass does not hold any of the cell
+fn narrow_to_cell(
+    (initial_slope_rect, reverse_z, plane): Pass,
+    delta: Vector3i,
+) -> Option<(Rect, usize)> {
+    let local = plane.to_local(delta);
+    let depth = match reverse_z {
+        true => -local.z,
+        false => local.z,
+    };
+    if depth < 1 {
+        return None;
+    }
+
+    // Signed distance to the face the scan tests the cell against, as in portal passes
+    let face = match reverse_z {
+        true => local.z as real + 0.5,
+        false => local.z as real - 0.5,
+    };
+    let inverse_slopes = |offset: i32| {
+        let a = (offset as real - 0.5) / face;
+        let b = (offset as real + 0.5) / face;
+        (a.min(b), a.max(b))
+    };
+    let (sx, ex) = inverse_slopes(local.x);
+    let (sy, ey) = inverse_slopes(local.y);
+    let footprint = Rect {
+        sx: 1.0 / sx,
+        sy: 1.0 / sy,
+        ex: 1.0 / ex,
+        ey: 1.0 / ey,
+    };
+    intersect_slope_rects(&initial_slope_rect, &footprint).map(|rect| (rect, depth as usize))
+}
@@ -0,0 +1,157 @@
+use godot::prelude::*;
+
+use crate::{
+    bitset::{BitGrid, cell_index, index_cell},
+    shadowcast::OneWayCells,
+};
+
+/// The 6 cells sharing a face with a cell
+const FACE_NEIGHBORS: [Vector3i; 6] = [
+    Vector3i::new(-1, 0, 0),
+    Vector3i::new(1, 0, 0),
+    Vector3i::new(0, -1, 0),
+    Vector3i::new(0, 1, 0),
+    Vector3i::new(0, 0, -1),
+    Vector3i::new(0, 0, 1),
+];
+
+/// The occluded cells of a grid with a face on an open cell, brought up to date lazily after
+/// edits. The others are buried in occluders that shadow everything they would, so scans can
+/// leave them out, see Caster::exposed. One-way cells count as open, as they let sight through
+/// from one side, and are always exposed themselves
+#[derive(Default)]
+pub struct ExposedCells {
+    exposed: BitGrid,
+    // inclusive box of the cells edited since the exposed cells were last brought up to date
+    dirty: Option<(Vector3i, Vector3i)>,
+    // whether every cell is out of date, as after the grid was replaced
+    dirty_all: bool,
+}
+
+impl ExposedCells {
+    /// Cells in the inclusive box from `min` to `max` changed occlusion, or became or stopped
+    /// being one-way
+    pub fn invalidate_box(&mut self, min: Vector3i, max: Vector3i) {
+        self.dirty = Some(match self.dirty {
+            Some((dirty_min, dirty_max)) => (dirty_min.coord_min(min), dirty_max.coord_max(max)),
+            None => (min, max),
+        });
+    }
+
+    pub fn invalidate_all(&mut self) {
+        self.dirty_all = true;
+    }
+
+    /// The exposed cells of `occluded`, redone only around the cells edited since the last
+    /// call, or everywhere when the grid's size changed
+    pub fn update(&mut self, occluded: &BitGrid, one_way: &OneWayCells) -> &BitGrid {
+        let size = occluded.size();
+        if self.dirty_all || self.exposed.size() != size {
+            self.exposed = BitGrid::new(size);
+            self.dirty_all = false;
+            self.dirty = None;
+            self.recompute(occluded, one_way, Vector3i::ZERO, index_cell(size));
+        } else if let Some((min, max)) = self.dirty.take() {
+            // An edit exposes or buries the cells beside it too
+            self.recompute(occluded, one_way, min - Vector3i::ONE, max + Vector3i::ONE);
+        }
+        &self.exposed
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.exposed.memory_bytes()
+    }
+
+    /// Redo whether each cell of the inclusive box is exposed
+    fn recompute(
+        &mut self,
+        occluded: &BitGrid,
+        one_way: &OneWayCells,
+        min: Vector3i,
+        max: Vector3i,
+    ) {
+        let (Some((min, max)), _) = occluded.clip_box(min, max) else {
+            return;
+        };
+        let open = |cell: Vector3i| {
+            let index = cell_index(cell);
+            occluded.get(index) != Some(true) || one_way.contains_key(&index)
+        };
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    let cell = index_cell((x, y, z));
+                    let exposed = occluded.get((x, y, z)) == Some(true)
+                        && (one_way.contains_key(&(x, y, z))
+                            || FACE_NEIGHBORS.iter().any(|&offset| open(cell + offset)));
+                    self.exposed.set((x, y, z), exposed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_keep_the_exposed_cells_as_a_rebuild_finds_them() {
+        let size = (9, 7, 11);
+        let mut seed = 0x5eed_u64;
+        let mut next = move |range: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 16) as usize % range
+        };
+        // Mostly solid, so that most occluded cells are buried
+        let mut occluded = BitGrid::new(size);
+        occluded.set_box((0, 0, 0), (8, 6, 10), true);
+        let mut one_way = OneWayCells::new();
+        let mut exposed = ExposedCells::default();
+        exposed.update(&occluded, &one_way);
+
+        for _ in 0..300 {
+            let cell = Vector3i::new(next(9) as i32, next(7) as i32, next(11) as i32);
+            let index = cell_index(cell);
+            match next(4) {
+                0 => {
+                    one_way.insert(index, Vector3i::new(0, 1, 0));
+                }
+                1 => {
+                    one_way.remove(&index);
+                }
+                value => {
+                    occluded.set(index, value == 2);
+                }
+            }
+            exposed.invalidate_box(cell, cell);
+            let updated = exposed.update(&occluded, &one_way).to_bytes();
+            let mut rebuilt = ExposedCells::default();
+            assert_eq!(updated, rebuilt.update(&occluded, &one_way).to_bytes());
+        }
+    }
+
+    #[test]
+    fn only_cells_touching_open_ones_are_exposed() {
+        let mut occluded = BitGrid::new((5, 5, 5));
+        occluded.set_box((0, 0, 0), (4, 4, 4), true);
+        let mut one_way = OneWayCells::new();
+        let mut exposed = ExposedCells::default();
+        // Only the cells on the grid's faces touch open cells, those past it
+        assert_eq!(exposed.update(&occluded, &one_way).count_set(), 125 - 27);
+
+        // A hole in the middle exposes the 6 cells around it
+        let middle = Vector3i::splat(2);
+        occluded.set(cell_index(middle), false);
+        exposed.invalidate_box(middle, middle);
+        assert_eq!(exposed.update(&occluded, &one_way).count_set(), 98 + 6);
+
+        // A one-way cell is exposed along with them
+        occluded.set(cell_index(middle), true);
+        one_way.insert(cell_index(middle), Vector3i::new(0, 1, 0));
+        exposed.invalidate_box(middle, middle);
+        assert_eq!(exposed.update(&occluded, &one_way).count_set(), 98 + 7);
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use godot::{builtin::real, prelude::*};
+
+use crate::{
+    bitset::BitGrid,
+    occlusion_source::OcclusionSource,
+    pass_cache::PassSettings,
+    portals::opening_pass,
+    shadowcast::{
+        Caster, INITIAL_SLOPE_RECTS, OneWayCells, RectLimit, UnitPlane3d, all_passes, cast_light,
+        intersect_slope_rects,
+    },
+    terrain::Terrain,
+};
+
+/// Horizontal layers of cells that block sight everywhere but at the holes cut into them, as
+/// the floors between the storeys of a building. Separators are keyed by their y, and their
+/// holes by (x, z)
+#[derive(Default)]
+pub struct FloorSeparators {
+    separators: BTreeMap<i32, BTreeSet<(i32, i32)>>,
+}
+
+impl FloorSeparators {
+    pub fn is_empty(&self) -> bool {
+        self.separators.is_empty()
+    }
+
+    /// Add a separator at `y` without holes. False if there already is one
+    pub fn add(&mut self, y: i32) -> bool {
+        if self.separators.contains_key(&y) {
+            return false;
+        }
+        self.separators.insert(y, BTreeSet::new());
+        true
+    }
+
+    /// False if there is no separator at `y`
+    pub fn remove(&mut self, y: i32) -> bool {
+        self.separators.remove(&y).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.separators.clear();
+    }
+
+    /// Cut a hole at `cell` into the separator at its y. False if there is no separator there
+    pub fn add_hole(&mut self, cell: Vector3i) -> bool {
+        let Some(holes) = self.separators.get_mut(&cell.y) else {
+            return false;
+        };
+        holes.insert((cell.x, cell.z));
+        true
+    }
+
+    /// Move every separator and hole by `-offset`, as BitGrid::shift() moves cells
+    pub fn shift(&mut self, offset: Vector3i) {
+        self.separators = std::mem::take(&mut self.separators)
+            .into_iter()
+            .map(|(y, holes)| {
+                let holes = holes
+                    .into_iter()
+                    .map(|(x, z)| (x - offset.x, z - offset.z))
+                    .collect();
+                (y - offset.y, holes)
+            })
+            .collect();
+    }
+
+    /// Whether a separator blocks sight at (x, y, z)
+    pub fn blocks(&self, x: i32, y: i32, z: i32) -> bool {
+        self.separators
+            .get(&y)
+            .is_some_and(|holes| !holes.contains(&(x, z)))
+    }
+
+    /// The y of the nearest separator strictly above `y`, or below it when `up` is false
+    fn nearest(&self, y: i32, up: bool) -> Option<i32> {
+        match up {
+            true => self.separators.range(y + 1..).next(),
+            false => self.separators.range(..y).next_back(),
+        }
+        .map(|(&y, _)| y)
+    }
+
+    /// The holes of the separator at `y`, merged into as few rectangles as rows of equal runs
+    /// allow, in the (z, x) axes of Portal::opening for openings along y
+    fn openings(&self, y: i32) -> Vec<Rect2> {
+        let Some(holes) = self.separators.get(&y) else {
+            return Vec::new();
+        };
+        // Runs along z of each row of holes, in ascending x then z
+        let mut runs: Vec<(i32, i32, i32)> = Vec::new();
+        for &(x, z) in holes {
+            match runs.last_mut() {
+                Some((run_x, _, end)) if *run_x == x && *end == z - 1 => *end = z,
+                _ => runs.push((x, z, z)),
+            }
+        }
+
+        // Runs spanning the same z in consecutive rows make one rectangle
+        let mut open: BTreeMap<(i32, i32), (i32, i32)> = BTreeMap::new();
+        let mut openings = Vec::new();
+        let rect = |(start, end): (i32, i32), (first_x, last_x): (i32, i32)| {
+            Rect2::new(
+                Vector2::new(start as real, first_x as real),
+                Vector2::new((end - start + 1) as real, (last_x - first_x + 1) as real),
+            )
+        };
+        for (x, start, end) in runs {
+            match open.get_mut(&(start, end)) {
+                Some((_, last_x)) if *last_x == x - 1 => *last_x = x,
+                _ => {
+                    if let Some(rows) = open.insert((start, end), (x, x)) {
+                        openings.push(rect((start, end), rows));
+                    }
+                }
+            }
+        }
+        openings.extend(open.into_iter().map(|(span, rows)| rect(span, rows)));
+        openings
+    }
+
+    /// Shadowcast from `origin` with every separator blocking sight but at its holes, as a cast
+    /// against SeparatedSource would. Passes along y stop at the nearest separator each way, and
+    /// what lies past it is cast through each of its openings as a pass narrowed to it, as
+    /// PortalGraph::cast() does through portals. The narrowed passes look from the origin's
+    /// center whatever the eye's jitter, and split the view up differently than casting plainly,
+    /// so a few cells right at shadow edges can differ
+    pub fn cast(
+        &self,
+        occluded: &dyn OcclusionSource,
+        one_way: &OneWayCells,
+        terrain: Option<&Terrain>,
+        visible: &mut BitGrid,
+        origin: Vector3i,
+        settings: &PassSettings,
+    ) {
+        let source = SeparatedSource {
+            inner: occluded,
+            floors: self,
+        };
+        let mut caster = Caster {
+            occluded: &source,
+            visible,
+            origin,
+            jitter: settings.eye_jitter,
+            max_depth: settings.max_depth,
+            lod: settings.lod,
+            corner_rule: settings.corner_rule,
+            debug_rects: None,
+            lit_rects: None,
+            fractions: None,
+            bounds: None,
+            one_way: Some(one_way),
+            blockers: None,
+            terrain,
+            exposed: None,
+            rects: RectLimit::new(settings.max_rects, settings.narrow),
+        };
+        caster.mark_origin_visible();
+
+        // Depth to the nearest separator each way, looking down then up
+        let separators = [false, true].map(|up| {
+            self.nearest(origin.y, up)
+                .map(|y| (y, y.abs_diff(origin.y) as usize))
+                .filter(|&(_, depth)| depth < settings.max_depth)
+        });
+        for (slope_rect, reverse_z, plane) in all_passes() {
+            caster.max_depth = match (plane, separators[!reverse_z as usize]) {
+                (UnitPlane3d::ZX, Some((_, depth))) => depth,
+                _ => settings.max_depth,
+            };
+            cast_light(&mut caster, &slope_rect, 1, reverse_z, &plane);
+        }
+
+        caster.max_depth = settings.max_depth;
+        for (y, up) in [(separators[0], false), (separators[1], true)] {
+            let Some((y, _)) = y else {
+                continue;
+            };
+            let step = if up { 1 } else { -1 };
+            let cells = [Vector3i::new(0, y - step, 0), Vector3i::new(0, y, 0)];
+            // Each opening is seen within the quadrants of the passes along y only, as passes
+            // along x and z see past it at shallower angles
+            for opening in self.openings(y) {
+                let Some((slope_rect, reverse_z, plane)) = opening_pass(cells, opening, origin)
+                else {
+                    continue;
+                };
+                for quadrant in &INITIAL_SLOPE_RECTS {
+                    if let Some(slope_rect) = intersect_slope_rects(quadrant, &slope_rect) {
+                        cast_light(&mut caster, &slope_rect, 1, reverse_z, &plane);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An occlusion source with the cells of every separator added, but for their holes
+pub struct SeparatedSource<'a> {
+    pub inner: &'a dyn OcclusionSource,
+    pub floors: &'a FloorSeparators,
+}
+
+impl OcclusionSource for SeparatedSource<'_> {
+    fn is_occluded(&self, x: i32, y: i32, z: i32) -> bool {
+        self.floors.blocks(x, y, z) || self.inner.is_occluded(x, y, z)
+    }
+
+    fn coarse_any_occluded(&self, min: [i32; 3], max: [i32; 3]) -> bool {
+        let area = (max[0] - min[0] + 1) as usize * (max[2] - min[2] + 1) as usize;
+        let separated = self
+            .floors
+            .separators
+            .range(min[1]..=max[1])
+            .any(|(_, holes)| {
+                let inside = holes
+                    .range((min[0], min[2])..=(max[0], max[2]))
+                    .filter(|(_, z)| (min[2]..=max[2]).contains(z))
+                    .count();
+                inside < area
+            });
+        separated || self.inner.coarse_any_occluded(min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitset::{Index3, index_cell},
+        shadowcast::tests::{caster, random_grid},
+    };
+
+    #[test]
+    fn holes_merge_into_rectangles() {
+        let mut floors = FloorSeparators::default();
+        assert!(floors.add(3));
+        assert!(!floors.add(3));
+        // A 2 by 3 stairwell, a lone hole and a row of 2 beside a row of 3
+        for (x, z) in [(1, 1), (1, 2), (1, 3), (2, 1), (2, 2), (2, 3)] {
+            floors.add_hole(Vector3i::new(x, 3, z));
+        }
+        floors.add_hole(Vector3i::new(5, 3, 5));
+        for (x, z) in [(7, 0), (7, 1), (8, 0), (8, 1), (8, 2)] {
+            floors.add_hole(Vector3i::new(x, 3, z));
+        }
+        assert!(!floors.add_hole(Vector3i::new(0, 4, 0)));
+
+        let mut openings: Vec<[real; 4]> = floors
+            .openings(3)
+            .iter()
+            .map(|rect| [rect.position.x, rect.position.y, rect.size.x, rect.size.y])
+            .collect();
+        openings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            openings,
+            [
+                [0.0, 7.0, 2.0, 1.0],
+                [0.0, 8.0, 3.0, 1.0],
+                [1.0, 1.0, 3.0, 2.0],
+                [5.0, 5.0, 1.0, 1.0],
+            ]
+        );
+        let cells: real = floors
+            .openings(3)
+            .iter()
+            .map(|rect| rect.size.x * rect.size.y)
+            .sum();
+        assert_eq!(cells, 12.0);
+        assert!(floors.blocks(0, 3, 0) && !floors.blocks(2, 3, 3) && !floors.blocks(2, 4, 0));
+    }
+
+    #[test]
+    fn casting_through_holes_sees_what_a_plain_cast_sees() {
+        let size: Index3 = (21, 21, 21);
+        let mut seed = 0x5eed_u64;
+        let occluded = random_grid(size, &mut seed);
+        let mut floors = FloorSeparators::default();
+        for y in [3, 8, 14] {
+            floors.add(y);
+        }
+        // A stairwell through every floor and a few single holes
+        for x in 9..12 {
+            for z in 4..6 {
+                for y in [3, 8, 14] {
+                    floors.add_hole(Vector3i::new(x, y, z));
+                }
+            }
+        }
+        for cell in [(2, 8, 17), (15, 14, 3), (16, 3, 16), (17, 3, 16)] {
+            floors.add_hole(index_cell(cell));
+        }
+
+        let settings = PassSettings {
+            max_depth: 12,
+            ..Default::default()
+        };
+        let mut next = move |range: i32| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            ((seed >> 16) % range as u64) as i32
+        };
+        for _ in 0..40 {
+            let origin = Vector3i::new(next(21), next(21), next(21));
+            let separated = SeparatedSource {
+                inner: &occluded,
+                floors: &floors,
+            };
+            let mut plain = BitGrid::new(size);
+            let mut caster = caster(&separated, &mut plain, origin);
+            caster.max_depth = settings.max_depth;
+            caster.rects = RectLimit::new(0, settings.narrow);
+            caster.cast_all();
+
+            let mut through_holes = BitGrid::new(size);
+            let one_way = OneWayCells::new();
+            floors.cast(
+                &occluded,
+                &one_way,
+                None,
+                &mut through_holes,
+                origin,
+                &settings,
+            );
+            let mut differences = Vec::new();
+            plain.for_each_difference(&through_holes, |index, in_plain| {
+                differences.push((index_cell(index), in_plain));
+            });
+            assert!(differences.is_empty(), "from {origin}: {differences:?}");
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use godot::{builtin::real, prelude::*};
+
+use crate::bitset::{BitGrid, UpAxis, cell_index};
+
+/// An immutable snapshot of what was visible from an origin, independent of the Display
+/// that computed it. Combine snapshots with intersect() and difference()
+#[derive(GodotClass)]
+#[class(tool, init, base=RefCounted)]
+pub struct FovResult {
+    base: Base<RefCounted>,
+    origin: Vector3i,
+    max_depth: usize,
+    visible: BitGrid,
+    /// The computing Display's up_axis, which positions are passed in and returned in
+    up_axis: UpAxis,
+}
+
+impl FovResult {
+    pub fn new_gd(
+        origin: Vector3i,
+        max_depth: usize,
+        visible: BitGrid,
+        up_axis: UpAxis,
+    ) -> Gd<Self> {
+        Gd::from_init_fn(|base| Self {
+            base,
+            origin,
+            max_depth,
+            visible,
+            up_axis,
+        })
+    }
+
+    /// A copy of this result with `combine` applied to its visibility and `other`'s
+    fn combined(&self, other: &Gd<FovResult>, combine: fn(&mut BitGrid, &BitGrid)) -> Gd<Self> {
+        let other = other.bind();
+        let mut visible = self.visible.clone();
+        if other.visible.size() == visible.size() {
+            combine(&mut visible, &other.visible);
+        } else {
+            godot_script_error!("FovResults were computed on grids of different sizes");
+        }
+        Self::new_gd(self.origin, self.max_depth, visible, self.up_axis)
+    }
+}
+
+#[godot_api]
+impl FovResult {
+    #[func]
+    pub fn get_origin(&self) -> Vector3i {
+        self.up_axis.from_grid(self.origin)
+    }
+
+    #[func]
+    pub fn get_max_depth(&self) -> i32 {
+        self.max_depth as i32
+    }
+
+    #[func]
+    pub fn is_visible(&self, pos: Vector3i) -> bool {
+        let index = cell_index(self.up_axis.to_grid(pos));
+        self.visible.get(index).unwrap_or(false)
+    }
+
+    /// Every visible cell, in ascending x, then y, then z order of the grid
+    #[func]
+    pub fn get_positions(&self) -> PackedVector3Array {
+        let mut positions = PackedVector3Array::new();
+        self.visible.for_each_set(|(x, y, z)| {
+            let position = Vector3::new(x as real, y as real, z as real);
+            positions.push(self.up_axis.from_grid(position))
+        });
+        positions
+    }
+
+    /// Number of visible cells
+    #[func]
+    pub fn count(&self) -> i64 {
+        self.visible.count_set() as i64
+    }
+
+    /// Cells visible in both results. The origin and max depth are kept from this one
+    #[func]
+    pub fn intersect(&self, other: Gd<FovResult>) -> Gd<FovResult> {
+        self.combined(&other, BitGrid::intersect_with)
+    }
+
+    /// Cells visible in this result but not in the other
+    #[func]
+    pub fn difference(&self, other: Gd<FovResult>) -> Gd<FovResult> {
+        self.combined(&other, BitGrid::subtract)
+    }
+}
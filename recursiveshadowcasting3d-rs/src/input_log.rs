@@ -0,0 +1,185 @@
+use godot::prelude::*;
+
+use crate::{bitset::BitGrid, error::ShadowcastError};
+
+/// Format version written by save_input_log(). Bump it whenever the saved fields change meaning
+pub const INPUT_LOG_VERSION: i64 = 1;
+
+/// The rolling visibility hash before any recompute, FNV-1a's offset basis
+pub const HASH_START: u64 = 0xCBF2_9CE4_8422_2325;
+
+/// Calls an input log may hold besides "set", which replay_input_log() refuses anything else of.
+/// Every one of them is logged by the method of that name
+pub const LOGGED_METHODS: [&str; 33] = [
+    "add_light_source_with_falloff",
+    "apply_change_patch",
+    "bake_lights",
+    "carve_box",
+    "clear_explored",
+    "clear_probability_seed",
+    "clear_terrain",
+    "create_channel",
+    "destroy_occluders_sphere",
+    "drop_checkpoints_before",
+    "paint_occlusion_cylinder",
+    "paint_occlusion_sphere",
+    "push_visibility_checkpoint",
+    "recompute_with_peek",
+    "remove_emissive",
+    "remove_light_source",
+    "restore_state",
+    "rollback_to_checkpoint",
+    "scroll_grid",
+    "seal_enclosed_regions",
+    "set_block_probability",
+    "set_emissive",
+    "set_light_channels",
+    "set_light_intensity",
+    "set_occluded",
+    "set_occluded_channel",
+    "set_occlusion_layer",
+    "set_one_way_occluder",
+    "set_origin_and_recompute",
+    "set_probability_seed",
+    "set_sight_channels",
+    "set_tag",
+    "set_terrain_heights",
+];
+
+/// A call recorded into an input log, with the rolling visibility hash after it for recomputes
+pub struct LoggedCall {
+    pub method: StringName,
+    pub args: VariantArray,
+    pub hash: Option<u64>,
+}
+
+/// The calls that changed a Display while it was recording, in order, see start_input_log()
+pub struct InputLog {
+    pub calls: Vec<LoggedCall>,
+    /// Whether calls are still being added, until stop_input_log()
+    pub recording: bool,
+    /// Exported properties as of the last time they were logged, to log only those that change
+    pub properties: Dictionary,
+    /// Rolling hash of the visibility after every recompute logged so far
+    pub hash: u64,
+}
+
+impl Default for InputLog {
+    fn default() -> Self {
+        Self {
+            calls: Vec::new(),
+            recording: true,
+            properties: Dictionary::new(),
+            hash: HASH_START,
+        }
+    }
+}
+
+impl InputLog {
+    pub fn push(&mut self, method: &str, args: &[Variant]) {
+        self.calls.push(LoggedCall {
+            method: StringName::from(method),
+            args: VariantArray::from(args),
+            hash: None,
+        });
+    }
+
+    /// Fold the visibility left by the last call into the rolling hash, and store the hash
+    /// with the call for replays to check against
+    pub fn record_hash(&mut self, visible: &BitGrid) {
+        self.hash = roll_hash(self.hash, visible);
+        if let Some(call) = self.calls.last_mut() {
+            call.hash = Some(self.hash);
+        }
+    }
+
+    /// The log as save_input_log() writes it: a Dictionary of "version" and "calls", an Array
+    /// of Dictionaries of "method", "args" and, for recomputes, "hash"
+    pub fn to_variant(&self) -> Variant {
+        let calls: VariantArray = self
+            .calls
+            .iter()
+            .map(|call| {
+                let mut entry = Dictionary::new();
+                entry.set("method", call.method.clone());
+                entry.set("args", call.args.clone());
+                if let Some(hash) = call.hash {
+                    entry.set("hash", hash as i64);
+                }
+                entry.to_variant()
+            })
+            .collect();
+        let mut log = Dictionary::new();
+        log.set("version", INPUT_LOG_VERSION);
+        log.set("calls", calls);
+        log.to_variant()
+    }
+}
+
+/// Read back the calls of a log written by InputLog::to_variant(). Calls to "set" must name one
+/// of `properties`, so a log cannot set anything it could not have recorded
+pub fn decode_calls(
+    log: &Variant,
+    properties: &[StringName],
+) -> Result<Vec<LoggedCall>, ShadowcastError> {
+    let invalid = |message: &str| ShadowcastError::InvalidData(format!("Input log {}", message));
+    let log = log
+        .try_to::<Dictionary>()
+        .map_err(|_| invalid("is not a Dictionary"))?;
+    let version = log
+        .get("version")
+        .and_then(|version| version.try_to::<i64>().ok());
+    if version != Some(INPUT_LOG_VERSION) {
+        return Err(invalid(&format!(
+            "has format version {:?}, but only version {} is supported",
+            version, INPUT_LOG_VERSION
+        )));
+    }
+    let calls = log
+        .get("calls")
+        .and_then(|calls| calls.try_to::<VariantArray>().ok())
+        .ok_or_else(|| invalid("has no calls"))?;
+
+    calls
+        .iter_shared()
+        .enumerate()
+        .map(|(i, entry)| {
+            let entry = entry
+                .try_to::<Dictionary>()
+                .map_err(|_| invalid(&format!("call {} is not a Dictionary", i)))?;
+            let method = entry
+                .get("method")
+                .and_then(|method| method.try_to::<StringName>().ok())
+                .ok_or_else(|| invalid(&format!("call {} has no method", i)))?;
+            let args = entry
+                .get("args")
+                .and_then(|args| args.try_to::<VariantArray>().ok())
+                .ok_or_else(|| invalid(&format!("call {} has no arguments", i)))?;
+            let allowed = match method.to_string().as_str() {
+                "set" => args
+                    .get(0)
+                    .and_then(|name| name.try_to::<StringName>().ok())
+                    .is_some_and(|name| properties.contains(&name)),
+                method => LOGGED_METHODS.contains(&method),
+            };
+            if !allowed {
+                return Err(invalid(&format!(
+                    "call {} to {} was never logged",
+                    i, method
+                )));
+            }
+            let hash = entry
+                .get("hash")
+                .and_then(|hash| hash.try_to::<i64>().ok())
+                .map(|hash| hash as u64);
+            Ok(LoggedCall { method, args, hash })
+        })
+        .collect()
+}
+
+/// Fold a visibility grid into a rolling hash with FNV-1a
+pub fn roll_hash(hash: u64, visible: &BitGrid) -> u64 {
+    visible.to_bytes().into_iter().fold(hash, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
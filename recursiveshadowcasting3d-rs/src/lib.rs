@@ -1,9 +1,43 @@
 use godot::prelude::*;
 
-mod display;
+mod bindings;
+mod bitset;
+mod channels;
+mod checkpoints;
+mod cone;
 mod debug_line_3d;
+pub mod display;
+mod distance_field;
+mod editor;
+mod error;
+mod explain;
+mod exposed;
+mod floors;
+mod fov_result;
+mod input_log;
+mod lights;
+mod line_of_sight;
+mod occlusion_grid;
+pub mod occlusion_source;
+mod pass_cache;
+mod patch;
+mod peek;
+mod perf_hud;
+mod portals;
+mod probability;
+mod profile_scene;
+mod propagation;
+mod reload;
+mod sampler_import;
+mod shadowcast;
+mod snapshot;
+mod state;
+mod terrain;
+mod views;
+mod visibility_chunks;
+mod watches;
 
 struct Rogue3dRustExtension;
 
 #[gdextension]
-unsafe impl ExtensionLibrary for Rogue3dRustExtension {}
\ No newline at end of file
+unsafe impl ExtensionLibrary for Rogue3dRustExtension {}
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+
+use godot::{builtin::real, prelude::*};
+use ndarray::{Array3, Zip, s};
+
+use crate::{
+    bitset::{BitGrid, Index3},
+    occlusion_source::OcclusionSource,
+    shadowcast::{Caster, CornerRule, Lod, OneWayCells, RectLimit},
+    terrain::Terrain,
+};
+
+/// How a light fades with distance, out to its radius. No light reaches past the radius
+#[derive(GodotConvert, Clone, Copy, Default, PartialEq)]
+#[godot(via = i64)]
+pub enum Falloff {
+    /// Fades linearly towards 0 just past the radius, so the furthest cells in range still
+    /// get a little
+    #[default]
+    Linear,
+    /// Intensity over 1 + distance squared, like real light without blowing up at the source
+    InverseSquare,
+    /// Bands of equal width out to the radius, the first at full intensity and every later
+    /// one dimmer by an equal share, like the bright and dim light of classic roguelikes
+    Stepped,
+}
+
+pub struct LightSource {
+    pub id: i64,
+    pub position: Vector3i,
+    pub radius: usize,
+    pub intensity: real,
+    /// Tint of the light added up in light colors, white for plain light
+    pub color: Color,
+    pub falloff: Falloff,
+    /// Number of bands for Falloff::Stepped, at least 1
+    pub steps: usize,
+}
+
+impl LightSource {
+    /// Light received at a cell this far away, within the radius
+    pub fn falloff(&self, distance: real) -> real {
+        self.intensity * self.fraction(distance)
+    }
+
+    /// falloff() at an intensity of 1
+    fn fraction(&self, distance: real) -> real {
+        let radius = self.radius as real;
+        match self.falloff {
+            Falloff::Linear => 1.0 - distance / (radius + 1.0),
+            Falloff::InverseSquare => 1.0 / (1.0 + distance * distance),
+            Falloff::Stepped => {
+                let steps = self.steps.max(1);
+                // Each band includes its outer edge, and the light's own cell is in the first
+                let band = (distance / radius * steps as real).ceil() as usize;
+                let band = band.saturating_sub(1).min(steps - 1);
+                1.0 - band as real / steps as real
+            }
+        }
+    }
+
+    /// Everything the light's shadowcast and falloff depend on, which is all but its intensity
+    fn key(&self) -> LightKey {
+        LightKey {
+            position: [self.position.x, self.position.y, self.position.z],
+            radius: self.radius,
+            falloff: self.falloff as u8,
+            steps: self.steps,
+        }
+    }
+
+    /// Outer edges of the light's bands, ending at the radius.
+    /// Only Falloff::Stepped has more than one band
+    pub fn band_radii(&self) -> Vec<real> {
+        let radius = self.radius as real;
+        match self.falloff {
+            Falloff::Stepped => {
+                let steps = self.steps.max(1);
+                (1..=steps)
+                    .map(|band| radius * band as real / steps as real)
+                    .collect()
+            }
+            _ => vec![radius],
+        }
+    }
+}
+
+/// Light given off by a cell itself, such as lava or a lit window
+#[derive(Clone, Copy)]
+pub struct Emitter {
+    pub color: Color,
+    pub intensity: real,
+}
+
+/// Emitting cells, ordered by cell
+pub type Emitters = BTreeMap<Index3, Emitter>;
+
+/// Most shadowcasts per light that a soft bake may take
+pub const MAX_SOFT_SAMPLES: usize = 64;
+
+/// Eye offsets within the light's cell for soft shadow samples, from a Halton sequence
+/// starting at index `seed`. Offsets stay a little inside the cell, so no sample sits on a face
+pub fn soft_sample_offsets(count: usize, seed: u64) -> Vec<Vector3> {
+    fn halton(mut index: u64, base: u64) -> real {
+        let mut result = 0.0;
+        let mut fraction = 1.0;
+        while index > 0 {
+            fraction /= base as real;
+            result += fraction * (index % base) as real;
+            index /= base;
+        }
+        result
+    }
+
+    (0..count as u64)
+        .map(|i| {
+            // Halton index 0 is the cell's corner, so start at 1
+            let index = seed.wrapping_add(i).wrapping_add(1);
+            Vector3::new(halton(index, 2), halton(index, 3), halton(index, 5)) * 0.9
+                - Vector3::splat(0.45)
+        })
+        .collect()
+}
+
+/// Size `levels` to the grid and set every cell to 0
+pub fn clear_light_levels<T: Clone + Default>(occluded: &BitGrid, levels: &mut Array3<T>) {
+    let size = occluded.size();
+    if levels.dim() != size {
+        *levels = Array3::default(size);
+    } else {
+        levels.fill(T::default());
+    }
+}
+
+/// A light as the cache tells lights apart, so lights differing only in intensity or color
+/// share a cast
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct LightKey {
+    position: [i32; 3],
+    radius: usize,
+    falloff: u8,
+    steps: usize,
+}
+
+impl LightKey {
+    /// Whether the light's radius reaches into the inclusive box from `min` to `max`
+    fn reaches(&self, min: Vector3i, max: Vector3i) -> bool {
+        let radius = self.radius as i32;
+        self.position
+            .iter()
+            .zip([min.x, min.y, min.z].iter().zip([max.x, max.y, max.z]))
+            .all(|(&center, (&min, max))| center - radius <= max && center + radius >= min)
+    }
+}
+
+/// Light a source gives at an intensity of 1, averaged over the soft samples, in the box of
+/// cells its radius reaches
+struct Contribution {
+    /// Grid cell of `levels[(0, 0, 0)]`
+    min: Index3,
+    levels: Array3<real>,
+}
+
+/// What every light gave in the last bake_lights(), so a bake where only intensities or colors
+/// changed, such as flickering torches, scales the light already cast rather than casting it
+/// again.
+/// Edits that change what blocks light must be reported with invalidate_box() or
+/// invalidate_all()
+#[derive(Default)]
+pub struct LightCache {
+    size: Index3,
+    jitters: Vec<Vector3>,
+    contributions: BTreeMap<LightKey, Contribution>,
+}
+
+impl LightCache {
+    /// Drop the light of every source whose radius reaches into the inclusive box
+    pub fn invalidate_box(&mut self, min: Vector3i, max: Vector3i) {
+        self.contributions.retain(|key, _| !key.reaches(min, max));
+    }
+
+    pub fn invalidate_all(&mut self) {
+        self.contributions.clear();
+    }
+
+    /// Get ready to bake `lights` into a grid of `size` with an eye at each of `jitters`,
+    /// forgetting lights that are gone. Returns the lights that must be cast again,
+    /// one of each where several share everything but intensity and color
+    pub fn retarget<'a>(
+        &mut self,
+        size: Index3,
+        jitters: &[Vector3],
+        lights: impl IntoIterator<Item = &'a LightSource>,
+    ) -> Vec<&'a LightSource> {
+        if self.size != size || self.jitters != jitters {
+            self.size = size;
+            self.jitters = jitters.to_vec();
+            self.contributions.clear();
+        }
+        let lights: BTreeMap<LightKey, &LightSource> = lights
+            .into_iter()
+            .map(|light| (light.key(), light))
+            .collect();
+        self.contributions.retain(|key, _| lights.contains_key(key));
+        lights
+            .into_iter()
+            .filter(|(key, _)| !self.contributions.contains_key(key))
+            .map(|(_, light)| light)
+            .collect()
+    }
+
+    /// Shadowcast from each of `lights`, with its eye offset by `jitter`, adding its light at
+    /// an intensity of 1 times `weight` to what the cache holds for it. `occluded` is read within
+    /// the size the cache was last retargeted to
+    pub fn cast(
+        &mut self,
+        occluded: &dyn OcclusionSource,
+        one_way: &OneWayCells,
+        terrain: Option<&Terrain>,
+        lights: &[&LightSource],
+        jitter: Vector3,
+        weight: real,
+    ) {
+        let mut lit = BitGrid::new(self.size);
+        for light in lights {
+            let radius = Vector3i::splat(light.radius as i32);
+            let (clipped, _) = lit.clip_box(light.position - radius, light.position + radius);
+            let contribution =
+                self.contributions
+                    .entry(light.key())
+                    .or_insert_with(|| match clipped {
+                        Some((min, max)) => Contribution {
+                            min,
+                            levels: Array3::zeros((
+                                max.0 - min.0 + 1,
+                                max.1 - min.1 + 1,
+                                max.2 - min.2 + 1,
+                            )),
+                        },
+                        None => Contribution {
+                            min: (0, 0, 0),
+                            levels: Array3::zeros((0, 0, 0)),
+                        },
+                    });
+            let Some((min, max)) = clipped else {
+                continue;
+            };
+
+            lit.clear();
+            Caster {
+                occluded,
+                visible: &mut lit,
+                origin: light.position,
+                jitter,
+                max_depth: light.radius,
+                lod: Lod::default(),
+                corner_rule: CornerRule::default(),
+                debug_rects: None,
+                lit_rects: None,
+                fractions: None,
+                bounds: None,
+                one_way: Some(one_way),
+                blockers: None,
+                terrain,
+                exposed: None,
+                rects: RectLimit::default(),
+            }
+            .cast_all();
+
+            let center = light.position.cast_float();
+            lit.for_each_set_in_box(min, max, |(x, y, z)| {
+                let distance = Vector3::new(x as real, y as real, z as real).distance_to(center);
+                if distance <= light.radius as real {
+                    contribution.levels[(x - min.0, y - min.1, z - min.2)] +=
+                        weight * light.fraction(distance);
+                }
+            });
+        }
+    }
+
+    /// Add the light of every one of `lights` at its intensity to `levels`, and tinted by its
+    /// color as red, green and blue to `colors`. Each must have been cast since the cache was
+    /// last retargeted or invalidated
+    pub fn accumulate<'a>(
+        &self,
+        lights: impl IntoIterator<Item = &'a LightSource>,
+        levels: &mut Array3<real>,
+        colors: &mut Array3<[real; 3]>,
+    ) {
+        for light in lights {
+            let Some(contribution) = self.contributions.get(&light.key()) else {
+                continue;
+            };
+            let (x, y, z) = contribution.min;
+            let (size_x, size_y, size_z) = contribution.levels.dim();
+            let slice = s![x..x + size_x, y..y + size_y, z..z + size_z];
+            levels
+                .slice_mut(slice)
+                .scaled_add(light.intensity, &contribution.levels);
+            let tint = [light.color.r, light.color.g, light.color.b]
+                .map(|channel| channel as real * light.intensity);
+            Zip::from(colors.slice_mut(slice))
+                .and(&contribution.levels)
+                .for_each(|color, &level| {
+                    for (color, tint) in color.iter_mut().zip(tint) {
+                        *color += tint * level;
+                    }
+                });
+        }
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.contributions
+            .values()
+            .map(|contribution| contribution.levels.len() * size_of::<real>())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light(falloff: Falloff, steps: usize) -> LightSource {
+        LightSource {
+            id: 0,
+            position: Vector3i::new(6, 6, 6),
+            radius: 4,
+            intensity: 2.0,
+            color: Color::WHITE,
+            falloff,
+            steps,
+        }
+    }
+
+    /// Light levels of a bake of just `light` in an empty grid
+    fn baked(light: &LightSource) -> Array3<real> {
+        let occluded = BitGrid::new((13, 13, 13));
+        let mut cache = LightCache::default();
+        let to_cast = cache.retarget(occluded.size(), &[Vector3::ZERO], [light]);
+        let one_way = OneWayCells::new();
+        cache.cast(&occluded, &one_way, None, &to_cast, Vector3::ZERO, 1.0);
+        let mut levels = Array3::zeros((0, 0, 0));
+        let mut colors = Array3::default((0, 0, 0));
+        clear_light_levels(&occluded, &mut levels);
+        clear_light_levels(&occluded, &mut colors);
+        cache.accumulate([light], &mut levels, &mut colors);
+        levels
+    }
+
+    #[test]
+    fn each_falloff_at_the_light_its_radius_and_past_it() {
+        let close = |a: real, b: real| (a - b).abs() < 1e-6;
+        let linear = light(Falloff::Linear, 1);
+        let inverse_square = light(Falloff::InverseSquare, 1);
+        // Two bands of 2 cells, each including its outer edge
+        let stepped = light(Falloff::Stepped, 2);
+        for (light, at_radius) in [
+            (&linear, 2.0 / 5.0),
+            (&inverse_square, 2.0 / 17.0),
+            (&stepped, 1.0),
+        ] {
+            assert_eq!(light.falloff(0.0), 2.0);
+            assert!(close(light.falloff(4.0), at_radius));
+
+            // A bake gives falloff() out to the radius and nothing just past it, along an axis
+            // and off the axes at √17 cells against √16
+            let levels = baked(light);
+            for distance in 0..=4 {
+                assert_eq!(
+                    levels[(6 + distance, 6, 6)],
+                    light.falloff(distance as real)
+                );
+            }
+            assert_eq!(levels[(11, 6, 6)], 0.0);
+            assert_eq!(levels[(10, 7, 6)], 0.0);
+            assert_eq!(levels[(6, 7, 10)], 0.0);
+            assert!(levels[(9, 8, 6)] > 0.0);
+        }
+        assert!(close(linear.falloff(2.0), 2.0 * 3.0 / 5.0));
+        assert!(close(inverse_square.falloff(2.0), 2.0 / 5.0));
+        assert_eq!(stepped.falloff(2.0), 2.0);
+        assert_eq!(stepped.falloff(2.01), 1.0);
+    }
+
+    #[test]
+    fn band_radii_end_at_the_radius() {
+        assert_eq!(light(Falloff::Stepped, 2).band_radii(), [2.0, 4.0]);
+        assert_eq!(
+            light(Falloff::Stepped, 3).band_radii(),
+            [4.0 / 3.0, 8.0 / 3.0, 4.0]
+        );
+        // No steps counts as one band
+        assert_eq!(light(Falloff::Stepped, 0).band_radii(), [4.0]);
+        assert_eq!(light(Falloff::Linear, 3).band_radii(), [4.0]);
+        assert_eq!(light(Falloff::InverseSquare, 3).band_radii(), [4.0]);
+    }
+
+    #[test]
+    fn intensity_and_color_changes_reuse_the_cast() {
+        let occluded = BitGrid::new((13, 13, 13));
+        let one_way = OneWayCells::new();
+        let mut cache = LightCache::default();
+        let mut torch = light(Falloff::Linear, 1);
+        let to_cast = cache.retarget(occluded.size(), &[Vector3::ZERO], [&torch]);
+        assert_eq!(to_cast.len(), 1);
+        cache.cast(&occluded, &one_way, None, &to_cast, Vector3::ZERO, 1.0);
+
+        torch.intensity = 0.5;
+        torch.color = Color::from_rgb(1.0, 0.5, 0.0);
+        assert!(
+            cache
+                .retarget(occluded.size(), &[Vector3::ZERO], [&torch])
+                .is_empty()
+        );
+        let mut levels = Array3::zeros((0, 0, 0));
+        let mut colors = Array3::default((0, 0, 0));
+        clear_light_levels(&occluded, &mut levels);
+        clear_light_levels(&occluded, &mut colors);
+        cache.accumulate([&torch], &mut levels, &mut colors);
+        for distance in 0..=4 {
+            let level = torch.falloff(distance as real);
+            assert_eq!(levels[(6 + distance, 6, 6)], level);
+            assert_eq!(colors[(6 + distance, 6, 6)], [level, level * 0.5, 0.0]);
+        }
+        assert_eq!(colors[(11, 6, 6)], [0.0; 3]);
+    }
+}
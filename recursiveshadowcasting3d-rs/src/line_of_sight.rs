@@ -0,0 +1,636 @@
+use godot::{builtin::real, prelude::*};
+
+use crate::{
+    bitset::{BitGrid, cell_index, index_cell},
+    occlusion_source::OcclusionSource,
+    pass_cache::PassSettings,
+    shadowcast::{
+        Caster, CornerRule, OneWayCells, RectLimit, all_passes, cast_light, pass_may_touch_box,
+    },
+    terrain::Terrain,
+};
+
+/// Which of `targets` a full cast from `from` with `settings` would see, without doing the full
+/// cast: only the passes that can reach a target run, and only as deep as the furthest target.
+/// Shallower layers are scanned exactly as in the full cast, so the answers always agree.
+/// `scratch` must be the size of the grid, and is left holding the partial cast
+pub fn visible_targets(
+    occluded: &dyn OcclusionSource,
+    one_way: &OneWayCells,
+    terrain: Option<&Terrain>,
+    scratch: &mut BitGrid,
+    settings: &PassSettings,
+    from: Vector3i,
+    targets: &[Vector3i],
+) -> Vec<bool> {
+    let depth_of = |target: Vector3i| {
+        let delta = (target - from).abs();
+        delta.x.max(delta.y).max(delta.z) as usize
+    };
+    let reachable: Vec<Vector3i> = targets
+        .iter()
+        .copied()
+        .filter(|&target| {
+            scratch.get(cell_index(target)).is_some() && depth_of(target) <= settings.max_depth
+        })
+        .collect();
+    let Some(max_depth) = reachable.iter().map(|&target| depth_of(target)).max() else {
+        return vec![false; targets.len()];
+    };
+
+    // Every cell a cast this deep can mark, plus the slack the depth scan reads around views
+    let reach = Vector3i::splat(max_depth as i32 + 2);
+    if let (Some((min, max)), _) = scratch.clip_box(from - reach, from + reach) {
+        scratch.set_box(min, max, false);
+    }
+
+    let mut caster = Caster {
+        occluded,
+        visible: scratch,
+        origin: from,
+        jitter: settings.eye_jitter,
+        max_depth,
+        lod: settings.lod,
+        corner_rule: settings.corner_rule,
+        debug_rects: None,
+        lit_rects: None,
+        fractions: None,
+        bounds: None,
+        one_way: Some(one_way),
+        blockers: None,
+        terrain,
+        exposed: None,
+        rects: RectLimit::new(settings.max_rects, settings.narrow),
+    };
+    caster.mark_origin_visible();
+    for pass in all_passes() {
+        let needed = reachable
+            .iter()
+            .any(|&target| pass_may_touch_box(pass, from, max_depth, target, target));
+        if needed {
+            let (initial_slope_rect, reverse_z, plane) = pass;
+            cast_light(&mut caster, &initial_slope_rect, 1, reverse_z, &plane);
+        }
+    }
+
+    targets
+        .iter()
+        .map(|&target| {
+            reachable.contains(&target) && scratch.get(cell_index(target)).unwrap_or(false)
+        })
+        .collect()
+}
+
+/// A cell of the inclusive box from `min` to `max` that a full cast from `from` with `settings`
+/// would see, or None if it would see none of them. Casts as visible_targets() does, skipping the
+/// passes that cannot reach the box and stopping after the first one that sees into it.
+/// The cell returned is the first in x, then y, then z order that this pass saw.
+/// `scratch` must be the size of the grid, and is left holding the partial cast
+#[allow(clippy::too_many_arguments)]
+pub fn visible_in_box(
+    occluded: &dyn OcclusionSource,
+    one_way: &OneWayCells,
+    terrain: Option<&Terrain>,
+    scratch: &mut BitGrid,
+    settings: &PassSettings,
+    from: Vector3i,
+    min: Vector3i,
+    max: Vector3i,
+) -> Option<Vector3i> {
+    // Only the part of the box within the grid and in range can be seen
+    let furthest = (min - from).abs().coord_max((max - from).abs());
+    let max_depth = (furthest.x.max(furthest.y).max(furthest.z) as usize).min(settings.max_depth);
+    let depth = Vector3i::splat(max_depth as i32);
+    let (min, max) = (min.coord_max(from - depth), max.coord_min(from + depth));
+    if min.coord_min(max) != min {
+        return None;
+    }
+    let (Some((min_index, max_index)), _) = scratch.clip_box(min, max) else {
+        return None;
+    };
+    let (min, max) = (index_cell(min_index), index_cell(max_index));
+
+    // Every cell a cast this deep can mark, plus the slack the depth scan reads around views
+    let reach = Vector3i::splat(max_depth as i32 + 2);
+    if let (Some((reach_min, reach_max)), _) = scratch.clip_box(from - reach, from + reach) {
+        scratch.set_box(reach_min, reach_max, false);
+    }
+
+    let mut caster = Caster {
+        occluded,
+        visible: scratch,
+        origin: from,
+        jitter: settings.eye_jitter,
+        max_depth,
+        lod: settings.lod,
+        corner_rule: settings.corner_rule,
+        debug_rects: None,
+        lit_rects: None,
+        fractions: None,
+        bounds: None,
+        one_way: Some(one_way),
+        blockers: None,
+        terrain,
+        exposed: None,
+        rects: RectLimit::new(settings.max_rects, settings.narrow),
+    };
+    caster.mark_origin_visible();
+    let first_seen = |grid: &BitGrid| {
+        let mut first = None;
+        grid.for_each_set_in_box(min_index, max_index, |index| {
+            first = first.or(Some(index_cell(index)));
+        });
+        first
+    };
+    if let Some(cell) = first_seen(caster.visible) {
+        return Some(cell);
+    }
+    for pass in all_passes() {
+        if !pass_may_touch_box(pass, from, max_depth, min, max) {
+            continue;
+        }
+        let (initial_slope_rect, reverse_z, plane) = pass;
+        cast_light(&mut caster, &initial_slope_rect, 1, reverse_z, &plane);
+        if let Some(cell) = first_seen(caster.visible) {
+            return Some(cell);
+        }
+    }
+    None
+}
+
+/// One cell a segment passes through, as supercover() lists it
+pub struct PathCell {
+    pub cell: Vector3i,
+    /// Whether the segment only touches the cell's edge or corner, passing exactly between it
+    /// and its neighbours
+    pub grazed: bool,
+}
+
+/// Every cell the segment from `from` to `to` touches, in order along it, starting with the cell
+/// holding `from` and ending with the one holding `to`. Where the segment passes exactly through
+/// an edge or corner, the cells around it that it only touches are listed as grazed before the
+/// cell it goes on into. Cells span half a unit either side of whole coordinates, as in the grid
+pub fn supercover(from: Vector3, to: Vector3) -> Vec<PathCell> {
+    let mut path = Vec::new();
+    walk_supercover(from, to, |cell| {
+        path.push(cell);
+        true
+    });
+    path
+}
+
+/// Call `visit` with every cell supercover() lists, in the same order but without collecting
+/// them, until it returns false. Returns whether it never did
+pub fn walk_supercover(
+    from: Vector3,
+    to: Vector3,
+    mut visit: impl FnMut(PathCell) -> bool,
+) -> bool {
+    // Shifted by half a cell, cell bounds fall on whole numbers
+    let start = [
+        from.x as f64 + 0.5,
+        from.y as f64 + 0.5,
+        from.z as f64 + 0.5,
+    ];
+    let end = [to.x as f64 + 0.5, to.y as f64 + 0.5, to.z as f64 + 0.5];
+    let mut cell = start.map(|value| value.floor() as i32);
+    let last = end.map(|value| value.floor() as i32);
+
+    // Per axis, the direction cells step in, how far along the segment (from 0 to 1) the next
+    // bound is, and how far apart bounds are
+    let mut step = [0; 3];
+    let mut next_bound = [f64::INFINITY; 3];
+    let mut bound_spacing = [f64::INFINITY; 3];
+    for axis in 0..3 {
+        let delta = end[axis] - start[axis];
+        if delta > 0.0 {
+            step[axis] = 1;
+            next_bound[axis] = (cell[axis] as f64 + 1.0 - start[axis]) / delta;
+            bound_spacing[axis] = 1.0 / delta;
+        } else if delta < 0.0 {
+            step[axis] = -1;
+            next_bound[axis] = (cell[axis] as f64 - start[axis]) / delta;
+            bound_spacing[axis] = -1.0 / delta;
+        }
+    }
+
+    let as_vector = |cell: [i32; 3]| Vector3i::new(cell[0], cell[1], cell[2]);
+    let mut visit = |cell: [i32; 3], grazed: bool| {
+        visit(PathCell {
+            cell: as_vector(cell),
+            grazed,
+        })
+    };
+    if !visit(cell, false) {
+        return false;
+    }
+    while cell != last {
+        let t = next_bound.iter().copied().fold(f64::INFINITY, f64::min);
+        if t > 1.0 + 1e-9 {
+            break;
+        }
+        // Bounds crossed at the same point make an edge or corner
+        let mut crossed = [0; 3];
+        let mut crossed_count = 0;
+        for (axis, bound) in next_bound.iter().enumerate() {
+            if bound - t <= 1e-9 {
+                crossed[crossed_count] = axis;
+                crossed_count += 1;
+            }
+        }
+        // The cells stepped into along fewer of the crossed axes come first
+        let full_mask = (1u32 << crossed_count) - 1;
+        for stepped_axes in 1..crossed_count as u32 {
+            for mask in (1..full_mask).filter(|mask| mask.count_ones() == stepped_axes) {
+                let mut grazed = cell;
+                for (bit, &axis) in crossed[..crossed_count].iter().enumerate() {
+                    if (mask >> bit) & 1 == 1 {
+                        grazed[axis] += step[axis];
+                    }
+                }
+                if !visit(grazed, true) {
+                    return false;
+                }
+            }
+        }
+        for &axis in &crossed[..crossed_count] {
+            cell[axis] += step[axis];
+            next_bound[axis] += bound_spacing[axis];
+        }
+        if !visit(cell, false) {
+            return false;
+        }
+    }
+    true
+}
+
+/// The first cell after the one holding `from` that a ray from `from` along `direction` enters
+/// and `stops` is true for, with the distance to where it enters it, or None if the ray goes
+/// `max_distance` without one. Where the ray passes exactly through an edge or corner it goes
+/// straight on into the diagonal cell, skipping those it only touches. `direction` may be of
+/// any length but zero
+pub fn march_ray(
+    from: Vector3,
+    direction: Vector3,
+    max_distance: f64,
+    mut stops: impl FnMut(Vector3i) -> bool,
+) -> Option<(Vector3i, f64)> {
+    let start = [from.x as f64, from.y as f64, from.z as f64];
+    let length = [direction.x as f64, direction.y as f64, direction.z as f64]
+        .iter()
+        .map(|value| value * value)
+        .sum::<f64>()
+        .sqrt();
+    let direction = [
+        direction.x as f64 / length,
+        direction.y as f64 / length,
+        direction.z as f64 / length,
+    ];
+    // Cell bounds lie half a unit either side of whole coordinates
+    let mut cell = start.map(|value| (value + 0.5).floor() as i32);
+
+    // Per axis as in supercover(), but with distances along the ray
+    let mut step = [0; 3];
+    let mut next_bound = [f64::INFINITY; 3];
+    let mut bound_spacing = [f64::INFINITY; 3];
+    for axis in 0..3 {
+        if direction[axis] > 0.0 {
+            step[axis] = 1;
+            next_bound[axis] = (cell[axis] as f64 + 0.5 - start[axis]) / direction[axis];
+            bound_spacing[axis] = 1.0 / direction[axis];
+        } else if direction[axis] < 0.0 {
+            step[axis] = -1;
+            next_bound[axis] = (cell[axis] as f64 - 0.5 - start[axis]) / direction[axis];
+            bound_spacing[axis] = -1.0 / direction[axis];
+        }
+    }
+
+    loop {
+        let distance = next_bound.iter().copied().fold(f64::INFINITY, f64::min);
+        if distance > max_distance {
+            return None;
+        }
+        for axis in 0..3 {
+            if next_bound[axis] - distance <= 1e-9 {
+                cell[axis] += step[axis];
+                next_bound[axis] += bound_spacing[axis];
+            }
+        }
+        let cell = Vector3i::new(cell[0], cell[1], cell[2]);
+        if stops(cell) {
+            return Some((cell, distance));
+        }
+    }
+}
+
+/// Points along each axis of a cell that cast_rays() aims at: its center and, just inside them,
+/// its faces
+const RAY_TARGETS: [real; 3] = [-0.495, 0.0, 0.495];
+
+/// Mark in `visible` every cell within `settings.max_depth` layers of `from` that a straight ray
+/// from the eye reaches, a brute-force stand-in for a full cast that beats the 24 passes when
+/// the depth is tiny. Rays go from the eye, moved by `settings.eye_jitter`, to the 27 points of
+/// a 3 by 3 by 3 lattice in each cell, and are stopped by any occluded cell they pass through
+/// other than the eye's and the target's, and under CornerRule::Block by any occluded cell they
+/// graze at an edge or corner, as the cast welds occluders together there. Terrain and one-way
+/// cells block as in the cast, while lod, narrow pieces and max_rects do not apply.
+/// Open cells come out as a cast with NarrowPolicy::Continue sees them from the eyes of the golden
+/// fixtures, but rays also see floors and walls at grazing angles, which the cast leaves out.
+/// Returns how many rays were traced
+pub fn cast_rays(
+    occluded: &dyn OcclusionSource,
+    one_way: Option<&OneWayCells>,
+    terrain: Option<&Terrain>,
+    visible: &mut BitGrid,
+    settings: &PassSettings,
+    from: Vector3i,
+) -> usize {
+    let size = visible.size();
+    let in_grid = |cell: Vector3i| {
+        let (x, y, z) = cell_index(cell);
+        x < size.0 && y < size.1 && z < size.2
+    };
+    // As Caster::is_occluded() and Caster::sees_through() decide it
+    let blocks = |cell: Vector3i| {
+        if !in_grid(cell) {
+            return false;
+        }
+        let index = cell_index(cell);
+        let occludes = occluded.is_occluded(cell.x, cell.y, cell.z)
+            || terrain.is_some_and(|terrain| terrain.occludes(index));
+        let sees_through = one_way
+            .and_then(|one_way| one_way.get(&index))
+            .is_some_and(|open| {
+                let delta = cell - from;
+                delta.x * open.x + delta.y * open.y + delta.z * open.z > 0
+            });
+        occludes && !sees_through
+    };
+    let block_corners = settings.corner_rule == CornerRule::Block;
+    let eye = from.cast_float() + settings.eye_jitter;
+    let mut rays = 0;
+    let mut reaches = |target: Vector3i, aim: Vector3| {
+        rays += 1;
+        walk_supercover(eye, aim, |path_cell| {
+            let blocker =
+                path_cell.cell != target && path_cell.cell != from && blocks(path_cell.cell);
+            // A ray running exactly along an occluder's edge passes it only under Allow
+            match path_cell.grazed {
+                true => !(block_corners && blocker),
+                false => !blocker,
+            }
+        })
+    };
+
+    let depth = settings.max_depth as i32;
+    visible.set(cell_index(from), true);
+    for x in -depth..=depth {
+        for y in -depth..=depth {
+            for z in -depth..=depth {
+                let target = from + Vector3i::new(x, y, z);
+                if target == from || !in_grid(target) {
+                    continue;
+                }
+                let center = target.cast_float();
+                let seen = RAY_TARGETS.iter().any(|&dx| {
+                    RAY_TARGETS.iter().any(|&dy| {
+                        RAY_TARGETS
+                            .iter()
+                            .any(|&dz| reaches(target, center + Vector3::new(dx, dy, dz)))
+                    })
+                });
+                if seen {
+                    visible.set(cell_index(target), true);
+                }
+            }
+        }
+    }
+    rays
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shadowcast::{
+        NarrowPolicy, NarrowRects,
+        tests::{FIXTURES, caster, fixture, random_grid},
+    };
+
+    /// The settings of shadowcast's test caster(), at a depth of 8
+    fn settings() -> PassSettings {
+        PassSettings {
+            max_depth: 8,
+            narrow: NarrowRects {
+                min_width: 0.25,
+                policy: NarrowPolicy::Snap,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// A cell of the test grid or up to 2 cells past it
+    fn around_grid(next: &mut impl FnMut(i32) -> i32) -> Vector3i {
+        Vector3i::new(next(24) - 2, next(24) - 2, next(24) - 2)
+    }
+
+    #[test]
+    fn targets_and_boxes_agree_with_the_full_cast() {
+        let size = (20, 20, 20);
+        let mut seed = 0x4242;
+        let occluded = random_grid(size, &mut seed);
+        let settings = settings();
+        let mut scratch = BitGrid::new(size);
+        let mut next = move |range: i32| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            ((seed >> 16) % range as u64) as i32
+        };
+        let (mut seen, mut boxes_seen) = (0, 0);
+        for _ in 0..30 {
+            let from = Vector3i::new(next(20), next(20), next(20));
+            if occluded.get(cell_index(from)) != Some(false) {
+                continue;
+            }
+            let mut full = BitGrid::new(size);
+            let mut full_cast = caster(&occluded, &mut full, from);
+            full_cast.max_depth = settings.max_depth;
+            full_cast.cast_all();
+
+            // Targets anywhere around the grid, some out of range and some outside it
+            let targets: Vec<Vector3i> = (0..40).map(|_| around_grid(&mut next)).collect();
+            let all = visible_targets(
+                &occluded,
+                &OneWayCells::new(),
+                None,
+                &mut scratch,
+                &settings,
+                from,
+                &targets,
+            );
+            for (&target, seen_with_all) in targets.iter().zip(all) {
+                let expected = full.get(cell_index(target)) == Some(true);
+                let alone = visible_targets(
+                    &occluded,
+                    &OneWayCells::new(),
+                    None,
+                    &mut scratch,
+                    &settings,
+                    from,
+                    &[target],
+                )[0];
+                assert_eq!(seen_with_all, expected, "from {from} to {target}");
+                assert_eq!(alone, expected, "from {from} to {target}");
+                seen += usize::from(expected);
+            }
+
+            for _ in 0..20 {
+                let min = around_grid(&mut next);
+                let max = min + Vector3i::new(next(4), next(4), next(4));
+                let found = visible_in_box(
+                    &occluded,
+                    &OneWayCells::new(),
+                    None,
+                    &mut scratch,
+                    &settings,
+                    from,
+                    min,
+                    max,
+                );
+                let (clipped, _) = full.clip_box(min, max);
+                let any = clipped.is_some_and(|(min, max)| full.count_in_box(min, max) > 0);
+                assert_eq!(found.is_some(), any, "from {from} into {min} to {max}");
+                if let Some(cell) = found {
+                    assert_eq!(cell.coord_max(min).coord_min(max), cell);
+                    assert_eq!(full.get(cell_index(cell)), Some(true));
+                    boxes_seen += 1;
+                }
+            }
+        }
+        assert!(seen > 20 && boxes_seen > 20, "{seen} {boxes_seen}");
+    }
+
+    #[test]
+    fn supercover_lists_the_cells_a_segment_only_grazes() {
+        let cells = |from: Vector3, to: Vector3| -> Vec<(Vector3i, bool)> {
+            supercover(from, to)
+                .into_iter()
+                .map(|cell| (cell.cell, cell.grazed))
+                .collect()
+        };
+        let v = Vector3i::new;
+        assert_eq!(
+            cells(Vector3::ZERO, Vector3::new(3.0, 0.0, 0.0)),
+            [0, 1, 2, 3].map(|x| (v(x, 0, 0), false))
+        );
+        assert_eq!(
+            cells(Vector3::ZERO, Vector3::new(2.0, 1.0, 0.0)),
+            [
+                (v(0, 0, 0), false),
+                (v(1, 0, 0), false),
+                (v(1, 1, 0), false),
+                (v(2, 1, 0), false),
+            ]
+        );
+        // Through an edge, then a corner, where the cells touched by fewer axes come first
+        assert_eq!(
+            cells(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 0.0)),
+            [
+                (v(0, 0, 0), false),
+                (v(1, 0, 0), true),
+                (v(0, 1, 0), true),
+                (v(1, 1, 0), false),
+            ]
+        );
+        let corner = cells(Vector3::new(0.0, 0.0, 0.0), Vector3::new(-1.0, 1.0, 1.0));
+        assert_eq!(corner.len(), 8);
+        assert_eq!(corner[0], (v(0, 0, 0), false));
+        assert_eq!(
+            corner[1..4],
+            [(v(-1, 0, 0), true), (v(0, 1, 0), true), (v(0, 0, 1), true)]
+        );
+        assert_eq!(
+            corner[4..7],
+            [(v(-1, 1, 0), true), (v(-1, 0, 1), true), (v(0, 1, 1), true)]
+        );
+        assert_eq!(corner[7], (v(-1, 1, 1), false));
+    }
+
+    #[test]
+    fn march_ray_stops_where_it_enters_a_cell() {
+        let (cell, distance) =
+            march_ray(Vector3::ZERO, Vector3::new(3.0, 0.0, 0.0), 10.0, |cell| {
+                cell.x == 4
+            })
+            .unwrap();
+        assert_eq!(cell, Vector3i::new(4, 0, 0));
+        assert!((distance - 3.5).abs() < 1e-9);
+        assert!(
+            march_ray(
+                Vector3::ZERO,
+                Vector3::new(0.0, -1.0, 0.0),
+                3.0,
+                |cell| cell.y == -4
+            )
+            .is_none()
+        );
+
+        // Through an edge, straight into the diagonal cell without the ones it touches
+        let mut entered = Vec::new();
+        let stop = march_ray(Vector3::ZERO, Vector3::new(1.0, 1.0, 0.0), 10.0, |cell| {
+            entered.push(cell);
+            cell.x == 2
+        });
+        assert_eq!(entered, [Vector3i::new(1, 1, 0), Vector3i::new(2, 2, 0)]);
+        let (_, distance) = stop.unwrap();
+        assert!((distance - 1.5 * 2f64.sqrt()).abs() < 1e-9);
+
+        // From off center, the first cell is entered at the nearest face along the ray
+        let (cell, distance) = march_ray(
+            Vector3::new(0.25, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, -2.0),
+            10.0,
+            |_| true,
+        )
+        .unwrap();
+        assert_eq!(cell, Vector3i::new(0, 0, -1));
+        assert!((distance - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rays_see_the_open_cells_the_cast_sees_at_small_depths() {
+        for name in FIXTURES {
+            let (occluded, origin, _) = fixture(name);
+            for max_depth in 1..=3 {
+                let mut cast = BitGrid::new(occluded.size());
+                let mut full_cast = caster(&occluded, &mut cast, origin);
+                full_cast.max_depth = max_depth;
+                // Rays leave narrow pieces out, which Snap would widen
+                full_cast.rects.narrow.policy = NarrowPolicy::Continue;
+                full_cast.cast_all();
+                let mut rays = BitGrid::new(occluded.size());
+                let settings = PassSettings {
+                    max_depth,
+                    ..settings()
+                };
+                cast_rays(&occluded, None, None, &mut rays, &settings, origin);
+
+                let mut differences = Vec::new();
+                rays.for_each_difference(&cast, |index, seen_by_rays| {
+                    if occluded.get(index) == Some(false) {
+                        differences.push((index_cell(index) - origin, seen_by_rays));
+                    }
+                });
+                assert!(
+                    differences.is_empty(),
+                    "{name} at depth {max_depth}: {differences:?}"
+                );
+                let depth = Vector3i::splat(max_depth as i32);
+                let (Some((min, max)), _) = rays.clip_box(origin - depth, origin + depth) else {
+                    panic!("{name}: the origin is outside the grid");
+                };
+                assert_eq!(rays.count_in_box(min, max), rays.count_set(), "{name}");
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    collections::VecDeque,
+    rc::Rc,
+};
+
+use godot::prelude::*;
+
+use crate::bitset::{BitGrid, Index3};
+
+/// How many edits a shared grid remembers the boxes of. A node that falls further behind than
+/// this drops everything it cached of the grid instead of only what the edits touched
+const CHANGE_LOG_LEN: usize = 64;
+
+struct GridData {
+    grid: BitGrid,
+    generation: u64,
+    // the generation each recent edit bumped the grid to, with the inclusive box it touched,
+    // None for edits that may have touched any cell
+    changes: VecDeque<(u64, Option<(Vector3i, Vector3i)>)>,
+}
+
+/// An occlusion grid one or more Displays read and edit together. Clones are handles to the
+/// same grid. Every edit bumps a generation counter, which each node compares against the one
+/// it last saw to drop what it cached of the cells other nodes edited.
+///
+/// The grid is not Send, so it can only ever be touched from the thread that made it, the main
+/// thread for any grid a Display holds. Work on other threads reads snapshots, such as an
+/// FovResult or a grid cloned out of grid(), never the shared grid itself
+#[derive(Clone)]
+pub struct SharedGrid(Rc<RefCell<GridData>>);
+
+impl SharedGrid {
+    pub fn new(grid: BitGrid) -> Self {
+        Self(Rc::new(RefCell::new(GridData {
+            grid,
+            generation: 0,
+            changes: VecDeque::new(),
+        })))
+    }
+
+    /// The grid, which must be let go of before anything edits it
+    pub fn grid(&self) -> Ref<'_, BitGrid> {
+        Ref::map(self.0.borrow(), |data| &data.grid)
+    }
+
+    /// The grid to edit. Taking `&mut self` keeps a node from editing it while still reading it
+    pub fn grid_mut(&mut self) -> RefMut<'_, BitGrid> {
+        RefMut::map(self.0.borrow_mut(), |data| &mut data.grid)
+    }
+
+    pub fn size(&self) -> Index3 {
+        self.grid().size()
+    }
+
+    pub fn get(&self, index: Index3) -> Option<bool> {
+        self.grid().get(index)
+    }
+
+    pub fn set(&mut self, index: Index3, value: bool) -> bool {
+        self.grid_mut().set(index, value)
+    }
+
+    pub fn set_box(&mut self, min: Index3, max: Index3, value: bool) {
+        self.grid_mut().set_box(min, max, value);
+    }
+
+    pub fn shift(&mut self, offset: Vector3i) {
+        self.grid_mut().shift(offset);
+    }
+
+    pub fn fill_unset_in_both(&mut self, other: &BitGrid) -> usize {
+        self.grid_mut().fill_unset_in_both(other)
+    }
+
+    /// Swap in another grid, which may be of another size
+    pub fn replace(&mut self, grid: BitGrid) {
+        self.0.borrow_mut().grid = grid;
+    }
+
+    pub fn count_set(&self) -> usize {
+        self.grid().count_set()
+    }
+
+    pub fn count_in_box(&self, min: Index3, max: Index3) -> usize {
+        self.grid().count_in_box(min, max)
+    }
+
+    pub fn is_box_full(&self, min: Index3, max: Index3) -> bool {
+        self.grid().is_box_full(min, max)
+    }
+
+    pub fn clip_box(&self, from: Vector3i, to: Vector3i) -> (Option<(Index3, Index3)>, bool) {
+        self.grid().clip_box(from, to)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.grid().to_bytes()
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.grid().memory_bytes()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.0.borrow().generation
+    }
+
+    /// Note an edit within the inclusive box, or anywhere for None, returning the generation
+    /// it bumped the grid to
+    pub fn record_change(&self, change: Option<(Vector3i, Vector3i)>) -> u64 {
+        let mut data = self.0.borrow_mut();
+        data.generation += 1;
+        let generation = data.generation;
+        if data.changes.len() == CHANGE_LOG_LEN {
+            data.changes.pop_front();
+        }
+        data.changes.push_back((generation, change));
+        generation
+    }
+
+    /// The boxes of the edits after `generation`, oldest first. None when the log no longer
+    /// goes back that far, so any cell may have changed
+    pub fn changes_since(&self, generation: u64) -> Option<Vec<Option<(Vector3i, Vector3i)>>> {
+        let data = self.0.borrow();
+        if generation >= data.generation {
+            return Some(Vec::new());
+        }
+        let oldest = data.changes.front().map_or(u64::MAX, |&(oldest, _)| oldest);
+        if oldest > generation + 1 {
+            return None;
+        }
+        Some(
+            data.changes
+                .iter()
+                .filter(|&&(changed_at, _)| changed_at > generation)
+                .map(|&(_, change)| change)
+                .collect(),
+        )
+    }
+
+    /// Whether both handles lead to the same grid
+    pub fn ptr_eq(&self, other: &SharedGrid) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Default for SharedGrid {
+    fn default() -> Self {
+        Self::new(BitGrid::new((0, 0, 0)))
+    }
+}
+
+/// An occlusion grid to assign to the occlusion_grid of several Displays, so they keep one copy
+/// of the map between them. A grid that was never assigned takes on the cells of the first
+/// Display it is assigned to. Only the default channel is shared, and the grid is not saved with
+/// the resource, see Display.save_state() for that
+#[derive(GodotClass)]
+#[class(tool, init, base=Resource)]
+pub struct OcclusionGrid3D {
+    base: Base<Resource>,
+    pub grid: SharedGrid,
+}
+
+#[godot_api]
+impl OcclusionGrid3D {
+    /// Number of cells along each axis, in the grid's own axes. Zero until first assigned
+    #[func]
+    pub fn get_grid_size(&self) -> Vector3i {
+        let (x, y, z) = self.grid.size();
+        Vector3i::new(x as i32, y as i32, z as i32)
+    }
+
+    /// Counts up with every edit through any Display it is assigned to
+    #[func]
+    pub fn get_generation(&self) -> i64 {
+        self.grid.generation() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::GridState;
+
+    fn cell_box(x: i32) -> Option<(Vector3i, Vector3i)> {
+        Some((Vector3i::new(x, 0, 0), Vector3i::new(x, 1, 1)))
+    }
+
+    #[test]
+    fn handles_share_edits_and_generations() {
+        let mut first = SharedGrid::new(BitGrid::new((4, 4, 4)));
+        let second = first.clone();
+        assert!(first.ptr_eq(&second));
+        assert!(!first.ptr_eq(&SharedGrid::new(BitGrid::new((4, 4, 4)))));
+
+        first.set((1, 2, 3), true);
+        assert_eq!(first.record_change(cell_box(1)), 1);
+        assert_eq!(second.get((1, 2, 3)), Some(true));
+        assert_eq!(second.generation(), 1);
+        assert_eq!(second.changes_since(0), Some(vec![cell_box(1)]));
+        assert_eq!(second.changes_since(1), Some(Vec::new()));
+
+        second.record_change(None);
+        assert_eq!(first.generation(), 2);
+        assert_eq!(first.changes_since(0), Some(vec![cell_box(1), None]));
+    }
+
+    #[test]
+    fn changes_since_forgets_past_the_log() {
+        let grid = SharedGrid::default();
+        for x in 0..CHANGE_LOG_LEN as i32 + 1 {
+            grid.record_change(cell_box(x));
+        }
+        let last = CHANGE_LOG_LEN as u64 + 1;
+        assert_eq!(grid.generation(), last);
+        // The first edit fell out of the log, so only nodes that saw it still get boxes
+        assert_eq!(grid.changes_since(0), None);
+        let changes = grid.changes_since(1).unwrap();
+        assert_eq!(changes.len(), CHANGE_LOG_LEN);
+        assert_eq!(changes[0], cell_box(1));
+        assert_eq!(
+            grid.changes_since(last - 1),
+            Some(vec![cell_box(last as i32 - 1)])
+        );
+        assert_eq!(grid.changes_since(last), Some(Vec::new()));
+    }
+
+    #[test]
+    fn grid_copied_through_its_bytes_is_edited_apart() {
+        // A duplicated Display gets the grid through grid_state, into the grid of its own that
+        // init() made
+        let mut original = SharedGrid::new(BitGrid::new((5, 6, 7)));
+        original.set_box((0, 0, 0), (4, 0, 6), true);
+        original.record_change(None);
+        let mut copy = SharedGrid::default();
+        let bytes = GridState::encode(&original.grid(), &BitGrid::new((0, 0, 0)));
+        copy.replace(GridState::decode(&bytes).unwrap().occluded);
+        assert_eq!(copy.size(), original.size());
+        assert_eq!(copy.to_bytes(), original.to_bytes());
+
+        copy.set((2, 3, 4), true);
+        copy.record_change(cell_box(2));
+        original.set((0, 0, 0), false);
+        original.record_change(cell_box(0));
+        assert!(!copy.ptr_eq(&original));
+        assert_eq!(original.get((2, 3, 4)), Some(false));
+        assert_eq!(copy.get((0, 0, 0)), Some(true));
+        assert_eq!(copy.changes_since(0), Some(vec![cell_box(2)]));
+    }
+}
@@ -0,0 +1,163 @@
+use godot::prelude::*;
+
+use crate::bitset::{BitGrid, Index3, cell_index};
+
+/// Where casts read occlusion from, so a game can cast against its own world representation
+/// (chunk store, octree, heightmap) instead of copying it into a grid. Coordinates are cells
+/// in the grid's own axes, before any up_axis conversion. Casts never ask about cells outside
+/// the grid of the Display they run for, which still sets how far they go
+pub trait OcclusionSource {
+    /// Whether the cell at (x, y, z) blocks sight
+    fn is_occluded(&self, x: i32, y: i32, z: i32) -> bool;
+
+    /// is_occluded() of the cell at a grid index
+    fn is_occluded_at(&self, (x, y, z): Index3) -> bool {
+        self.is_occluded(x as i32, y as i32, z as i32)
+    }
+
+    /// Whether any cell in the inclusive box from `min` to `max` blocks sight. Past the LOD
+    /// start depth, casts treat every block of cells this is true for as one occluder, so it
+    /// must be exact. Sources that can skip empty regions should override the cell-by-cell
+    /// default
+    fn coarse_any_occluded(&self, min: [i32; 3], max: [i32; 3]) -> bool {
+        (min[0]..=max[0]).any(|x| {
+            (min[1]..=max[1]).any(|y| (min[2]..=max[2]).any(|z| self.is_occluded(x, y, z)))
+        })
+    }
+}
+
+impl OcclusionSource for BitGrid {
+    fn is_occluded(&self, x: i32, y: i32, z: i32) -> bool {
+        self.get(cell_index(Vector3i::new(x, y, z))) == Some(true)
+    }
+
+    fn coarse_any_occluded(&self, min: [i32; 3], max: [i32; 3]) -> bool {
+        let corner = |[x, y, z]: [i32; 3]| Vector3i::new(x, y, z);
+        let (clipped, _) = self.clip_box(corner(min), corner(max));
+        clipped.is_some_and(|(min, max)| self.count_in_box(min, max) > 0)
+    }
+}
+
+/// A source backed by a closure of (x, y, z), such as a lookup into a game's chunk store
+pub struct OcclusionFn<F>(pub F);
+
+impl<F: Fn(i32, i32, i32) -> bool> OcclusionSource for OcclusionFn<F> {
+    fn is_occluded(&self, x: i32, y: i32, z: i32) -> bool {
+        (self.0)(x, y, z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        explain::{Explanation, explain_cell},
+        line_of_sight::visible_targets,
+        pass_cache::PassSettings,
+        shadowcast::{
+            Lod, OneWayCells,
+            tests::{caster, work_fixtures},
+        },
+    };
+
+    #[test]
+    fn closure_source_casts_as_the_grid_does() {
+        // Past the LOD start depth, casts go through coarse_any_occluded(), which the grid
+        // overrides and the closure takes the default of
+        let lods = [
+            Lod::default(),
+            Lod {
+                start_depth: 4,
+                factor: 2,
+                ..Lod::default()
+            },
+        ];
+        for (name, grid, origins) in work_fixtures() {
+            let source = OcclusionFn(|x: i32, y: i32, z: i32| {
+                grid.get(cell_index(Vector3i::new(x, y, z))) == Some(true)
+            });
+            for lod in lods {
+                for &origin in &origins {
+                    let cast = |occluded: &dyn OcclusionSource| {
+                        let mut visible = BitGrid::new(grid.size());
+                        let mut caster = caster(occluded, &mut visible, origin);
+                        caster.lod = lod;
+                        caster.cast_all();
+                        visible
+                    };
+                    let (from_grid, from_closure) = (cast(&grid), cast(&source));
+                    assert!(from_grid.count_set() > 1, "{name} from {origin}");
+                    let mut differences = Vec::new();
+                    from_grid
+                        .for_each_difference(&from_closure, |index, _| differences.push(index));
+                    assert!(
+                        differences.is_empty(),
+                        "{name} from {origin}: {differences:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn line_of_sight_and_explanations_read_the_source() {
+        let settings = PassSettings {
+            max_depth: 40,
+            ..Default::default()
+        };
+        let one_way = OneWayCells::new();
+        for (name, grid, origins) in work_fixtures() {
+            let source = OcclusionFn(|x: i32, y: i32, z: i32| {
+                grid.get(cell_index(Vector3i::new(x, y, z))) == Some(true)
+            });
+            let origin = origins[0];
+            let targets: Vec<Vector3i> = (-2..34)
+                .step_by(5)
+                .flat_map(|x| (-2..34).step_by(5).map(move |y| (x, y)))
+                .flat_map(|(x, y)| (-2..34).step_by(5).map(move |z| Vector3i::new(x, y, z)))
+                .collect();
+            let seen = |occluded: &dyn OcclusionSource| {
+                let mut scratch = BitGrid::new(grid.size());
+                visible_targets(
+                    occluded,
+                    &one_way,
+                    None,
+                    &mut scratch,
+                    &settings,
+                    origin,
+                    &targets,
+                )
+            };
+            let from_grid = seen(&grid);
+            assert_eq!(from_grid, seen(&source), "{name} from {origin}");
+            assert!(
+                from_grid.contains(&true) && from_grid.contains(&false),
+                "{name}"
+            );
+
+            for (&target, &visible) in targets.iter().zip(&from_grid) {
+                let explain = |occluded: &dyn OcclusionSource| {
+                    let size = grid.size();
+                    explain_cell(
+                        occluded, size, &one_way, None, &settings, origin, target, visible,
+                    )
+                };
+                let (by_grid, by_source) = (explain(&grid), explain(&source));
+                assert!(
+                    by_grid.verdict == by_source.verdict,
+                    "{name}: {target} is {} by the grid and {} by the source",
+                    by_grid.verdict.name(),
+                    by_source.verdict.name()
+                );
+                let blockers = |explanation: &Explanation| -> Vec<Vec<Index3>> {
+                    explanation
+                        .passes
+                        .iter()
+                        .map(|trace| trace.blockers.clone())
+                        .collect()
+                };
+                assert_eq!(blockers(&by_grid), blockers(&by_source), "{name}: {target}");
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use godot::prelude::*;
+
+use crate::{
+    bitset::{BitGrid, Index3},
+    cone::ViewCone,
+    shadowcast::{
+        CornerRule, DebugRect, LitRects, Lod, NarrowRects, PASS_COUNT, Rect, all_passes,
+        pass_may_touch_box,
+    },
+};
+
+/// Output of one pass from the cache's origin
+pub struct CachedPass {
+    pub visible: BitGrid,
+    pub debug_rects: Vec<DebugRect>,
+    /// Fraction of each reached cell that is in view, when PassSettings::track_fractions is set
+    pub fractions: HashMap<Index3, f32>,
+    /// Unblocked pieces of every view scanned, when PassSettings::capture_lit_volume is set
+    pub lit_rects: Vec<LitRects>,
+    // edit generation of the pass when this was computed
+    generation: u64,
+}
+
+/// Everything besides the origin and occluders that changes what a pass produces
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct PassSettings {
+    pub max_depth: usize,
+    pub lod: Lod,
+    pub corner_rule: CornerRule,
+    pub track_fractions: bool,
+    pub capture_lit_volume: bool,
+    /// Most unblocked pieces one view keeps before merging, or 0 for no cap
+    pub max_rects: usize,
+    pub narrow: NarrowRects,
+    /// Where in the origin cell passes cast from, relative to its center
+    pub eye_jitter: Vector3,
+    /// The view cone recomputes from the eye see within, whose passes are clipped to it
+    pub view_cone: Option<ViewCone>,
+}
+
+/// Per-pass shadowcasting results, reused while no occluder edit falls inside a pass's frustum
+#[derive(Default)]
+pub struct PassCache {
+    origin: Option<Vector3i>,
+    settings: PassSettings,
+    // bumped by every occluder edit that may fall inside the pass's frustum from origin
+    generations: [u64; PASS_COUNT],
+    passes: Vec<Option<CachedPass>>,
+}
+
+impl PassCache {
+    /// Forget every pass unless they were computed from this origin and with these settings
+    pub fn retarget(&mut self, origin: Vector3i, settings: PassSettings) {
+        if self.origin != Some(origin) || self.settings != settings {
+            self.origin = Some(origin);
+            self.settings = settings;
+            self.invalidate_all();
+        }
+    }
+
+    /// Mark every pass as dirty, e.g. after an edit that could be anywhere in the grid
+    pub fn invalidate_all(&mut self) {
+        for generation in &mut self.generations {
+            *generation += 1;
+        }
+    }
+
+    /// Mark the passes whose frustum may overlap an edited inclusive box as dirty
+    pub fn invalidate_box(&mut self, min: Vector3i, max: Vector3i) {
+        let Some(origin) = self.origin else {
+            return;
+        };
+        for (pass, generation) in all_passes().zip(&mut self.generations) {
+            if pass_may_touch_box(pass, origin, self.settings.max_depth, min, max) {
+                *generation += 1;
+            }
+        }
+    }
+
+    /// Whether pass `pass` has a result that is still valid
+    pub fn is_clean(&self, pass: usize) -> bool {
+        self.passes
+            .get(pass)
+            .and_then(Option::as_ref)
+            .is_some_and(|cached| cached.generation == self.generations[pass])
+    }
+
+    /// Empty output buffers for recomputing pass `pass` on a grid of `size`, marked as clean.
+    /// Buffers of an earlier result are reused
+    pub fn start_pass(&mut self, pass: usize, size: (usize, usize, usize)) -> &mut CachedPass {
+        if self.passes.len() < PASS_COUNT {
+            self.passes.resize_with(PASS_COUNT, || None);
+        }
+        let generation = self.generations[pass];
+        let cached = self.passes[pass].get_or_insert_with(|| CachedPass {
+            visible: BitGrid::default(),
+            debug_rects: Vec::new(),
+            fractions: HashMap::new(),
+            lit_rects: Vec::new(),
+            generation,
+        });
+        if cached.visible.size() == size {
+            cached.visible.clear();
+        } else {
+            cached.visible = BitGrid::new(size);
+        }
+        cached.debug_rects.clear();
+        match self.settings.track_fractions {
+            true => cached.fractions.clear(),
+            false => cached.fractions = HashMap::new(),
+        }
+        match self.settings.capture_lit_volume {
+            true => cached.lit_rects.clear(),
+            false => cached.lit_rects = Vec::new(),
+        }
+        cached.generation = generation;
+        cached
+    }
+
+    /// start_pass() for several passes at once, returning their buffers in ascending pass order
+    pub fn start_passes(
+        &mut self,
+        passes: &[usize],
+        size: (usize, usize, usize),
+    ) -> Vec<&mut CachedPass> {
+        for &pass in passes {
+            self.start_pass(pass, size);
+        }
+        self.passes
+            .iter_mut()
+            .enumerate()
+            .filter(|(pass, _)| passes.contains(pass))
+            .filter_map(|(_, cached)| cached.as_mut())
+            .collect()
+    }
+
+    /// Bytes allocated for every cached pass, counting the fraction maps by their entries
+    pub fn memory_bytes(&self) -> usize {
+        self.passes()
+            .map(|cached| {
+                cached.visible.memory_bytes()
+                    + cached.debug_rects.capacity() * size_of::<DebugRect>()
+                    + cached.fractions.capacity() * size_of::<(Index3, f32)>()
+                    + cached.lit_rects.capacity() * size_of::<LitRects>()
+                    + cached
+                        .lit_rects
+                        .iter()
+                        .map(|lit| lit.rects.capacity() * size_of::<Rect>())
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Mark a single pass as dirty, e.g. when its result was cut short
+    pub fn invalidate_pass(&mut self, pass: usize) {
+        self.generations[pass] += 1;
+    }
+
+    /// Every cached pass result
+    pub fn passes(&self) -> impl Iterator<Item = &CachedPass> {
+        self.passes.iter().flatten()
+    }
+}
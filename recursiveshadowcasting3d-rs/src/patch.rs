@@ -0,0 +1,263 @@
+use crate::{
+    bitset::{BitGrid, Index3},
+    error::ShadowcastError,
+};
+
+/// First bytes of every patch, ending in the format version
+const MAGIC: [u8; 4] = *b"SCP\x01";
+const HEADER_LEN: usize = MAGIC.len() + 3 * 4;
+
+/// Encode every cell of `current` that differs from `base` (which must be the same size)
+/// along with its new value.
+///
+/// The header is MAGIC and the grid size as three little-endian u32. Then come runs of changed
+/// cells in grid order, each as a LEB128 count of unchanged cells skipped since the previous run,
+/// a LEB128 run length, and the run's new values packed 8 to a byte, lowest bit first
+pub fn encode_patch(base: &BitGrid, current: &BitGrid) -> Vec<u8> {
+    let (size_x, size_y, size_z) = current.size();
+    let mut bytes = MAGIC.to_vec();
+    for axis in [size_x, size_y, size_z] {
+        bytes.extend_from_slice(&(axis as u32).to_le_bytes());
+    }
+
+    // (first cell, new values) of every run of consecutive changed cells
+    let mut runs: Vec<(usize, Vec<bool>)> = Vec::new();
+    current.for_each_difference(base, |(x, y, z), value| {
+        let cell = (x * size_y + y) * size_z + z;
+        match runs.last_mut() {
+            Some((start, values)) if *start + values.len() == cell => values.push(value),
+            _ => runs.push((cell, vec![value])),
+        }
+    });
+
+    let mut end = 0;
+    for (start, values) in runs {
+        write_varint(&mut bytes, start - end);
+        write_varint(&mut bytes, values.len());
+        for chunk in values.chunks(8) {
+            let packed = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |packed, (i, &value)| packed | ((value as u8) << i));
+            bytes.push(packed);
+        }
+        end = start + values.len();
+    }
+    bytes
+}
+
+/// Every cell a patch from encode_patch() changes and its new value, in grid order,
+/// or why the patch does not apply to a grid of `size`
+pub fn decode_patch(size: Index3, bytes: &[u8]) -> Result<Vec<(Index3, bool)>, ShadowcastError> {
+    let mut changes = Vec::new();
+    for_each_change(size, bytes, |index, value| changes.push((index, value)))?;
+    Ok(changes)
+}
+
+/// Pass every cell a patch from encode_patch() changes and its new value to `each`, in grid
+/// order, without collecting them, or return why the patch does not apply to a grid of `size`.
+/// `each` may have been passed the cells before the point where such a patch goes wrong
+pub fn for_each_change(
+    size: Index3,
+    bytes: &[u8],
+    mut each: impl FnMut(Index3, bool),
+) -> Result<(), ShadowcastError> {
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+        return Err(ShadowcastError::InvalidData(
+            "Not a change patch, or one of an unknown format".to_string(),
+        ));
+    }
+    let axis = |i: usize| {
+        let start = MAGIC.len() + i * 4;
+        u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap()) as usize
+    };
+    let patch_size = (axis(0), axis(1), axis(2));
+    if patch_size != size {
+        return Err(ShadowcastError::InvalidData(format!(
+            "Patch is for a grid of size {:?}, but this grid is {:?}",
+            patch_size, size
+        )));
+    }
+
+    // A grid of this size could not have been made if its cells do not fit in a usize
+    let Some(len) = size
+        .0
+        .checked_mul(size.1)
+        .and_then(|len| len.checked_mul(size.2))
+    else {
+        return Err(ShadowcastError::InvalidData(format!(
+            "Patch is for a grid of size {:?}, which is too large",
+            size
+        )));
+    };
+    let truncated = || ShadowcastError::InvalidData("Change patch is truncated".to_string());
+    let mut rest = &bytes[HEADER_LEN..];
+    let mut cell = 0usize;
+    while !rest.is_empty() {
+        let gap = read_varint(&mut rest).ok_or_else(truncated)?;
+        let run = read_varint(&mut rest).ok_or_else(truncated)?;
+        let start = cell.checked_add(gap).filter(|&start| start <= len);
+        let Some(start) = start.filter(|&start| run != 0 && run <= len - start) else {
+            return Err(ShadowcastError::InvalidData(
+                "Change patch has a run outside the grid".to_string(),
+            ));
+        };
+        let packed = rest.get(..run.div_ceil(8)).ok_or_else(truncated)?;
+        rest = &rest[packed.len()..];
+        for i in 0..run {
+            let cell = start + i;
+            let index = (
+                cell / size.2 / size.1,
+                cell / size.2 % size.1,
+                cell % size.2,
+            );
+            each(index, (packed[i / 8] >> (i % 8)) & 1 == 1);
+        }
+        cell = start + run;
+    }
+    Ok(())
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// None if the bytes run out or the value does not fit in a usize
+fn read_varint(bytes: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        let bits = (byte & 0x7f) as usize;
+        if bits.checked_shl(shift)? >> shift != bits {
+            return None;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 16) as usize
+        }
+
+        fn index(&mut self, size: Index3) -> Index3 {
+            (
+                self.next() % size.0,
+                self.next() % size.1,
+                self.next() % size.2,
+            )
+        }
+
+        /// A grid of `size` with random single cells and boxes set
+        fn grid(&mut self, size: Index3) -> BitGrid {
+            let mut grid = BitGrid::new(size);
+            for _ in 0..self.next() % 60 {
+                let (a, b) = (self.index(size), self.index(size));
+                if self.next().is_multiple_of(4) {
+                    let min = (a.0.min(b.0), a.1.min(b.1), a.2.min(b.2));
+                    let max = (a.0.max(b.0), a.1.max(b.1), a.2.max(b.2));
+                    grid.set_box(min, max, self.next().is_multiple_of(2));
+                } else {
+                    grid.set(a, true);
+                }
+            }
+            grid
+        }
+    }
+
+    #[test]
+    fn random_patches_bring_the_base_to_the_current_grid() {
+        let mut rng = Rng(3);
+        for size in [(1, 1, 1), (7, 5, 3), (20, 17, 33), (64, 8, 64)] {
+            for round in 0..20 {
+                let base = rng.grid(size);
+                let current = rng.grid(size);
+                let patch = encode_patch(&base, &current);
+                let mut applied = base.clone();
+                for_each_change(size, &patch, |index, value| {
+                    assert_ne!(base.get(index), Some(value), "{size:?} round {round}");
+                    applied.set(index, value);
+                })
+                .unwrap();
+                assert!(
+                    applied.to_bytes() == current.to_bytes(),
+                    "{size:?} round {round}"
+                );
+                assert_eq!(decode_patch(size, &patch).unwrap().len(), {
+                    let mut differences = 0;
+                    current.for_each_difference(&base, |_, _| differences += 1);
+                    differences
+                });
+            }
+            // Nothing changed is just the header
+            let grid = rng.grid(size);
+            let patch = encode_patch(&grid, &grid);
+            assert_eq!(patch.len(), HEADER_LEN);
+            assert!(decode_patch(size, &patch).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn malformed_and_wrong_size_patches_are_refused() {
+        let size = (7, 5, 3);
+        let mut base = BitGrid::new(size);
+        let mut current = BitGrid::new(size);
+        base.set((6, 4, 2), true);
+        current.set_box((1, 0, 0), (3, 4, 2), true);
+        let patch = encode_patch(&base, &current);
+        assert!(decode_patch(size, &patch).is_ok());
+
+        let refused = |size: Index3, bytes: &[u8]| match decode_patch(size, bytes) {
+            Err(ShadowcastError::InvalidData(message)) => message,
+            other => panic!("{:?} was not refused", other.map(|changes| changes.len())),
+        };
+        assert!(refused((8, 5, 3), &patch).contains("but this grid is"));
+        assert!(refused((3, 5, 7), &patch).contains("but this grid is"));
+        assert!(refused(size, &patch[..HEADER_LEN - 1]).contains("unknown format"));
+        let mut wrong_version = patch.clone();
+        wrong_version[3] = 2;
+        assert!(refused(size, &wrong_version).contains("unknown format"));
+        // Cut between runs, a patch just changes fewer cells, otherwise it is truncated
+        let changes = decode_patch(size, &patch).unwrap();
+        for len in HEADER_LEN + 1..patch.len() {
+            match decode_patch(size, &patch[..len]) {
+                Ok(prefix) => assert!(changes.starts_with(&prefix) && prefix.len() < changes.len()),
+                Err(_) => assert!(refused(size, &patch[..len]).contains("truncated"), "{len}"),
+            }
+        }
+
+        let header = &patch[..HEADER_LEN];
+        let with_runs = |runs: &[u8]| [header, runs].concat();
+        // A run past the last cell, one of no cells, and a skip past the last cell
+        assert!(refused(size, &with_runs(&[100, 6, 0xff])).contains("outside the grid"));
+        assert!(refused(size, &with_runs(&[0, 0])).contains("outside the grid"));
+        assert!(refused(size, &with_runs(&[0x80, 0x80, 0x01, 1, 1])).contains("outside the grid"));
+        // A varint too long for a usize
+        assert!(refused(size, &with_runs(&[0xff; 11])).contains("truncated"));
+
+        // No garbage after a valid header panics
+        let mut rng = Rng(9);
+        for _ in 0..20000 {
+            let runs: Vec<u8> = (0..rng.next() % 40).map(|_| rng.next() as u8).collect();
+            let _ = decode_patch(size, &with_runs(&runs));
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use godot::{builtin::real, prelude::*};
+
+use crate::{
+    bitset::{Index3, index_cell},
+    occlusion_source::OcclusionSource,
+};
+
+/// Where recompute_with_peek() peeks from without offsets: half a cell toward each face
+pub const FACE_PEEKS: [Vector3; 6] = [
+    Vector3::new(0.5, 0.0, 0.0),
+    Vector3::new(-0.5, 0.0, 0.0),
+    Vector3::new(0.0, 0.5, 0.0),
+    Vector3::new(0.0, -0.5, 0.0),
+    Vector3::new(0.0, 0.0, 0.5),
+    Vector3::new(0.0, 0.0, -0.5),
+];
+
+/// The jitter to cast from for each of `offsets`, given in cells from the eye at `eye_jitter`
+/// within the origin cell, in the grid's axes. Peeking leans out of the cell without leaving
+/// it, so the jitter is clamped to the cell's faces.
+///
+/// None for offsets that lean against an occluded cell, touching it on a face, edge or corner,
+/// and for those that would cast from the eye or an earlier offset again
+pub fn peek_jitters(
+    occluded: &dyn OcclusionSource,
+    size: Index3,
+    origin: Vector3i,
+    eye_jitter: Vector3,
+    offsets: &[Vector3],
+) -> Vec<Option<Vector3>> {
+    let mut cast = vec![eye_jitter];
+    offsets
+        .iter()
+        .map(|&offset| {
+            let jitter = (eye_jitter + offset).clamp(Vector3::splat(-0.5), Vector3::splat(0.5));
+            if cast.contains(&jitter) || leans_on_occluder(occluded, size, origin, jitter) {
+                return None;
+            }
+            cast.push(jitter);
+            Some(jitter)
+        })
+        .collect()
+}
+
+/// Whether the eye at `jitter` within the origin cell touches an occluded cell of the grid
+fn leans_on_occluder(
+    occluded: &dyn OcclusionSource,
+    size: Index3,
+    origin: Vector3i,
+    jitter: Vector3,
+) -> bool {
+    // the steps along an axis to the cells the eye touches, the origin cell's own first
+    let steps = |jitter: real| -> &'static [i32] {
+        match jitter {
+            jitter if jitter >= 0.5 => &[0, 1],
+            jitter if jitter <= -0.5 => &[0, -1],
+            _ => &[0],
+        }
+    };
+    let max = index_cell(size) - Vector3i::ONE;
+    steps(jitter.x).iter().any(|&x| {
+        steps(jitter.y).iter().any(|&y| {
+            steps(jitter.z).iter().any(|&z| {
+                let cell = origin + Vector3i::new(x, y, z);
+                cell != origin
+                    && cell.coord_min(Vector3i::ZERO) == Vector3i::ZERO
+                    && cell.coord_max(max) == max
+                    && occluded.is_occluded(cell.x, cell.y, cell.z)
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitset::{BitGrid, cell_index},
+        occlusion_source::OcclusionFn,
+        shadowcast::tests::caster,
+    };
+
+    /// Visible cells from the eye at the center of `origin` and from each jitter
+    fn cast_union(occluded: &BitGrid, origin: Vector3i, jitters: &[Option<Vector3>]) -> BitGrid {
+        let mut visible = BitGrid::new(occluded.size());
+        caster(occluded, &mut visible, origin).cast_all();
+        for &jitter in jitters.iter().flatten() {
+            let mut seen = BitGrid::new(occluded.size());
+            let mut caster = caster(occluded, &mut seen, origin);
+            caster.jitter = jitter;
+            caster.cast_all();
+            visible.union_with(&seen);
+        }
+        visible
+    }
+
+    #[test]
+    fn peeking_sees_around_a_corner() {
+        // A solid block with an L-shaped corridor, along x at z = 1 and then along z at x = 5
+        let mut grid = BitGrid::new((9, 3, 9));
+        grid.set_box((0, 0, 0), (8, 2, 8), true);
+        for x in 1..=5 {
+            grid.set((x, 1, 1), false);
+        }
+        for z in 1..=7 {
+            grid.set((5, 1, z), false);
+        }
+        let occluded = OcclusionFn(|x: i32, y: i32, z: i32| {
+            grid.get(cell_index(Vector3i::new(x, y, z))) == Some(true)
+        });
+        let origin = Vector3i::new(4, 1, 1);
+
+        // Only leaning along the corridor stays clear of the walls
+        let jitters = peek_jitters(&occluded, grid.size(), origin, Vector3::ZERO, &FACE_PEEKS);
+        let open: Vec<bool> = jitters.iter().map(Option::is_some).collect();
+        assert_eq!(open, [true, true, false, false, false, false]);
+
+        let center = cast_union(&grid, origin, &[]);
+        let peeked = cast_union(&grid, origin, &jitters);
+        let mut lost = 0;
+        peeked.for_each_difference(&center, |_, seen| lost += !seen as usize);
+        assert_eq!(lost, 0);
+        let down_corridor = |visible: &BitGrid| {
+            (1..=7)
+                .filter(|&z| visible.get((5, 1, z)) == Some(true))
+                .count()
+        };
+        assert!(down_corridor(&peeked) > down_corridor(&center));
+
+        // Peeking is one-sided: two cells short of the turn, leaning toward it sees a corner of
+        // the first cell past it, whose center does not see back past the corridor's wall
+        let origin = Vector3i::new(3, 1, 1);
+        let jitters = peek_jitters(&occluded, grid.size(), origin, Vector3::ZERO, &FACE_PEEKS);
+        let peeked = cast_union(&grid, origin, &jitters);
+        let mut visible = BitGrid::new(grid.size());
+        caster(&grid, &mut visible, Vector3i::new(5, 1, 2)).cast_all();
+        assert_eq!(peeked.get((5, 1, 2)), Some(true));
+        assert_eq!(visible.get(cell_index(origin)), Some(false));
+    }
+
+    #[test]
+    fn peek_jitters_clamp_and_skip_repeats() {
+        let empty = BitGrid::new((5, 5, 5));
+        let origin = Vector3i::new(2, 2, 2);
+        let offsets = [
+            Vector3::ZERO,
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.5, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, -0.25),
+        ];
+        let jitters = peek_jitters(&empty, empty.size(), origin, Vector3::ZERO, &offsets);
+        assert_eq!(
+            jitters,
+            [
+                None,
+                Some(Vector3::new(0.5, 0.0, 0.0)),
+                None,
+                Some(Vector3::new(0.0, 0.0, -0.25)),
+            ]
+        );
+
+        // Cells outside the grid are not occluders to lean on
+        let lean = [Vector3::splat(-0.5)];
+        let jitters = peek_jitters(&empty, empty.size(), Vector3i::ZERO, Vector3::ZERO, &lean);
+        assert!(jitters[0].is_some());
+
+        // Leaning into a corner touches the occluded cell diagonal to it
+        let mut occluded = BitGrid::new((5, 5, 5));
+        occluded.set((3, 3, 2), true);
+        let offsets = [Vector3::new(0.5, 0.5, 0.0), Vector3::new(0.5, 0.0, 0.0)];
+        let jitters = peek_jitters(&occluded, occluded.size(), origin, Vector3::ZERO, &offsets);
+        assert_eq!(jitters, [None, Some(Vector3::new(0.5, 0.0, 0.0))]);
+    }
+}
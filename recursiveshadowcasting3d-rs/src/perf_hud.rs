@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+
+use godot::{
+    classes::{CanvasLayer, ICanvasLayer, InputEvent, InputMap, Label},
+    prelude::*,
+};
+
+use crate::{display::Display, shadowcast::PASS_COUNT};
+
+/// Live recompute numbers for a Display in the corner of the screen: the last recompute time
+/// and its rolling average, visible cells, work items, how many passes the cache served, and
+/// whether max_work_items, reduce_depth_over_budget or max_rects_per_node kicked in.
+///
+/// It only reads recompute_finished and get_last_recompute_stats(), as any script could
+#[derive(GodotClass)]
+#[class(base=CanvasLayer)]
+pub struct ShadowcastPerfHud {
+    base: Base<CanvasLayer>,
+    /// The Display to report on. It can be changed at any time
+    #[export]
+    display: Option<Gd<Display>>,
+    /// Seconds between updates of the text
+    #[export]
+    refresh_interval: f64,
+    /// Number of recomputes the average is taken over
+    #[export]
+    average_window: i32,
+    /// Input action that shows and hides the HUD. Nothing toggles it while no such action exists
+    #[export]
+    toggle_action: StringName,
+    // the Display recompute_finished is connected on, to disconnect when display changes
+    connected: Option<Gd<Display>>,
+    // elapsed microseconds of the latest recomputes, oldest first
+    samples: VecDeque<i64>,
+    since_refresh: f64,
+    label: Option<Gd<Label>>,
+}
+
+#[godot_api]
+impl ICanvasLayer for ShadowcastPerfHud {
+    fn init(base: Base<CanvasLayer>) -> Self {
+        Self {
+            base,
+            display: None,
+            refresh_interval: 0.25,
+            average_window: 30,
+            toggle_action: StringName::from("toggle_perf_hud"),
+            connected: None,
+            samples: VecDeque::new(),
+            since_refresh: 0.0,
+            label: None,
+        }
+    }
+
+    fn ready(&mut self) {
+        let mut label = Label::new_alloc();
+        label.set_position(Vector2::new(8.0, 8.0));
+        self.base_mut().add_child(&label);
+        self.label = Some(label);
+        self.refresh();
+    }
+
+    fn process(&mut self, delta: f64) {
+        if self.connected != self.display {
+            self.connect_display();
+        }
+        self.since_refresh += delta;
+        if self.since_refresh >= self.refresh_interval {
+            self.since_refresh = 0.0;
+            self.refresh();
+        }
+    }
+
+    fn unhandled_input(&mut self, event: Gd<InputEvent>) {
+        if !InputMap::singleton().has_action(&self.toggle_action) {
+            return;
+        }
+        if event.is_action_pressed(&self.toggle_action) {
+            let visible = self.base().is_visible();
+            self.base_mut().set_visible(!visible);
+        }
+    }
+}
+
+#[godot_api]
+impl ShadowcastPerfHud {
+    /// Record the time of a recompute. Called through recompute_finished, while the Display
+    /// is still busy, so it must not touch the Display
+    #[func]
+    fn on_recompute_finished(&mut self, elapsed_usec: i64) {
+        self.samples.push_back(elapsed_usec);
+        while self.samples.len() > self.average_window.max(1) as usize {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Move the recompute_finished connection over to the current display
+    fn connect_display(&mut self) {
+        let callable = Callable::from_object_method(&self.to_gd(), "on_recompute_finished");
+        if let Some(mut old) = self.connected.take() {
+            if old.is_instance_valid() && old.is_connected("recompute_finished", &callable) {
+                old.disconnect("recompute_finished", &callable);
+            }
+        }
+        if let Some(mut display) = self.display.clone() {
+            display.connect("recompute_finished", &callable);
+            self.connected = Some(display);
+        }
+        self.samples.clear();
+    }
+
+    fn refresh(&mut self) {
+        let text = self.text();
+        if let Some(label) = self.label.as_mut() {
+            label.set_text(text.as_str());
+        }
+    }
+
+    fn text(&self) -> String {
+        let Some(display) = self
+            .display
+            .as_ref()
+            .filter(|display| display.is_instance_valid())
+        else {
+            return "No Display to report on".to_string();
+        };
+        let Some(&last) = self.samples.back() else {
+            return "Waiting for a recompute".to_string();
+        };
+
+        let display = display.bind();
+        let stats = display.get_last_recompute_stats();
+        let stat = |key: &str| {
+            stats
+                .get(key)
+                .and_then(|value| value.try_to::<i64>().ok())
+                .unwrap_or(0)
+        };
+        let average = self.samples.iter().sum::<i64>() / self.samples.len() as i64;
+        let cached = stat("cached_passes");
+        let truncated = stats
+            .get("truncated")
+            .and_then(|value| value.try_to::<bool>().ok())
+            .unwrap_or(false);
+
+        let mut mitigations = Vec::new();
+        if truncated {
+            mitigations.push("truncated by max_work_items".to_string());
+        }
+        let depth_reduction = display.get_budget_depth_reduction();
+        if depth_reduction > 0 {
+            mitigations.push(format!("{} layers off for budget", depth_reduction));
+        }
+        let merges = stat("rect_merges");
+        if merges > 0 {
+            mitigations.push(format!("{} views merged by max_rects", merges));
+        }
+        if mitigations.is_empty() {
+            mitigations.push("none".to_string());
+        }
+
+        format!(
+            "Recompute: {} usec (average {} over {})\n\
+             Visible cells: {}\n\
+             Work items: {}\n\
+             Cached passes: {}/{} ({}%)\n\
+             Mitigations: {}",
+            last,
+            average,
+            self.samples.len(),
+            stat("visible_cells"),
+            stat("work_items"),
+            cached,
+            PASS_COUNT,
+            cached * 100 / PASS_COUNT as i64,
+            mitigations.join(", ")
+        )
+    }
+}
@@ -0,0 +1,229 @@
+use godot::{builtin::real, prelude::*};
+
+use crate::{
+    bitset::BitGrid,
+    occlusion_source::OcclusionSource,
+    pass_cache::PassSettings,
+    shadowcast::{
+        Caster, OneWayCells, Pass, Rect, RectLimit, UnitPlane3d, all_passes, cast_light,
+        intersect_slope_rects, is_valid_slope_rect,
+    },
+    terrain::Terrain,
+};
+
+/// Casts through portals allowed per recompute before falling back to casting plainly,
+/// which bounds the work in maps with many loops between rooms
+const MAX_PORTAL_CASTS: usize = 256;
+
+/// An inclusive box of cells closed off by occluders, walls included
+#[derive(Clone, Copy)]
+pub struct Room {
+    pub min: Vector3i,
+    pub max: Vector3i,
+}
+
+impl Room {
+    pub fn contains(&self, cell: Vector3i) -> bool {
+        cell.coord_max(self.min) == cell && cell.coord_min(self.max) == cell
+    }
+}
+
+/// An opening between two rooms, across the face between two neighbouring cells
+#[derive(Clone, Copy)]
+pub struct Portal {
+    pub rooms: [usize; 2],
+    /// The cell on either side of the opening, each in the room at the same index
+    pub cells: [Vector3i; 2],
+    /// The cells the opening spans across the portal's axis, in the casting plane's own axes:
+    /// (x, y) for portals along z, (z, y) along x and (z, x) along y
+    pub opening: Rect2,
+}
+
+impl Portal {
+    /// The casting plane the opening lies in, across the portal's axis
+    pub fn plane(&self) -> UnitPlane3d {
+        plane_between(self.cells)
+    }
+}
+
+/// The casting plane across the face between two neighbouring cells
+fn plane_between(cells: [Vector3i; 2]) -> UnitPlane3d {
+    match cells[1] - cells[0] {
+        Vector3i { x: 0, y: 0, z: _ } => UnitPlane3d::XY,
+        Vector3i { x: _, y: 0, z: 0 } => UnitPlane3d::ZY,
+        _ => UnitPlane3d::ZX,
+    }
+}
+
+/// Rooms and the portals between them, so recomputes only scan rooms that can be seen into
+#[derive(Default)]
+pub struct PortalGraph {
+    pub rooms: Vec<Room>,
+    pub portals: Vec<Portal>,
+}
+
+impl PortalGraph {
+    pub fn is_empty(&self) -> bool {
+        self.portals.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.rooms.clear();
+        self.portals.clear();
+    }
+
+    /// Move every room and portal by `-offset`, as BitGrid::shift() moves cells
+    pub fn shift(&mut self, offset: Vector3i) {
+        for room in &mut self.rooms {
+            room.min -= offset;
+            room.max -= offset;
+        }
+        for portal in &mut self.portals {
+            let local = portal.plane().to_local(offset);
+            portal.cells = portal.cells.map(|cell| cell - offset);
+            portal.opening.position -= Vector2::new(local.x as real, local.y as real);
+        }
+    }
+
+    /// A room containing `cell`, preferring one that does not also contain `other`.
+    /// Rooms may share their walls, so a cell in a doorway can be in both rooms
+    pub fn room_of(&self, cell: Vector3i, other: Option<Vector3i>) -> Option<usize> {
+        let mut rooms = (0..self.rooms.len()).filter(|&room| self.rooms[room].contains(cell));
+        let first = rooms.next()?;
+        let Some(other) = other else {
+            return Some(first);
+        };
+        Some(
+            std::iter::once(first)
+                .chain(rooms)
+                .find(|&room| !self.rooms[room].contains(other))
+                .unwrap_or(first),
+        )
+    }
+
+    /// Shadowcast from `origin` through its room and every chain of portals leading out of it,
+    /// never scanning cells outside the rooms along a chain.
+    /// Returns false without a complete result if the origin is in no room or the portals
+    /// branch too much, in which case cast plainly instead
+    pub fn cast(
+        &self,
+        occluded: &dyn OcclusionSource,
+        one_way: &OneWayCells,
+        terrain: Option<&Terrain>,
+        visible: &mut BitGrid,
+        origin: Vector3i,
+        settings: &PassSettings,
+    ) -> bool {
+        let Some(start) = self.room_of(origin, None) else {
+            return false;
+        };
+
+        let mut cast_within = |chain: &[usize], passes: &mut dyn Iterator<Item = Pass>| {
+            let bounds: Vec<(Vector3i, Vector3i)> = chain
+                .iter()
+                .map(|&room| (self.rooms[room].min, self.rooms[room].max))
+                .collect();
+            let mut caster = Caster {
+                occluded,
+                visible: &mut *visible,
+                origin,
+                jitter: settings.eye_jitter,
+                max_depth: settings.max_depth,
+                lod: settings.lod,
+                corner_rule: settings.corner_rule,
+                debug_rects: None,
+                lit_rects: None,
+                fractions: None,
+                bounds: Some(&bounds),
+                one_way: Some(one_way),
+                blockers: None,
+                terrain,
+                exposed: None,
+                rects: RectLimit::new(settings.max_rects, settings.narrow),
+            };
+            caster.mark_origin_visible();
+            for (slope_rect, reverse_z, plane) in passes {
+                cast_light(&mut caster, &slope_rect, 1, reverse_z, &plane);
+            }
+        };
+        cast_within(&[start], &mut all_passes());
+
+        // Depth first along simple chains of rooms, each narrowing the view to its latest portal
+        let mut casts = 0;
+        let mut stack: Vec<(Vec<usize>, Option<Pass>)> = vec![(vec![start], None)];
+        while let Some((chain, frustum)) = stack.pop() {
+            let room = chain[chain.len() - 1];
+            for portal in &self.portals {
+                let Some(side) = portal.rooms.iter().position(|&r| r == room) else {
+                    continue;
+                };
+                let next = portal.rooms[1 - side];
+                if chain.contains(&next) {
+                    continue;
+                }
+                let Some(pass) = portal_pass(portal, side, origin) else {
+                    continue;
+                };
+                let pass = match frustum {
+                    Some(frustum) if (frustum.1, frustum.2) == (pass.1, pass.2) => {
+                        match intersect_slope_rects(&frustum.0, &pass.0) {
+                            Some(slope_rect) => (slope_rect, pass.1, pass.2),
+                            None => continue,
+                        }
+                    }
+                    _ => pass,
+                };
+
+                casts += 1;
+                if casts > MAX_PORTAL_CASTS {
+                    return false;
+                }
+                let mut next_chain = chain.clone();
+                next_chain.push(next);
+                cast_within(&next_chain, &mut std::iter::once(pass));
+                stack.push((next_chain, Some(pass)));
+            }
+        }
+        true
+    }
+}
+
+/// The pass looking from `origin` through a portal, leaving the room on `side`.
+/// None if the origin is not on that side of the opening
+fn portal_pass(portal: &Portal, side: usize, origin: Vector3i) -> Option<Pass> {
+    let cells = [portal.cells[side], portal.cells[1 - side]];
+    opening_pass(cells, portal.opening, origin)
+}
+
+/// The pass looking from `origin` through an opening across the face between two neighbouring
+/// cells, from the first to the second, spanning the cells in `opening` as Portal::opening
+/// does. None if the origin is not on the first cell's side
+pub fn opening_pass(cells: [Vector3i; 2], opening: Rect2, origin: Vector3i) -> Option<Pass> {
+    let [from, to] = cells;
+    let plane = plane_between(cells);
+    let origin = plane.to_local(origin);
+    let along = plane.to_local(from + to).z;
+    let direction = plane.to_local(to - from).z;
+
+    // Signed distance from the origin's center to the face between the two cells
+    let depth = along as real / 2.0 - origin.z as real;
+    if depth * direction as real <= 0.0 {
+        return None;
+    }
+
+    // Slopes are depth over offset, so offsets over depth can be intersected and ordered
+    let inverse_slopes = |start: real, size: real, origin: i32| {
+        let a = (start - 0.5 - origin as real) / depth;
+        let b = (start + size - 0.5 - origin as real) / depth;
+        (a.min(b), a.max(b))
+    };
+    let (sx, ex) = inverse_slopes(opening.position.x, opening.size.x, origin.x);
+    let (sy, ey) = inverse_slopes(opening.position.y, opening.size.y, origin.y);
+    let slope_rect = Rect {
+        sx: 1.0 / sx,
+        sy: 1.0 / sy,
+        ex: 1.0 / ex,
+        ey: 1.0 / ey,
+    };
+    is_valid_slope_rect(&slope_rect).then_some((slope_rect, depth < 0.0, plane))
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    bitset::{BitGrid, Index3, cell_index},
+    line_of_sight::PathCell,
+};
+
+/// Chance each cell with one blocks sight, such as swaying foliage, see set_block_probability()
+pub type BlockProbabilities = BTreeMap<Index3, f32>;
+
+/// Whether a cell that blocks with `probability` does so under `seed`. Every cast made with
+/// the same seed gets the same answer, so observers resolved together agree
+pub fn resolves_blocked(seed: u64, index: Index3, probability: f32) -> bool {
+    seeded_chance(seed, index, probability)
+}
+
+/// Whether something that happens to a cell with `chance` does under `seed`, the same every
+/// time for the same seed and cell
+pub fn seeded_chance(seed: u64, index: Index3, chance: f32) -> bool {
+    let mut hash = seed;
+    for coordinate in [index.0, index.1, index.2] {
+        hash = splitmix64(hash ^ coordinate as u64);
+    }
+    // The top 53 bits, as a uniform fraction in [0, 1)
+    let fraction = (hash >> 11) as f64 / (1u64 << 53) as f64;
+    fraction < chance as f64
+}
+
+/// The cells of `probabilities` that block under `seed`, in a grid of `size`
+pub fn resolve_all(probabilities: &BlockProbabilities, seed: u64, size: Index3) -> BitGrid {
+    let mut resolved = BitGrid::new(size);
+    for (&index, &probability) in probabilities {
+        if resolves_blocked(seed, index, probability) {
+            resolved.set(index, true);
+        }
+    }
+    resolved
+}
+
+/// Chance that sight along a path gets through every cell with a block probability it crosses,
+/// leaving out the cells at either end and those it only grazes
+pub fn transmission(probabilities: &BlockProbabilities, path: &[PathCell]) -> f32 {
+    let inner = path
+        .get(1..path.len().saturating_sub(1))
+        .unwrap_or_default();
+    inner
+        .iter()
+        .filter(|step| !step.grazed)
+        .filter_map(|step| probabilities.get(&cell_index(step.cell)))
+        .map(|probability| 1.0 - probability)
+        .product()
+}
+
+/// SplitMix64's finalizer, which spreads every input bit over the whole output
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,336 @@
+use std::{collections::BTreeSet, f32::consts::TAU};
+
+use godot::{
+    builtin::real,
+    classes::{FileAccess, Json, file_access::ModeFlags},
+    prelude::*,
+};
+
+use crate::{
+    bitset::{Index3, cell_at, cell_index},
+    display::Display,
+    probability::resolves_blocked,
+    shadowcast::{PASS_COUNT, UPDATE_EXPECTED_WORK_ENV},
+};
+
+/// Times the path is sampled at to clear the cells around it, enough that any point on the
+/// path is within a cell of a sample
+const PATH_SAMPLES: usize = 1024;
+
+/// The map a FovProfileScene builds
+#[derive(GodotConvert, Var, Export, Clone, Copy, Default, PartialEq, Debug)]
+#[godot(via = i64)]
+pub enum ProfileScenario {
+    /// A floor with scattered boulders, where sight reaches as far as it can
+    #[default]
+    OpenField,
+    /// A floor with full height pillars, cutting sight into many thin views
+    PillarForest,
+    /// A quarter of all cells occluded at random, the worst case for splitting views
+    DenseNoise,
+    /// A walled tower of floors with windows, climbed by a helix through holes in the floors
+    MultiFloorTower,
+}
+
+/// A reproducible benchmark, for comparing recompute performance between builds: builds the
+/// map of `scenario` from `seed` in a Display of the default size, moves the origin along a
+/// fixed loop through it `recomputes` times and reports the times and visible cells.
+///
+/// Run it headless with a script that calls run() and quits, e.g.
+/// `godot --headless --script profile.gd`
+///
+/// Times vary between machines, but the views each pass scans do not, so runs also check those
+/// against expected_work_path to catch a change that makes one pass do more work while the
+/// total stays about the same. After a change meant to alter the work, run the scenarios again
+/// with update_expected_work or SHADOWCAST_UPDATE_EXPECTED_WORK set to record the new counts
+#[derive(GodotClass)]
+#[class(base=Node)]
+pub struct FovProfileScene {
+    base: Base<Node>,
+    #[export]
+    scenario: ProfileScenario,
+    /// Seed the scenario's occluders are placed with. The same seed always builds the same map
+    #[export]
+    seed: i64,
+    /// Number of recomputes, spread evenly along the path
+    #[export]
+    recomputes: i32,
+    /// JSON file of the views each pass is expected to scan over a run, keyed by scenario,
+    /// seed and recomputes, such as res://profile_expected_work.json. Empty skips the check
+    #[export]
+    expected_work_path: GString,
+    /// Fraction more views than expected a pass may scan before run() reports it
+    #[export]
+    work_tolerance: real,
+    /// Whether run() records the views each pass scanned into expected_work_path instead of
+    /// checking them
+    #[export]
+    update_expected_work: bool,
+}
+
+#[godot_api]
+impl INode for FovProfileScene {
+    fn init(base: Base<Node>) -> Self {
+        Self {
+            base,
+            scenario: ProfileScenario::OpenField,
+            seed: 0,
+            recomputes: 200,
+            expected_work_path: GString::new(),
+            work_tolerance: 0.05,
+            update_expected_work: false,
+        }
+    }
+}
+
+#[godot_api]
+impl FovProfileScene {
+    /// Build the scenario, run the recomputes and print a summary. Returns "scenario", "seed",
+    /// "recomputes", "occluded_cells", "mean_usec", "p95_usec" and "max_usec" (recompute times),
+    /// "mean_visible", "min_visible" and "max_visible" (visible cells per recompute),
+    /// "pass_work_items" (views each of the 24 passes scanned over the run) and
+    /// "regressed_passes" (the passes that scanned more than work_tolerance over what
+    /// expected_work_path expects, each also reported as an error)
+    #[func]
+    pub fn run(&mut self) -> Dictionary {
+        let recomputes = self.recomputes.max(1) as usize;
+        let mut display = Display::new_alloc();
+        let size = display.bind().get_grid_size();
+        let size = (size.x as usize, size.y as usize, size.z as usize);
+        let layers = self.build_layers(size);
+
+        let mut usec = Vec::with_capacity(recomputes);
+        let mut visible = Vec::with_capacity(recomputes);
+        let mut pass_work = [0i64; PASS_COUNT];
+        let occluded_cells = {
+            let mut display = display.bind_mut();
+            for (z, layer) in layers.into_iter().enumerate() {
+                display.set_occlusion_layer(z as i32, layer);
+            }
+            for i in 0..recomputes {
+                let t = i as f32 / recomputes as f32;
+                display.set_origin_and_recompute(path_point(self.scenario, size, t));
+                let stats = display.get_last_recompute_stats();
+                let stat = |key: &str| {
+                    stats
+                        .get(key)
+                        .and_then(|value| value.try_to::<i64>().ok())
+                        .unwrap_or(0)
+                };
+                usec.push(stat("total_usec"));
+                visible.push(stat("visible_cells"));
+                let work_items = stats
+                    .get("pass_work_items")
+                    .and_then(|value| value.try_to::<PackedInt64Array>().ok())
+                    .unwrap_or_default();
+                for (total, work_items) in pass_work.iter_mut().zip(work_items.as_slice()) {
+                    *total += work_items;
+                }
+            }
+            display.get_occluded_count()
+        };
+        display.free();
+
+        usec.sort_unstable();
+        let mean = |values: &[i64]| values.iter().sum::<i64>() as f64 / values.len() as f64;
+        // Nearest-rank percentile
+        let p95 = usec[(usec.len() * 95).div_ceil(100) - 1];
+
+        let mut summary = Dictionary::new();
+        summary.set("scenario", self.scenario);
+        summary.set("seed", self.seed);
+        summary.set("recomputes", recomputes as i64);
+        summary.set("occluded_cells", occluded_cells);
+        summary.set("mean_usec", mean(&usec));
+        summary.set("p95_usec", p95);
+        summary.set("max_usec", usec[usec.len() - 1]);
+        summary.set("mean_visible", mean(&visible));
+        summary.set("min_visible", visible.iter().copied().min().unwrap_or(0));
+        summary.set("max_visible", visible.iter().copied().max().unwrap_or(0));
+        summary.set(
+            "pass_work_items",
+            PackedInt64Array::from(pass_work.as_slice()),
+        );
+        summary.set("regressed_passes", self.check_work(&pass_work));
+        godot_print!(
+            "{:?} (seed {}): {} recomputes of {} occluded cells, mean {:.0} usec, p95 {} usec, \
+             max {} usec, {:.0} visible cells on average",
+            self.scenario,
+            self.seed,
+            recomputes,
+            occluded_cells,
+            mean(&usec),
+            p95,
+            usec[usec.len() - 1],
+            mean(&visible)
+        );
+        summary
+    }
+
+    /// Check the views each pass scanned against expected_work_path, or record them there when
+    /// updating. Returns the passes that scanned more than work_tolerance over what they are
+    /// expected to
+    fn check_work(&self, pass_work: &[i64]) -> PackedInt64Array {
+        let mut regressed = PackedInt64Array::new();
+        let path = &self.expected_work_path;
+        if path.is_empty() {
+            return regressed;
+        }
+        let key = format!(
+            "{:?} seed {}, {} recomputes",
+            self.scenario,
+            self.seed,
+            self.recomputes.max(1)
+        );
+        let mut expected = FileAccess::open(path, ModeFlags::READ)
+            .and_then(|file| {
+                Json::parse_string(&file.get_as_text())
+                    .try_to::<Dictionary>()
+                    .ok()
+            })
+            .unwrap_or_default();
+
+        if self.update_expected_work || std::env::var_os(UPDATE_EXPECTED_WORK_ENV).is_some() {
+            expected.set(key.as_str(), PackedInt64Array::from(pass_work));
+            let Some(mut file) = FileAccess::open(path, ModeFlags::WRITE) else {
+                godot_script_error!("Could not open {} for writing", path);
+                return regressed;
+            };
+            file.store_string(
+                &Json::stringify_ex(&expected.to_variant())
+                    .indent("\t")
+                    .done(),
+            );
+            file.close();
+            godot_print!("Recorded the work of {} in {}", key, path);
+            return regressed;
+        }
+
+        let counts = expected
+            .get(key.as_str())
+            .and_then(|counts| counts.try_to::<VariantArray>().ok());
+        let Some(counts) = counts.filter(|counts| counts.len() == pass_work.len()) else {
+            godot_warn!(
+                "{} has no expected work for {}, run with update_expected_work to record it",
+                path,
+                key
+            );
+            return regressed;
+        };
+        let tolerance = self.work_tolerance.max(0.0) as f64;
+        for (pass, (&actual, expected)) in pass_work.iter().zip(counts.iter_shared()).enumerate() {
+            // JSON numbers come back as floats
+            let expected = expected.try_to::<f64>().unwrap_or(0.0);
+            if actual as f64 > expected * (1.0 + tolerance) {
+                godot_script_error!(
+                    "Pass {} scanned {} views in {}, more than the {} expected",
+                    pass,
+                    actual,
+                    key,
+                    expected
+                );
+                regressed.push(pass as i64);
+            }
+        }
+        regressed
+    }
+
+    /// The scenario's occlusion, one byte per cell as set_occlusion_layer() takes, with the
+    /// cells around the path left empty so the origin is never inside an occluder
+    fn build_layers(&self, size: Index3) -> Vec<PackedByteArray> {
+        let (size_x, size_y, size_z) = size;
+        let seed = self.seed as u64;
+        let path = path_clearance(self.scenario, size);
+        (0..size_z)
+            .map(|z| {
+                let mut layer = vec![0u8; size_x * size_y];
+                for x in 0..size_x {
+                    for y in 0..size_y {
+                        let cell = (x, y, z);
+                        if !path.contains(&cell) && occludes(self.scenario, seed, size, cell) {
+                            layer[x * size_y + y] = 1;
+                        }
+                    }
+                }
+                PackedByteArray::from(layer)
+            })
+            .collect()
+    }
+}
+
+/// Whether a cell of the scenario is occluded, before the path is cleared
+fn occludes(scenario: ProfileScenario, seed: u64, size: Index3, (x, y, z): Index3) -> bool {
+    let (size_x, _, size_z) = size;
+    match scenario {
+        ProfileScenario::OpenField => y == 0 || (y <= 2 && resolves_blocked(seed, (x, 0, z), 0.01)),
+        ProfileScenario::PillarForest => y == 0 || resolves_blocked(seed, (x, 0, z), 0.08),
+        ProfileScenario::DenseNoise => resolves_blocked(seed, (x, y, z), 0.25),
+        ProfileScenario::MultiFloorTower => {
+            let (min_x, max_x) = (size_x / 5, size_x - 1 - size_x / 5);
+            let (min_z, max_z) = (size_z / 5, size_z - 1 - size_z / 5);
+            if !(min_x..=max_x).contains(&x) || !(min_z..=max_z).contains(&z) {
+                return false;
+            }
+            let wall = x == min_x || x == max_x || z == min_z || z == max_z;
+            let floor = y % 8 == 0;
+            // Windows a storey's middle rows high, where the seed picks
+            let window = (3..=5).contains(&(y % 8)) && resolves_blocked(seed, (x, y / 8, z), 0.3);
+            floor || (wall && !window)
+        }
+    }
+}
+
+/// Where the origin is a fraction `t` of the way around the scenario's path. Every path is a
+/// closed loop around the middle of the grid, so recomputes at even steps cover it evenly
+fn path_point(scenario: ProfileScenario, size: Index3, t: f32) -> Vector3 {
+    let (size_x, size_y, size_z) = size;
+    let center_x = size_x as f32 / 2.0;
+    let center_z = size_z as f32 / 2.0;
+    let radius = size_x.min(size_z) as f32 / 4.0;
+    let (angle, y) = match scenario {
+        ProfileScenario::OpenField | ProfileScenario::PillarForest => (t * TAU, 2.0),
+        ProfileScenario::DenseNoise => (
+            t * TAU,
+            size_y as f32 / 2.0 + radius / 2.0 * (2.0 * t * TAU).sin(),
+        ),
+        // Up three turns of a helix, then straight back down from where it ends
+        ProfileScenario::MultiFloorTower => {
+            let height = (size_y - 3) as f32;
+            if t < 0.5 {
+                (t * 2.0 * 3.0 * TAU, 1.0 + t * 2.0 * height)
+            } else {
+                (0.0, 1.0 + (1.0 - t) * 2.0 * height)
+            }
+        }
+    };
+    Vector3::new(
+        (center_x + radius * angle.cos()) as real,
+        y as real,
+        (center_z + radius * angle.sin()) as real,
+    )
+}
+
+/// The cells within one cell of the path along every axis
+fn path_clearance(scenario: ProfileScenario, size: Index3) -> BTreeSet<Index3> {
+    let (size_x, size_y, size_z) = size;
+    let mut cells = BTreeSet::new();
+    for sample in 0..PATH_SAMPLES {
+        let t = sample as f32 / PATH_SAMPLES as f32;
+        let center = cell_at(path_point(scenario, size, t));
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let cell = center + Vector3i::new(dx, dy, dz);
+                    if cell.x < 0 || cell.y < 0 || cell.z < 0 {
+                        continue;
+                    }
+                    let (x, y, z) = cell_index(cell);
+                    if x < size_x && y < size_y && z < size_z {
+                        cells.insert((x, y, z));
+                    }
+                }
+            }
+        }
+    }
+    cells
+}
@@ -0,0 +1,137 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+use ndarray::Array3;
+
+use crate::bitset::{BitGrid, Index3};
+
+/// A cell waiting to be settled, ordered so that the cheapest cell pops first
+struct Frontier {
+    cost: f32,
+    index: Index3,
+}
+
+impl PartialEq for Frontier {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost.total_cmp(&other.cost) == Ordering::Equal
+    }
+}
+
+impl Eq for Frontier {}
+
+impl PartialOrd for Frontier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Frontier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+/// Spread power outwards from `origin` through 6-connected steps, where each step costs
+/// `air_cost` to enter an empty cell or `wall_cost` to enter an occluded one.
+/// Walls attenuate rather than block: power keeps going until the accumulated cost uses it up.
+/// `levels` is overwritten with the power received at every cell, 0 where it never arrives
+pub fn propagate(
+    occluded: &BitGrid,
+    origin: Index3,
+    initial_power: f32,
+    wall_cost: f32,
+    air_cost: f32,
+    levels: &mut Array3<f32>,
+) {
+    let size = occluded.size();
+    if levels.dim() != size {
+        *levels = Array3::zeros(size);
+    } else {
+        levels.fill(0.0);
+    }
+
+    // Plain Dijkstra over path cost, where a cell's received power is initial_power minus its
+    // cheapest path cost. levels doubles as the best-known power so far, 0 meaning unreached
+    let mut heap = BinaryHeap::new();
+    levels[origin] = initial_power;
+    heap.push(Frontier {
+        cost: 0.0,
+        index: origin,
+    });
+
+    while let Some(Frontier { cost, index }) = heap.pop() {
+        if initial_power - cost < levels[index] {
+            // Stale entry, this cell was already settled more cheaply
+            continue;
+        }
+
+        let (x, y, z) = index;
+        // Out of range neighbors wrap around to usize::MAX and are rejected by get()
+        for neighbor in [
+            (x.wrapping_sub(1), y, z),
+            (x + 1, y, z),
+            (x, y.wrapping_sub(1), z),
+            (x, y + 1, z),
+            (x, y, z.wrapping_sub(1)),
+            (x, y, z + 1),
+        ] {
+            let Some(is_wall) = occluded.get(neighbor) else {
+                continue;
+            };
+            let next_cost = cost + if is_wall { wall_cost } else { air_cost };
+            let next_power = initial_power - next_cost;
+            if next_power > 0.0 && next_power > levels[neighbor] {
+                levels[neighbor] = next_power;
+                heap.push(Frontier {
+                    cost: next_cost,
+                    index: neighbor,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_wall_takes_its_cost_rather_than_all_the_power() {
+        // A one cell wide corridor with a wall across it at z = 4
+        let mut occluded = BitGrid::new((1, 1, 12));
+        occluded.set((0, 0, 4), true);
+        let mut levels = Array3::zeros((0, 0, 0));
+        propagate(&occluded, (0, 0, 0), 10.0, 3.0, 1.0, &mut levels);
+        let along: Vec<f32> = (0..12).map(|z| levels[(0, 0, z)]).collect();
+        assert_eq!(
+            along,
+            [10.0, 9.0, 8.0, 7.0, 4.0, 3.0, 2.0, 1.0, 0.0, 0.0, 0.0, 0.0]
+        );
+
+        // A wall costing everything does block, and levels from an earlier call are cleared
+        propagate(&occluded, (0, 0, 0), 10.0, 10.0, 1.0, &mut levels);
+        assert_eq!(levels[(0, 0, 3)], 7.0);
+        assert_eq!(levels[(0, 0, 4)], 0.0);
+        assert_eq!(levels[(0, 0, 5)], 0.0);
+    }
+
+    #[test]
+    fn power_takes_the_cheapest_way_around_or_through() {
+        // A wall across the grid at x = 4, with a door in it at (4, 4, 8)
+        let mut occluded = BitGrid::new((9, 9, 9));
+        occluded.set_box((4, 0, 0), (4, 8, 8), true);
+        occluded.set((4, 4, 8), false);
+        let mut levels = Array3::zeros((0, 0, 0));
+        propagate(&occluded, (2, 4, 4), 20.0, 5.0, 1.0, &mut levels);
+        assert_eq!(levels.dim(), (9, 9, 9));
+        // Straight across, the wall costs 4 more than air where the door is 4 steps out of the way.
+        // Beside the door, going through it is cheaper than the wall
+        assert_eq!(levels[(6, 4, 4)], 20.0 - 8.0);
+        assert_eq!(levels[(4, 4, 8)], 20.0 - 6.0);
+        assert_eq!(levels[(6, 4, 8)], 20.0 - 8.0);
+        assert_eq!(levels[(5, 4, 7)], 20.0 - 8.0);
+        assert_eq!(levels[(4, 4, 7)], 20.0 - 9.0);
+        // Levels are symmetric across the origin's planes on its own side of the wall
+        assert_eq!(levels[(0, 2, 4)], levels[(0, 6, 4)]);
+        assert_eq!(levels[(0, 4, 1)], levels[(0, 4, 7)]);
+    }
+}
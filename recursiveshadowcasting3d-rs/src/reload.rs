@@ -0,0 +1,223 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+};
+
+use crate::{
+    bitset::{BitGrid, Index3},
+    error::ShadowcastError,
+    patch::{encode_patch, for_each_change},
+};
+
+/// First bytes of a Display's grid state, ending in the format version
+const MAGIC: [u8; 4] = *b"SCR\x01";
+const HEADER_LEN: usize = MAGIC.len() + 5 * 4;
+/// More cells than a grid state can have, 512 MiB as bits or what a usize can count on smaller
+/// targets such as wasm32, so a corrupted header is refused rather than allocated
+const MAX_CELLS: u64 = match (usize::MAX as u64) < 1 << 32 {
+    true => usize::MAX as u64,
+    false => 1 << 32,
+};
+
+/// What the last Display to hand out its grid state while holding things the state leaves out
+/// left behind, by a hash of the bytes. duplicate() takes the bytes in on the copy straight
+/// away, and a GDExtension reload on the new instance, while a saved scene is only loaded later
+static LEFT_BEHIND: Mutex<Option<(u64, Vec<&'static str>)>> = Mutex::new(None);
+
+/// The per-cell state of a Display that has no exported property of its own, which goes
+/// through the storage-only grid_state property to copies, saved scenes and across a
+/// GDExtension reload
+pub struct GridState {
+    pub occluded: BitGrid,
+    pub explored: BitGrid,
+}
+
+impl GridState {
+    /// The header is MAGIC, the grid size as three little-endian u32 and the lengths of the
+    /// two patches as two more, since a patch cut short between runs still decodes. Then come
+    /// the occluded and explored cells as patches from an empty grid, see encode_patch(), so
+    /// mostly empty grids stay small. Explored cells of another size than the grid are left out
+    pub fn encode(occluded: &BitGrid, explored: &BitGrid) -> Vec<u8> {
+        let size = occluded.size();
+        let empty = BitGrid::new(size);
+        let occluded = encode_patch(&empty, occluded);
+        let explored = match explored.size() == size {
+            true => encode_patch(&empty, explored),
+            false => encode_patch(&empty, &empty),
+        };
+        let mut bytes = MAGIC.to_vec();
+        for value in [size.0, size.1, size.2, occluded.len(), explored.len()] {
+            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&occluded);
+        bytes.extend_from_slice(&explored);
+        bytes
+    }
+
+    /// The state encode() wrote, or why the bytes are not a state of this format version.
+    /// Both patches are checked against the size before the grids are allocated, then decoded
+    /// straight into them
+    pub fn decode(bytes: &[u8]) -> Result<Self, ShadowcastError> {
+        if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+            return Err(ShadowcastError::InvalidData(
+                "Not a grid state, or one of another format version".to_string(),
+            ));
+        }
+        let value = |i: usize| {
+            let start = MAGIC.len() + i * 4;
+            u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap())
+        };
+        let cells = [value(1), value(2)]
+            .into_iter()
+            .try_fold(value(0) as u64, |cells, axis| {
+                cells.checked_mul(axis as u64)
+            });
+        if cells.is_none_or(|cells| cells > MAX_CELLS) {
+            return Err(ShadowcastError::InvalidData(format!(
+                "Grid state is for a grid of size {:?}, which is too large",
+                (value(0), value(1), value(2))
+            )));
+        }
+        let size: Index3 = (value(0) as usize, value(1) as usize, value(2) as usize);
+        let truncated = || ShadowcastError::InvalidData("Grid state is truncated".to_string());
+        let rest = &bytes[HEADER_LEN..];
+        if rest.len() as u64 != value(3) as u64 + value(4) as u64 {
+            return Err(truncated());
+        }
+        let (occluded, explored) = rest.split_at(value(3) as usize);
+        // for_each_change() checks that each patch is for this size and stays inside it
+        for patch in [occluded, explored] {
+            for_each_change(size, patch, |_, _| {})?;
+        }
+        Ok(Self {
+            occluded: grid_from_patch(size, occluded)?,
+            explored: grid_from_patch(size, explored)?,
+        })
+    }
+
+    /// Note what the Display handing out `bytes` leaves behind, for the one taking them in
+    pub fn note_left_behind(bytes: &[u8], left_behind: Vec<&'static str>) {
+        let note = (!left_behind.is_empty()).then(|| (hash(bytes), left_behind));
+        *LEFT_BEHIND.lock().unwrap() = note;
+    }
+
+    /// What the Display that handed out `bytes` in this run left behind, as noted by
+    /// note_left_behind(), empty for bytes from elsewhere such as a saved scene
+    pub fn take_left_behind(bytes: &[u8]) -> Vec<&'static str> {
+        let mut note = LEFT_BEHIND.lock().unwrap();
+        match note.take() {
+            Some((noted, left_behind)) if noted == hash(bytes) => left_behind,
+            other => {
+                *note = other;
+                Vec::new()
+            }
+        }
+    }
+}
+
+fn grid_from_patch(size: Index3, patch: &[u8]) -> Result<BitGrid, ShadowcastError> {
+    let mut grid = BitGrid::new(size);
+    for_each_change(size, patch, |index, value| {
+        grid.set(index, value);
+    })?;
+    Ok(grid)
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffers() -> (BitGrid, BitGrid) {
+        let size = (9, 7, 5);
+        let mut occluded = BitGrid::new(size);
+        let mut explored = BitGrid::new(size);
+        for x in 0..9 {
+            occluded.set((x, x % 7, (x * 3) % 5), true);
+            explored.set((x, 6 - x % 7, x % 5), true);
+        }
+        (occluded, explored)
+    }
+
+    fn header_with_size(size: [u32; 3]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for value in [size[0], size[1], size[2], 0, 0] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let (occluded, explored) = buffers();
+        let decoded = GridState::decode(&GridState::encode(&occluded, &explored)).unwrap();
+        assert_eq!(decoded.occluded.size(), occluded.size());
+        assert_eq!(decoded.occluded.to_bytes(), occluded.to_bytes());
+        assert_eq!(decoded.explored.to_bytes(), explored.to_bytes());
+    }
+
+    #[test]
+    fn explored_of_another_size_is_left_out() {
+        let (occluded, _) = buffers();
+        let mut explored = BitGrid::new((2, 2, 2));
+        explored.set((1, 1, 1), true);
+        let decoded = GridState::decode(&GridState::encode(&occluded, &explored)).unwrap();
+        assert_eq!(decoded.explored.size(), occluded.size());
+        assert_eq!(decoded.explored.count_set(), 0);
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let (occluded, explored) = buffers();
+        let mut bytes = GridState::encode(&occluded, &explored);
+        bytes[MAGIC.len() - 1] = 2;
+        assert!(GridState::decode(&bytes).is_err());
+        assert!(GridState::decode(b"SCR").is_err());
+    }
+
+    #[test]
+    fn truncated_buffers_are_refused() {
+        let (occluded, explored) = buffers();
+        let bytes = GridState::encode(&occluded, &explored);
+        for len in 0..bytes.len() {
+            assert!(GridState::decode(&bytes[..len]).is_err(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn huge_sizes_are_refused_without_allocating() {
+        let huge = header_with_size([1 << 20; 3]);
+        assert!(GridState::decode(&huge).is_err());
+        let overflowing = header_with_size([u32::MAX; 3]);
+        assert!(GridState::decode(&overflowing).is_err());
+    }
+
+    #[test]
+    fn patches_for_another_size_are_refused() {
+        let (occluded, explored) = buffers();
+        let mut bytes = GridState::encode(&occluded, &explored);
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&1024u32.to_le_bytes());
+        assert!(GridState::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn only_the_copy_taking_the_bytes_in_hears_what_was_left_behind() {
+        let (occluded, explored) = buffers();
+        let bytes = GridState::encode(&occluded, &explored);
+        let other = GridState::encode(&explored, &occluded);
+        GridState::note_left_behind(&bytes, vec!["views", "portals"]);
+        // A saved scene from another run takes in other bytes, and leaves the note alone
+        assert!(GridState::take_left_behind(&other).is_empty());
+        assert_eq!(GridState::take_left_behind(&bytes), ["views", "portals"]);
+        assert!(GridState::take_left_behind(&bytes).is_empty());
+        // Handing out bytes with nothing left behind forgets an earlier note
+        GridState::note_left_behind(&bytes, vec!["views"]);
+        GridState::note_left_behind(&bytes, Vec::new());
+        assert!(GridState::take_left_behind(&bytes).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use godot::prelude::*;
+
+use crate::bitset::Index3;
+
+/// A bulk import of occlusion from another voxel store, a batch of cells per frame,
+/// see Display.import_via_sampler(). The sampler is generic only so tests need no engine
+#[derive(Clone, PartialEq)]
+pub struct SamplerImport<S = Callable> {
+    pub sampler: S,
+    min: Index3,
+    size: Index3,
+    batch_size: usize,
+    /// Number of cells imported so far, taken in ascending x, then y, then z order
+    pub imported: usize,
+}
+
+impl<S> SamplerImport<S> {
+    /// An import of the inclusive box from `min` to `max`, `batch_size` cells at a time,
+    /// or the whole box at once for 0
+    pub fn new(sampler: S, min: Index3, max: Index3, batch_size: usize) -> Self {
+        let size = (max.0 - min.0 + 1, max.1 - min.1 + 1, max.2 - min.2 + 1);
+        Self {
+            sampler,
+            min,
+            size,
+            batch_size,
+            imported: 0,
+        }
+    }
+
+    /// Number of cells in the box
+    pub fn total(&self) -> usize {
+        self.size.0 * self.size.1 * self.size.2
+    }
+
+    /// Whether every cell was imported
+    pub fn is_done(&self) -> bool {
+        self.imported == self.total()
+    }
+
+    /// The cells of the next batch, none once every cell was imported
+    pub fn next_batch(&self) -> Vec<Index3> {
+        let (_, size_y, size_z) = self.size;
+        let end = match self.batch_size {
+            0 => self.total(),
+            batch_size => (self.imported + batch_size).min(self.total()),
+        };
+        (self.imported..end)
+            .map(|i| {
+                (
+                    self.min.0 + i / (size_y * size_z),
+                    self.min.1 + i / size_z % size_y,
+                    self.min.2 + i % size_z,
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The batches of an import run to the end, counting each as imported
+    fn run(mut import: SamplerImport<()>) -> Vec<Vec<Index3>> {
+        let mut batches = Vec::new();
+        while !import.is_done() {
+            let batch = import.next_batch();
+            assert!(!batch.is_empty());
+            import.imported += batch.len();
+            batches.push(batch);
+        }
+        assert!(import.next_batch().is_empty());
+        batches
+    }
+
+    #[test]
+    fn batches_cover_the_box_in_order() {
+        let import = SamplerImport::new((), (1, 2, 3), (2, 3, 5), 5);
+        assert_eq!(import.total(), 12);
+        let batches = run(import);
+        let lens: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(lens, [5, 5, 2]);
+        let mut cells = Vec::new();
+        for x in 1..=2 {
+            for y in 2..=3 {
+                for z in 3..=5 {
+                    cells.push((x, y, z));
+                }
+            }
+        }
+        assert_eq!(batches.concat(), cells);
+
+        // A batch boundary at the end of a row, and a batch size that divides the box
+        let import = SamplerImport::new((), (0, 0, 0), (1, 1, 2), 3);
+        assert_eq!(run(import).concat().len(), 12);
+        let import = SamplerImport::new((), (0, 0, 0), (3, 3, 3), 0);
+        assert_eq!(run(import).iter().map(Vec::len).collect::<Vec<_>>(), [64]);
+        let import = SamplerImport::new((), (4, 4, 4), (4, 4, 4), 7);
+        assert_eq!(run(import), [vec![(4, 4, 4)]]);
+    }
+
+    #[test]
+    fn restarted_imports_are_told_apart() {
+        // advance_sampler_import() drops a batch whose import was cancelled or replaced while
+        // the sampler ran, even by one with the same sampler and progress
+        let sampler = "sampler";
+        let mut import = SamplerImport::new(sampler, (0, 0, 0), (3, 3, 3), 4);
+        let restarted = import.clone();
+        assert!(import == restarted);
+        import.imported += import.next_batch().len();
+        assert!(import != restarted);
+        assert!(restarted != SamplerImport::new(sampler, (0, 0, 1), (3, 3, 3), 4));
+        assert!(restarted != SamplerImport::new(sampler, (0, 0, 0), (3, 3, 3), 8));
+        assert!(restarted != SamplerImport::new("another", (0, 0, 0), (3, 3, 3), 4));
+    }
+}
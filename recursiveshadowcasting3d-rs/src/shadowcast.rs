@@ -0,0 +1,3254 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::{Add, Sub},
+};
+
+use godot::{builtin::real, prelude::*};
+use smallvec::SmallVec;
+
+use crate::{
+    bitset::{Axes, BitGrid, Index3, cell_index, index_cell},
+    occlusion_source::OcclusionSource,
+    pass_cache::PassSettings,
+    terrain::Terrain,
+};
+
+pub const MAX_DEPTH: usize = 15;
+
+/// Most depth slices only have a handful of occluders, so keep rect lists on the stack
+type Rects = SmallVec<[Rect; 16]>;
+
+/// The two grid axes a pass spans, which become its local x and y, while it casts along the
+/// third as its local z. Grid coordinates permute into local ones as:
+/// - XY: local (x, y, z) is grid (x, y, z), casting along z
+/// - ZY: local (x, y, z) is grid (z, y, x), casting along x
+/// - ZX: local (x, y, z) is grid (z, x, y), casting along y
+///
+/// Each permutation swaps or rotates the axes without mirroring any, so the same offsets and
+/// slopes work in every plane
+#[derive(GodotConvert, Clone, Copy, PartialEq)]
+#[godot(via = i64)]
+pub enum UnitPlane3d {
+    XY,
+    ZY,
+    ZX,
+}
+
+impl UnitPlane3d {
+    /// A grid space vector in this plane's local coordinates
+    pub fn to_local(&self, v: Vector3i) -> Vector3i {
+        match self {
+            UnitPlane3d::XY => v,
+            UnitPlane3d::ZY => Vector3i::new(v.z, v.y, v.x),
+            UnitPlane3d::ZX => Vector3i::new(v.z, v.x, v.y),
+        }
+    }
+
+    /// to_local() for positions between cell centers
+    pub fn to_local_float(&self, v: Vector3) -> Vector3 {
+        match self {
+            UnitPlane3d::XY => v,
+            UnitPlane3d::ZY => Vector3::new(v.z, v.y, v.x),
+            UnitPlane3d::ZX => Vector3::new(v.z, v.x, v.y),
+        }
+    }
+
+    /// A vector in this plane's local coordinates in grid space, undoing to_local()
+    pub fn to_grid(&self, v: Vector3i) -> Vector3i {
+        match self {
+            UnitPlane3d::XY => v,
+            UnitPlane3d::ZY => Vector3i::new(v.z, v.y, v.x),
+            UnitPlane3d::ZX => Vector3i::new(v.y, v.z, v.x),
+        }
+    }
+
+    /// to_grid() for positions between cell centers, such as the corners of debug rects
+    pub fn to_grid_float(&self, v: Vector3) -> Vector3 {
+        match self {
+            UnitPlane3d::XY => v,
+            UnitPlane3d::ZY => Vector3::new(v.z, v.y, v.x),
+            UnitPlane3d::ZX => Vector3::new(v.y, v.z, v.x),
+        }
+    }
+
+    /// The grid index of the cell at local (x, y) in the layer at local z
+    pub fn grid_index(&self, x: usize, y: usize, z: i32) -> Index3 {
+        cell_index(self.to_grid(Vector3i::new(x as i32, y as i32, z)))
+    }
+}
+
+/// With y and z traded, casting along z becomes casting along y and the other way around,
+/// while casting along x stays. The local axes across the plane trade places in every plane
+impl Axes for UnitPlane3d {
+    fn swap_up(self) -> Self {
+        match self {
+            UnitPlane3d::XY => UnitPlane3d::ZX,
+            UnitPlane3d::ZY => UnitPlane3d::ZY,
+            UnitPlane3d::ZX => UnitPlane3d::XY,
+        }
+    }
+}
+
+/// A half-open rectangle, covering [sx, ex) by [sy, ey)
+#[derive(Clone, Copy)]
+pub struct Rect {
+    pub sx: real,
+    pub sy: real,
+    // represent end, not length
+    pub ex: real,
+    pub ey: real,
+}
+
+impl Rect {
+    fn is_valid(&self) -> bool {
+        self.sx < self.ex && self.sy < self.ey
+    }
+
+    fn grown(&self, amount: real) -> Rect {
+        Rect {
+            sx: self.sx - amount,
+            sy: self.sy - amount,
+            ex: self.ex + amount,
+            ey: self.ey + amount,
+        }
+    }
+
+    fn area(&self) -> real {
+        (self.ex - self.sx) * (self.ey - self.sy)
+    }
+
+    fn intersects(&self, other: &Rect) -> bool {
+        self.sx < other.ex && self.ex > other.sx && self.sy < other.ey && self.ey > other.sy
+    }
+
+    fn intersection(&self, other: &Rect) -> Option<Rect> {
+        if !self.intersects(other) {
+            return None;
+        }
+
+        let result = Rect {
+            sx: self.sx.max(other.sx),
+            sy: self.sy.max(other.sy),
+            ex: self.ex.min(other.ex),
+            ey: self.ey.min(other.ey),
+        };
+
+        if result.is_valid() {
+            Some(result)
+        } else {
+            None
+        }
+    }
+
+    /// Move the sides out to the cell boundaries around them, in cells, without crossing the
+    /// eye from the side of it they start on, so quadrants keep to their own sides
+    fn round_out_to_cells(&mut self, eye: (real, real)) {
+        for (start, end, eye) in [
+            (&mut self.sx, &mut self.ex, eye.0),
+            (&mut self.sy, &mut self.ey, eye.1),
+        ] {
+            let mut rounded_start = (*start + 0.5).floor() - 0.5;
+            let mut rounded_end = (*end - 0.5).ceil() + 0.5;
+            if *start >= eye {
+                rounded_start = rounded_start.max(eye);
+            }
+            if *end <= eye {
+                rounded_end = rounded_end.min(eye);
+            }
+            (*start, *end) = (rounded_start, rounded_end);
+        }
+    }
+
+    fn swap_start_and_end(&self) -> Rect {
+        Rect {
+            sx: self.ex,
+            sy: self.ey,
+            ex: self.sx,
+            ey: self.sy,
+        }
+    }
+
+    pub const ZERO: Rect = Rect {
+        sx: 0.0,
+        sy: 0.0,
+        ex: 0.0,
+        ey: 0.0,
+    };
+}
+
+impl Add for Rect {
+    type Output = Rect;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Rect {
+            sx: self.sx + rhs.sx,
+            sy: self.sy + rhs.sy,
+            ex: self.ex + rhs.ex,
+            ey: self.ey + rhs.ey,
+        }
+    }
+}
+
+impl Sub for Rect {
+    type Output = Rect;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Rect {
+            sx: self.sx - rhs.sx,
+            sy: self.sy - rhs.sy,
+            ex: self.ex - rhs.ex,
+            ey: self.ey - rhs.ey,
+        }
+    }
+}
+
+/// Occluders that only block sight one way, by the direction sight passes through them freely.
+/// Ordered by cell, so saving them lists them in the same order every time
+pub type OneWayCells = BTreeMap<Index3, Vector3i>;
+
+/// A rectangle drawn by the debug visualization, in plane-local coordinates
+#[derive(Clone, Copy)]
+pub struct DebugRect {
+    pub plane: UnitPlane3d,
+    pub depth: real,
+    pub rect: Rect,
+    pub color: Color,
+}
+
+/// The unblocked pieces of one view, which light goes on through, in plane-local coordinates
+/// on the face of the layer the view was scanned at
+#[derive(Clone)]
+pub struct LitRects {
+    pub plane: UnitPlane3d,
+    pub reverse_z: bool,
+    pub depth: usize,
+    /// Plane-local z of the face
+    pub face: real,
+    pub rects: Vec<Rect>,
+}
+
+/// What happens to light passing exactly between two occluders that touch at an edge or corner
+#[derive(GodotConvert, Var, Export, Clone, Copy, Default, PartialEq)]
+#[godot(via = i64)]
+pub enum CornerRule {
+    /// Touching occluders are welded together, so nothing squeezes between them
+    #[default]
+    Block,
+    /// Occluders touching only at a corner leave a thin gap that light passes through
+    Allow,
+}
+
+/// How far occluders reach to be considered touching
+const CORNER_EPSILON: real = 1e-4;
+/// How close a view edge has to be to a cell boundary to be treated as exactly on it. Edges
+/// drift by more than 1e-5 from f32 rounding deep into a cast, and it stays under the
+/// CORNER_EPSILON the weld moves edges by, so neither lands a width exactly on it
+const BOUNDARY_EPSILON: real = 5e-5;
+/// Width below which a piece of a view is a sliver. Between the CORNER_EPSILON the weld moves
+/// an edge by and twice that, so a sliver the weld leaves is never exactly on it
+const SLIVER_WIDTH: real = 1.5e-4;
+/// Width of the gap kept open between occluders meeting at a corner under CornerRule::Allow
+const CORNER_GAP: real = 1e-3;
+
+/// Angular level of detail: far layers sample the occlusion grid in coarser blocks.
+/// Any occluder in a block makes the whole block occlude, which hides cells near block edges
+/// that are actually visible. Occluders only ever grow, but the view gets split differently,
+/// so a few cells right at shadow edges can also end up visible where the exact cast hides them.
+/// Visibility itself is still marked per cell
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Lod {
+    /// Depth from which blocks are used, 0 to always sample single cells
+    pub start_depth: usize,
+    /// How much the block size grows every start_depth layers
+    pub factor: usize,
+    /// Angle in radians below which a cell is too small to matter, 0 to never cull by angle
+    pub min_angle: real,
+    pub angle_cull: AngleCull,
+}
+
+/// What happens to layers whose cells subtend less than the minimum angle
+#[derive(GodotConvert, Var, Export, Clone, Copy, Default, PartialEq)]
+#[godot(via = i64)]
+pub enum AngleCull {
+    /// Occluders are sampled in blocks wide enough to subtend the minimum angle
+    #[default]
+    Coarsen,
+    /// The cast stops before the first such layer
+    Stop,
+}
+
+impl Lod {
+    const MAX_STRIDE: usize = 16;
+
+    /// Width in cells of the blocks the occlusion grid is sampled in, at some depth. With both
+    /// distance and angle LOD the wider of the two blocks is used, they do not multiply
+    pub fn stride(&self, depth: usize) -> usize {
+        self.distance_stride(depth).max(self.angle_stride(depth))
+    }
+
+    /// Whether the cast ends before reaching `depth`, for AngleCull::Stop
+    pub fn stops_before(&self, depth: usize) -> bool {
+        self.angle_cull == AngleCull::Stop && self.angle_stride(depth) > 1
+    }
+
+    fn distance_stride(&self, depth: usize) -> usize {
+        if self.start_depth == 0 || self.factor < 2 || depth < self.start_depth {
+            return 1;
+        }
+        let level = (depth / self.start_depth) as u32;
+        self.factor.saturating_pow(level).min(Self::MAX_STRIDE)
+    }
+
+    /// How many cells wide a block must be to subtend min_angle at `depth`, seen head on from
+    /// the origin, or 1 when a single cell already does
+    fn angle_stride(&self, depth: usize) -> usize {
+        if self.min_angle <= 0.0 || depth == 0 {
+            return 1;
+        }
+        let cell_angle = 2.0 * (0.5 / depth as real).atan();
+        if cell_angle >= self.min_angle {
+            return 1;
+        }
+        ((self.min_angle / cell_angle).ceil() as usize).clamp(2, Self::MAX_STRIDE)
+    }
+}
+
+/// Everything a shadowcasting run reads from and writes to
+pub struct Caster<'a> {
+    /// Occlusion is read from here, a grid or any other source the size of `visible`
+    pub occluded: &'a dyn OcclusionSource,
+    pub visible: &'a mut BitGrid,
+    pub origin: Vector3i,
+    /// Offset of the eye from the center of the origin cell, for soft shadow samples
+    pub jitter: Vector3,
+    pub max_depth: usize,
+    pub lod: Lod,
+    pub corner_rule: CornerRule,
+    /// When set, view and occluder rectangles are collected here for visualization
+    pub debug_rects: Option<&'a mut Vec<DebugRect>>,
+    /// When set, the unblocked pieces of every view that has any are collected here
+    pub lit_rects: Option<&'a mut Vec<LitRects>>,
+    /// When set, the area of each reached cell's face that is in view is added up here.
+    /// The view pieces within one pass never overlap, so use one map per pass
+    pub fractions: Option<&'a mut HashMap<Index3, f32>>,
+    /// When set, only cells inside these inclusive boxes are marked, and every cell outside them
+    /// occludes, so the cast never leaves them
+    pub bounds: Option<&'a [(Vector3i, Vector3i)]>,
+    /// When set, occluded cells in here only block sight going against their open direction
+    pub one_way: Option<&'a OneWayCells>,
+    /// When set, every occluded cell whose occlusion cut into a view is added here,
+    /// once per view it cut into
+    pub blockers: Option<&'a mut Vec<Index3>>,
+    /// When set, cells covered by this terrain occlude as well as those in `occluded`
+    pub terrain: Option<&'a Terrain>,
+    /// When set, the ExposedCells of `occluded` and `one_way`. Past the first layer, blocks of
+    /// occluded cells none of which is exposed are left out, as the occluders around them cast
+    /// every shadow they would. Ignored past the LOD start depth
+    pub exposed: Option<&'a BitGrid>,
+    pub rects: RectLimit,
+}
+
+/// What happens to an unblocked piece of a view narrower than a cell can show through
+#[derive(GodotConvert, Var, Export, Clone, Copy, Default, PartialEq, Debug)]
+#[godot(via = i64)]
+pub enum NarrowPolicy {
+    /// The piece is dropped, so nothing is seen through it any deeper
+    Stop,
+    /// The piece's narrow sides move out or in to the nearest cell boundaries. A piece over a
+    /// cell's center widens to the whole cell, one between two centers is dropped
+    #[default]
+    Snap,
+    /// The piece is cast through exactly
+    Continue,
+}
+
+/// How pieces of a view thinner than some fraction of a cell are treated
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct NarrowRects {
+    /// Width or height in cells below which a piece is narrow, 0 for none
+    pub min_width: real,
+    pub policy: NarrowPolicy,
+}
+
+impl NarrowRects {
+    /// Whether the policy changes any piece, which makes the way a view is cut matter
+    fn is_active(&self) -> bool {
+        self.min_width > 0.0 && self.policy != NarrowPolicy::Continue
+    }
+
+    /// Apply the policy to an unblocked piece of a view, in cells, seen from `eye` across the
+    /// view. Returns false if it is dropped
+    fn admit(&self, rect: &mut Rect, eye: (real, real)) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        for (start, end, eye) in [
+            (&mut rect.sx, &mut rect.ex, eye.0),
+            (&mut rect.sy, &mut rect.ey, eye.1),
+        ] {
+            // A piece exactly min_width wide rounds to either side of it, so allow for that the
+            // way cells_in_span does for edges on cell boundaries
+            if *end - *start >= self.min_width - BOUNDARY_EPSILON {
+                continue;
+            }
+            if self.policy == NarrowPolicy::Stop {
+                return false;
+            }
+            // Cell centers lie on whole coordinates and boundaries halfway between. A side on a
+            // center, or within rounding of it, counts it as covered either way, so pieces
+            // mirrored across it snap alike
+            let mut snapped_start = (*start - BOUNDARY_EPSILON).ceil() - 0.5;
+            let mut snapped_end = (*end + BOUNDARY_EPSILON).floor() + 0.5;
+            // Quadrants meet across the eye, so pieces along them keep to their own side
+            if *start >= eye {
+                snapped_start = snapped_start.max(eye);
+            }
+            if *end <= eye {
+                snapped_end = snapped_end.min(eye);
+            }
+            if snapped_end <= snapped_start {
+                return false;
+            }
+            (*start, *end) = (snapped_start, snapped_end);
+        }
+        true
+    }
+}
+
+/// A cap on the unblocked pieces one view splits into, and how casting under it went
+#[derive(Clone, Copy, Default)]
+pub struct RectLimit {
+    /// Most pieces a view keeps, or 0 for no cap. Past it the smallest pieces are merged into
+    /// their bounding box, and the views cut from it round their pieces out to whole cells,
+    /// which can only make extra cells visible, never hide any
+    pub max_per_view: usize,
+    /// What happens to pieces narrower than a cell
+    pub narrow: NarrowRects,
+    /// Most unblocked pieces any one view split into, before merging
+    pub most_seen: usize,
+    /// Views whose pieces were merged
+    pub merged_views: usize,
+}
+
+impl RectLimit {
+    pub fn new(max_per_view: usize, narrow: NarrowRects) -> Self {
+        Self {
+            max_per_view,
+            narrow,
+            ..Self::default()
+        }
+    }
+}
+
+impl Caster<'_> {
+    /// Run every pass (all quadrants, both directions, all planes) and mark the origin itself
+    pub fn cast_all(&mut self) {
+        self.mark_origin_visible();
+        for (initial_slope_rect, reverse_z, plane) in all_passes() {
+            cast_light(self, &initial_slope_rect, 1, reverse_z, &plane);
+        }
+    }
+
+    /// Whether the grid or the terrain occludes a cell, ignoring one-way cells
+    fn is_occluded(&self, index: Index3) -> bool {
+        let cell = index_cell(index);
+        self.occluded.is_occluded(cell.x, cell.y, cell.z)
+            || self.terrain.is_some_and(|terrain| terrain.occludes(index))
+    }
+
+    /// Whether an occluded cell lets sight from the origin through, because it is one-way and
+    /// the cell lies on the open side of the origin
+    fn sees_through(&self, index: Index3) -> bool {
+        let Some(open_direction) = self.one_way.and_then(|one_way| one_way.get(&index)) else {
+            return false;
+        };
+        let delta = index_cell(index) - self.origin;
+        delta.x * open_direction.x + delta.y * open_direction.y + delta.z * open_direction.z > 0
+    }
+
+    /// The passes start one layer away from the origin, so the origin cell is never scanned
+    pub fn mark_origin_visible(&mut self) {
+        let index = cell_index(self.origin);
+        self.visible.set(index, true);
+    }
+}
+
+/// Call `visitor` once with every cell a full cast from `origin` would see, however many passes
+/// reach it, nearest first: by the cell's largest distance along any axis from the origin, its
+/// depth, then within a depth in ascending x, then y, then z order. It gets the cell's depth and
+/// the largest fraction of its face any single pass saw, as get_visibility_fraction() reports
+/// it. `scratch` must be the size of the grid, and is left holding the cast
+pub fn walk_frustum(
+    occluded: &dyn OcclusionSource,
+    one_way: &OneWayCells,
+    terrain: Option<&Terrain>,
+    scratch: &mut BitGrid,
+    settings: &PassSettings,
+    origin: Vector3i,
+    mut visitor: impl FnMut(Index3, usize, f32),
+) {
+    scratch.clear();
+    let mut best_fractions: HashMap<Index3, f32> = HashMap::new();
+    let mut fractions = HashMap::new();
+    for (initial_slope_rect, reverse_z, plane) in all_passes() {
+        let mut caster = Caster {
+            occluded,
+            visible: scratch,
+            origin,
+            jitter: settings.eye_jitter,
+            max_depth: settings.max_depth,
+            lod: settings.lod,
+            corner_rule: settings.corner_rule,
+            debug_rects: None,
+            lit_rects: None,
+            fractions: Some(&mut fractions),
+            bounds: None,
+            one_way: Some(one_way),
+            blockers: None,
+            terrain,
+            exposed: None,
+            rects: RectLimit::new(settings.max_rects, settings.narrow),
+        };
+        cast_light(&mut caster, &initial_slope_rect, 1, reverse_z, &plane);
+        // The view pieces of one pass never overlap, but those of different passes can
+        for (index, fraction) in fractions.drain() {
+            let best = best_fractions.entry(index).or_insert(0.0);
+            *best = best.max(fraction.min(1.0));
+        }
+    }
+    scratch.set(cell_index(origin), true);
+
+    // Cells come out of the grid in x, then y, then z order, which each ring of depth keeps
+    let mut rings: Vec<Vec<Index3>> = Vec::new();
+    scratch.for_each_set(|index| {
+        let delta = (index_cell(index) - origin).abs();
+        let depth = delta.x.max(delta.y).max(delta.z) as usize;
+        if depth >= rings.len() {
+            rings.resize(depth + 1, Vec::new());
+        }
+        rings[depth].push(index);
+    });
+    for (depth, ring) in rings.into_iter().enumerate() {
+        for index in ring {
+            let fraction = match depth {
+                0 => 1.0,
+                _ => best_fractions.get(&index).copied().unwrap_or(0.0),
+            };
+            visitor(index, depth, fraction);
+        }
+    }
+}
+
+/// One quadrant of slopes around the casting axis each
+pub const INITIAL_SLOPE_RECTS: [Rect; 4] = [
+    Rect {
+        sx: real::INFINITY,
+        sy: real::INFINITY,
+        ex: 1.0,
+        ey: 1.0,
+    },
+    Rect {
+        sx: -1.0,
+        sy: real::INFINITY,
+        ex: real::INFINITY,
+        ey: 1.0,
+    },
+    Rect {
+        sx: real::INFINITY,
+        sy: -1.0,
+        ex: 1.0,
+        ey: real::INFINITY,
+    },
+    Rect {
+        sx: -1.0,
+        sy: -1.0,
+        ex: real::INFINITY,
+        ey: real::INFINITY,
+    },
+];
+
+pub const PASS_COUNT: usize = 24;
+
+/// Environment variable that, when set, records the views each pass scans as the expected work
+/// instead of checking against it, both in FovProfileScene runs and in this module's tests
+pub const UPDATE_EXPECTED_WORK_ENV: &str = "SHADOWCAST_UPDATE_EXPECTED_WORK";
+
+/// Every pass as its initial slope rect, direction and plane, in the order cast_all() runs them
+pub fn all_passes() -> impl Iterator<Item = (Rect, bool, UnitPlane3d)> {
+    INITIAL_SLOPE_RECTS
+        .into_iter()
+        .flat_map(|initial_slope_rect| {
+            [false, true].into_iter().flat_map(move |reverse_z| {
+                [UnitPlane3d::XY, UnitPlane3d::ZX, UnitPlane3d::ZY]
+                    .into_iter()
+                    .map(move |plane| (initial_slope_rect, reverse_z, plane))
+            })
+        })
+}
+
+/// Whether a slope rect covers some area, following the conventions of INITIAL_SLOPE_RECTS:
+/// each slope is depth over offset from the axis, so infinite slopes lie on the axis
+pub fn is_valid_slope_rect(slope_rect: &Rect) -> bool {
+    let offsets = Rect {
+        sx: 1.0 / slope_rect.sx,
+        sy: 1.0 / slope_rect.sy,
+        ex: 1.0 / slope_rect.ex,
+        ey: 1.0 / slope_rect.ey,
+    };
+    offsets.is_valid()
+}
+
+/// The slopes within both slope rects of passes along the same plane and direction
+pub fn intersect_slope_rects(a: &Rect, b: &Rect) -> Option<Rect> {
+    let slope_rect = Rect {
+        sx: 1.0 / (1.0 / a.sx).max(1.0 / b.sx),
+        sy: 1.0 / (1.0 / a.sy).max(1.0 / b.sy),
+        ex: 1.0 / (1.0 / a.ex).min(1.0 / b.ex),
+        ey: 1.0 / (1.0 / a.ey).min(1.0 / b.ey),
+    };
+    is_valid_slope_rect(&slope_rect).then_some(slope_rect)
+}
+
+/// Whether a pass from `origin` may read or mark any cell in the inclusive box from `min` to `max`.
+/// This is a conservative bound of the pass's frustum, padded by the cells the depth scan
+/// looks at around each view rect
+pub fn pass_may_touch_box(
+    (initial_slope_rect, reverse_z, plane): (Rect, bool, UnitPlane3d),
+    origin: Vector3i,
+    max_depth: usize,
+    min: Vector3i,
+    max: Vector3i,
+) -> bool {
+    const SLACK: i32 = 2;
+
+    // Box relative to the origin, in plane-local coordinates, with z pointing along the pass
+    let (min, max) = (min - origin, max - origin);
+    let (mut min, mut max) = (plane.to_local(min), plane.to_local(max));
+    if reverse_z {
+        (min.z, max.z) = (-max.z, -min.z);
+    }
+
+    let z_end = max.z.min(max_depth as i32);
+    if z_end < min.z.max(1) {
+        return false;
+    }
+
+    // Cells further out than the deepest layer in the box are never reached
+    let reach = z_end + SLACK;
+    // Quadrants starting at an infinite slope lie on the positive side of the axis,
+    // which is mirrored for passes going the other way
+    let positive_x = initial_slope_rect.sx.is_infinite() != reverse_z;
+    let positive_y = initial_slope_rect.sy.is_infinite() != reverse_z;
+    let (x_start, x_end) = match positive_x {
+        true => (-SLACK, reach),
+        false => (-reach, SLACK),
+    };
+    let (y_start, y_end) = match positive_y {
+        true => (-SLACK, reach),
+        false => (-reach, SLACK),
+    };
+    min.x <= x_end && max.x >= x_start && min.y <= y_end && max.y >= y_start
+}
+
+/// A pass's initial slope rect, direction and plane
+pub type Pass = (Rect, bool, UnitPlane3d);
+
+/// A view still to be scanned: the slopes of one piece of a pass, at one depth
+#[derive(Clone, Copy)]
+pub struct WorkItem {
+    pub slope_rect: Rect,
+    pub depth: usize,
+    pub reverse_z: bool,
+    pub plane: UnitPlane3d,
+    /// Whether the pieces of this view or of one it was cut from were merged by the rect cap
+    pub merged: bool,
+}
+
+/// How far cast_layered() got
+pub struct LayeredCast {
+    /// Views scanned, each one piece of a pass at one depth
+    pub work_items: usize,
+    /// Deepest layer that every pass finished
+    pub completed_depth: usize,
+    /// Whether layers were left unscanned to stay within the budget
+    pub truncated: bool,
+    /// Time spent in each pass, as measured by the given clock
+    pub pass_usec: Vec<u64>,
+    /// Views scanned in each pass, which unlike the time is the same on every machine
+    pub pass_work_items: Vec<usize>,
+}
+
+/// One view scanned by cast_layered(), for profiling where a cast spends its time
+#[derive(Clone, Copy)]
+pub struct TracedItem {
+    /// Index into the passes cast_layered() was given
+    pub pass: usize,
+    /// Index into the trace of the view this one is an unblocked piece of, None at depth 1
+    pub parent: Option<usize>,
+    pub depth: usize,
+    pub slope_rect: Rect,
+    /// The view at its depth, in plane-local coordinates
+    pub view_rect: Rect,
+    /// Occluders that cut into the view, after merging occluded cells into blocks
+    pub occluders: usize,
+    /// Unblocked pieces of the view queued for the next depth
+    pub children: usize,
+    /// Measured with std::time, which is finer than the given clock, except on web exports,
+    /// which have no std::time clock and fall back to the given one
+    pub elapsed_nsec: u64,
+}
+
+/// A trace as the JSON dump_last_recompute_trace() writes
+pub fn trace_json(trace: &[TracedItem]) -> String {
+    use std::fmt::Write;
+
+    // Display writes infinities as inf and -inf, which JSON only takes as strings
+    let number = |value: real| match value.is_infinite() {
+        true => format!("\"{}\"", value),
+        false => value.to_string(),
+    };
+    let rect = |rect: &Rect| {
+        format!(
+            "[{}, {}, {}, {}]",
+            number(rect.sx),
+            number(rect.sy),
+            number(rect.ex),
+            number(rect.ey)
+        )
+    };
+    let mut json = String::with_capacity(trace.len() * 160 + 16);
+    json.push_str("{\"items\": [");
+    for (index, item) in trace.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        let parent = item
+            .parent
+            .map_or("null".to_string(), |parent| parent.to_string());
+        let _ = write!(
+            json,
+            "\n{{\"pass\": {}, \"parent\": {}, \"depth\": {}, \"slope_rect\": {}, \"view_rect\": {}, \
+             \"occluders\": {}, \"children\": {}, \"elapsed_nsec\": {}}}",
+            item.pass,
+            parent,
+            item.depth,
+            rect(&item.slope_rect),
+            rect(&item.view_rect),
+            item.occluders,
+            item.children,
+            item.elapsed_nsec
+        );
+    }
+    json.push_str("\n]}\n");
+    json
+}
+
+/// What scan_layer() found in a view
+struct LayerScan {
+    view_rect: Rect,
+    occluders: usize,
+}
+
+/// Scan a view and everything visible through it, from `depth` outwards
+pub fn cast_light(
+    caster: &mut Caster,
+    slope_rect: &Rect,
+    depth: usize,
+    reverse_z: bool,
+    plane: &UnitPlane3d,
+) {
+    let mut pending = vec![WorkItem {
+        slope_rect: *slope_rect,
+        depth,
+        reverse_z,
+        plane: *plane,
+        merged: false,
+    }];
+    while let Some(item) = pending.pop() {
+        scan_layer(caster, &item, &mut pending);
+    }
+}
+
+/// A cast that scans its views a few at a time, for spreading a recompute over frames. Every
+/// call to advance() must use a caster from the same origin with the same settings
+pub struct SlicedCast {
+    pending: Vec<WorkItem>,
+    /// Views scanned so far
+    pub work_items: usize,
+}
+
+impl SlicedCast {
+    /// A cast of `passes` with nothing scanned yet
+    pub fn new(passes: impl Iterator<Item = Pass>) -> Self {
+        let pending = passes
+            .map(|(slope_rect, reverse_z, plane)| WorkItem {
+                slope_rect,
+                depth: 1,
+                reverse_z,
+                plane,
+                merged: false,
+            })
+            .collect();
+        SlicedCast {
+            pending,
+            work_items: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Scan views until none are left or `stop` returns true, which it is asked after every
+    /// view while any are left. Returns whether the cast is done
+    pub fn advance(&mut self, caster: &mut Caster, mut stop: impl FnMut() -> bool) -> bool {
+        while let Some(item) = self.pending.pop() {
+            scan_layer(caster, &item, &mut self.pending);
+            self.work_items += 1;
+            if !self.pending.is_empty() && stop() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Run passes side by side one depth layer at a time, each into the caster at the same index.
+/// With a `max_work_items` budget above 0, the first layer that would go over it and everything
+/// beyond it is left unscanned, so what gets cut off is always further away than what was scanned.
+/// With a trace, every view scanned is also added to it, in the order they were scanned
+pub fn cast_layered(
+    casters: &mut [Caster],
+    passes: &[Pass],
+    max_work_items: usize,
+    clock: impl Fn() -> u64,
+    mut trace: Option<&mut Vec<TracedItem>>,
+) -> LayeredCast {
+    let mut layer: Vec<Vec<WorkItem>> = passes
+        .iter()
+        .map(|&(slope_rect, reverse_z, plane)| {
+            vec![WorkItem {
+                slope_rect,
+                depth: 1,
+                reverse_z,
+                plane,
+                merged: false,
+            }]
+        })
+        .collect();
+    let mut next_layer: Vec<Vec<WorkItem>> = vec![Vec::new(); passes.len()];
+    // trace indices of the views each item of the layer was cut from, when tracing
+    let mut parents: Vec<Vec<usize>> = vec![Vec::new(); passes.len()];
+    let mut next_parents: Vec<Vec<usize>> = vec![Vec::new(); passes.len()];
+    let mut result = LayeredCast {
+        work_items: 0,
+        completed_depth: 0,
+        truncated: false,
+        pass_usec: vec![0; passes.len()],
+        pass_work_items: vec![0; passes.len()],
+    };
+
+    loop {
+        let count: usize = layer.iter().map(Vec::len).sum();
+        if count == 0 {
+            break;
+        }
+        if max_work_items > 0 && result.work_items + count > max_work_items {
+            result.truncated = true;
+            break;
+        }
+
+        for (pass, items) in layer.iter().enumerate() {
+            let start = clock();
+            for (index, item) in items.iter().enumerate() {
+                let Some(trace) = trace.as_deref_mut() else {
+                    scan_layer(&mut casters[pass], item, &mut next_layer[pass]);
+                    continue;
+                };
+                let queued = next_layer[pass].len();
+                #[cfg(not(target_family = "wasm"))]
+                let started = std::time::Instant::now();
+                #[cfg(target_family = "wasm")]
+                let started = clock();
+                let scan = scan_layer(&mut casters[pass], item, &mut next_layer[pass]);
+                #[cfg(not(target_family = "wasm"))]
+                let elapsed_nsec = started.elapsed().as_nanos() as u64;
+                #[cfg(target_family = "wasm")]
+                let elapsed_nsec = (clock() - started) * 1000;
+                let Some(LayerScan {
+                    view_rect,
+                    occluders,
+                }) = scan
+                else {
+                    continue;
+                };
+                let children = next_layer[pass].len() - queued;
+                next_parents[pass].extend(std::iter::repeat_n(trace.len(), children));
+                trace.push(TracedItem {
+                    pass,
+                    parent: parents[pass].get(index).copied(),
+                    depth: item.depth,
+                    slope_rect: item.slope_rect,
+                    view_rect,
+                    occluders,
+                    children,
+                    elapsed_nsec,
+                });
+            }
+            result.pass_usec[pass] += clock() - start;
+            result.pass_work_items[pass] += items.len();
+        }
+        result.work_items += count;
+        result.completed_depth += 1;
+
+        for (items, next_items) in layer.iter_mut().zip(&mut next_layer) {
+            items.clear();
+            std::mem::swap(items, next_items);
+        }
+        for (items, next_items) in parents.iter_mut().zip(&mut next_parents) {
+            items.clear();
+            std::mem::swap(items, next_items);
+        }
+    }
+    result
+}
+
+/// Scan one view at its depth, and queue the unblocked pieces of it for the next depth.
+/// None if the view is past the caster's max depth
+fn scan_layer(
+    caster: &mut Caster,
+    item: &WorkItem,
+    pending: &mut Vec<WorkItem>,
+) -> Option<LayerScan> {
+    let WorkItem {
+        ref slope_rect,
+        depth,
+        reverse_z,
+        ref plane,
+        mut merged,
+    } = *item;
+    if depth > caster.max_depth || caster.lod.stops_before(depth) {
+        return None;
+    }
+
+    let origin = plane.to_local(caster.origin);
+    let origin_float = origin.cast_float() + plane.to_local_float(caster.jitter);
+
+    let z = match reverse_z {
+        true => -(depth as i32),
+        false => depth as i32,
+    };
+    let z_real = z as real;
+
+    // Calculate the rectangle encompassing the view at this depth, given our slopes and offset (view rect)
+    let z_half_offset = match reverse_z {
+        true => 0.5,
+        false => -0.5,
+    };
+
+    let view_rect = match reverse_z {
+        true => Rect {
+            ex: ((z_real + z_half_offset) / slope_rect.sx) + origin_float.x,
+            ey: ((z_real + z_half_offset) / slope_rect.sy) + origin_float.y,
+            sx: ((z_real + z_half_offset) / slope_rect.ex) + origin_float.x,
+            sy: ((z_real + z_half_offset) / slope_rect.ey) + origin_float.y,
+        },
+        false => Rect {
+            sx: ((z_real + z_half_offset) / slope_rect.sx) + origin_float.x,
+            sy: ((z_real + z_half_offset) / slope_rect.sy) + origin_float.y,
+            ex: ((z_real + z_half_offset) / slope_rect.ex) + origin_float.x,
+            ey: ((z_real + z_half_offset) / slope_rect.ey) + origin_float.y,
+        },
+    };
+
+    // Visualize view rectangle
+    if let Some(debug_rects) = caster.debug_rects.as_deref_mut() {
+        debug_rects.push(DebugRect {
+            plane: *plane,
+            depth: z_real + origin_float.z + z_half_offset,
+            rect: view_rect,
+            color: Color::CYAN,
+        });
+    }
+
+    // Find the cells in view, and around them the ones which could possibly occlude the view
+    // through their side faces
+    let (first_x, end_x) = cells_in_span(view_rect.sx, view_rect.ex);
+    let (first_y, end_y) = cells_in_span(view_rect.sy, view_rect.ey);
+    let s_ix = first_x.saturating_sub(1);
+    let s_iy = first_y.saturating_sub(1);
+    let e_ix = end_x + 1;
+    let e_iy = end_y + 1;
+
+    // Past the LOD start depth, whether each stride x stride block in the current column of
+    // blocks holds any occluder
+    let stride = caster.lod.stride(depth);
+    let mut block_column: SmallVec<[bool; 16]> = SmallVec::new();
+    let z_grid = z + origin.z;
+    let grid_max = index_cell(caster.visible.size()) - Vector3i::ONE;
+    let any_occluded_in_block = |bx: usize, by: usize| {
+        let to_grid = |x: usize, y: usize| plane.to_grid(Vector3i::new(x as i32, y as i32, z_grid));
+        let (from, to) = (to_grid(bx, by), to_grid(bx + stride - 1, by + stride - 1));
+        // Sources are only asked about cells inside the grid
+        let min = from.coord_min(to).coord_max(Vector3i::ZERO);
+        let max = from.coord_max(to).coord_min(grid_max);
+        if min.x > max.x || min.y > max.y || min.z > max.z {
+            return false;
+        }
+        caster
+            .occluded
+            .coarse_any_occluded([min.x, min.y, min.z], [max.x, max.y, max.z])
+            || caster
+                .terrain
+                .is_some_and(|terrain| terrain.occludes_any(cell_index(min), cell_index(max)))
+    };
+
+    // Find occluded indices and merge them into blocks: runs along y within a column, then
+    // runs of equal extent in neighbouring columns. A wall becomes one occluder, not one per cell
+    let mut blocks: SmallVec<[CellBlock; 16]> = SmallVec::new();
+    // blocks that reach the previous column, their ex is not known yet
+    let mut open_blocks: SmallVec<[CellBlock; 16]> = SmallVec::new();
+    let mut column_runs: SmallVec<[(usize, usize); 16]> = SmallVec::new();
+    let bounds = caster.bounds;
+    let in_bounds = |index: Index3| {
+        bounds.is_none_or(|bounds| {
+            let cell = index_cell(index);
+            bounds
+                .iter()
+                .any(|&(min, max)| cell.coord_max(min) == cell && cell.coord_min(max) == cell)
+        })
+    };
+    for x in s_ix..e_ix {
+        if stride > 1 && (x == s_ix || x % stride == 0) {
+            block_column.clear();
+            let bx = x / stride * stride;
+            for by in (s_iy / stride * stride..e_iy).step_by(stride) {
+                block_column.push(any_occluded_in_block(bx, by));
+            }
+        }
+
+        column_runs.clear();
+        let mut run_start = None;
+        for y in s_iy..e_iy {
+            let (x_check, y_check, z_check) = plane.grid_index(x, y, z_grid);
+
+            let in_bounds = in_bounds((x_check, y_check, z_check));
+
+            // Any cell overlapping the view at this depth is visible, occluders included
+            let in_view = (first_x..end_x).contains(&x) && (first_y..end_y).contains(&y);
+            if in_bounds && in_view {
+                caster.visible.set((x_check, y_check, z_check), true);
+                if let Some(fractions) = caster.fractions.as_deref_mut() {
+                    let cell_rect = Rect {
+                        sx: x as real - 0.5,
+                        sy: y as real - 0.5,
+                        ex: x as real + 0.5,
+                        ey: y as real + 0.5,
+                    };
+                    let overlap = cell_rect.intersection(&view_rect).map_or(0.0, |r| r.area());
+                    *fractions.entry((x_check, y_check, z_check)).or_insert(0.0) += overlap as f32;
+                }
+            }
+
+            let occluded = !in_bounds
+                || match stride {
+                    1 => {
+                        caster.is_occluded((x_check, y_check, z_check))
+                            && !caster.sees_through((x_check, y_check, z_check))
+                    }
+                    _ => block_column[y / stride - s_iy / stride],
+                };
+            match (occluded, run_start) {
+                (true, None) => run_start = Some(y),
+                (false, Some(start)) => {
+                    column_runs.push((start, y - 1));
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            column_runs.push((start, e_iy - 1));
+        }
+
+        // Close the blocks that do not continue into this column
+        open_blocks.retain(|block| {
+            let continues = column_runs.contains(&(block.sy, block.ey));
+            if !continues {
+                blocks.push(CellBlock {
+                    ex: x - 1,
+                    ..*block
+                });
+            }
+            continues
+        });
+        // Start new blocks for runs that do not continue an open one
+        for &(sy, ey) in &column_runs {
+            if !open_blocks
+                .iter()
+                .any(|block| (block.sy, block.ey) == (sy, ey))
+            {
+                open_blocks.push(CellBlock {
+                    sx: x,
+                    sy,
+                    ex: x,
+                    ey,
+                });
+            }
+        }
+    }
+    for block in open_blocks {
+        blocks.push(CellBlock {
+            ex: e_ix - 1,
+            ..block
+        });
+    }
+
+    // Buried blocks are in the shadow of the layer before or of the blocks around them. Blocks
+    // holding any other cell stay whole, since cutting them up would only add occluders
+    if let Some(exposed) = caster.exposed.filter(|_| depth > 1 && stride == 1) {
+        let buried = |index: Index3| {
+            in_bounds(index)
+                && exposed.get(index) == Some(false)
+                && caster
+                    .occluded
+                    .is_occluded(index.0 as i32, index.1 as i32, index.2 as i32)
+        };
+        blocks.retain(|block| {
+            !(block.sx..=block.ex)
+                .all(|x| (block.sy..=block.ey).all(|y| buried(plane.grid_index(x, y, z_grid))))
+        });
+    }
+
+    // Convert the blocks to occluder rectangles
+    let mut occluding_rectangles = Rects::new();
+    for block in &blocks {
+        let rect_occluded = get_block_occlusion(block, z_real, origin_float, slope_rect, reverse_z);
+
+        if let Some(debug_rects) = caster.debug_rects.as_deref_mut() {
+            debug_rects.push(DebugRect {
+                plane: *plane,
+                depth: z_real + origin_float.z + z_half_offset,
+                rect: rect_occluded,
+                color: Color::RED,
+            });
+        }
+
+        // Taken out while it is filled in, so the caster can still be asked about occlusion
+        if let Some(blockers) = caster.blockers.take() {
+            if rect_occluded.intersects(&view_rect) {
+                for x in block.sx..=block.ex {
+                    for y in block.sy..=block.ey {
+                        let index = plane.grid_index(x, y, z_grid);
+                        // LOD blocks and cells outside the bounds occlude without an occluder
+                        if caster.is_occluded(index) {
+                            blockers.push(index);
+                        }
+                    }
+                }
+            }
+            caster.blockers = Some(blockers);
+        }
+
+        occluding_rectangles.push(rect_occluded);
+    }
+
+    // Weld occluders that touch, whether they only just do or just miss due to rounding
+    if caster.corner_rule == CornerRule::Block {
+        for rect in &mut occluding_rectangles {
+            *rect = rect.grown(CORNER_EPSILON);
+        }
+    }
+
+    // Subtract big occluders first: they swallow smaller ones instead of being shattered by them
+    let clipped_area = |rect: &Rect| rect.intersection(&view_rect).map_or(0.0, |r| r.area());
+    occluding_rectangles.sort_unstable_by(|a, b| clipped_area(b).total_cmp(&clipped_area(a)));
+
+    // Find the difference between the view rect and these rectangles,
+    let mut unblocked = rectangle_minus_rectangles(view_rect, &occluding_rectangles);
+    // The narrow policy acts on pieces, so cut them by the area alone for mirrored or rotated
+    // maps to get the same pieces mirrored or rotated
+    let narrow = caster.rects.narrow;
+    if narrow.is_active() {
+        unblocked = canonical_pieces(&unblocked);
+    }
+    // A view cut from merged pieces covers more than the exact one, so its pieces are cut
+    // differently, and snapping or dropping them could miss cells the exact pieces see. Every
+    // cell those see overlaps the area, so rounding the pieces out to whole cells keeps them
+    if merged {
+        for rect in &mut unblocked {
+            rect.round_out_to_cells((origin_float.x, origin_float.y));
+        }
+    } else {
+        // View and occluder edges are cell boundaries seen from the eye, and different ones are
+        // almost always far more than SLIVER_WIDTH apart. A sliver this thin lies between two
+        // roundings of the same edge, one through the view's slopes and one through the occluder's
+        // cell, and would see through like a ray wherever they round apart, which differs between
+        // the two sides of the origin. Block's weld already closes these, and Allow's real gaps
+        // between occluders are the corner gaps, opened below
+        unblocked
+            .retain(|rect| rect.ex - rect.sx > SLIVER_WIDTH && rect.ey - rect.sy > SLIVER_WIDTH);
+        // The narrow policy goes before the corner gaps, which are narrow on purpose and lie on
+        // cell boundaries, so Snap would always drop them
+        unblocked.retain(|rect| narrow.admit(rect, (origin_float.x, origin_float.y)));
+    }
+    if caster.corner_rule == CornerRule::Allow {
+        let admitted = unblocked.len();
+        open_corner_gaps(&view_rect, &occluding_rectangles, &mut unblocked);
+        if narrow.is_active() {
+            let gaps = canonical_pieces(&unblocked[admitted..]);
+            unblocked.truncate(admitted);
+            unblocked.extend(gaps);
+        }
+    }
+    caster.rects.most_seen = caster.rects.most_seen.max(unblocked.len());
+    let max_per_view = caster.rects.max_per_view;
+    if max_per_view > 0 && unblocked.len() > max_per_view {
+        merge_smallest_rects(&mut unblocked, max_per_view);
+        caster.rects.merged_views += 1;
+        merged = true;
+    }
+
+    // The pieces are what leaves the layer through its far face, and a cell beside the view can
+    // be reached only there, like those on the diagonals of an open room: a cell seen at its
+    // near face only touches the views of the passes meeting at it
+    let far_scale = (z_real - z_half_offset) / (z_real + z_half_offset);
+    for rect in &unblocked {
+        let far_rect = Rect {
+            sx: (rect.sx - origin_float.x) * far_scale + origin_float.x,
+            sy: (rect.sy - origin_float.y) * far_scale + origin_float.y,
+            ex: (rect.ex - origin_float.x) * far_scale + origin_float.x,
+            ey: (rect.ey - origin_float.y) * far_scale + origin_float.y,
+        };
+        let (far_first_x, far_end_x) = cells_in_span(far_rect.sx, far_rect.ex);
+        let (far_first_y, far_end_y) = cells_in_span(far_rect.sy, far_rect.ey);
+        for x in far_first_x..far_end_x {
+            for y in far_first_y..far_end_y {
+                let in_view = (first_x..end_x).contains(&x) && (first_y..end_y).contains(&y);
+                let index = plane.grid_index(x, y, z_grid);
+                if in_view || !in_bounds(index) {
+                    continue;
+                }
+                caster.visible.set(index, true);
+                // Cells in the view at their near face already have their fraction from there
+                if let Some(fractions) = caster.fractions.as_deref_mut() {
+                    let cell_rect = Rect {
+                        sx: x as real - 0.5,
+                        sy: y as real - 0.5,
+                        ex: x as real + 0.5,
+                        ey: y as real + 0.5,
+                    };
+                    let overlap = cell_rect.intersection(&far_rect).map_or(0.0, |r| r.area());
+                    *fractions.entry(index).or_insert(0.0) += overlap as f32;
+                }
+            }
+        }
+    }
+
+    // Convert unblocked rectangles back to slopes, then make recursive calls at next depth
+    let mut lit = caster.lit_rects.is_some().then(Vec::new);
+    for rect in unblocked {
+        if let Some(lit) = lit.as_mut() {
+            lit.push(rect);
+        }
+        let new_slope_rect = match reverse_z {
+            true => Rect {
+                ex: (z_real + z_half_offset) / (rect.sx - origin_float.x),
+                ey: (z_real + z_half_offset) / (rect.sy - origin_float.y),
+                sx: (z_real + z_half_offset) / (rect.ex - origin_float.x),
+                sy: (z_real + z_half_offset) / (rect.ey - origin_float.y),
+            },
+            false => Rect {
+                sx: (z_real + z_half_offset) / (rect.sx - origin_float.x),
+                sy: (z_real + z_half_offset) / (rect.sy - origin_float.y),
+                ex: (z_real + z_half_offset) / (rect.ex - origin_float.x),
+                ey: (z_real + z_half_offset) / (rect.ey - origin_float.y),
+            },
+        };
+        if depth < caster.max_depth {
+            pending.push(WorkItem {
+                slope_rect: new_slope_rect,
+                depth: depth + 1,
+                reverse_z,
+                plane: *plane,
+                merged,
+            });
+        }
+    }
+    let lit = lit.filter(|rects| !rects.is_empty());
+    if let (Some(lit_rects), Some(rects)) = (caster.lit_rects.as_deref_mut(), lit) {
+        lit_rects.push(LitRects {
+            plane: *plane,
+            reverse_z,
+            depth,
+            face: origin.z as real + z_real + z_half_offset,
+            rects,
+        });
+    }
+    Some(LayerScan {
+        view_rect,
+        occluders: occluding_rectangles.len(),
+    })
+}
+
+/// Spans and cells are half-open: a span covers [start, end) and cell i covers [i - 0.5, i + 0.5).
+/// Returns the first cell a span overlaps and the cell after the last, so both the scan window
+/// and the visibility test agree on cells whose boundary lies exactly on the span's edge.
+/// Edges within BOUNDARY_EPSILON of a cell boundary count as on it, so rounding in the slopes
+/// does not flip such cells as the origin moves
+fn cells_in_span(start: real, end: real) -> (usize, usize) {
+    let first = (start + 0.5 + BOUNDARY_EPSILON).floor();
+    let end = (end + 0.5 - BOUNDARY_EPSILON).ceil();
+    // Negative coordinates are outside the grid, so the casts saturating at 0 is fine
+    (first as usize, (end as usize).max(first as usize))
+}
+
+/// Keep the `max` - 1 largest rects and replace the rest with their bounding box, which covers
+/// everything they did and stays within the view they were cut from
+fn merge_smallest_rects(rects: &mut Rects, max: usize) {
+    rects.sort_unstable_by(|a, b| b.area().total_cmp(&a.area()));
+    let keep = max.saturating_sub(1);
+    let merged = rects[keep..].iter().copied().reduce(|a, b| Rect {
+        sx: a.sx.min(b.sx),
+        sy: a.sy.min(b.sy),
+        ex: a.ex.max(b.ex),
+        ey: a.ey.max(b.ey),
+    });
+    rects.truncate(keep);
+    rects.extend(merged);
+}
+
+/// Add a CORNER_GAP wide hole to `unblocked` wherever two occluders meet only at a corner,
+/// unless other occluders cover that spot anyway
+fn open_corner_gaps(view_rect: &Rect, occluding_rectangles: &[Rect], unblocked: &mut Rects) {
+    let mut gaps = Rects::new();
+    for (i, a) in occluding_rectangles.iter().enumerate() {
+        for (j, b) in occluding_rectangles.iter().enumerate().skip(i + 1) {
+            // b diagonally right or left of a, then above or below it
+            for (ax, bx) in [(a.ex, b.sx), (a.sx, b.ex)] {
+                for (ay, by) in [(a.ey, b.sy), (a.sy, b.ey)] {
+                    if (ax - bx).abs() > CORNER_EPSILON || (ay - by).abs() > CORNER_EPSILON {
+                        continue;
+                    }
+                    let corner = Rect {
+                        sx: (ax + bx) / 2.0,
+                        sy: (ay + by) / 2.0,
+                        ex: (ax + bx) / 2.0,
+                        ey: (ay + by) / 2.0,
+                    };
+                    let Some(gap) = corner.grown(CORNER_GAP / 2.0).intersection(view_rect) else {
+                        continue;
+                    };
+
+                    // Only reopen the corners of a and b themselves, without overlapping
+                    // what is already unblocked
+                    let mut covering: Rects = occluding_rectangles
+                        .iter()
+                        .enumerate()
+                        .filter(|&(k, _)| k != i && k != j)
+                        .map(|(_, rect)| *rect)
+                        .collect();
+                    covering.extend(unblocked.iter().chain(&gaps).copied());
+                    gaps.extend(rectangle_minus_rectangles(gap, &covering));
+                }
+            }
+        }
+    }
+    unblocked.extend(gaps);
+}
+
+/// An inclusive block of occluded cells at one depth, in plane-local cell coordinates
+#[derive(Clone, Copy)]
+struct CellBlock {
+    sx: usize,
+    sy: usize,
+    ex: usize,
+    ey: usize,
+}
+
+/// Get occlusion from a solid block of cubes. Side faces between cubes of the block are hidden,
+/// and a cube's occlusion grows monotonically with its position, so the start corner cube bounds
+/// the start of the occlusion and the end corner cube bounds the end
+fn get_block_occlusion(
+    block: &CellBlock,
+    z: real,
+    origin: Vector3,
+    slope_rect: &Rect,
+    reverse_z: bool,
+) -> Rect {
+    let start = get_cube_occlusion(
+        block.sx as real,
+        block.sy as real,
+        z,
+        origin,
+        slope_rect,
+        reverse_z,
+    );
+    let end = get_cube_occlusion(
+        block.ex as real,
+        block.ey as real,
+        z,
+        origin,
+        slope_rect,
+        reverse_z,
+    );
+    Rect {
+        sx: start.sx,
+        sy: start.sy,
+        ex: end.ex,
+        ey: end.ey,
+    }
+}
+
+/// Get occlusion from the front and side sides of a cube from some origin point, at some depth
+/// in grid units. Non-cubic cells need no other extents here, as a Display's cell_size stretches
+/// the whole cast along with them
+fn get_cube_occlusion(
+    x: real,
+    y: real,
+    z: real,
+    origin: Vector3,
+    slope_rect: &Rect,
+    reverse_z: bool,
+) -> Rect {
+    let z_half_offset = match reverse_z {
+        true => 0.5,
+        false => -0.5,
+    };
+
+    let base_occluded = Rect {
+        sx: x - 0.5,
+        sy: y - 0.5,
+        ex: x + 0.5,
+        ey: y + 0.5,
+    };
+
+    // grow rectangle based on occlusion of cube side-faces
+    let mut extra = Rect::ZERO;
+
+    if slope_rect.ex > 0.0 && slope_rect.ex.is_finite() {
+        extra.sx = (x + z_half_offset - origin.x) / (z - z_half_offset);
+    }
+    if slope_rect.ey > 0.0 && slope_rect.ey.is_finite() {
+        extra.sy = (y + z_half_offset - origin.y) / (z - z_half_offset);
+    }
+    if slope_rect.sx < 0.0 && slope_rect.sx.is_finite() {
+        extra.ex = (x - z_half_offset - origin.x) / (z - z_half_offset);
+    }
+    if slope_rect.sy < 0.0 && slope_rect.sy.is_finite() {
+        extra.ey = (y - z_half_offset - origin.y) / (z - z_half_offset);
+    }
+
+    match reverse_z {
+        true => base_occluded + extra.swap_start_and_end(),
+        false => base_occluded - extra,
+    }
+}
+
+/// Boolean difference: remove all rectangles from rectangle
+/// The result is decomposed into a *reasonably small* set of rectangles, (since optimally small is NP-hard)
+fn rectangle_minus_rectangles(rectangle: Rect, rectangles: &[Rect]) -> Rects {
+    let mut result = Rects::new();
+    result.push(rectangle);
+
+    for subtract_rect in rectangles {
+        // Nothing left to split. A rectangle inside an earlier one needs no check of its own
+        // either: none of the pieces left overlap it, so the scan below passes it by, which is
+        // cheaper than looking for the earlier one
+        if result.is_empty() {
+            break;
+        }
+
+        // Work in place: a split rect is swapped out and its pieces appended at the end.
+        // The pieces only touch the intersection, so re-checking them against it is harmless
+        let mut j = 0;
+        while j < result.len() {
+            let rect = result[j];
+            let Some(intersection) = rect.intersection(subtract_rect) else {
+                // No intersection, keep the rectangle as is
+                j += 1;
+                continue;
+            };
+            result.swap_remove(j);
+
+            // Left part
+            if rect.sx < intersection.sx {
+                result.push(Rect {
+                    sx: rect.sx,
+                    sy: rect.sy,
+                    ex: intersection.sx,
+                    ey: rect.ey,
+                });
+            }
+
+            // Right part
+            if intersection.ex < rect.ex {
+                result.push(Rect {
+                    sx: intersection.ex,
+                    sy: rect.sy,
+                    ex: rect.ex,
+                    ey: rect.ey,
+                });
+            }
+
+            // Top part (only the middle section to avoid overlap)
+            if rect.sy < intersection.sy {
+                result.push(Rect {
+                    sx: intersection.sx,
+                    sy: rect.sy,
+                    ex: intersection.ex,
+                    ey: intersection.sy,
+                });
+            }
+
+            // Bottom part (only the middle section to avoid overlap)
+            if intersection.ey < rect.ey {
+                result.push(Rect {
+                    sx: intersection.sx,
+                    sy: intersection.ey,
+                    ex: intersection.ex,
+                    ey: rect.ey,
+                });
+            }
+        }
+    }
+
+    result
+}
+
+/// The area covered by `pieces`, which must not overlap, cut into rectangles by the area
+/// alone: every reflex corner is cut off along the shorter of its two edges extended to the far
+/// side, or along both when they are about as long. Mirroring the area or trading its axes
+/// mirrors or trades the pieces along with it, whichever way `pieces` were cut. Edges closer
+/// than SLIVER_WIDTH are taken as one, which drops the slivers between them
+fn canonical_pieces(pieces: &[Rect]) -> Rects {
+    if pieces.len() < 2 {
+        return pieces.iter().copied().collect();
+    }
+    let xs = EdgeClusters::new(pieces.iter().flat_map(|rect| [rect.sx, rect.ex]));
+    let ys = EdgeClusters::new(pieces.iter().flat_map(|rect| [rect.sy, rect.ey]));
+    // The edges cut the area into a grid of cells, each wholly inside or outside it
+    let (columns, rows) = (xs.len() - 1, ys.len() - 1);
+    let mut inside = vec![false; columns * rows];
+    for rect in pieces {
+        for row in ys.index(rect.sy)..ys.index(rect.ey) {
+            for column in xs.index(rect.sx)..xs.index(rect.ex) {
+                inside[row * columns + column] = true;
+            }
+        }
+    }
+    let is_inside = |column: usize, row: usize| inside[row * columns + column];
+
+    // Cuts between cells side by side, at the edge left of a cell, and between cells above one
+    // another, at the edge above a cell
+    let mut cut_left = vec![false; columns * rows];
+    let mut cut_above = vec![false; columns * rows];
+    for row in 1..rows {
+        for column in 1..columns {
+            let around = [
+                (column - 1, row - 1),
+                (column, row - 1),
+                (column - 1, row),
+                (column, row),
+            ];
+            let mut outside = around.iter().filter(|&&(c, r)| !is_inside(c, r));
+            let (Some(&(out_column, out_row)), None) = (outside.next(), outside.next()) else {
+                continue;
+            };
+            // The edges at the corner go on into the area, away from the cell outside it
+            let down = out_row < row;
+            let along_column = |&r: &usize| is_inside(column - 1, r) && is_inside(column, r);
+            let vertical: Vec<usize> = match down {
+                true => (row..rows).take_while(along_column).collect(),
+                false => (0..row).rev().take_while(along_column).collect(),
+            };
+            let right = out_column < column;
+            let along_row = |&c: &usize| is_inside(c, row - 1) && is_inside(c, row);
+            let horizontal: Vec<usize> = match right {
+                true => (column..columns).take_while(along_row).collect(),
+                false => (0..column).rev().take_while(along_row).collect(),
+            };
+            let far_edge = |cells: &[usize], forward: bool| match forward {
+                true => cells[cells.len() - 1] + 1,
+                false => cells[cells.len() - 1],
+            };
+            let vertical_len = (ys.edge(far_edge(&vertical, down)) - ys.edge(row)).abs();
+            let horizontal_len = (xs.edge(far_edge(&horizontal, right)) - xs.edge(column)).abs();
+            if vertical_len <= horizontal_len + BOUNDARY_EPSILON {
+                for &r in &vertical {
+                    cut_left[r * columns + column] = true;
+                }
+            }
+            if horizontal_len <= vertical_len + BOUNDARY_EPSILON {
+                for &c in &horizontal {
+                    cut_above[row * columns + c] = true;
+                }
+            }
+        }
+    }
+
+    // Cells joined across edges that are not cut make up rectangles
+    let mut result = Rects::new();
+    let mut taken = vec![false; columns * rows];
+    let mut stack = Vec::new();
+    for start in 0..columns * rows {
+        if !inside[start] || taken[start] {
+            continue;
+        }
+        taken[start] = true;
+        stack.push(start);
+        let (mut min, mut max) = ((columns, rows), (0, 0));
+        while let Some(cell) = stack.pop() {
+            let (column, row) = (cell % columns, cell / columns);
+            min = (min.0.min(column), min.1.min(row));
+            max = (max.0.max(column), max.1.max(row));
+            let mut neighbours: SmallVec<[usize; 4]> = SmallVec::new();
+            if column > 0 && !cut_left[cell] {
+                neighbours.push(cell - 1);
+            }
+            if column + 1 < columns && !cut_left[cell + 1] {
+                neighbours.push(cell + 1);
+            }
+            if row > 0 && !cut_above[cell] {
+                neighbours.push(cell - columns);
+            }
+            if row + 1 < rows && !cut_above[cell + columns] {
+                neighbours.push(cell + columns);
+            }
+            for next in neighbours {
+                if inside[next] && !taken[next] {
+                    taken[next] = true;
+                    stack.push(next);
+                }
+            }
+        }
+        result.push(Rect {
+            sx: xs.edge(min.0),
+            sy: ys.edge(min.1),
+            ex: xs.edge(max.0 + 1),
+            ey: ys.edge(max.1 + 1),
+        });
+    }
+    result
+}
+
+/// Sorted edge coordinates along one axis, with those closer than SLIVER_WIDTH taken as one
+/// at the middle of their range, so it stays where it is when the axis is mirrored
+struct EdgeClusters {
+    /// The least and greatest coordinate of each
+    ranges: Vec<(real, real)>,
+}
+
+impl EdgeClusters {
+    fn new(coordinates: impl Iterator<Item = real>) -> Self {
+        let mut coordinates: Vec<real> = coordinates.collect();
+        coordinates.sort_unstable_by(real::total_cmp);
+        let mut ranges: Vec<(real, real)> = Vec::new();
+        for coordinate in coordinates {
+            match ranges.last_mut() {
+                Some((_, end)) if coordinate - *end < SLIVER_WIDTH => *end = coordinate,
+                _ => ranges.push((coordinate, coordinate)),
+            }
+        }
+        Self { ranges }
+    }
+
+    fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The index of the edge a coordinate it was made from is taken as
+    fn index(&self, coordinate: real) -> usize {
+        self.ranges.partition_point(|&(_, end)| end < coordinate)
+    }
+
+    fn edge(&self, index: usize) -> real {
+        let (start, end) = self.ranges[index];
+        (start + end) / 2.0
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::exposed::ExposedCells;
+
+    /// A caster over `occluded` with the settings a Display starts with
+    pub(crate) fn caster<'a>(
+        occluded: &'a dyn OcclusionSource,
+        visible: &'a mut BitGrid,
+        origin: Vector3i,
+    ) -> Caster<'a> {
+        Caster {
+            occluded,
+            visible,
+            origin,
+            jitter: Vector3::ZERO,
+            max_depth: MAX_DEPTH,
+            lod: Lod::default(),
+            corner_rule: CornerRule::default(),
+            debug_rects: None,
+            lit_rects: None,
+            fractions: None,
+            bounds: None,
+            one_way: None,
+            blockers: None,
+            terrain: None,
+            exposed: None,
+            rects: RectLimit::new(
+                0,
+                NarrowRects {
+                    min_width: 0.25,
+                    policy: NarrowPolicy::Snap,
+                },
+            ),
+        }
+    }
+
+    const PLANES: [UnitPlane3d; 3] = [UnitPlane3d::XY, UnitPlane3d::ZY, UnitPlane3d::ZX];
+
+    #[test]
+    fn plane_permutations_undo_each_other() {
+        for plane in PLANES {
+            for v in [
+                Vector3i::new(1, 2, 3),
+                Vector3i::new(-4, 0, 7),
+                Vector3i::new(9, -8, -6),
+            ] {
+                assert_eq!(plane.to_grid(plane.to_local(v)), v);
+                assert_eq!(plane.to_local(plane.to_grid(v)), v);
+                let halved = v.cast_float() * 0.5;
+                assert_eq!(plane.to_grid_float(plane.to_local_float(halved)), halved);
+                assert_eq!(plane.to_local_float(plane.to_grid_float(halved)), halved);
+                assert_eq!(
+                    plane.to_local_float(halved),
+                    plane.to_local(v).cast_float() * 0.5
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn grid_index_agrees_with_to_local() {
+        for plane in PLANES {
+            for x in 0..4 {
+                for y in 0..5 {
+                    for z in 0..6 {
+                        let cell = Vector3i::new(x, y, z);
+                        let local = plane.to_local(cell);
+                        let index = plane.grid_index(local.x as usize, local.y as usize, local.z);
+                        assert_eq!(index, cell_index(cell));
+                    }
+                }
+            }
+        }
+        // Each plane casts along the axis its doc names
+        let along = |plane: UnitPlane3d| plane.to_grid(Vector3i::new(0, 0, 1));
+        assert_eq!(along(UnitPlane3d::XY), Vector3i::new(0, 0, 1));
+        assert_eq!(along(UnitPlane3d::ZY), Vector3i::new(1, 0, 0));
+        assert_eq!(along(UnitPlane3d::ZX), Vector3i::new(0, 1, 0));
+    }
+
+    #[test]
+    fn allow_sees_between_diagonal_occluders_with_narrow_snap() {
+        // Two cells meeting only along an edge straight below or above an eye on that edge,
+        // with the layer they are in across each axis in turn, so every pass meets them
+        for (axis, (layer, beyond)) in (0..3).flat_map(|axis| [(axis, (3, 2)), (axis, (5, 6))]) {
+            // A cell at u and v along the other two axes, in order, and w along this one
+            let at = |u: i32, v: i32, w: i32| {
+                let mut cell = [w; 3];
+                cell[(axis + 1) % 3] = u;
+                cell[(axis + 2) % 3] = v;
+                Vector3i::new(cell[0], cell[1], cell[2])
+            };
+            let mut occluded = BitGrid::new((9, 9, 9));
+            occluded.set(cell_index(at(4, 4, layer)), true);
+            occluded.set(cell_index(at(5, 5, layer)), true);
+            let behind = cell_index(at(4, 4, beyond));
+            let mut jitter = [0.5; 3];
+            jitter[axis] = 0.0;
+            for (corner_rule, sees_behind) in
+                [(CornerRule::Block, false), (CornerRule::Allow, true)]
+            {
+                let mut visible = BitGrid::new(occluded.size());
+                let mut caster = caster(&occluded, &mut visible, Vector3i::new(4, 4, 4));
+                caster.jitter = Vector3::new(jitter[0], jitter[1], jitter[2]);
+                caster.corner_rule = corner_rule;
+                caster.cast_all();
+                assert_eq!(
+                    visible.get(behind),
+                    Some(sees_behind),
+                    "axis {axis}, layer {layer}, blocking corners {}",
+                    corner_rule == CornerRule::Block
+                );
+            }
+        }
+    }
+
+    /// Cells of a `size` grid set with probability 6 in 64, from a seeded generator
+    pub(crate) fn random_grid(size: Index3, seed: &mut u64) -> BitGrid {
+        let mut grid = BitGrid::new(size);
+        for x in 0..size.0 {
+            for y in 0..size.1 {
+                for z in 0..size.2 {
+                    *seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    if *seed >> 58 < 6 {
+                        grid.set((x, y, z), true);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn mirrored_maps_cast_mirrored_shadows() {
+        // An odd size with the origin in the middle cell, so mirroring keeps the origin
+        let n = 21;
+        let origin = Vector3i::new(10, 10, 10);
+        let mut seed = 5;
+        for round in 0..16 {
+            let mut occluded = random_grid((n, n, n), &mut seed);
+            occluded.set(cell_index(origin), false);
+            let corner_rule = match round % 2 {
+                0 => CornerRule::Block,
+                _ => CornerRule::Allow,
+            };
+            let jitter = Vector3::new(0.13, -0.21, 0.3) * (round / 2 % 2) as real;
+            // A Display's rect limits, narrow policy included, which must not tell mirrored maps
+            // apart either
+            let cast = |occluded: &BitGrid, jitter: Vector3| {
+                let mut visible = BitGrid::new(occluded.size());
+                let mut caster = caster(occluded, &mut visible, origin);
+                caster.jitter = jitter;
+                caster.corner_rule = corner_rule;
+                caster.cast_all();
+                visible
+            };
+            let visible = cast(&occluded, jitter);
+            // Mirroring across each axis flips the z direction of the two planes scanning along it
+            for axis in 0..3 {
+                let mirror = |(x, y, z): Index3| match axis {
+                    0 => (n - 1 - x, y, z),
+                    1 => (x, n - 1 - y, z),
+                    _ => (x, y, n - 1 - z),
+                };
+                let mut mirrored = BitGrid::new(occluded.size());
+                occluded.for_each_set(|index| {
+                    mirrored.set(mirror(index), true);
+                });
+                let flip = match axis {
+                    0 => Vector3::new(-1.0, 1.0, 1.0),
+                    1 => Vector3::new(1.0, -1.0, 1.0),
+                    _ => Vector3::new(1.0, 1.0, -1.0),
+                };
+                let mut seen_back = BitGrid::new(occluded.size());
+                cast(&mirrored, jitter * flip).for_each_set(|index| {
+                    seen_back.set(mirror(index), true);
+                });
+                let mut differences = Vec::new();
+                visible.for_each_difference(&seen_back, |index, _| differences.push(index));
+                assert!(
+                    differences.is_empty(),
+                    "round {round}, mirrored across axis {axis}: {differences:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn slicing_a_cast_sees_what_casting_it_whole_does() {
+        let mut seed = 9;
+        let occluded = random_grid((24, 24, 24), &mut seed);
+        let origin = Vector3i::new(11, 12, 10);
+        let mut whole = BitGrid::new(occluded.size());
+        caster(&occluded, &mut whole, origin).cast_all();
+
+        for views_per_slice in [1, 7, 100] {
+            let mut sliced = BitGrid::new(occluded.size());
+            let mut caster = caster(&occluded, &mut sliced, origin);
+            caster.mark_origin_visible();
+            let mut cast = SlicedCast::new(all_passes());
+            let mut slices = 1;
+            loop {
+                let mut views = 0;
+                let done = cast.advance(&mut caster, || {
+                    views += 1;
+                    views == views_per_slice
+                });
+                if done {
+                    break;
+                }
+                slices += 1;
+            }
+            assert!(cast.is_done());
+            assert_eq!(slices, cast.work_items.div_ceil(views_per_slice));
+            assert_eq!(
+                sliced.to_bytes(),
+                whole.to_bytes(),
+                "{views_per_slice} views a slice"
+            );
+        }
+    }
+
+    /// Checked-in views each pass scans in the work fixtures, one line per fixture as its name
+    /// and then the count of every pass
+    const EXPECTED_WORK_PATH: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/expected_pass_work.txt"
+    );
+    /// Fraction more views than expected a pass may scan before the work test fails
+    const WORK_TOLERANCE: f64 = 0.05;
+
+    /// The maps the work test casts in, each a 32 cell cube with the origins to cast from
+    pub(crate) fn work_fixtures() -> Vec<(&'static str, BitGrid, Vec<Vector3i>)> {
+        let size = (32, 32, 32);
+        let mut seed = 11;
+        let mut open_field = BitGrid::new(size);
+        open_field.set_box((0, 0, 0), (31, 0, 31), true);
+        random_grid(size, &mut seed).for_each_set(|(x, y, z)| {
+            if y < 3 && (x + z) % 5 == 0 {
+                open_field.set((x, y, z), true);
+            }
+        });
+        let mut pillar_forest = BitGrid::new(size);
+        for x in (2..32).step_by(4) {
+            for z in (2..32).step_by(4) {
+                pillar_forest.set_box((x, 0, z), (x, 31, z), true);
+            }
+        }
+        let dense_noise = random_grid(size, &mut seed);
+        let origins = vec![
+            Vector3i::new(16, 4, 16),
+            Vector3i::new(5, 6, 27),
+            Vector3i::new(29, 15, 3),
+        ];
+        [
+            ("open_field", open_field),
+            ("pillar_forest", pillar_forest),
+            ("dense_noise", dense_noise),
+        ]
+        .into_iter()
+        .map(|(name, mut occluded)| {
+            for &origin in &origins {
+                occluded.set(cell_index(origin), false);
+            }
+            (name, occluded, origins.clone())
+        })
+        .collect()
+    }
+
+    /// A map checked in as testdata/fixtures/`name`.txt, the cell to cast from and the cells a
+    /// cast from it with a Display's settings sees. The file has layers from y = 0 up, separated
+    /// by blank lines, of rows along z, each a line of cells along x: `.` empty, `o` empty and
+    /// seen, `#` occluded, `X` occluded and seen, and `@` the cell to cast from. Lines starting
+    /// with `//` are comments
+    pub(crate) fn fixture(name: &str) -> (BitGrid, Vector3i, BitGrid) {
+        let path = format!(
+            "{}/testdata/fixtures/{name}.txt",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let text = std::fs::read_to_string(&path).unwrap_or_else(|_| panic!("no {path}"));
+        let lines: Vec<&str> = text
+            .lines()
+            .filter(|line| !line.starts_with("//"))
+            .collect();
+        let layers: Vec<&[&str]> = lines
+            .split(|line| line.is_empty())
+            .filter(|layer| !layer.is_empty())
+            .collect();
+        let size = (layers[0][0].len(), layers.len(), layers[0].len());
+        let mut occluded = BitGrid::new(size);
+        let mut seen = BitGrid::new(size);
+        let mut origin = None;
+        for (y, layer) in layers.iter().enumerate() {
+            assert_eq!(
+                layer.len(),
+                size.2,
+                "{name}: layer {y} is not {} rows",
+                size.2
+            );
+            for (z, row) in layer.iter().enumerate() {
+                assert_eq!(row.len(), size.0, "{name}: row {z} of layer {y}");
+                for (x, cell) in row.chars().enumerate() {
+                    let index = (x, y, z);
+                    match cell {
+                        '.' => {}
+                        'o' => {
+                            seen.set(index, true);
+                        }
+                        '#' => {
+                            occluded.set(index, true);
+                        }
+                        'X' => {
+                            occluded.set(index, true);
+                            seen.set(index, true);
+                        }
+                        '@' => {
+                            seen.set(index, true);
+                            origin = Some(Vector3i::new(x as i32, y as i32, z as i32));
+                        }
+                        _ => panic!("{name}: {cell:?} at {x}, {y}, {z}"),
+                    }
+                }
+            }
+        }
+        let origin = origin.unwrap_or_else(|| panic!("{name} has no @"));
+        (occluded, origin, seen)
+    }
+
+    /// Views each pass scans casting from every origin of a fixture, with a Display's settings
+    fn pass_work(occluded: &BitGrid, origins: &[Vector3i]) -> Vec<usize> {
+        let passes: Vec<Pass> = all_passes().collect();
+        let mut work = vec![0; passes.len()];
+        for &origin in origins {
+            let mut visible = vec![BitGrid::new(occluded.size()); passes.len()];
+            let mut casters: Vec<Caster> = visible
+                .iter_mut()
+                .map(|visible| caster(occluded, visible, origin))
+                .collect();
+            let cast = cast_layered(&mut casters, &passes, 0, || 0, None);
+            for (total, count) in work.iter_mut().zip(cast.pass_work_items) {
+                *total += count;
+            }
+        }
+        work
+    }
+
+    /// Counts are deterministic, unlike times, so a change that makes one pass scan more views
+    /// fails here even when the total stays about the same. After a change meant to alter the
+    /// work, run the tests with SHADOWCAST_UPDATE_EXPECTED_WORK set to record the new counts
+    #[test]
+    fn pass_work_within_expected() {
+        let measured: Vec<(&str, Vec<usize>)> = work_fixtures()
+            .iter()
+            .map(|(name, occluded, origins)| (*name, pass_work(occluded, origins)))
+            .collect();
+        if std::env::var_os(UPDATE_EXPECTED_WORK_ENV).is_some() {
+            let lines: Vec<String> = measured
+                .iter()
+                .map(|(name, work)| {
+                    let counts: Vec<String> = work.iter().map(usize::to_string).collect();
+                    format!("{} {}\n", name, counts.join(" "))
+                })
+                .collect();
+            std::fs::write(EXPECTED_WORK_PATH, lines.concat()).unwrap();
+            return;
+        }
+
+        let expected = std::fs::read_to_string(EXPECTED_WORK_PATH).unwrap();
+        for (name, work) in measured {
+            let counts: Vec<usize> = expected
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                .unwrap_or_else(|| panic!("no expected work for {name}"))
+                .split(' ')
+                .map(|count| count.parse().unwrap())
+                .collect();
+            assert_eq!(counts.len(), work.len(), "expected work for {name}");
+            for (pass, (&actual, &expected)) in work.iter().zip(&counts).enumerate() {
+                assert!(
+                    actual as f64 <= expected as f64 * (1.0 + WORK_TOLERANCE),
+                    "pass {pass} scanned {actual} views in {name}, more than the {expected} expected"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn walk_frustum_visits_the_cast_nearest_first() {
+        let settings = PassSettings {
+            max_depth: 12,
+            lod: Lod::default(),
+            corner_rule: CornerRule::default(),
+            track_fractions: false,
+            capture_lit_volume: false,
+            max_rects: 0,
+            narrow: NarrowRects {
+                min_width: 0.25,
+                policy: NarrowPolicy::Snap,
+            },
+            eye_jitter: Vector3::ZERO,
+            view_cone: None,
+        };
+        for (name, occluded, origins) in work_fixtures() {
+            for origin in origins {
+                let mut cast = BitGrid::new(occluded.size());
+                let mut caster = caster(&occluded, &mut cast, origin);
+                caster.max_depth = settings.max_depth;
+                caster.cast_all();
+
+                let mut scratch = BitGrid::new(occluded.size());
+                let mut walked = BitGrid::new(occluded.size());
+                let mut last: Option<(usize, Index3)> = None;
+                let one_way = OneWayCells::default();
+                walk_frustum(
+                    &occluded,
+                    &one_way,
+                    None,
+                    &mut scratch,
+                    &settings,
+                    origin,
+                    |index, depth, fraction| {
+                        let delta = (index_cell(index) - origin).abs();
+                        assert_eq!(depth, delta.x.max(delta.y).max(delta.z) as usize);
+                        // Depth never goes down, and a ring is in ascending x, then y, then z
+                        assert!(
+                            last < Some((depth, index)),
+                            "{name}: {index:?} after {last:?}"
+                        );
+                        last = Some((depth, index));
+                        assert!(fraction > 0.0 && fraction <= 1.0, "{name}: {index:?}");
+                        walked.set(index, true);
+                    },
+                );
+                assert_eq!(walked.to_bytes(), cast.to_bytes(), "{name} from {origin}");
+            }
+        }
+    }
+
+    /// Checked-in digests of the cells casts in the work fixtures see, one line per cast as the
+    /// fixture's name, the origin, the eye's offset in the origin cell and the digest
+    const EXPECTED_VISIBLE_PATH: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/expected_visible.txt");
+    /// Environment variable that, when set, records the digests the precision test computes as
+    /// the expected ones instead of checking against them
+    const UPDATE_EXPECTED_VISIBLE_ENV: &str = "SHADOWCAST_UPDATE_EXPECTED_VISIBLE";
+
+    /// 64 bit FNV-1a hash of some bytes
+    fn digest(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// Visible sets are whole cells, so rounding in the slopes must not move any of them. The
+    /// expected digests are shared by every build, so running the tests both as they are and
+    /// with the double-precision feature checks that f32 and f64 casts see the same cells in
+    /// the golden and work fixtures. The eye offsets are exact in either precision, and a
+    /// quarter cell lands view edges on cell centers and pieces on the narrow width
+    #[test]
+    fn casts_see_the_same_cells_in_either_precision() {
+        let jitters = [
+            Vector3::ZERO,
+            Vector3::new(0.25, -0.375, 0.125),
+            Vector3::new(-0.125, 0.25, -0.25),
+        ];
+        let golden = FIXTURES.iter().map(|&name| {
+            let (occluded, origin, _) = fixture(name);
+            (name, occluded, vec![origin])
+        });
+        let mut lines = Vec::new();
+        for (name, occluded, origins) in golden.chain(work_fixtures()) {
+            for origin in origins {
+                for jitter in jitters {
+                    for corner_rule in [CornerRule::Block, CornerRule::Allow] {
+                        let mut visible = BitGrid::new(occluded.size());
+                        let mut caster = caster(&occluded, &mut visible, origin);
+                        caster.jitter = jitter;
+                        caster.corner_rule = corner_rule;
+                        caster.max_depth = 31;
+                        caster.cast_all();
+                        let rule = match corner_rule {
+                            CornerRule::Block => "block",
+                            CornerRule::Allow => "allow",
+                        };
+                        lines.push(format!(
+                            "{name} {},{},{} {},{},{} {rule} {:016x}\n",
+                            origin.x,
+                            origin.y,
+                            origin.z,
+                            jitter.x,
+                            jitter.y,
+                            jitter.z,
+                            digest(&visible.to_bytes())
+                        ));
+                    }
+                }
+            }
+        }
+        if std::env::var_os(UPDATE_EXPECTED_VISIBLE_ENV).is_some() {
+            std::fs::write(EXPECTED_VISIBLE_PATH, lines.concat()).unwrap();
+            return;
+        }
+
+        let expected = std::fs::read_to_string(EXPECTED_VISIBLE_PATH).unwrap();
+        let expected: Vec<&str> = expected.lines().collect();
+        assert_eq!(
+            expected.len(),
+            lines.len(),
+            "casts in {EXPECTED_VISIBLE_PATH}"
+        );
+        for (line, expected) in lines.iter().zip(expected) {
+            assert_eq!(
+                line.trim_end(),
+                expected,
+                "with {} precision",
+                size_of::<real>() * 8
+            );
+        }
+    }
+
+    /// The maps checked in under testdata/fixtures
+    pub(crate) const FIXTURES: [&str; 7] = [
+        "ceiling_hole",
+        "ceiling_hole_grazing",
+        "ceiling_hole_offset",
+        "ceiling_hole_under",
+        "l_corridor",
+        "pillar_room",
+        "slit",
+    ];
+
+    #[test]
+    fn permuted_maps_cast_permuted_shadows() {
+        // Every reordering of the axes, as the positions of x, y and z to take each axis from.
+        // The first keeps the map as it is, so the fixture's own cells are checked too
+        let permutations = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        for name in FIXTURES {
+            let (occluded, origin, seen) = fixture(name);
+            for permutation in permutations {
+                let permute = |(x, y, z): Index3| {
+                    let axes = [x, y, z];
+                    (
+                        axes[permutation[0]],
+                        axes[permutation[1]],
+                        axes[permutation[2]],
+                    )
+                };
+                let axes = [origin.x, origin.y, origin.z];
+                let permuted_origin = Vector3i::new(
+                    axes[permutation[0]],
+                    axes[permutation[1]],
+                    axes[permutation[2]],
+                );
+                let mut permuted = BitGrid::new(permute(occluded.size()));
+                occluded.for_each_set(|index| {
+                    permuted.set(permute(index), true);
+                });
+                let mut seen_permuted = BitGrid::new(permuted.size());
+                seen.for_each_set(|index| {
+                    seen_permuted.set(permute(index), true);
+                });
+                let mut visible = BitGrid::new(permuted.size());
+                caster(&permuted, &mut visible, permuted_origin).cast_all();
+                let mut differences = Vec::new();
+                visible.for_each_difference(&seen_permuted, |index, _| differences.push(index));
+                assert!(
+                    differences.is_empty(),
+                    "{name}, axes {permutation:?}: {differences:?}"
+                );
+            }
+        }
+    }
+
+    /// Whether a straight line from `eye`, below the ceiling_hole fixture's ceiling, through
+    /// its hole reaches a point of the box above the ceiling spanning `xs`, `ys` and `zs`. The
+    /// hole's sides are moved out by `margin`, or in for a negative one
+    fn through_ceiling_hole(
+        eye: Vector3,
+        xs: (real, real),
+        ys: (real, real),
+        zs: (real, real),
+        margin: real,
+    ) -> bool {
+        // The runs along an axis per unit of rise of the lines through both of the hole's faces
+        let hole_runs = |eye_along: real| {
+            let (low, high) = (2.5 - margin - eye_along, 3.5 + margin - eye_along);
+            let (bottom, top) = (2.5 - eye.y, 3.5 - eye.y);
+            (
+                (low / bottom).max(low / top),
+                (high / bottom).min(high / top),
+            )
+        };
+        let (hole_x, hole_z) = (hole_runs(eye.x), hole_runs(eye.z));
+        let steps = 100;
+        (0..=steps).any(|step| {
+            let rise = ys.0 + (ys.1 - ys.0) * step as real / steps as real - eye.y;
+            let overlaps = |(start, end): (real, real), (from, to): (real, real), eye: real| {
+                start.max((from - eye) / rise) <= end.min((to - eye) / rise)
+            };
+            overlaps(hole_x, xs, eye.x) && overlaps(hole_z, zs, eye.z)
+        })
+    }
+
+    #[test]
+    fn ceiling_hole_shows_what_lines_through_it_reach() {
+        let (occluded, _, _) = fixture("ceiling_hole");
+        let (size_x, size_y, size_z) = occluded.size();
+        // From every cell below the ceiling, passes looking up and those looking along x and z
+        // at the hole from the side alike
+        for (x, y, z) in
+            (0..size_x).flat_map(|x| (1..3).flat_map(move |y| (0..size_z).map(move |z| (x, y, z))))
+        {
+            let origin = index_cell((x, y, z));
+            let mut visible = BitGrid::new(occluded.size());
+            // Snap widens the pieces through the hole seen from right below it, as the
+            // ceiling_hole_under fixture shows, so the lines are held to exact pieces
+            let mut caster = caster(&occluded, &mut visible, origin);
+            caster.rects.narrow.policy = NarrowPolicy::Continue;
+            caster.cast_all();
+            let eye = origin.cast_float();
+            for index in (0..size_x)
+                .flat_map(|x| (4..size_y).flat_map(move |y| (0..size_z).map(move |z| (x, y, z))))
+            {
+                let center = index_cell(index).cast_float();
+                let span = |center: real, inset: real| (center - 0.5 + inset, center + 0.5 - inset);
+                // The roof is seen on its bottom faces, open cells anywhere in them
+                let roof = occluded.get(index) == Some(true);
+                let ys = |inset| match roof {
+                    true => (center.y - 0.5, center.y - 0.5),
+                    false => span(center.y, inset),
+                };
+                if visible.get(index) == Some(true) {
+                    assert!(
+                        through_ceiling_hole(
+                            eye,
+                            span(center.x, 0.0),
+                            ys(0.0),
+                            span(center.z, 0.0),
+                            1e-3
+                        ),
+                        "{origin} sees {index:?}, which no line through the hole reaches"
+                    );
+                } else {
+                    assert!(
+                        !through_ceiling_hole(
+                            eye,
+                            span(center.x, 0.05),
+                            ys(0.05),
+                            span(center.z, 0.05),
+                            -0.05
+                        ),
+                        "{origin} misses {index:?}, which lines through the hole reach"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Whether a point is in the shadow a cube of cells `size` large casts from an eye that
+    /// looks straight at its face nearest the eye along `axis`, all in world space
+    fn behind_cube(
+        eye: Vector3,
+        point: Vector3,
+        cube: Vector3,
+        size: Vector3,
+        axis: usize,
+    ) -> bool {
+        let [eye, point, cube, size] = [eye, point, cube, size].map(|v| [v.x, v.y, v.z]);
+        let face = cube[axis] - 0.5 * size[axis] * (cube[axis] - eye[axis]).signum();
+        let along = (face - eye[axis]) / (point[axis] - eye[axis]);
+        (0.0..=1.0).contains(&along)
+            && (0..3).all(|i| {
+                let hit = eye[i] + (point[i] - eye[i]) * along;
+                i == axis || (hit - cube[i]).abs() <= 0.5 * size[i]
+            })
+    }
+
+    #[test]
+    fn half_height_cells_cast_shadows_where_world_geometry_puts_them() {
+        // Cells 1 wide and 0.5 high, as a Display with cell_size (1, 0.5, 1) lays them out. The
+        // cast runs in cells, and stretching every cell alike keeps lines straight, so the
+        // shadow of a single cube has to land where it does in world space
+        let size = Vector3::new(1.0, 0.5, 1.0);
+        let mut occluded = BitGrid::new((16, 16, 9));
+        occluded.set((4, 4, 4), true);
+        let cast = |origin: Vector3i| {
+            let mut visible = BitGrid::new(occluded.size());
+            caster(&occluded, &mut visible, origin).cast_all();
+            move |x, y, z| visible.get((x, y, z)) == Some(true)
+        };
+
+        // Looking up, the cube's bottom face is 1.75 above the eye and reaches 0.5 to each
+        // side, so lines past it climb 3.5 for every unit they go sideways. A cell m cells to
+        // the side is wholly in shadow once its bottom corner nearest the eye, m + 0.5 to the
+        // side and (y - 0.5) / 2 high, is, which is from y = 4 + 7m up
+        let below = Vector3i::new(4, 0, 4);
+        let seen = cast(below);
+        assert!((5..16).all(|y| !seen(4, y, 4)));
+        assert!(seen(5, 10, 4) && seen(4, 10, 5) && seen(5, 10, 5));
+        assert!((11..16).all(|y| !seen(5, y, 4) && !seen(3, y, 3) && !seen(4, y, 5)));
+        assert!((0..16).all(|y| seen(6, y, 4) && seen(2, y, 6)));
+
+        // Looking along x, the face is 3.5 away and reaches 0.25 up and down, so lines past it
+        // rise a unit every 14, and a cell 0.5 high m cells up is in shadow from x = 4 + 7m on
+        // just as a cell 1 wide m cells across is
+        let beside = Vector3i::new(0, 4, 4);
+        let seen = cast(beside);
+        assert!((5..16).all(|x| !seen(x, 4, 4)));
+        assert!(seen(10, 5, 4) && seen(10, 4, 5) && seen(10, 5, 5));
+        assert!((11..16).all(|x| !seen(x, 5, 4) && !seen(x, 3, 3) && !seen(x, 4, 5)));
+        assert!((0..16).all(|x| seen(x, 6, 4) && seen(x, 4, 6)));
+
+        // Every other cell is seen just when one of its corners is out of the shadow
+        let cube = Vector3::new(4.0, 4.0, 4.0) * size;
+        for (origin, axis) in [(below, 1), (beside, 0)] {
+            let seen = cast(origin);
+            let eye = origin.cast_float() * size;
+            let (size_x, size_y, size_z) = occluded.size();
+            for index in (0..size_x)
+                .flat_map(|x| (0..size_y).flat_map(move |y| (0..size_z).map(move |z| (x, y, z))))
+            {
+                let center = index_cell(index).cast_float() * size;
+                if center == cube {
+                    continue;
+                }
+                let shadowed = (0..8).all(|bits: usize| {
+                    let offset = |bit: usize| if bits & bit != 0 { 0.5 } else { -0.5 };
+                    let corner = Vector3::new(offset(1), offset(2), offset(4)) * size;
+                    behind_cube(eye, center + corner, cube, size, axis)
+                });
+                assert_eq!(
+                    seen(index.0, index.1, index.2),
+                    !shadowed,
+                    "{origin} sees {index:?}"
+                );
+            }
+        }
+    }
+
+    /// Cells at z = 8 and beyond seen on the slit fixture's corridor, as (x, z)
+    fn seen_past_slit(policy: NarrowPolicy) -> Vec<(usize, usize)> {
+        let (occluded, origin, _) = fixture("slit");
+        let mut visible = BitGrid::new(occluded.size());
+        let mut caster = caster(&occluded, &mut visible, origin);
+        caster.rects.narrow.policy = policy;
+        caster.cast_all();
+        let mut seen = Vec::new();
+        visible.for_each_set(|(x, y, z)| {
+            if y == 1 && z >= 8 {
+                seen.push((x, z));
+            }
+        });
+        seen
+    }
+
+    #[test]
+    fn stop_loses_the_slit_once_it_narrows() {
+        assert_eq!(seen_past_slit(NarrowPolicy::Stop), []);
+    }
+
+    #[test]
+    fn snap_widens_the_slit_to_whole_cells() {
+        assert_eq!(
+            seen_past_slit(NarrowPolicy::Snap),
+            [
+                (6, 8),
+                (6, 9),
+                (6, 10),
+                (7, 8),
+                (7, 9),
+                (7, 10),
+                (7, 11),
+                (7, 12),
+                (8, 8),
+                (8, 9),
+                (8, 10),
+                (8, 11),
+                (8, 12),
+                (9, 10),
+                (9, 11),
+                (9, 12),
+                (10, 12)
+            ]
+        );
+    }
+
+    #[test]
+    fn continue_casts_the_slit_exactly() {
+        assert_eq!(
+            seen_past_slit(NarrowPolicy::Continue),
+            [
+                (6, 8),
+                (7, 8),
+                (7, 9),
+                (7, 10),
+                (8, 10),
+                (8, 11),
+                (8, 12),
+                (9, 12)
+            ]
+        );
+    }
+
+    /// A solid map with caves carved into it by random walks, each step opening the cell it
+    /// moves to, and the cell the first walk started from
+    fn caves(size: Index3, seed: &mut u64) -> (BitGrid, Vector3i) {
+        let mut next = |range: usize| {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            (*seed >> 16) as usize % range
+        };
+        let mut occluded = BitGrid::new(size);
+        let max = index_cell(size) - Vector3i::ONE;
+        occluded.set_box((0, 0, 0), cell_index(max), true);
+        let start = index_cell((next(size.0), next(size.1), next(size.2)));
+        for _ in 0..4 {
+            let mut cell = start;
+            for _ in 0..150 {
+                occluded.set(cell_index(cell), false);
+                let sign = if next(2) == 0 { -1 } else { 1 };
+                let step = match next(3) {
+                    0 => Vector3i::new(sign, 0, 0),
+                    1 => Vector3i::new(0, sign, 0),
+                    _ => Vector3i::new(0, 0, sign),
+                };
+                cell = (cell + step).coord_max(Vector3i::ZERO).coord_min(max);
+            }
+        }
+        (occluded, start)
+    }
+
+    #[test]
+    fn leaving_buried_occluders_out_sees_the_same_cells() {
+        let size = (24, 24, 24);
+        let mut seed = 0xcafe_u64;
+        for round in 0..40 {
+            let (mut occluded, origin) = match round % 4 {
+                0 => {
+                    let mut occluded = random_grid(size, &mut seed);
+                    let origin = Vector3i::splat(12);
+                    occluded.set(cell_index(origin), false);
+                    (occluded, origin)
+                }
+                _ => caves(size, &mut seed),
+            };
+            // One-way cells on the cave walls, letting sight through along y or back
+            let mut one_way = OneWayCells::new();
+            for y in (1..24).step_by(5) {
+                for x in 0..24 {
+                    let index = (x, y, (x * 7 + round) % 24);
+                    if occluded.get(index) == Some(true) {
+                        let open = if x % 2 == 0 { 1 } else { -1 };
+                        one_way.insert(index, Vector3i::new(0, open, 0));
+                    }
+                }
+            }
+            occluded.set(cell_index(origin), false);
+            let exposed = ExposedCells::default().update(&occluded, &one_way).clone();
+            let jitter = Vector3::new(0.1 * (round % 3) as real, -0.2, 0.05 * (round % 5) as real);
+            for corner_rule in [CornerRule::Block, CornerRule::Allow] {
+                let cast = |exposed: Option<&BitGrid>| {
+                    let mut visible = BitGrid::new(size);
+                    let mut debug_rects = Vec::new();
+                    let mut caster = caster(&occluded, &mut visible, origin);
+                    caster.one_way = Some(&one_way);
+                    caster.corner_rule = corner_rule;
+                    caster.jitter = jitter;
+                    caster.exposed = exposed;
+                    caster.debug_rects = Some(&mut debug_rects);
+                    caster.cast_all();
+                    let occluders = debug_rects
+                        .iter()
+                        .filter(|rect| rect.color == Color::RED)
+                        .count();
+                    (visible, occluders)
+                };
+                let (naive, naive_occluders) = cast(None);
+                let (culled, culled_occluders) = cast(Some(&exposed));
+                let mut differences = Vec::new();
+                culled.for_each_difference(&naive, |index, _| differences.push(index));
+                assert!(
+                    differences.is_empty(),
+                    "round {round}, blocking corners {}: {differences:?}",
+                    corner_rule == CornerRule::Block
+                );
+                assert!(culled_occluders <= naive_occluders, "round {round}");
+            }
+        }
+    }
+
+    /// The subtraction as it was before it worked in place, one Vec per step, as a reference
+    fn vec_rectangle_minus_rectangles(rectangle: Rect, rectangles: Vec<Rect>) -> Vec<Rect> {
+        let mut result = vec![rectangle];
+
+        for subtract_rect in rectangles {
+            let mut new_result = Vec::new();
+
+            for rect in result {
+                if let Some(intersection) = rect.intersection(&subtract_rect) {
+                    // Split the rectangle around the intersection
+                    let mut splits = Vec::new();
+
+                    // Left part
+                    if rect.sx < intersection.sx {
+                        splits.push(Rect {
+                            sx: rect.sx,
+                            sy: rect.sy,
+                            ex: intersection.sx,
+                            ey: rect.ey,
+                        });
+                    }
+
+                    // Right part
+                    if intersection.ex < rect.ex {
+                        splits.push(Rect {
+                            sx: intersection.ex,
+                            sy: rect.sy,
+                            ex: rect.ex,
+                            ey: rect.ey,
+                        });
+                    }
+
+                    // Top part (only the middle section to avoid overlap)
+                    if rect.sy < intersection.sy {
+                        splits.push(Rect {
+                            sx: intersection.sx,
+                            sy: rect.sy,
+                            ex: intersection.ex,
+                            ey: intersection.sy,
+                        });
+                    }
+
+                    // Bottom part (only the middle section to avoid overlap)
+                    if intersection.ey < rect.ey {
+                        splits.push(Rect {
+                            sx: intersection.sx,
+                            sy: intersection.ey,
+                            ex: intersection.ex,
+                            ey: rect.ey,
+                        });
+                    }
+
+                    // Add all valid splits
+                    for split in splits {
+                        if split.is_valid() {
+                            new_result.push(split);
+                        }
+                    }
+                } else {
+                    // No intersection, keep the rectangle as is
+                    new_result.push(rect);
+                }
+            }
+
+            result = new_result;
+        }
+
+        result
+    }
+
+    /// A view a few cells across and `count` rects to subtract from it, mostly about the size
+    /// of an occluding cell and sometimes several. Half the time they lie on a quarter cell
+    /// grid, so edges often meet exactly, which is where splitting goes wrong if anywhere
+    fn random_rects(count: usize, seed: &mut u64) -> (Rect, Vec<Rect>) {
+        let mut next = || {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (*seed >> 40) as real / (1u64 << 24) as real
+        };
+        let snapped = next() < 0.5;
+        let mut rect = |across: real, max_size: real| {
+            let size = max_size * next() * next();
+            let mut values = [
+                next() * across,
+                next() * across,
+                0.25 + size * next(),
+                0.25 + size * next(),
+            ];
+            if snapped {
+                values = values.map(|value| (value * 4.0).round() / 4.0);
+            }
+            Rect {
+                sx: values[0],
+                sy: values[1],
+                ex: values[0] + values[2],
+                ey: values[1] + values[3],
+            }
+        };
+        (
+            rect(2.0, 8.0),
+            (0..count).map(|_| rect(10.0, 4.0)).collect(),
+        )
+    }
+
+    fn sorted(rects: &[Rect]) -> Vec<[real; 4]> {
+        let mut rects: Vec<[real; 4]> = rects.iter().map(|r| [r.sx, r.sy, r.ex, r.ey]).collect();
+        rects.sort_by(|a, b| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| a.total_cmp(b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        rects
+    }
+
+    #[test]
+    fn in_place_subtraction_cuts_the_same_pieces() {
+        let mut seed = 7;
+        for round in 0..3000 {
+            let count = [0, 1, 2, 3, 8, 16, 64][round % 7];
+            let (view, rects) = random_rects(count, &mut seed);
+            let expected = vec_rectangle_minus_rectangles(view, rects.clone());
+            let actual = rectangle_minus_rectangles(view, &rects);
+            assert_eq!(sorted(&actual), sorted(&expected), "round {round}");
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture subtraction_benchmark`
+    #[test]
+    #[ignore]
+    fn subtraction_benchmark() {
+        const ROUNDS: usize = 20000;
+        for count in [1, 8, 64] {
+            let mut seed = 3;
+            let inputs: Vec<(Rect, Vec<Rect>)> = (0..ROUNDS)
+                .map(|_| random_rects(count, &mut seed))
+                .collect();
+            let started = std::time::Instant::now();
+            let pieces: usize = inputs
+                .iter()
+                .map(|(view, rects)| vec_rectangle_minus_rectangles(*view, rects.clone()).len())
+                .sum();
+            let vec_nsec = started.elapsed().as_nanos() / ROUNDS as u128;
+            let started = std::time::Instant::now();
+            let in_place_pieces: usize = inputs
+                .iter()
+                .map(|(view, rects)| rectangle_minus_rectangles(*view, rects).len())
+                .sum();
+            let in_place_nsec = started.elapsed().as_nanos() / ROUNDS as u128;
+            assert_eq!(pieces, in_place_pieces);
+            println!(
+                "{count} rects: {vec_nsec} nsec with Vecs, {in_place_nsec} nsec in place, \
+                 {} pieces on average",
+                pieces / ROUNDS
+            );
+        }
+    }
+
+    /// Subtracting occluders largest-first leaves the same area as in the order the scan finds
+    /// them in, and no more pieces on any map. Occluders overlap in noise and on the ground,
+    /// where the big ones swallow the ones around them
+    #[test]
+    fn largest_first_subtraction_cuts_fewer_pieces() {
+        let golden = FIXTURES.iter().map(|&name| {
+            let (occluded, origin, _) = fixture(name);
+            (name, occluded, vec![origin])
+        });
+        let (mut all_scan_order, mut all_largest_first) = (0, 0);
+        for (name, occluded, origins) in golden.chain(work_fixtures()) {
+            let (mut scan_order_pieces, mut largest_first_pieces) = (0, 0);
+            for origin in origins {
+                let mut visible = BitGrid::new(occluded.size());
+                let mut debug_rects = Vec::new();
+                let mut caster = caster(&occluded, &mut visible, origin);
+                caster.debug_rects = Some(&mut debug_rects);
+                caster.cast_all();
+
+                // Each view comes with its occluders in the order the scan found them, before
+                // the weld and the sort
+                let views = debug_rects
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, rect)| rect.color == Color::CYAN);
+                for (i, view) in views {
+                    let view = view.rect;
+                    let mut occluders: Vec<Rect> = debug_rects[i + 1..]
+                        .iter()
+                        .take_while(|rect| rect.color == Color::RED)
+                        .map(|rect| rect.rect.grown(CORNER_EPSILON))
+                        .collect();
+                    let scan_order = rectangle_minus_rectangles(view, &occluders);
+                    let clipped_area =
+                        |rect: &Rect| rect.intersection(&view).map_or(0.0, |r| r.area());
+                    occluders.sort_by(|a, b| clipped_area(b).total_cmp(&clipped_area(a)));
+                    let largest_first = rectangle_minus_rectangles(view, &occluders);
+                    // The cast goes on with the area cut into canonical pieces, so the same
+                    // pieces here means the same cells are seen
+                    assert_eq!(
+                        sorted(&canonical_pieces(&largest_first)),
+                        sorted(&canonical_pieces(&scan_order)),
+                        "{name}, view {:?} from {origin}",
+                        sorted(&[view])
+                    );
+                    scan_order_pieces += scan_order.len();
+                    largest_first_pieces += largest_first.len();
+                }
+            }
+            assert!(
+                largest_first_pieces <= scan_order_pieces,
+                "{name}: {largest_first_pieces} pieces largest first, {scan_order_pieces} in \
+                 scan order"
+            );
+            all_scan_order += scan_order_pieces;
+            all_largest_first += largest_first_pieces;
+        }
+        assert!(all_largest_first < all_scan_order);
+    }
+
+    /// A block's one occluder covers what one per cell of it would, so a view loses the same
+    /// area to it, in no more pieces
+    #[test]
+    fn merged_blocks_occlude_what_their_cells_do() {
+        let mut seed = 5_u64;
+        let mut next = |range: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % range
+        };
+        let (mut cell_pieces, mut block_pieces) = (0, 0);
+        for round in 0..4000 {
+            let reverse_z = round % 2 == 1;
+            let depth = 1 + next(12) as i32;
+            let (z, z_half_offset) = match reverse_z {
+                true => (-depth as real, 0.5),
+                false => (depth as real, -0.5),
+            };
+            let origin = Vector3::new(
+                10.0 + next(8) as real / 8.0 - 0.5,
+                10.0 + next(8) as real / 8.0 - 0.5,
+                0.0,
+            );
+            // A view on a quarter cell grid around the origin's column, and the slopes that
+            // scan it at this depth
+            let mut edge = || 2.0 + next(72) as real / 4.0;
+            let (x0, x1, y0, y1) = (edge(), edge(), edge(), edge());
+            let view = Rect {
+                sx: x0.min(x1),
+                sy: y0.min(y1),
+                ex: x0.max(x1) + 0.25,
+                ey: y0.max(y1) + 0.25,
+            };
+            let slope = |edge: real, origin: real| (z + z_half_offset) / (edge - origin);
+            let slope_rect = match reverse_z {
+                true => Rect {
+                    sx: slope(view.ex, origin.x),
+                    sy: slope(view.ey, origin.y),
+                    ex: slope(view.sx, origin.x),
+                    ey: slope(view.sy, origin.y),
+                },
+                false => Rect {
+                    sx: slope(view.sx, origin.x),
+                    sy: slope(view.sy, origin.y),
+                    ex: slope(view.ex, origin.x),
+                    ey: slope(view.ey, origin.y),
+                },
+            };
+            let (sx, sy) = (2 + next(18), 2 + next(18));
+            let block = CellBlock {
+                sx,
+                sy,
+                ex: sx + next(6),
+                ey: sy + next(6),
+            };
+
+            let cells: Vec<Rect> = (block.sx..=block.ex)
+                .flat_map(|x| (block.sy..=block.ey).map(move |y| (x, y)))
+                .map(|(x, y)| {
+                    get_cube_occlusion(x as real, y as real, z, origin, &slope_rect, reverse_z)
+                })
+                .collect();
+            let merged = get_block_occlusion(&block, z, origin, &slope_rect, reverse_z);
+            let by_cell = rectangle_minus_rectangles(view, &cells);
+            let by_block = rectangle_minus_rectangles(view, &[merged]);
+            assert_eq!(
+                sorted(&canonical_pieces(&by_block)),
+                sorted(&canonical_pieces(&by_cell)),
+                "round {round}"
+            );
+            assert!(by_block.len() <= by_cell.len(), "round {round}");
+            cell_pieces += by_cell.len();
+            block_pieces += by_block.len();
+        }
+        assert!(block_pieces < cell_pieces);
+    }
+
+    /// LOD may lose cells at block edges and see a few next to what the exact cast sees, but
+    /// it never changes anything short of the start depth or sees through a wall
+    #[test]
+    fn lod_only_loses_cells_near_what_the_exact_cast_sees() {
+        let lod = Lod {
+            start_depth: 6,
+            factor: 2,
+            ..Lod::default()
+        };
+        let cast = |occluded: &BitGrid, origin: Vector3i, lod: Lod| {
+            let mut visible = BitGrid::new(occluded.size());
+            let mut caster = caster(occluded, &mut visible, origin);
+            caster.lod = lod;
+            caster.max_depth = 31;
+            caster.cast_all();
+            visible
+        };
+        let mut lost = 0;
+        for (name, occluded, origins) in work_fixtures() {
+            for origin in origins {
+                let exact = cast(&occluded, origin, Lod::default());
+                let coarse = cast(&occluded, origin, lod);
+                coarse.for_each_difference(&exact, |index, seen_coarse| {
+                    let delta = (index_cell(index) - origin).abs();
+                    assert!(
+                        delta.x.max(delta.y).max(delta.z) >= lod.start_depth as i32,
+                        "{name} from {origin}: {index:?} is within the start depth"
+                    );
+                    if !seen_coarse {
+                        lost += 1;
+                        return;
+                    }
+                    let cell = index_cell(index);
+                    let beside_seen = (-1..=1).any(|x| {
+                        (-1..=1).any(|y| {
+                            (-1..=1).any(|z| {
+                                let neighbour = cell + Vector3i::new(x, y, z);
+                                exact.get(cell_index(neighbour)) == Some(true)
+                            })
+                        })
+                    });
+                    assert!(
+                        beside_seen,
+                        "{name} from {origin}: {index:?} is seen only with LOD, away from any \
+                         cell the exact cast sees"
+                    );
+                });
+            }
+        }
+        assert!(lost > 0, "LOD is not coarse enough here to lose any cell");
+
+        // A solid wall across each axis, with noise in front of it to cut up the views
+        let size = (32, 32, 32);
+        let mut seed = 17;
+        let origin = Vector3i::splat(6);
+        for axis in 0..3 {
+            let mut occluded = BitGrid::new(size);
+            random_grid(size, &mut seed).for_each_set(|index| {
+                let axes = [index.0, index.1, index.2];
+                if axes[axis] > 8 && axes[axis] < 20 && index.0 % 3 == 0 {
+                    occluded.set(index, true);
+                }
+            });
+            let mut to = [31, 31, 31];
+            to[axis] = 22;
+            let mut from = [0, 0, 0];
+            from[axis] = 22;
+            occluded.set_box(from.into(), to.into(), true);
+            let coarse = cast(&occluded, origin, lod);
+            coarse.for_each_set(|index| {
+                let axes = [index.0, index.1, index.2];
+                assert!(axes[axis] <= 22, "{index:?} is seen through the wall");
+            });
+        }
+    }
+
+    /// Sweeping the eye across its cell in 0.1 steps beside a wall's edge moves the shadow the
+    /// wall casts one way only, so no cell flickers back to what it was
+    #[test]
+    fn sweeping_the_eye_changes_each_cell_at_most_once() {
+        let size = (24, 24, 24);
+        for axis in 0..3 {
+            // A cell at u along the axis the eye moves along, v across the wall and w along
+            // its edge
+            let at = |u: i32, v: i32, w: i32| {
+                let mut cell = [0; 3];
+                cell[axis] = u;
+                cell[(axis + 1) % 3] = v;
+                cell[(axis + 2) % 3] = w;
+                Vector3i::new(cell[0], cell[1], cell[2])
+            };
+            let mut occluded = BitGrid::new(size);
+            for u in 0..12 {
+                for w in 0..24 {
+                    occluded.set(cell_index(at(u, 10, w)), true);
+                }
+            }
+            let origin = at(12, 6, 12);
+            let sweep: Vec<BitGrid> = (0..10)
+                .map(|step| {
+                    let mut jitter = [0.0; 3];
+                    jitter[axis] = -0.45 + 0.1 * step as real;
+                    let mut visible = BitGrid::new(size);
+                    let mut caster = caster(&occluded, &mut visible, origin);
+                    caster.jitter = Vector3::new(jitter[0], jitter[1], jitter[2]);
+                    caster.cast_all();
+                    visible
+                })
+                .collect();
+
+            let mut changes: HashMap<Index3, usize> = HashMap::new();
+            for (before, after) in sweep.iter().zip(&sweep[1..]) {
+                after.for_each_difference(before, |index, _| {
+                    *changes.entry(index).or_insert(0) += 1
+                });
+            }
+            assert!(!changes.is_empty(), "axis {axis}: the shadow does not move");
+            let flickering: Vec<_> = changes.iter().filter(|&(_, &count)| count > 1).collect();
+            assert!(flickering.is_empty(), "axis {axis}: {flickering:?}");
+        }
+    }
+
+    /// Noise splits views into ever more pieces. Under a budget the cast stops before the
+    /// first layer that would go over it, having scanned no more views than that and seen
+    /// everything out to the last layer it finished
+    #[test]
+    fn max_work_items_cuts_off_noise_beyond_the_completed_depth() {
+        let mut seed = 21;
+        let mut occluded = random_grid((48, 48, 48), &mut seed);
+        let origin = Vector3i::splat(24);
+        occluded.set(cell_index(origin), false);
+        let passes: Vec<Pass> = all_passes().collect();
+        let cast = |max_work_items: usize| {
+            let mut visible = vec![BitGrid::new(occluded.size()); passes.len()];
+            let mut casters: Vec<Caster> = visible
+                .iter_mut()
+                .map(|visible| caster(&occluded, visible, origin))
+                .collect();
+            let cast = cast_layered(&mut casters, &passes, max_work_items, || 0, None);
+            drop(casters);
+            let mut seen = BitGrid::new(occluded.size());
+            for visible in &visible {
+                seen.union_with(visible);
+            }
+            (cast, seen)
+        };
+
+        let (whole, seen_whole) = cast(0);
+        assert!(!whole.truncated);
+        let budget = whole.work_items / 4;
+        let (cut, seen_cut) = cast(budget);
+        assert!(cut.truncated);
+        assert!(cut.work_items <= budget, "{} views", cut.work_items);
+        assert!(cut.completed_depth > 0 && cut.completed_depth < whole.completed_depth);
+        seen_cut.for_each_difference(&seen_whole, |index, seen| {
+            let delta = (index_cell(index) - origin).abs();
+            let distance = delta.x.max(delta.y).max(delta.z) as usize;
+            assert!(!seen, "{index:?} is seen only by the cut off cast");
+            assert!(
+                distance > cut.completed_depth,
+                "{index:?} is missed within depth {}",
+                cut.completed_depth
+            );
+        });
+    }
+
+    /// A wall of one-way cells across each axis lets a viewer on the side it opens away from
+    /// see through it, and one on the other side not
+    #[test]
+    fn one_way_walls_see_through_from_one_side_only() {
+        let size = (15, 15, 15);
+        for axis in 0..3 {
+            for open in [-1, 1] {
+                // A cell at u along this axis, with the other two at v
+                let at = |u: i32, v: i32| {
+                    let mut cell = [v; 3];
+                    cell[axis] = u;
+                    Vector3i::new(cell[0], cell[1], cell[2])
+                };
+                let mut open_direction = [0; 3];
+                open_direction[axis] = open;
+                let open_direction =
+                    Vector3i::new(open_direction[0], open_direction[1], open_direction[2]);
+                let mut occluded = BitGrid::new(size);
+                occluded.set_box(cell_index(at(7, 0)), cell_index(at(7, 14)), true);
+                let mut one_way = OneWayCells::new();
+                occluded.for_each_set(|index| {
+                    one_way.insert(index, open_direction);
+                });
+
+                // Looking along the open direction, then against it
+                let (near, far) = match open {
+                    1 => (3, 11),
+                    _ => (11, 3),
+                };
+                for (from, to, sees) in [(near, far, true), (far, near, false)] {
+                    let mut visible = BitGrid::new(size);
+                    let mut caster = caster(&occluded, &mut visible, at(from, 7));
+                    caster.one_way = Some(&one_way);
+                    caster.cast_all();
+                    assert_eq!(
+                        visible.get(cell_index(at(to, 7))),
+                        Some(sees),
+                        "axis {axis}, open {open}, from {from}"
+                    );
+                    assert_eq!(
+                        visible.get(cell_index(at(7, 7))),
+                        Some(true),
+                        "the wall itself is seen"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Casting a map twice, in layers, or one pass per thread sees the same cells and lists
+    /// them in the same order, so bulk getters give byte-identical arrays every way
+    #[test]
+    fn casts_list_the_same_cells_in_the_same_order_every_way() {
+        let mut seed = 5;
+        let mut occluded = random_grid((40, 40, 40), &mut seed);
+        let origin = Vector3i::splat(20);
+        occluded.set(cell_index(origin), false);
+        let passes: Vec<Pass> = all_passes().collect();
+        let listed = |grid: &BitGrid| {
+            let mut cells = Vec::new();
+            grid.for_each_set(|index| cells.push(index_cell(index)));
+            (cells, grid.to_bytes())
+        };
+        let cast_all = || {
+            let mut visible = BitGrid::new(occluded.size());
+            caster(&occluded, &mut visible, origin).cast_all();
+            visible
+        };
+        let union = |mut visible: Vec<BitGrid>| {
+            let mut seen = BitGrid::new(occluded.size());
+            seen.set(cell_index(origin), true);
+            for visible in visible.drain(..) {
+                seen.union_with(&visible);
+            }
+            seen
+        };
+
+        let first = listed(&cast_all());
+        assert!(first.0.len() > 1000, "{} cells", first.0.len());
+        assert!(
+            first
+                .0
+                .windows(2)
+                .all(|pair| (pair[0].x, pair[0].y, pair[0].z) < (pair[1].x, pair[1].y, pair[1].z))
+        );
+        assert!(listed(&cast_all()) == first, "a second cast differs");
+
+        let mut visible = vec![BitGrid::new(occluded.size()); passes.len()];
+        let mut casters: Vec<Caster> = visible
+            .iter_mut()
+            .map(|visible| caster(&occluded, visible, origin))
+            .collect();
+        cast_layered(&mut casters, &passes, 0, || 0, None);
+        drop(casters);
+        assert!(listed(&union(visible)) == first, "the layered cast differs");
+
+        let threaded = std::thread::scope(|scope| {
+            let threads: Vec<_> = passes
+                .iter()
+                .map(|(slope_rect, reverse_z, plane)| {
+                    let occluded = &occluded;
+                    scope.spawn(move || {
+                        let mut visible = BitGrid::new(occluded.size());
+                        let mut caster = caster(occluded, &mut visible, origin);
+                        cast_light(&mut caster, slope_rect, 1, *reverse_z, plane);
+                        visible
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect()
+        });
+        assert!(
+            listed(&union(threaded)) == first,
+            "the threaded cast differs"
+        );
+    }
+
+    /// Capping the pieces a view keeps merges some views, and every cell the uncapped cast
+    /// sees is still seen
+    #[test]
+    fn capped_views_only_see_more() {
+        let mut maps: Vec<(&str, BitGrid, Vec<Vector3i>)> = work_fixtures();
+        for name in FIXTURES {
+            let (occluded, origin, _) = fixture(name);
+            maps.push((name, occluded, vec![origin]));
+        }
+        for max_per_view in [1, 2, 4] {
+            let mut merged_views = 0;
+            for (name, occluded, origins) in &maps {
+                for &origin in origins {
+                    let mut uncapped = BitGrid::new(occluded.size());
+                    caster(occluded, &mut uncapped, origin).cast_all();
+                    let mut capped = BitGrid::new(occluded.size());
+                    let mut caster = caster(occluded, &mut capped, origin);
+                    caster.rects.max_per_view = max_per_view;
+                    caster.cast_all();
+                    merged_views += caster.rects.merged_views;
+                    capped.for_each_difference(&uncapped, |index, seen_capped| {
+                        assert!(
+                            seen_capped,
+                            "{name} from {origin} capped at {max_per_view}: {index:?} is lost"
+                        );
+                    });
+                }
+            }
+            assert!(merged_views > 0, "no view was merged at {max_per_view}");
+        }
+    }
+
+    /// The JSON dump of a traced cast lists every view in scan order, each cut from a view
+    /// of its pass one layer nearer, which lists it among its children
+    #[test]
+    fn trace_json_links_each_view_to_the_one_it_was_cut_from() {
+        let mut seed = 17;
+        let mut occluded = random_grid((24, 24, 24), &mut seed);
+        let origin = Vector3i::splat(12);
+        occluded.set(cell_index(origin), false);
+        let passes: Vec<Pass> = all_passes().collect();
+        let mut visible = vec![BitGrid::new(occluded.size()); passes.len()];
+        let mut casters: Vec<Caster> = visible
+            .iter_mut()
+            .map(|visible| caster(&occluded, visible, origin))
+            .collect();
+        let mut trace = Vec::new();
+        let cast = cast_layered(&mut casters, &passes, 0, || 0, Some(&mut trace));
+        assert_eq!(trace.len(), cast.work_items);
+        assert!(cast.completed_depth > 5, "{} layers", cast.completed_depth);
+
+        let json = trace_json(&trace);
+        let items = json
+            .strip_prefix("{\"items\": [")
+            .and_then(|items| items.strip_suffix("\n]}\n"))
+            .expect("an items array");
+        // The value of a number or null field of an item
+        let field = |item: &str, key: &str| -> Option<usize> {
+            let start = item.find(&format!("\"{key}\": ")).unwrap() + key.len() + 4;
+            let value = &item[start..];
+            let value = &value[..value.find([',', '}']).unwrap()];
+            match value {
+                "null" => None,
+                _ => Some(value.parse().unwrap()),
+            }
+        };
+        let items: Vec<(usize, Option<usize>, usize, usize, &str)> = items
+            .split(",\n")
+            .map(|item| {
+                let item = item.trim_start_matches('\n');
+                assert!(item.starts_with('{') && item.ends_with('}'), "{item}");
+                let pass = field(item, "pass").unwrap();
+                let depth = field(item, "depth").unwrap();
+                let children = field(item, "children").unwrap();
+                (pass, field(item, "parent"), depth, children, item)
+            })
+            .collect();
+        assert_eq!(items.len(), trace.len());
+
+        let mut children = vec![0; items.len()];
+        for (index, &(pass, parent, depth, _, item)) in items.iter().enumerate() {
+            let Some(parent) = parent else {
+                assert_eq!(depth, 1, "{item}");
+                assert!(item.contains("\"inf\""), "{item}");
+                continue;
+            };
+            assert!(parent < index, "{item}");
+            let (parent_pass, _, parent_depth, _, _) = items[parent];
+            assert_eq!(pass, parent_pass, "{item}");
+            assert_eq!(depth, parent_depth + 1, "{item}");
+            children[parent] += 1;
+        }
+        for (&(_, _, _, listed, item), &found) in items.iter().zip(&children) {
+            assert_eq!(listed, found, "{item}");
+        }
+        let roots = items.iter().filter(|item| item.1.is_none()).count();
+        assert_eq!(roots, passes.len());
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use godot::prelude::*;
+
+use crate::bitset::{BitGrid, UpAxis, cell_index};
+
+/// Visibility as of one recompute, in the grid's own axes. Nothing in it changes after it is
+/// taken, so it can be cloned into and read from any number of threads
+#[derive(Clone, Default)]
+pub struct VisibilitySnapshot {
+    pub origin: Vector3i,
+    pub visible: Arc<BitGrid>,
+}
+
+impl VisibilitySnapshot {
+    pub fn is_visible(&self, pos: Vector3i) -> bool {
+        let index = cell_index(pos);
+        self.visible.get(index).unwrap_or(false)
+    }
+
+    /// Visible cells in the inclusive box between two corners, ignoring any part outside the grid
+    pub fn count_visible_in_box(&self, from: Vector3i, to: Vector3i) -> usize {
+        let (clipped, _) = self.visible.clip_box(from, to);
+        clipped.map_or(0, |(min, max)| self.visible.count_in_box(min, max))
+    }
+}
+
+/// A read-only copy of a Display's visibility, taken by get_visibility_snapshot().
+///
+/// Threading contract: the query functions only read data that never changes after the snapshot
+/// is taken, and never touch the scene tree or log through the engine, so they are safe to call
+/// from any thread, including several at once. Later recomputes never change a snapshot,
+/// they replace the Display's own buffer instead. Creating snapshots, and anything else on the
+/// Display, still has to happen on the main thread
+#[derive(GodotClass)]
+#[class(init, base=RefCounted)]
+pub struct FovSnapshot {
+    base: Base<RefCounted>,
+    snapshot: VisibilitySnapshot,
+    /// The Display's up_axis when the snapshot was taken, which the queries take positions in
+    up_axis: UpAxis,
+}
+
+impl FovSnapshot {
+    pub fn new_gd(snapshot: VisibilitySnapshot, up_axis: UpAxis) -> Gd<Self> {
+        Gd::from_init_fn(|base| Self {
+            base,
+            snapshot,
+            up_axis,
+        })
+    }
+
+    /// The shared data itself, for Rust code to move into its own threads
+    pub fn snapshot(&self) -> VisibilitySnapshot {
+        self.snapshot.clone()
+    }
+}
+
+#[godot_api]
+impl FovSnapshot {
+    #[func]
+    pub fn get_origin(&self) -> Vector3i {
+        self.up_axis.from_grid(self.snapshot.origin)
+    }
+
+    /// Whether a cell was visible when the snapshot was taken, false outside the grid
+    #[func]
+    pub fn is_visible(&self, pos: Vector3i) -> bool {
+        self.snapshot.is_visible(self.up_axis.to_grid(pos))
+    }
+
+    /// Visible cells in the inclusive box between two corners, ignoring any part outside the grid
+    #[func]
+    pub fn count_visible_in_box(&self, from: Vector3i, to: Vector3i) -> i64 {
+        let (from, to) = (self.up_axis.to_grid(from), self.up_axis.to_grid(to));
+        self.snapshot.count_visible_in_box(from, to) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitset::Index3;
+
+    /// Every cell of a grid the size of `size` and one cell around it, in x, y, z order
+    fn cells_around(size: Index3) -> Vec<Vector3i> {
+        let mut cells = Vec::new();
+        for x in -1..=size.0 as i32 {
+            for y in -1..=size.1 as i32 {
+                for z in -1..=size.2 as i32 {
+                    cells.push(Vector3i::new(x, y, z));
+                }
+            }
+        }
+        cells
+    }
+
+    #[test]
+    fn clones_read_the_source_grid_from_several_threads() {
+        let size = (9, 6, 7);
+        let mut visible = BitGrid::new(size);
+        let mut seed = 11u64;
+        for _ in 0..120 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let index = (
+                seed as usize % size.0,
+                (seed >> 20) as usize % size.1,
+                (seed >> 40) as usize % size.2,
+            );
+            visible.set(index, true);
+        }
+        let snapshot = VisibilitySnapshot {
+            origin: Vector3i::new(4, 3, 3),
+            visible: Arc::new(visible.clone()),
+        };
+        // Inside, straddling and wholly outside the grid, with corners in either order
+        let boxes = [
+            (Vector3i::new(0, 0, 0), Vector3i::new(8, 5, 6)),
+            (Vector3i::new(2, 1, 3), Vector3i::new(5, 4, 3)),
+            (Vector3i::new(-3, -1, 4), Vector3i::new(3, 9, 20)),
+            (Vector3i::new(6, 2, 1), Vector3i::new(1, 4, 5)),
+            (Vector3i::new(9, 0, 0), Vector3i::new(12, 5, 6)),
+        ];
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshot = snapshot.clone();
+                std::thread::spawn(move || {
+                    let cells: Vec<bool> = cells_around(size)
+                        .into_iter()
+                        .map(|cell| snapshot.is_visible(cell))
+                        .collect();
+                    let counts: Vec<usize> = boxes
+                        .iter()
+                        .map(|&(from, to)| snapshot.count_visible_in_box(from, to))
+                        .collect();
+                    (cells, counts)
+                })
+            })
+            .collect();
+
+        // What each thread should read, straight from the grid the snapshot was taken of
+        let source = |cell: Vector3i| {
+            cell.x >= 0 && cell.y >= 0 && cell.z >= 0 && visible.get(cell_index(cell)) == Some(true)
+        };
+        let expected_cells: Vec<bool> = cells_around(size).into_iter().map(source).collect();
+        let expected_counts: Vec<usize> = boxes
+            .iter()
+            .map(|&(from, to)| {
+                let (min, max) = (from.coord_min(to), from.coord_max(to));
+                let mut count = 0;
+                for x in min.x..=max.x {
+                    for y in min.y..=max.y {
+                        for z in min.z..=max.z {
+                            count += usize::from(source(Vector3i::new(x, y, z)));
+                        }
+                    }
+                }
+                count
+            })
+            .collect();
+        assert!(expected_counts[0] > 0);
+        assert_eq!(expected_counts[4], 0);
+        for thread in threads {
+            let (cells, counts) = thread.join().unwrap();
+            assert_eq!(cells, expected_cells);
+            assert_eq!(counts, expected_counts);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use godot::{builtin::real, prelude::*};
+
+use crate::{error::ShadowcastError, shadowcast::CornerRule};
+
+/// Format version written by capture_state(). Bump it whenever the saved fields change meaning
+pub const STATE_VERSION: i64 = 1;
+
+/// Version of this extension, as in Cargo.toml
+pub const EXTENSION_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Whether a state saved with format `version` can be restored. Older and newer saves are
+/// rejected rather than read as if they had this version's fields
+pub fn check_version(version: i64) -> Result<(), ShadowcastError> {
+    if version == STATE_VERSION {
+        return Ok(());
+    }
+    Err(ShadowcastError::InvalidData(format!(
+        "ShadowcastState has format version {version}, but only version {STATE_VERSION} is \
+         supported"
+    )))
+}
+
+/// Everything needed to restore a Display: the occlusion grid, terrain, light sources,
+/// emissive cells and settings.
+/// Created by Display.capture_state() and read back by Display.restore_state()
+#[derive(GodotClass)]
+#[class(init, base=Resource)]
+pub struct ShadowcastState {
+    base: Base<Resource>,
+    #[export]
+    pub version: i64,
+    /// Version of the extension that saved the state. Restoring only checks version
+    #[export]
+    pub extension_version: GString,
+    #[export]
+    pub size: Vector3i,
+    /// The occlusion grid as packed little-endian 64 bit words
+    #[export]
+    pub occluded: PackedByteArray,
+    #[export]
+    pub origin: Vector3,
+    /// One-way occluders as parallel arrays: each cell and the direction sight passes through it
+    #[export]
+    pub one_way_positions: PackedVector3Array,
+    #[export]
+    pub one_way_directions: PackedVector3Array,
+    /// Cell tags as little-endian 16 bit values in the grid's cell order, empty without tags
+    #[export]
+    pub tags: PackedByteArray,
+    /// Terrain columns as in Display.set_terrain_heights(), no heights for no terrain
+    #[export]
+    pub terrain_width: i32,
+    #[export]
+    pub terrain_depth: i32,
+    #[export]
+    pub terrain_heights: PackedFloat32Array,
+    /// Emitting cells as parallel arrays, one entry per cell
+    #[export]
+    pub emissive_positions: PackedVector3Array,
+    #[export]
+    pub emissive_colors: PackedColorArray,
+    #[export]
+    pub emissive_intensities: PackedFloat64Array,
+    #[export]
+    pub emissive_radius: i32,
+    /// Light sources as parallel arrays, one entry per light
+    #[export]
+    pub light_ids: PackedInt64Array,
+    #[export]
+    pub light_positions: PackedVector3Array,
+    #[export]
+    pub light_radii: PackedInt32Array,
+    #[export]
+    pub light_intensities: PackedFloat64Array,
+    /// Falloff and number of bands of every light, both empty for linear lights saved before
+    /// falloffs could be chosen
+    #[export]
+    pub light_falloffs: PackedInt32Array,
+    #[export]
+    pub light_steps: PackedInt32Array,
+    /// Color of every light, empty for lights saved before lights had colors
+    #[export]
+    pub light_colors: PackedColorArray,
+    #[export]
+    pub next_light_id: i64,
+    #[export]
+    pub darkness_threshold: real,
+    #[export]
+    pub use_innate_light: bool,
+    #[export]
+    pub innate_light_radius: real,
+    #[export]
+    pub soft_samples: i32,
+    #[export]
+    pub soft_seed: i64,
+    #[export]
+    pub corner_rule: CornerRule,
+    #[export]
+    pub track_visibility_fraction: bool,
+    #[export]
+    pub lod_start_depth: i32,
+    #[export]
+    pub lod_factor: i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use godot::global::Error;
+
+    use super::*;
+
+    #[test]
+    fn only_the_current_version_restores() {
+        assert!(check_version(STATE_VERSION).is_ok());
+        // Never saved with a version, as a state made with new() in a script
+        for version in [0, STATE_VERSION - 1, STATE_VERSION + 1, -1] {
+            let error = check_version(version).unwrap_err();
+            assert_eq!(error.code(), Error::ERR_INVALID_DATA, "version {version}");
+            assert!(
+                error.to_string().contains(&format!("version {version},")),
+                "{error}"
+            );
+        }
+    }
+}
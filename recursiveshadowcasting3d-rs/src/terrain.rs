@@ -0,0 +1,125 @@
+use godot::prelude::*;
+
+use crate::bitset::{Index3, shift_span};
+
+/// Width of the square blocks of columns whose highest point is kept, so queries over many
+/// columns can skip whole blocks
+const BLOCK: usize = 8;
+
+/// 2.5D terrain: every cell at or below its column's height occludes.
+/// Columns are indexed by x and z, at x * depth + z
+#[derive(Clone, Default)]
+pub struct Terrain {
+    width: usize,
+    depth: usize,
+    heights: Vec<f32>,
+    // highest column in every BLOCK x BLOCK block, laid out like the columns
+    block_max: Vec<f32>,
+    blocks_depth: usize,
+}
+
+impl Terrain {
+    /// None if there is not exactly one height per column
+    pub fn new(width: usize, depth: usize, heights: &[f32]) -> Option<Self> {
+        if heights.len() != width * depth {
+            return None;
+        }
+        let blocks_width = width.div_ceil(BLOCK);
+        let blocks_depth = depth.div_ceil(BLOCK);
+        let mut block_max = vec![f32::NEG_INFINITY; blocks_width * blocks_depth];
+        for x in 0..width {
+            for z in 0..depth {
+                let block = &mut block_max[x / BLOCK * blocks_depth + z / BLOCK];
+                *block = block.max(heights[x * depth + z]);
+            }
+        }
+        Some(Self {
+            width,
+            depth,
+            heights: heights.to_vec(),
+            block_max,
+            blocks_depth,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn heights(&self) -> &[f32] {
+        &self.heights
+    }
+
+    /// Bytes allocated for the heights and their per-block maxima
+    pub fn memory_bytes(&self) -> usize {
+        (self.heights.capacity() + self.block_max.capacity()) * size_of::<f32>()
+    }
+
+    /// The terrain moved as BitGrid::shift() moves cells: every column by `-offset` along x and
+    /// z, and `offset.y` lower. Columns moved past an edge are dropped and the ones left behind
+    /// are empty
+    pub fn shifted(&self, offset: Vector3i) -> Self {
+        let mut heights = vec![f32::NEG_INFINITY; self.heights.len()];
+        let (kept_x, moved_x) = shift_span(self.width, offset.x);
+        let (kept_z, moved_z) = shift_span(self.depth, offset.z);
+        for (from, to) in kept_x.zip(moved_x) {
+            let (from, to) = (from * self.depth, to * self.depth);
+            let source = &self.heights[from + kept_z.start..from + kept_z.end];
+            let target = &mut heights[to + moved_z.start..to + moved_z.end];
+            for (target, &height) in target.iter_mut().zip(source) {
+                *target = height - offset.y as f32;
+            }
+        }
+        Self::new(self.width, self.depth, &heights).unwrap_or_default()
+    }
+
+    /// Whether the terrain covers a cell. Columns outside the terrain are empty
+    pub fn occludes(&self, (x, y, z): Index3) -> bool {
+        x < self.width && z < self.depth && y as f32 <= self.heights[x * self.depth + z]
+    }
+
+    /// Whether the terrain covers any cell in the inclusive box from `min` to `max`
+    pub fn occludes_any(&self, min: Index3, max: Index3) -> bool {
+        min.1 as f32 <= self.max_height((min.0, min.2), (max.0, max.2))
+    }
+
+    /// Highest column in the inclusive range of (x, z) columns, whole blocks at a time
+    /// where the range covers them
+    fn max_height(&self, min: (usize, usize), max: (usize, usize)) -> f32 {
+        let mut highest = f32::NEG_INFINITY;
+        if self.width == 0 || self.depth == 0 {
+            return highest;
+        }
+        let max = (max.0.min(self.width - 1), max.1.min(self.depth - 1));
+        if min.0 > max.0 || min.1 > max.1 {
+            return highest;
+        }
+        for bx in min.0 / BLOCK..=max.0 / BLOCK {
+            for bz in min.1 / BLOCK..=max.1 / BLOCK {
+                let (sx, sz) = ((bx * BLOCK).max(min.0), (bz * BLOCK).max(min.1));
+                let (ex, ez) = (
+                    (bx * BLOCK + BLOCK - 1).min(max.0),
+                    (bz * BLOCK + BLOCK - 1).min(max.1),
+                );
+                let block = self.block_max[bx * self.blocks_depth + bz];
+                if block <= highest {
+                    continue;
+                }
+                if ex - sx + 1 == BLOCK && ez - sz + 1 == BLOCK {
+                    highest = block;
+                    continue;
+                }
+                for x in sx..=ex {
+                    for z in sz..=ez {
+                        highest = highest.max(self.heights[x * self.depth + z]);
+                    }
+                }
+            }
+        }
+        highest
+    }
+}
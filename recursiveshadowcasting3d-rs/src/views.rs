@@ -0,0 +1,187 @@
+use godot::{builtin::real, prelude::*};
+
+use crate::{
+    bitset::{BitGrid, Index3},
+    occlusion_source::OcclusionSource,
+    shadowcast::{
+        Caster, CornerRule, Lod, MAX_DEPTH, OneWayCells, Rect, RectLimit, UnitPlane3d, all_passes,
+        cast_light,
+    },
+    terrain::Terrain,
+};
+
+/// The shape of a view's range around its origin
+#[derive(GodotConvert, Clone, Copy, Default, PartialEq)]
+#[godot(via = i64)]
+pub enum RangeShape {
+    /// Every cell up to max_depth layers away along each axis
+    #[default]
+    Cube,
+    /// Only cells up to max_depth away from the origin
+    Sphere,
+}
+
+/// What a vision modifier does to the view it is added to
+#[derive(GodotConvert, Clone, Copy, PartialEq, Debug)]
+#[godot(via = i64)]
+pub enum VisionModifierKind {
+    /// Multiplies the range by the magnitude, such as 2 for a scrying buff.
+    /// Several multiply together
+    RangeMultiplier,
+    /// Keeps the range at least the magnitude, once every multiplier is applied
+    RangeFloor,
+    /// Limits the range to 1, whatever else applies
+    Blindness,
+    /// Cells the view sees within the magnitude of it count as lit
+    Darkvision,
+}
+
+/// A status effect on a view, see Display.add_vision_modifier()
+#[derive(Clone, Copy)]
+pub struct VisionModifier {
+    pub kind: VisionModifierKind,
+    pub magnitude: real,
+    /// Recomputes of the view left before it lapses, None to last until cleared
+    pub remaining: Option<u32>,
+}
+
+/// An observer with its own results, sharing the occlusion grid with every other view
+pub struct View {
+    pub id: i64,
+    pub max_depth: usize,
+    pub range_shape: RangeShape,
+    /// Whether cells exactly max_depth away, along an axis or in the sphere, are in range
+    pub range_is_inclusive: bool,
+    /// Status effects, in the order they were added
+    pub modifiers: Vec<VisionModifier>,
+    // empty until the first recompute, then reused while the grid size stays the same
+    pub visible: BitGrid,
+    pub origin: Vector3i,
+}
+
+impl View {
+    pub fn new(id: i64) -> Self {
+        Self {
+            id,
+            max_depth: MAX_DEPTH,
+            range_shape: RangeShape::Cube,
+            range_is_inclusive: true,
+            modifiers: Vec::new(),
+            visible: BitGrid::default(),
+            origin: Vector3i::ZERO,
+        }
+    }
+
+    /// How far the view reaches with its modifiers: max_depth times every range multiplier,
+    /// raised to the highest range floor, or 1 while blinded
+    pub fn effective_max_depth(&self) -> usize {
+        let of_kind = |kind| {
+            self.modifiers
+                .iter()
+                .filter(move |modifier| modifier.kind == kind)
+                .map(|modifier| modifier.magnitude)
+        };
+        if of_kind(VisionModifierKind::Blindness).next().is_some() {
+            return 1;
+        }
+        let multiplied = of_kind(VisionModifierKind::RangeMultiplier)
+            .fold(self.max_depth as real, |depth, factor| depth * factor);
+        let floored = of_kind(VisionModifierKind::RangeFloor).fold(multiplied, real::max);
+        floored.max(0.0).round() as usize
+    }
+
+    /// The widest darkvision on the view, None without any
+    pub fn darkvision_radius(&self) -> Option<real> {
+        self.modifiers
+            .iter()
+            .filter(|modifier| modifier.kind == VisionModifierKind::Darkvision)
+            .map(|modifier| modifier.magnitude)
+            .reduce(real::max)
+    }
+
+    /// Count a recompute off every modifier with a duration, removing and returning those
+    /// that lapsed
+    pub fn tick_modifiers(&mut self) -> Vec<VisionModifier> {
+        let mut lapsed = Vec::new();
+        self.modifiers.retain_mut(|modifier| {
+            let Some(remaining) = modifier.remaining.as_mut() else {
+                return true;
+            };
+            *remaining = remaining.saturating_sub(1);
+            if *remaining > 0 {
+                return true;
+            }
+            lapsed.push(*modifier);
+            false
+        });
+        lapsed
+    }
+
+    /// Shadowcast from `origin` into this view's own buffer
+    pub fn recompute(
+        &mut self,
+        occluded: &dyn OcclusionSource,
+        size: Index3,
+        one_way: &OneWayCells,
+        terrain: Option<&Terrain>,
+        origin: Vector3i,
+    ) {
+        self.cast_passes(occluded, size, one_way, terrain, origin, all_passes());
+    }
+
+    /// Shadowcast from `origin` into this view's own buffer, sized to a grid of `size`, running
+    /// only the given passes
+    pub fn cast_passes(
+        &mut self,
+        occluded: &dyn OcclusionSource,
+        size: Index3,
+        one_way: &OneWayCells,
+        terrain: Option<&Terrain>,
+        origin: Vector3i,
+        passes: impl IntoIterator<Item = (Rect, bool, UnitPlane3d)>,
+    ) {
+        if self.visible.size() == size {
+            self.visible.clear();
+        } else {
+            self.visible = BitGrid::new(size);
+        }
+        self.origin = origin;
+
+        let max_depth = self.effective_max_depth();
+        let mut caster = Caster {
+            occluded,
+            visible: &mut self.visible,
+            origin,
+            jitter: Vector3::ZERO,
+            max_depth: match self.range_is_inclusive {
+                true => max_depth,
+                false => max_depth.saturating_sub(1),
+            },
+            lod: Lod::default(),
+            corner_rule: CornerRule::default(),
+            debug_rects: None,
+            lit_rects: None,
+            fractions: None,
+            bounds: None,
+            one_way: Some(one_way),
+            blockers: None,
+            terrain,
+            exposed: None,
+            rects: RectLimit::default(),
+        };
+        caster.mark_origin_visible();
+        for (initial_slope_rect, reverse_z, plane) in passes {
+            cast_light(&mut caster, &initial_slope_rect, 1, reverse_z, &plane);
+        }
+
+        if self.range_shape == RangeShape::Sphere {
+            let center = origin.cast_float();
+            let max_distance = max_depth as real;
+            let inclusive = self.range_is_inclusive;
+            self.visible.retain_set(|(x, y, z)| {
+                let distance = Vector3::new(x as real, y as real, z as real).distance_to(center);
+                distance < max_distance || (inclusive && distance == max_distance)
+            });
+        }
+    }
+}
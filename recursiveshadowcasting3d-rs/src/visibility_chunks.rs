@@ -0,0 +1,59 @@
+use crate::bitset::{BitGrid, Index3};
+
+/// The chunks of the grid whose visibility changed since they were last taken, see
+/// Display.take_dirty_visibility_chunks()
+pub struct DirtyChunks {
+    chunk_size: usize,
+    // one bit per chunk
+    dirty: BitGrid,
+    // visibility as of the last update, to tell which cells changed
+    previous: BitGrid,
+}
+
+impl DirtyChunks {
+    /// Track chunks of `chunk_size` cells along each axis. The first update marks every chunk
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size: chunk_size.max(1),
+            dirty: BitGrid::new((0, 0, 0)),
+            previous: BitGrid::new((0, 0, 0)),
+        }
+    }
+
+    /// Mark the chunks holding a cell whose visibility differs from the last update, or every
+    /// chunk when the grid changed size since
+    pub fn update(&mut self, visible: &BitGrid) {
+        let size = visible.size();
+        let chunk_size = self.chunk_size;
+        if self.previous.size() != size {
+            let chunks = (
+                size.0.div_ceil(chunk_size),
+                size.1.div_ceil(chunk_size),
+                size.2.div_ceil(chunk_size),
+            );
+            self.dirty = BitGrid::new(chunks);
+            if chunks.0 > 0 && chunks.1 > 0 && chunks.2 > 0 {
+                self.dirty
+                    .set_box((0, 0, 0), (chunks.0 - 1, chunks.1 - 1, chunks.2 - 1), true);
+            }
+        } else {
+            let dirty = &mut self.dirty;
+            visible.for_each_differing_run(&self.previous, chunk_size, |(x, y, z)| {
+                dirty.set((x / chunk_size, y / chunk_size, z / chunk_size), true);
+            });
+        }
+        self.previous.clone_from(visible);
+    }
+
+    /// The marked chunks in ascending x, then y, then z order, unmarking them
+    pub fn take(&mut self) -> Vec<Index3> {
+        let mut chunks = Vec::new();
+        self.dirty.for_each_set(|chunk| chunks.push(chunk));
+        self.dirty.clear();
+        chunks
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.dirty.memory_bytes() + self.previous.memory_bytes()
+    }
+}
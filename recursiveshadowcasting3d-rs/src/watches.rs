@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use godot::prelude::*;
+
+use crate::bitset::{BitGrid, cell_index};
+
+/// Cells scripts asked to hear about when they become or stop being visible, by the id add()
+/// gave each. Ids are never reused, and changes come in ascending id order
+#[derive(Default)]
+pub struct Watches {
+    // each watch's cell and whether it was visible at the last update
+    cells: BTreeMap<i64, (Vector3i, bool)>,
+    next_id: i64,
+}
+
+impl Watches {
+    /// Watch a cell, starting from whether it is visible in `visible`, returning the watch's id
+    pub fn add(&mut self, cell: Vector3i, visible: &BitGrid) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.cells.insert(id, (cell, is_visible(visible, cell)));
+        id
+    }
+
+    /// Returns false if there is no watch with this id
+    pub fn remove(&mut self, id: i64) -> bool {
+        self.cells.remove(&id).is_some()
+    }
+
+    /// The watches whose cell became or stopped being visible since the last update, each with
+    /// whether it is visible now. Cells outside the grid count as not visible
+    pub fn update(&mut self, visible: &BitGrid) -> Vec<(i64, bool)> {
+        let mut changed = Vec::new();
+        for (&id, (cell, was_visible)) in self.cells.iter_mut() {
+            let now_visible = is_visible(visible, *cell);
+            if now_visible != *was_visible {
+                *was_visible = now_visible;
+                changed.push((id, now_visible));
+            }
+        }
+        changed
+    }
+}
+
+fn is_visible(visible: &BitGrid, cell: Vector3i) -> bool {
+    visible.get(cell_index(cell)) == Some(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_report_changes_in_id_order() {
+        let mut visible = BitGrid::new((4, 4, 4));
+        visible.set((1, 1, 1), true);
+        let mut watches = Watches::default();
+        let seen = watches.add(Vector3i::new(1, 1, 1), &visible);
+        let hidden = watches.add(Vector3i::new(2, 2, 2), &visible);
+        let outside = watches.add(Vector3i::new(-1, 9, 0), &visible);
+        assert_eq!((seen, hidden, outside), (0, 1, 2));
+        assert!(watches.update(&visible).is_empty());
+
+        visible.set((1, 1, 1), false);
+        visible.set((2, 2, 2), true);
+        assert_eq!(watches.update(&visible), [(seen, false), (hidden, true)]);
+        assert!(watches.update(&visible).is_empty());
+
+        assert!(watches.remove(hidden));
+        assert!(!watches.remove(hidden));
+        visible.set((2, 2, 2), false);
+        assert!(watches.update(&visible).is_empty());
+        assert_eq!(watches.add(Vector3i::new(2, 2, 2), &visible), 3);
+    }
+}